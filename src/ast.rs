@@ -1,4 +1,6 @@
-#[derive(Debug, Clone, Default)]
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Span {
     pub start: usize,
     pub end: usize,
@@ -6,7 +8,7 @@ pub struct Span {
     pub column: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Statement {
     Block {                // { ... }
         statements: Vec<Statement>,
@@ -18,22 +20,39 @@ pub enum Statement {
         else_branch: Option<Box<Statement>>,
         span: Span,
     },
-    While {                // while (condition) { ... }
+    While {                // outer: while (condition) { ... }
+        label: Option<String>,
         condition: Box<Expression>,
         body: Box<Statement>,
         span: Span,
     },
-    For {                  // for (init; condition; increment) { ... }
+    For {                  // outer: for (init; condition; increment) { ... }
+        label: Option<String>,
         init: Option<Box<Statement>>,
         condition: Box<Expression>,
         increment: Option<Box<Statement>>,
         body: Box<Statement>,
         span: Span,
     },
+    ForIn {                // outer: for x in 0..n { ... } or for item in array { ... }
+        label: Option<String>,
+        variable: String,
+        iterable: Iterable,
+        body: Box<Statement>,
+        span: Span,
+    },
     Return {               // return value;
         value: Option<Box<Expression>>,
         span: Span,
     },
+    Break {                // break; or break outer;
+        label: Option<String>,
+        span: Span,
+    },
+    Continue {             // continue; or continue outer;
+        label: Option<String>,
+        span: Span,
+    },
     Expression {           // expression;
         expression: Box<Expression>,
         span: Span,
@@ -44,12 +63,13 @@ pub enum Statement {
         initializer: Option<Box<Expression>>,
         span: Span,
     },
-    // FunctionDeclaration {  // fun foo(x, y) { ... }
-    //     name: String,
-    //     parameters: Vec<String>,
-    //     body: Box<Statement>,
-    //     span: Span,
-    // },
+    FunctionDeclaration {  // fun foo(x: Int, y): Int { ... }
+        name: String,
+        parameters: Vec<Parameter>,
+        return_type: Option<Type>,
+        body: Box<Statement>,
+        span: Span,
+    },
     // FunctionCall {         // foo(x, y);
     //     callee: Box<Expression>,
     //     arguments: Vec<Expression>,
@@ -79,6 +99,24 @@ pub enum Statement {
     // },
 }
 
+// what a for-in loop walks over
+#[derive(Debug, Clone)]
+pub enum Iterable {
+    Range {                // 0..n, or 0..=n with the end included
+        start: Box<Expression>,
+        end: Box<Expression>,
+        inclusive: bool,
+    },
+    Array(Box<Expression>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Parameter {     // x: Int
+    pub name: String,
+    pub type_ann: Option<Type>,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone)]
 pub enum Expression {
     Binary {                    // 5 + 3
@@ -110,6 +148,122 @@ pub enum Expression {
         value: Box<Expression>,
         span: Span,
     },
+    CompoundAssign {           // x += 5, the target is only evaluated once
+        target: Box<Expression>,
+        operator: AssignOp,
+        value: Box<Expression>,
+        span: Span,
+    },
+    Increment {                // ++x or --x, the value is x after the step
+        target: Box<Expression>,
+        operator: IncrementOp,
+        span: Span,
+    },
+    Index {                    // a[i]
+        array: Box<Expression>,
+        index: Box<Expression>,
+        span: Span,
+    },
+    Array {                    // [1, 2, 3]
+        elements: Vec<Expression>,
+        span: Span,
+    },
+    Match {                    // match x { 0 | 1 => a, [first, ..] => b, n if n > 9 => c, _ => d }
+        scrutinee: Box<Expression>,
+        arms: Vec<MatchArm>,
+        span: Span,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {          // pattern if guard => body
+    pub pattern: Pattern,
+    pub guard: Option<Box<Expression>>,
+    pub body: Box<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Wildcard {                 // _
+        span: Span,
+    },
+    Literal {                  // 1, "a", 'c', true
+        value: LiteralValue,
+        span: Span,
+    },
+    Binding {                  // n, binds the whole value
+        name: String,
+        span: Span,
+    },
+    Array {                    // [a, b], or [first, ..] when rest allows more elements
+        elements: Vec<Pattern>,
+        rest: bool,
+        span: Span,
+    },
+    Or {                       // 0 | 1
+        alternatives: Vec<Pattern>,
+        span: Span,
+    },
+}
+
+impl Statement {
+    pub fn span(&self) -> &Span {
+        match self {
+            Statement::Block { span, .. }
+            | Statement::If { span, .. }
+            | Statement::While { span, .. }
+            | Statement::For { span, .. }
+            | Statement::ForIn { span, .. }
+            | Statement::Return { span, .. }
+            | Statement::Break { span, .. }
+            | Statement::Continue { span, .. }
+            | Statement::Expression { span, .. }
+            | Statement::VariableDeclaration { span, .. }
+            | Statement::FunctionDeclaration { span, .. } => span,
+        }
+    }
+}
+
+impl Expression {
+    pub fn span(&self) -> &Span {
+        match self {
+            Expression::Binary { span, .. }
+            | Expression::Unary { span, .. }
+            | Expression::Literal { span, .. }
+            | Expression::Variable { span, .. }
+            | Expression::Call { span, .. }
+            | Expression::Assign { span, .. }
+            | Expression::CompoundAssign { span, .. }
+            | Expression::Increment { span, .. }
+            | Expression::Index { span, .. }
+            | Expression::Array { span, .. }
+            | Expression::Match { span, .. } => span,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn span(&self) -> &Span {
+        match self {
+            Pattern::Wildcard { span }
+            | Pattern::Literal { span, .. }
+            | Pattern::Binding { span, .. }
+            | Pattern::Array { span, .. }
+            | Pattern::Or { span, .. } => span,
+        }
+    }
+
+    // names bound by the pattern in the order they first appear
+    // every alternative of an or-pattern binds the same names, so the first one decides
+    pub fn bindings(&self) -> Vec<&str> {
+        match self {
+            Pattern::Wildcard { .. } | Pattern::Literal { .. } => Vec::new(),
+            Pattern::Binding { name, .. } => vec![name],
+            Pattern::Array { elements, .. } => elements.iter().flat_map(|element| element.bindings()).collect(),
+            Pattern::Or { alternatives, .. } => alternatives.first().map(|first| first.bindings()).unwrap_or_default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -133,6 +287,61 @@ pub enum BinaryOp {
     GreaterEqual,      // >=
     And,               // &&
     Or,                // ||
+    BitAnd,            // &
+    BitOr,             // |
+    BitXor,            // ^
+    ShiftLeft,         // <<
+    ShiftRight,        // >>
+}
+
+// the operators that have an `op=` form, && || and the comparisons have none
+#[derive(Debug, Clone)]
+pub enum AssignOp {
+    Plus,              // +=
+    Minus,             // -=
+    Multiply,          // *=
+    Divide,            // /=
+    Modulo,            // %=
+    BitAnd,            // &=
+    BitOr,             // |=
+    BitXor,            // ^=
+    ShiftLeft,         // <<=
+    ShiftRight,        // >>=
+}
+
+impl AssignOp {
+    // x op= v computes x op v
+    pub fn binary(&self) -> BinaryOp {
+        match self {
+            AssignOp::Plus => BinaryOp::Plus,
+            AssignOp::Minus => BinaryOp::Minus,
+            AssignOp::Multiply => BinaryOp::Multiply,
+            AssignOp::Divide => BinaryOp::Divide,
+            AssignOp::Modulo => BinaryOp::Modulo,
+            AssignOp::BitAnd => BinaryOp::BitAnd,
+            AssignOp::BitOr => BinaryOp::BitOr,
+            AssignOp::BitXor => BinaryOp::BitXor,
+            AssignOp::ShiftLeft => BinaryOp::ShiftLeft,
+            AssignOp::ShiftRight => BinaryOp::ShiftRight,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum IncrementOp {
+    Increment,         // ++
+    Decrement,         // --
+}
+
+impl IncrementOp {
+    // ++x is x += 1 and --x is x -= 1, the 1 takes the span of the whole ++x
+    pub fn lower(&self, span: &Span) -> (AssignOp, Expression) {
+        let operator = match self {
+            IncrementOp::Increment => AssignOp::Plus,
+            IncrementOp::Decrement => AssignOp::Minus,
+        };
+        (operator, Expression::Literal { value: LiteralValue::Integer(1), span: span.clone() })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -143,14 +352,266 @@ pub enum LiteralValue {
     Bool(bool),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Int,
     Float,
     String,
+    Char,
     Bool,
     Void,
     Array(Box<Type>),
     Object(String),
     Function(Vec<Type>, Box<Type>), // (param_types) -> return_type
-}
\ No newline at end of file
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "Int"),
+            Type::Float => write!(f, "Float"),
+            Type::String => write!(f, "String"),
+            Type::Char => write!(f, "Char"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Void => write!(f, "Void"),
+            Type::Array(elem) => write!(f, "[{}]", elem),
+            Type::Object(name) => write!(f, "{}", name),
+            Type::Function(params, ret) => {
+                let params = params.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "fun({}): {}", params.join(", "), ret)
+            }
+        }
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Binary { left, operator, right, .. } => write!(f, "({} {} {})", left, operator, right),
+            Expression::Unary { operator, operand, .. } => write!(f, "{}{}", operator, operand),
+            Expression::Literal { value, .. } => write!(f, "{}", value),
+            Expression::Variable { name, .. } => write!(f, "{}", name),
+            Expression::Call { callee, arguments, .. } => {
+                let arguments = arguments.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+                write!(f, "{}({})", callee, arguments.join(", "))
+            }
+            Expression::Assign { target, value, .. } => write!(f, "{} = {}", target, value),
+            Expression::CompoundAssign { target, operator, value, .. } => write!(f, "{} {}= {}", target, operator.binary(), value),
+            Expression::Increment { target, operator, .. } => write!(f, "{}{}", operator, target),
+            Expression::Index { array, index, .. } => write!(f, "{}[{}]", array, index),
+            Expression::Array { elements, .. } => {
+                let elements = elements.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Expression::Match { scrutinee, arms, .. } => {
+                let arms = arms.iter().map(|arm| match &arm.guard {
+                    Some(guard) => format!("{} if {} => {}", arm.pattern, guard, arm.body),
+                    None => format!("{} => {}", arm.pattern, arm.body),
+                }).collect::<Vec<_>>();
+                write!(f, "match {} {{ {} }}", scrutinee, arms.join(", "))
+            }
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Wildcard { .. } => write!(f, "_"),
+            Pattern::Literal { value, .. } => write!(f, "{}", value),
+            Pattern::Binding { name, .. } => write!(f, "{}", name),
+            Pattern::Array { elements, rest, .. } => {
+                let mut elements = elements.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                if *rest {
+                    elements.push("..".to_string());
+                }
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Pattern::Or { alternatives, .. } => {
+                let alternatives = alternatives.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", alternatives.join(" | "))
+            }
+        }
+    }
+}
+
+impl Display for IncrementOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncrementOp::Increment => write!(f, "++"),
+            IncrementOp::Decrement => write!(f, "--"),
+        }
+    }
+}
+
+impl Display for PrefixOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrefixOp::Not => write!(f, "!"),
+            PrefixOp::Neg => write!(f, "-"),
+        }
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            BinaryOp::Plus => "+",
+            BinaryOp::Minus => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Modulo => "%",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::LessThan => "<",
+            BinaryOp::GreaterThan => ">",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::ShiftLeft => "<<",
+            BinaryOp::ShiftRight => ">>",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl Display for LiteralValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LiteralValue::Integer(n) => write!(f, "{}", n),
+            LiteralValue::String(s) => write!(f, "{:?}", s),
+            LiteralValue::Char(c) => write!(f, "{:?}", c),
+            LiteralValue::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+// builds trees without a parser, every node gets the default span
+// bench uses these for its programs and the tests for theirs
+pub mod build {
+    use super::{AssignOp, BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Parameter, Pattern, Span, Statement};
+
+    pub fn int(n: i64) -> Box<Expression> {
+        Box::new(Expression::Literal { value: LiteralValue::Integer(n), span: Span::default() })
+    }
+
+    #[cfg(test)]
+    pub fn boolean(b: bool) -> Box<Expression> {
+        Box::new(Expression::Literal { value: LiteralValue::Bool(b), span: Span::default() })
+    }
+
+    #[cfg(test)]
+    pub fn unary(operator: super::PrefixOp, operand: Box<Expression>) -> Box<Expression> {
+        Box::new(Expression::Unary { operator, operand, span: Span::default() })
+    }
+
+    pub fn var(name: &str) -> Box<Expression> {
+        Box::new(Expression::Variable { name: name.to_string(), span: Span::default() })
+    }
+
+    pub fn bin(left: Box<Expression>, operator: BinaryOp, right: Box<Expression>) -> Box<Expression> {
+        Box::new(Expression::Binary { left, operator, right, span: Span::default() })
+    }
+
+    pub fn call(name: &str, arguments: Vec<Expression>) -> Box<Expression> {
+        Box::new(Expression::Call { callee: var(name), arguments, span: Span::default() })
+    }
+
+    #[cfg(test)]
+    pub fn index(array: Box<Expression>, index: Box<Expression>) -> Box<Expression> {
+        Box::new(Expression::Index { array, index, span: Span::default() })
+    }
+
+    #[cfg(test)]
+    pub fn array(elements: Vec<Expression>) -> Box<Expression> {
+        Box::new(Expression::Array { elements, span: Span::default() })
+    }
+
+    #[cfg(test)]
+    pub fn assign(target: Box<Expression>, value: Box<Expression>) -> Box<Expression> {
+        Box::new(Expression::Assign { target, value, span: Span::default() })
+    }
+
+    pub fn compound(target: Box<Expression>, operator: AssignOp, value: Box<Expression>) -> Box<Expression> {
+        Box::new(Expression::CompoundAssign { target, operator, value, span: Span::default() })
+    }
+
+    #[cfg(test)]
+    pub fn increment(target: Box<Expression>, operator: super::IncrementOp) -> Box<Expression> {
+        Box::new(Expression::Increment { target, operator, span: Span::default() })
+    }
+
+    pub fn match_on(scrutinee: Box<Expression>, arms: Vec<MatchArm>) -> Box<Expression> {
+        Box::new(Expression::Match { scrutinee, arms, span: Span::default() })
+    }
+
+    pub fn arm(pattern: Pattern, guard: Option<Box<Expression>>, body: Box<Expression>) -> MatchArm {
+        MatchArm { pattern, guard, body, span: Span::default() }
+    }
+
+    pub fn int_pattern(n: i64) -> Pattern {
+        Pattern::Literal { value: LiteralValue::Integer(n), span: Span::default() }
+    }
+
+    pub fn binding(name: &str) -> Pattern {
+        Pattern::Binding { name: name.to_string(), span: Span::default() }
+    }
+
+    pub fn wildcard() -> Pattern {
+        Pattern::Wildcard { span: Span::default() }
+    }
+
+    #[cfg(test)]
+    pub fn bool_pattern(b: bool) -> Pattern {
+        Pattern::Literal { value: LiteralValue::Bool(b), span: Span::default() }
+    }
+
+    #[cfg(test)]
+    pub fn array_pattern(elements: Vec<Pattern>, rest: bool) -> Pattern {
+        Pattern::Array { elements, rest, span: Span::default() }
+    }
+
+    pub fn or(alternatives: Vec<Pattern>) -> Pattern {
+        Pattern::Or { alternatives, span: Span::default() }
+    }
+
+    pub fn expr(expression: Box<Expression>) -> Statement {
+        Statement::Expression { expression, span: Span::default() }
+    }
+
+    pub fn decl(name: &str, initializer: Box<Expression>) -> Statement {
+        Statement::VariableDeclaration { name: name.to_string(), type_ann: None, initializer: Some(initializer), span: Span::default() }
+    }
+
+    pub fn block(statements: Vec<Statement>) -> Box<Statement> {
+        Box::new(Statement::Block { statements, span: Span::default() })
+    }
+
+    pub fn ret(value: Box<Expression>) -> Statement {
+        Statement::Return { value: Some(value), span: Span::default() }
+    }
+
+    pub fn if_then(condition: Box<Expression>, then_branch: Vec<Statement>) -> Statement {
+        Statement::If { condition, then_branch: block(then_branch), else_branch: None, span: Span::default() }
+    }
+
+    // for variable in 0..end { body }
+    pub fn range(variable: &str, end: Box<Expression>, body: Vec<Statement>) -> Statement {
+        let iterable = Iterable::Range { start: int(0), end, inclusive: false };
+        Statement::ForIn { label: None, variable: variable.to_string(), iterable, body: block(body), span: Span::default() }
+    }
+
+    pub fn function(name: &str, parameters: &[&str], body: Vec<Statement>) -> Statement {
+        Statement::FunctionDeclaration {
+            name: name.to_string(),
+            parameters: parameters.iter().map(|name| Parameter { name: name.to_string(), type_ann: None, span: Span::default() }).collect(),
+            return_type: None,
+            body: block(body),
+            span: Span::default(),
+        }
+    }
+}
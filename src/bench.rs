@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use crate::{ast::{build::{arm, bin, binding, call, compound, decl, expr, function, if_then, int, int_pattern, match_on, or, range, ret, var, wildcard}, AssignOp, BinaryOp, Statement}, codegen::{CodeGen, Object}, peephole::Peephole, regcodegen::RegisterCodeGen, regvm::RegisterVM, vm::VM};
+
+// runs one program on the stack vm and the register vm and times both
+// the results are compared too, so the program should end with an expression statement
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub result: Object,
+    pub stack: Duration, // fastest of the runs, compiling isn't counted
+    pub register: Duration,
+    pub stack_instructions: usize,
+    pub register_instructions: usize,
+}
+
+// the stack vm gets the peephole pass too, so this compares the vms and not the compilers
+pub fn compare(program: Vec<Statement>, runs: usize) -> Result<Comparison, String> {
+    let mut codegen = CodeGen::new();
+    codegen.compile(program.clone())?;
+    let (bytecode, _) = Peephole::optimize(codegen.bytecode());
+    let mut regcodegen = RegisterCodeGen::new();
+    regcodegen.compile(program)?;
+    let register_program = regcodegen.program();
+    // functions are counted too
+    let functions = bytecode.constants.iter().filter_map(|constant| match constant {
+        Object::Function(function) => Some(function),
+        _ => None,
+    });
+
+    let mut comparison = Comparison {
+        result: Object::Null,
+        stack: Duration::MAX,
+        register: Duration::MAX,
+        stack_instructions: bytecode.instructions.len() + functions.map(|function| function.instructions.len()).sum::<usize>(),
+        register_instructions: register_program.instructions.len() + register_program.functions.iter().map(|function| function.instructions.len()).sum::<usize>(),
+    };
+    for _ in 0..runs.max(1) {
+        let mut vm = VM::new(bytecode.clone())?;
+        let start = Instant::now();
+        vm.run().map_err(|e| e.to_string())?;
+        comparison.stack = comparison.stack.min(start.elapsed());
+        comparison.result = vm.last_popped().clone();
+
+        let mut vm = RegisterVM::new(register_program.clone());
+        let start = Instant::now();
+        vm.run()?;
+        comparison.register = comparison.register.min(start.elapsed());
+        if *vm.result() != comparison.result {
+            return Err(format!("the stack vm left {:?} but the register vm left {:?}", comparison.result, vm.result()));
+        }
+    }
+    Ok(comparison)
+}
+
+// the built-in programs, the parser is a stub so these are written as trees
+// size scales the work, the tests use small sizes and `bench` a large one
+pub fn suite(size: i64) -> Vec<(&'static str, Vec<Statement>)> {
+    vec![("nested loops", nested_loops(size)), ("recursive fib", fib(size.min(25))), ("match", classify(size))]
+}
+
+// let s = 0; for i in 0..n { for j in 0..n { s += i * j % 7; } } s;
+fn nested_loops(n: i64) -> Vec<Statement> {
+    let term = bin(bin(var("i"), BinaryOp::Multiply, var("j")), BinaryOp::Modulo, int(7));
+    let add = expr(compound(var("s"), AssignOp::Plus, term));
+    vec![decl("s", int(0)), range("i", int(n), vec![range("j", int(n), vec![add])]), expr(var("s"))]
+}
+
+// fun fib(n) { if n < 2 { ret n; } ret fib(n - 1) + fib(n - 2); } fib(n);
+fn fib(n: i64) -> Vec<Statement> {
+    let recurse = |k: i64| call("fib", vec![*bin(var("n"), BinaryOp::Minus, int(k))]);
+    let body = vec![
+        if_then(bin(var("n"), BinaryOp::LessThan, int(2)), vec![ret(var("n"))]),
+        ret(bin(recurse(1), BinaryOp::Plus, recurse(2))),
+    ];
+    vec![function("fib", &["n"], body), expr(call("fib", vec![*int(n)]))]
+}
+
+// fun classify(x) { ret match x % 5 { 0 | 1 => 1, 2 => 10, k if k > 3 => 100, _ => 1000 }; }
+// let s = 0; for i in 0..n * n { s += classify(i); } s;
+fn classify(n: i64) -> Vec<Statement> {
+    let arms = vec![
+        arm(or(vec![int_pattern(0), int_pattern(1)]), None, int(1)),
+        arm(int_pattern(2), None, int(10)),
+        arm(binding("k"), Some(bin(var("k"), BinaryOp::GreaterThan, int(3))), int(100)),
+        arm(wildcard(), None, int(1000)),
+    ];
+    let add = expr(compound(var("s"), AssignOp::Plus, call("classify", vec![*var("i")])));
+    vec![
+        function("classify", &["x"], vec![ret(match_on(bin(var("x"), BinaryOp::Modulo, int(5)), arms))]),
+        decl("s", int(0)),
+        range("i", bin(int(n), BinaryOp::Multiply, int(n)), vec![add]),
+        expr(var("s")),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let comparison = compare(nested_loops(10), 1).unwrap();
+        assert_eq!(comparison.result, Object::Integer((0..10).flat_map(|i| (0..10).map(move |j| i * j % 7)).sum()));
+        assert!(comparison.register_instructions < comparison.stack_instructions);
+    }
+
+    #[test]
+    fn test_suite() {
+        let results = suite(10).into_iter().map(|(_, program)| compare(program, 1).map(|comparison| comparison.result)).collect::<Vec<_>>();
+        // 100 numbers: 40 of them are 0 or 1 mod 5, 20 are 2, 20 are 4 and 20 are 3
+        assert_eq!(results[1..], [Ok(Object::Integer(55)), Ok(Object::Integer(40 + 200 + 2000 + 20000))]);
+    }
+
+    // cargo test --release bench -- --ignored --nocapture, or rust-compiler bench
+    #[test]
+    #[ignore]
+    fn bench_suite() {
+        for (name, program) in suite(1000) {
+            let comparison = compare(program, 5).unwrap();
+            println!("{}: stack vm {:?}, register vm {:?}", name, comparison.stack, comparison.register);
+        }
+    }
+}
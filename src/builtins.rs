@@ -0,0 +1,34 @@
+use crate::codegen::Object;
+
+// functions every program can call without defining them
+// the symbol table puts their names in the outermost scope, GetBuiltin and the vm find them by index here
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: usize,
+    pub run: fn(&[Object]) -> Result<Object, String>,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin { name: "len", arity: 1, run: len },
+    Builtin { name: "push", arity: 2, run: push },
+];
+
+fn len(arguments: &[Object]) -> Result<Object, String> {
+    match arguments {
+        [Object::Array(elements)] => Ok(Object::Integer(elements.len() as i64)),
+        [Object::String(s)] => Ok(Object::Integer(s.chars().count() as i64)),
+        other => Err(format!("len cannot be applied to {:?}", other)),
+    }
+}
+
+// arrays are values, the array passed in stays as it was
+fn push(arguments: &[Object]) -> Result<Object, String> {
+    match arguments {
+        [Object::Array(elements), value] => {
+            let mut elements = elements.clone();
+            elements.push(value.clone());
+            Ok(Object::Array(elements))
+        }
+        other => Err(format!("push cannot be applied to {:?}", other)),
+    }
+}
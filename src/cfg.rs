@@ -0,0 +1,390 @@
+use std::fmt::Write;
+
+use crate::ast::{Expression, Iterable, Statement};
+
+pub type BlockId = usize;
+
+// where break and continue jump to inside one loop
+struct LoopTargets<'a> {
+    label: Option<&'a str>,
+    next: BlockId,  // the header of a while, the increment of a for
+    after: BlockId,
+}
+
+// how control leaves a basic block
+#[derive(Debug, Clone)]
+pub enum Terminator<'a> {
+    Goto(BlockId),
+    Branch {
+        condition: &'a Expression,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    // header of a for-in loop: binds the next element and enters the body, or leaves when there is none
+    Iterate {
+        variable: &'a str,
+        iterable: &'a Iterable,
+        body_block: BlockId,
+        after: BlockId,
+    },
+    Return {
+        value: Option<&'a Expression>,
+    },
+    Exit, // only the exit block ends like this
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock<'a> {
+    pub id: BlockId,
+    // straight-line statements only: expressions, declarations and nested functions
+    pub statements: Vec<&'a Statement>,
+    pub terminator: Terminator<'a>,
+    pub successors: Vec<BlockId>,
+    pub predecessors: Vec<BlockId>,
+}
+
+// control-flow graph of one function body (or the top level of a script)
+// every ret jumps to the exit block, and so does falling off the end
+#[derive(Debug)]
+pub struct Cfg<'a> {
+    pub blocks: Vec<BasicBlock<'a>>,
+    pub entry: BlockId,
+    pub exit: BlockId,
+}
+
+impl<'a> Cfg<'a> {
+    pub fn build(statements: &'a [Statement]) -> Self {
+        let mut cfg = Cfg { blocks: Vec::new(), entry: 0, exit: 1 };
+        let entry = cfg.new_block();
+        let exit = cfg.new_block();
+        cfg.blocks[exit].terminator = Terminator::Exit;
+
+        // the last block falls through to the exit, new_block already set that up
+        let mut current = entry;
+        let mut loops = Vec::new();
+        for stmt in statements {
+            current = cfg.lower(stmt, current, &mut loops);
+        }
+
+        cfg.link();
+        cfg
+    }
+
+    pub fn block(&self, id: BlockId) -> &BasicBlock<'a> {
+        &self.blocks[id]
+    }
+
+    // blocks reachable from the entry, in the order a depth first walk finds them
+    pub fn reachable(&self) -> Vec<BlockId> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        let mut stack = vec![self.entry];
+        while let Some(id) = stack.pop() {
+            if std::mem::replace(&mut seen[id], true) {
+                continue;
+            }
+            order.push(id);
+            stack.extend(self.blocks[id].successors.iter().rev());
+        }
+        order
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        let id = self.blocks.len();
+        self.blocks.push(BasicBlock {
+            id,
+            statements: Vec::new(),
+            terminator: Terminator::Goto(self.exit),
+            successors: Vec::new(),
+            predecessors: Vec::new(),
+        });
+        id
+    }
+
+    fn goto(&mut self, from: BlockId, to: BlockId) {
+        self.blocks[from].terminator = Terminator::Goto(to);
+    }
+
+    // lowers stmt into `current` and returns the block control continues in
+    // `loops` are the enclosing loops, innermost last, for break and continue to find their target
+    fn lower(&mut self, stmt: &'a Statement, current: BlockId, loops: &mut Vec<LoopTargets<'a>>) -> BlockId {
+        match stmt {
+            Statement::Block { statements, .. } => {
+                statements.iter().fold(current, |block, stmt| self.lower(stmt, block, loops))
+            }
+            Statement::If { condition, then_branch, else_branch, .. } => {
+                let then_block = self.new_block();
+                let join = self.new_block();
+                let else_block = match else_branch {
+                    Some(_) => self.new_block(),
+                    None => join,
+                };
+                self.blocks[current].terminator = Terminator::Branch { condition, then_block, else_block };
+
+                let then_end = self.lower(then_branch, then_block, loops);
+                self.goto(then_end, join);
+                if let Some(else_branch) = else_branch {
+                    let else_end = self.lower(else_branch, else_block, loops);
+                    self.goto(else_end, join);
+                }
+                join
+            }
+            Statement::While { label, condition, body, .. } => {
+                let header = self.new_block();
+                let body_block = self.new_block();
+                let after = self.new_block();
+                self.goto(current, header);
+                self.blocks[header].terminator = Terminator::Branch { condition, then_block: body_block, else_block: after };
+
+                loops.push(LoopTargets { label: label.as_deref(), next: header, after });
+                let body_end = self.lower(body, body_block, loops);
+                loops.pop();
+                self.goto(body_end, header);
+                after
+            }
+            Statement::For { label, init, condition, increment, body, .. } => {
+                let current = match init {
+                    Some(init) => self.lower(init, current, loops),
+                    None => current,
+                };
+                let header = self.new_block();
+                let body_block = self.new_block();
+                let latch = self.new_block(); // runs the increment, back edge to the header
+                let after = self.new_block();
+                self.goto(current, header);
+                self.blocks[header].terminator = Terminator::Branch { condition, then_block: body_block, else_block: after };
+
+                loops.push(LoopTargets { label: label.as_deref(), next: latch, after });
+                let body_end = self.lower(body, body_block, loops);
+                loops.pop();
+                self.goto(body_end, latch);
+                let latch_end = match increment {
+                    Some(increment) => self.lower(increment, latch, loops),
+                    None => latch,
+                };
+                self.goto(latch_end, header);
+                after
+            }
+            Statement::ForIn { label, variable, iterable, body, .. } => {
+                let header = self.new_block();
+                let body_block = self.new_block();
+                let after = self.new_block();
+                self.goto(current, header);
+                self.blocks[header].terminator = Terminator::Iterate { variable, iterable, body_block, after };
+
+                loops.push(LoopTargets { label: label.as_deref(), next: header, after });
+                let body_end = self.lower(body, body_block, loops);
+                loops.pop();
+                self.goto(body_end, header);
+                after
+            }
+            Statement::Return { value, .. } => {
+                self.blocks[current].terminator = Terminator::Return { value: value.as_deref() };
+                // whatever follows is unreachable, it gets a block without predecessors
+                self.new_block()
+            }
+            Statement::Break { label, .. } | Statement::Continue { label, .. } => {
+                // a jump to no loop at all is the compiler's error to report, here it just falls through
+                let found = loops.iter().rev().find(|l| label.is_none() || l.label == label.as_deref());
+                let Some(targets) = found else { return current };
+                let to = if matches!(stmt, Statement::Break { .. }) { targets.after } else { targets.next };
+                self.goto(current, to);
+                self.new_block()
+            }
+            Statement::Expression { .. } | Statement::VariableDeclaration { .. } | Statement::FunctionDeclaration { .. } => {
+                self.blocks[current].statements.push(stmt);
+                current
+            }
+        }
+    }
+
+    // fill successor and predecessor lists from the terminators
+    // edges leaving unreachable code don't count as predecessors, so analyses can ignore it
+    fn link(&mut self) {
+        for id in 0..self.blocks.len() {
+            self.blocks[id].successors = match &self.blocks[id].terminator {
+                Terminator::Goto(to) => vec![*to],
+                Terminator::Branch { then_block, else_block, .. } if then_block == else_block => vec![*then_block],
+                Terminator::Branch { then_block, else_block, .. } => vec![*then_block, *else_block],
+                Terminator::Iterate { body_block, after, .. } => vec![*body_block, *after],
+                Terminator::Return { .. } => vec![self.exit],
+                Terminator::Exit => vec![],
+            };
+        }
+
+        let mut reachable = self.reachable();
+        reachable.sort();
+        for id in reachable {
+            for to in self.blocks[id].successors.clone() {
+                self.blocks[to].predecessors.push(id);
+            }
+        }
+    }
+
+    // graphviz, e.g. `dot -Tsvg`
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph \"{}\" {{", escape(name)).unwrap();
+        writeln!(out, "    node [shape=box, fontname=monospace];").unwrap();
+
+        let reachable = self.reachable();
+        for block in &self.blocks {
+            // unreachable blocks that ended up empty are just noise
+            if !reachable.contains(&block.id) && block.statements.is_empty() {
+                continue;
+            }
+
+            let mut label = match block.id {
+                id if id == self.entry => "entry\\l".to_string(),
+                id if id == self.exit => "exit\\l".to_string(),
+                id => format!("b{}\\l", id),
+            };
+            for stmt in &block.statements {
+                label.push_str(&escape(&describe(stmt)));
+                label.push_str("\\l");
+            }
+            match &block.terminator {
+                Terminator::Branch { condition, .. } => label.push_str(&format!("if {}\\l", escape(&condition.to_string()))),
+                Terminator::Iterate { variable, iterable, .. } => {
+                    label.push_str(&format!("for {} in {}\\l", variable, escape(&describe_iterable(iterable))));
+                }
+                Terminator::Return { value: Some(value), .. } => label.push_str(&format!("ret {}\\l", escape(&value.to_string()))),
+                Terminator::Return { value: None, .. } => label.push_str("ret\\l"),
+                Terminator::Goto(_) | Terminator::Exit => {}
+            }
+            let style = if reachable.contains(&block.id) { "" } else { ", style=dashed" };
+            writeln!(out, "    b{} [label=\"{}\"{}];", block.id, label, style).unwrap();
+        }
+
+        for block in &self.blocks {
+            if !reachable.contains(&block.id) && block.statements.is_empty() {
+                continue;
+            }
+            match &block.terminator {
+                Terminator::Branch { then_block, else_block, .. } => {
+                    writeln!(out, "    b{} -> b{} [label=\"true\"];", block.id, then_block).unwrap();
+                    writeln!(out, "    b{} -> b{} [label=\"false\"];", block.id, else_block).unwrap();
+                }
+                Terminator::Iterate { body_block, after, .. } => {
+                    writeln!(out, "    b{} -> b{} [label=\"next\"];", block.id, body_block).unwrap();
+                    writeln!(out, "    b{} -> b{} [label=\"done\"];", block.id, after).unwrap();
+                }
+                _ => {
+                    for to in &block.successors {
+                        writeln!(out, "    b{} -> b{};", block.id, to).unwrap();
+                    }
+                }
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn describe(stmt: &Statement) -> String {
+    match stmt {
+        Statement::Expression { expression, .. } => expression.to_string(),
+        Statement::VariableDeclaration { name, initializer: Some(init), .. } => format!("def {} = {}", name, init),
+        Statement::VariableDeclaration { name, initializer: None, .. } => format!("def {}", name),
+        Statement::FunctionDeclaration { name, parameters, .. } => {
+            let parameters = parameters.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+            format!("fun {}({})", name, parameters.join(", "))
+        }
+        _ => unreachable!("control flow statements are lowered into edges"),
+    }
+}
+
+fn describe_iterable(iterable: &Iterable) -> String {
+    match iterable {
+        Iterable::Range { start, end, inclusive: false } => format!("{}..{}", start, end),
+        Iterable::Range { start, end, inclusive: true } => format!("{}..={}", start, end),
+        Iterable::Array(array) => array.to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{build::*, BinaryOp, Span};
+
+    use super::*;
+
+    #[test]
+    fn test_if_else_diamond() {
+        // if (c) { ret 1; } else { a; } b;
+        let program = vec![
+            Statement::If {
+                condition: var("c"),
+                then_branch: Box::new(ret(int(1))),
+                else_branch: Some(Box::new(expr(var("a")))),
+                span: Span::default(),
+            },
+            expr(var("b")),
+        ];
+        let cfg = Cfg::build(&program);
+
+        let entry = cfg.block(cfg.entry);
+        let [then_block, else_block] = entry.successors[..] else { panic!("expected two successors") };
+        assert_eq!(cfg.block(then_block).successors, vec![cfg.exit]);
+
+        let join = cfg.block(else_block).successors[0];
+        assert_eq!(cfg.block(join).predecessors, vec![else_block]);
+        assert_eq!(cfg.block(join).statements.len(), 1);
+        assert_eq!(cfg.block(join).successors, vec![cfg.exit]);
+
+        let mut exit_preds = cfg.block(cfg.exit).predecessors.clone();
+        exit_preds.sort();
+        assert_eq!(exit_preds, vec![then_block, join]);
+    }
+
+    #[test]
+    fn test_loop_back_edges() {
+        // while (i < 3) { i; }  for (; c; i) { ret i; }
+        let program = vec![
+            Statement::While {
+                label: None,
+                condition: bin(var("i"), BinaryOp::LessThan, int(3)),
+                body: Box::new(expr(var("i"))),
+                span: Span::default(),
+            },
+            Statement::For {
+                label: None,
+                init: None,
+                condition: var("c"),
+                increment: Some(Box::new(expr(var("i")))),
+                body: Box::new(ret(var("i"))),
+                span: Span::default(),
+            },
+        ];
+        let cfg = Cfg::build(&program);
+
+        let header = cfg.block(cfg.entry).successors[0];
+        let body = cfg.block(header).successors[0];
+        assert_eq!(cfg.block(body).successors, vec![header]);
+        assert_eq!(cfg.block(header).predecessors, vec![cfg.entry, body]);
+
+        // the body always returns so the for loop's latch is unreachable
+        let reachable = cfg.reachable();
+        let latch = cfg.blocks.iter().find(|b| b.statements.len() == 1 && b.successors.len() == 1 && b.id != body).unwrap();
+        assert!(!reachable.contains(&latch.id));
+    }
+
+    #[test]
+    fn test_dot_output() {
+        let program = vec![
+            decl("x", int(1)),
+            Statement::If { condition: var("x"), then_branch: Box::new(ret(var("x"))), else_branch: None, span: Span::default() },
+        ];
+        let dot = Cfg::build(&program).to_dot("main");
+
+        assert!(dot.starts_with("digraph \"main\" {"));
+        assert!(dot.contains("b0 [label=\"entry\\ldef x = 1\\lif x\\l\"];"));
+        assert!(dot.contains("b0 -> b2 [label=\"true\"];"));
+        assert!(dot.contains("b2 -> b1;"));
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+use crate::codegen::Instruction;
+
+// the byte encoding of Instruction that the vm runs and files store
+// every instruction is a one byte opcode followed by its operands, big endian
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    LoadConstant,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    LessEqual,
+    GreaterEqual,
+    And,
+    Or,
+    Not,
+    Neg,
+    True,
+    False,
+    Null,
+    Dup,
+    Jump,
+    JumpNotTruthy,
+    JumpTruthy,
+    SetGlobal,
+    GetGlobal,
+    SetLocal,
+    GetLocal,
+    Call,
+    Return,
+    Pop,
+    Array,
+    Index,
+    SetIndex,
+    Length,
+    JumpTable,
+    CurrentFunction,
+    GetBuiltin,
+}
+
+// in the order of the discriminants, so a byte indexes straight into it
+const OPCODES: [Opcode; 42] = [
+    Opcode::LoadConstant, Opcode::Add, Opcode::Sub, Opcode::Mul, Opcode::Div, Opcode::Mod,
+    Opcode::BitAnd, Opcode::BitOr, Opcode::BitXor, Opcode::ShiftLeft, Opcode::ShiftRight,
+    Opcode::Equal, Opcode::NotEqual, Opcode::LessThan, Opcode::GreaterThan, Opcode::LessEqual, Opcode::GreaterEqual,
+    Opcode::And, Opcode::Or, Opcode::Not, Opcode::Neg, Opcode::True, Opcode::False, Opcode::Null, Opcode::Dup,
+    Opcode::Jump, Opcode::JumpNotTruthy, Opcode::JumpTruthy, Opcode::SetGlobal, Opcode::GetGlobal, Opcode::SetLocal, Opcode::GetLocal,
+    Opcode::Call, Opcode::Return, Opcode::Pop, Opcode::Array, Opcode::Index, Opcode::SetIndex, Opcode::Length,
+    Opcode::JumpTable, Opcode::CurrentFunction, Opcode::GetBuiltin,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    Byte,   // u8
+    Short,  // u16
+    Varint, // zigzag leb128, any i64
+    Shorts, // an unsigned leb128 count, then that many u16
+}
+
+pub struct Definition {
+    pub name: &'static str,
+    pub operands: &'static [Width],
+}
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    pub fn definition(self) -> Definition {
+        let operands: &'static [Width] = match self {
+            Opcode::LoadConstant | Opcode::Jump | Opcode::JumpNotTruthy | Opcode::JumpTruthy
+            | Opcode::SetGlobal | Opcode::GetGlobal | Opcode::Array => &[Width::Short],
+            Opcode::SetLocal | Opcode::GetLocal | Opcode::Call | Opcode::GetBuiltin => &[Width::Byte],
+            // low, default, targets
+            Opcode::JumpTable => &[Width::Varint, Width::Short, Width::Shorts],
+            _ => &[],
+        };
+        let name = match self {
+            Opcode::LoadConstant => "LoadConstant",
+            Opcode::Add => "Add",
+            Opcode::Sub => "Sub",
+            Opcode::Mul => "Mul",
+            Opcode::Div => "Div",
+            Opcode::Mod => "Mod",
+            Opcode::BitAnd => "BitAnd",
+            Opcode::BitOr => "BitOr",
+            Opcode::BitXor => "BitXor",
+            Opcode::ShiftLeft => "ShiftLeft",
+            Opcode::ShiftRight => "ShiftRight",
+            Opcode::Equal => "Equal",
+            Opcode::NotEqual => "NotEqual",
+            Opcode::LessThan => "LessThan",
+            Opcode::GreaterThan => "GreaterThan",
+            Opcode::LessEqual => "LessEqual",
+            Opcode::GreaterEqual => "GreaterEqual",
+            Opcode::And => "And",
+            Opcode::Or => "Or",
+            Opcode::Not => "Not",
+            Opcode::Neg => "Neg",
+            Opcode::True => "True",
+            Opcode::False => "False",
+            Opcode::Null => "Null",
+            Opcode::Dup => "Dup",
+            Opcode::Jump => "Jump",
+            Opcode::JumpNotTruthy => "JumpNotTruthy",
+            Opcode::JumpTruthy => "JumpTruthy",
+            Opcode::SetGlobal => "SetGlobal",
+            Opcode::GetGlobal => "GetGlobal",
+            Opcode::SetLocal => "SetLocal",
+            Opcode::GetLocal => "GetLocal",
+            Opcode::Call => "Call",
+            Opcode::Return => "Return",
+            Opcode::Pop => "Pop",
+            Opcode::Array => "Array",
+            Opcode::Index => "Index",
+            Opcode::SetIndex => "SetIndex",
+            Opcode::Length => "Length",
+            Opcode::JumpTable => "JumpTable",
+            Opcode::CurrentFunction => "CurrentFunction",
+            Opcode::GetBuiltin => "GetBuiltin",
+        };
+        Definition { name, operands }
+    }
+}
+
+// operands are flat, a Shorts operand is its count followed by the values
+pub fn make(op: Opcode, operands: &[i64]) -> Result<Vec<u8>, String> {
+    let definition = op.definition();
+    let mut bytes = vec![op as u8];
+    let mut rest = operands;
+    for width in definition.operands {
+        let Some((&operand, tail)) = rest.split_first() else {
+            return Err(format!("{} is missing operands", definition.name));
+        };
+        rest = tail;
+        match width {
+            Width::Byte => bytes.push(fit::<u8>(definition.name, operand)?),
+            Width::Short => bytes.extend(fit::<u16>(definition.name, operand)?.to_be_bytes()),
+            Width::Varint => write_varint(&mut bytes, ((operand << 1) ^ (operand >> 63)) as u64),
+            Width::Shorts => {
+                let count = usize::try_from(operand).ok().filter(|&count| count <= rest.len());
+                let Some(count) = count else {
+                    return Err(format!("{} expects {} more operands", definition.name, operand));
+                };
+                write_varint(&mut bytes, count as u64);
+                for &value in &rest[..count] {
+                    bytes.extend(fit::<u16>(definition.name, value)?.to_be_bytes());
+                }
+                rest = &rest[count..];
+            }
+        }
+    }
+    if !rest.is_empty() {
+        return Err(format!("{} got {} operands too many", definition.name, rest.len()));
+    }
+    Ok(bytes)
+}
+
+// reads the operands right after an opcode, returns them and how many bytes they took
+pub fn read_operands(definition: &Definition, bytes: &[u8]) -> Result<(Vec<i64>, usize), String> {
+    let mut operands = Vec::new();
+    let mut offset = 0;
+    for width in definition.operands {
+        match width {
+            Width::Byte => {
+                operands.push(*bytes.get(offset).ok_or_else(|| truncated(definition))? as i64);
+                offset += 1;
+            }
+            Width::Short => {
+                operands.push(read_u16(bytes, offset).ok_or_else(|| truncated(definition))? as i64);
+                offset += 2;
+            }
+            Width::Varint => {
+                let (value, size) = read_zigzag(&bytes[offset..]).ok_or_else(|| truncated(definition))?;
+                operands.push(value);
+                offset += size;
+            }
+            Width::Shorts => {
+                let (count, size) = read_varint(&bytes[offset..]).ok_or_else(|| truncated(definition))?;
+                offset += size;
+                operands.push(count as i64);
+                for _ in 0..count {
+                    operands.push(read_u16(bytes, offset).ok_or_else(|| truncated(definition))? as i64);
+                    offset += 2;
+                }
+            }
+        }
+    }
+    Ok((operands, offset))
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let pair = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([pair[0], pair[1]]))
+}
+
+// returns the value and how many bytes it took, None when the input ends in the middle
+pub fn read_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        // the tenth byte only has room for the top bit
+        if i == 9 && *byte > 1 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+// a varint with the sign in its lowest bit, so small negative numbers stay short
+pub fn read_zigzag(bytes: &[u8]) -> Option<(i64, usize)> {
+    let (value, size) = read_varint(bytes)?;
+    Some((((value >> 1) as i64) ^ -((value & 1) as i64), size))
+}
+
+pub fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn fit<T: TryFrom<i64>>(name: &str, operand: i64) -> Result<T, String> {
+    T::try_from(operand).map_err(|_| format!("operand {} of {} doesn't fit in {} bytes", operand, name, std::mem::size_of::<T>()))
+}
+
+fn truncated(definition: &Definition) -> String {
+    format!("{} is cut off in the middle of its operands", definition.name)
+}
+
+// jump targets are instruction indices in Instruction and byte offsets once encoded
+pub fn encode(instructions: &[Instruction]) -> Result<Vec<u8>, String> {
+    let offsets = offsets(instructions)?;
+    let size = offsets[instructions.len()];
+    let offset = |target: usize| offsets.get(target).map(|&o| o as i64).ok_or_else(|| format!("jump to {} is past the end", target));
+    let mut bytes = Vec::with_capacity(size);
+    for instruction in instructions {
+        let (op, mut operands) = split(instruction);
+        match instruction {
+            Instruction::Jump(target) | Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => operands[0] = offset(*target)?,
+            Instruction::JumpTable { targets, default, .. } => {
+                operands[1] = offset(*default)?;
+                for (operand, target) in operands[3..].iter_mut().zip(targets) {
+                    *operand = offset(*target)?;
+                }
+            }
+            _ => {}
+        }
+        bytes.extend(make(op, &operands)?);
+    }
+    Ok(bytes)
+}
+
+// the byte offset each instruction will be encoded at, plus the total size at the end
+// jump targets are always Shorts, so each size is known before the targets are
+pub fn offsets(instructions: &[Instruction]) -> Result<Vec<usize>, String> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut size = 0;
+    for instruction in instructions {
+        offsets.push(size);
+        let (op, operands) = split(instruction);
+        size += make(op, &operands)?.len();
+    }
+    offsets.push(size);
+    Ok(offsets)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, String> {
+    let mut decoded = Vec::new();
+    let mut indices = HashMap::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let op = Opcode::from_byte(bytes[offset]).ok_or_else(|| format!("unknown opcode {} at {}", bytes[offset], offset))?;
+        let (operands, size) = read_operands(&op.definition(), &bytes[offset + 1..])?;
+        indices.insert(offset, decoded.len());
+        decoded.push((op, operands));
+        offset += 1 + size;
+    }
+    indices.insert(bytes.len(), decoded.len());
+
+    let index = |target: i64| {
+        usize::try_from(target).ok().and_then(|t| indices.get(&t).copied())
+            .ok_or_else(|| format!("jump to {} is not the start of an instruction", target))
+    };
+    let operand = |operands: &[i64]| operands[0] as usize;
+    decoded.iter().map(|(op, operands)| Ok(match op {
+        Opcode::LoadConstant => Instruction::LoadConstant(operand(operands)),
+        Opcode::Add => Instruction::Add,
+        Opcode::Sub => Instruction::Sub,
+        Opcode::Mul => Instruction::Mul,
+        Opcode::Div => Instruction::Div,
+        Opcode::Mod => Instruction::Mod,
+        Opcode::BitAnd => Instruction::BitAnd,
+        Opcode::BitOr => Instruction::BitOr,
+        Opcode::BitXor => Instruction::BitXor,
+        Opcode::ShiftLeft => Instruction::ShiftLeft,
+        Opcode::ShiftRight => Instruction::ShiftRight,
+        Opcode::Equal => Instruction::Equal,
+        Opcode::NotEqual => Instruction::NotEqual,
+        Opcode::LessThan => Instruction::LessThan,
+        Opcode::GreaterThan => Instruction::GreaterThan,
+        Opcode::LessEqual => Instruction::LessEqual,
+        Opcode::GreaterEqual => Instruction::GreaterEqual,
+        Opcode::And => Instruction::And,
+        Opcode::Or => Instruction::Or,
+        Opcode::Not => Instruction::Not,
+        Opcode::Neg => Instruction::Neg,
+        Opcode::True => Instruction::True,
+        Opcode::False => Instruction::False,
+        Opcode::Null => Instruction::Null,
+        Opcode::Dup => Instruction::Dup,
+        Opcode::Jump => Instruction::Jump(index(operands[0])?),
+        Opcode::JumpNotTruthy => Instruction::JumpNotTruthy(index(operands[0])?),
+        Opcode::JumpTruthy => Instruction::JumpTruthy(index(operands[0])?),
+        Opcode::SetGlobal => Instruction::SetGlobal(operand(operands)),
+        Opcode::GetGlobal => Instruction::GetGlobal(operand(operands)),
+        Opcode::SetLocal => Instruction::SetLocal(operand(operands)),
+        Opcode::GetLocal => Instruction::GetLocal(operand(operands)),
+        Opcode::Call => Instruction::Call(operand(operands)),
+        Opcode::Return => Instruction::Return,
+        Opcode::Pop => Instruction::Pop,
+        Opcode::Array => Instruction::Array(operand(operands)),
+        Opcode::Index => Instruction::Index,
+        Opcode::SetIndex => Instruction::SetIndex,
+        Opcode::Length => Instruction::Length,
+        Opcode::JumpTable => Instruction::JumpTable {
+            low: operands[0],
+            default: index(operands[1])?,
+            targets: operands[3..].iter().map(|&target| index(target)).collect::<Result<_, String>>()?,
+        },
+        Opcode::CurrentFunction => Instruction::CurrentFunction,
+        Opcode::GetBuiltin => Instruction::GetBuiltin(operand(operands)),
+    })).collect()
+}
+
+// the opcode and flat operands, jump targets still as instruction indices
+pub fn split(instruction: &Instruction) -> (Opcode, Vec<i64>) {
+    let n = |value: &usize| *value as i64;
+    match instruction {
+        Instruction::LoadConstant(index) => (Opcode::LoadConstant, vec![n(index)]),
+        Instruction::Add => (Opcode::Add, vec![]),
+        Instruction::Sub => (Opcode::Sub, vec![]),
+        Instruction::Mul => (Opcode::Mul, vec![]),
+        Instruction::Div => (Opcode::Div, vec![]),
+        Instruction::Mod => (Opcode::Mod, vec![]),
+        Instruction::BitAnd => (Opcode::BitAnd, vec![]),
+        Instruction::BitOr => (Opcode::BitOr, vec![]),
+        Instruction::BitXor => (Opcode::BitXor, vec![]),
+        Instruction::ShiftLeft => (Opcode::ShiftLeft, vec![]),
+        Instruction::ShiftRight => (Opcode::ShiftRight, vec![]),
+        Instruction::Equal => (Opcode::Equal, vec![]),
+        Instruction::NotEqual => (Opcode::NotEqual, vec![]),
+        Instruction::LessThan => (Opcode::LessThan, vec![]),
+        Instruction::GreaterThan => (Opcode::GreaterThan, vec![]),
+        Instruction::LessEqual => (Opcode::LessEqual, vec![]),
+        Instruction::GreaterEqual => (Opcode::GreaterEqual, vec![]),
+        Instruction::And => (Opcode::And, vec![]),
+        Instruction::Or => (Opcode::Or, vec![]),
+        Instruction::Not => (Opcode::Not, vec![]),
+        Instruction::Neg => (Opcode::Neg, vec![]),
+        Instruction::True => (Opcode::True, vec![]),
+        Instruction::False => (Opcode::False, vec![]),
+        Instruction::Null => (Opcode::Null, vec![]),
+        Instruction::Dup => (Opcode::Dup, vec![]),
+        Instruction::Jump(target) => (Opcode::Jump, vec![n(target)]),
+        Instruction::JumpNotTruthy(target) => (Opcode::JumpNotTruthy, vec![n(target)]),
+        Instruction::JumpTruthy(target) => (Opcode::JumpTruthy, vec![n(target)]),
+        Instruction::SetGlobal(index) => (Opcode::SetGlobal, vec![n(index)]),
+        Instruction::GetGlobal(index) => (Opcode::GetGlobal, vec![n(index)]),
+        Instruction::SetLocal(index) => (Opcode::SetLocal, vec![n(index)]),
+        Instruction::GetLocal(index) => (Opcode::GetLocal, vec![n(index)]),
+        Instruction::Call(count) => (Opcode::Call, vec![n(count)]),
+        Instruction::Return => (Opcode::Return, vec![]),
+        Instruction::Pop => (Opcode::Pop, vec![]),
+        Instruction::Array(count) => (Opcode::Array, vec![n(count)]),
+        Instruction::Index => (Opcode::Index, vec![]),
+        Instruction::SetIndex => (Opcode::SetIndex, vec![]),
+        Instruction::Length => (Opcode::Length, vec![]),
+        Instruction::JumpTable { low, targets, default } => {
+            let mut operands = vec![*low, n(default), targets.len() as i64];
+            operands.extend(targets.iter().map(n));
+            (Opcode::JumpTable, operands)
+        }
+        Instruction::CurrentFunction => (Opcode::CurrentFunction, vec![]),
+        Instruction::GetBuiltin(index) => (Opcode::GetBuiltin, vec![n(index)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_and_read_operands() {
+        let cases: [(Opcode, Vec<i64>, Vec<u8>); 4] = [
+            (Opcode::LoadConstant, vec![65534], vec![Opcode::LoadConstant as u8, 255, 254]),
+            (Opcode::GetLocal, vec![255], vec![Opcode::GetLocal as u8, 255]),
+            (Opcode::Add, vec![], vec![Opcode::Add as u8]),
+            // low -2 zigzags to 3, default 9, two targets
+            (Opcode::JumpTable, vec![-2, 9, 2, 4, 300], vec![Opcode::JumpTable as u8, 3, 0, 9, 2, 0, 4, 1, 44]),
+        ];
+        for (op, operands, expected) in cases {
+            let bytes = make(op, &operands).unwrap();
+            assert_eq!(bytes, expected);
+            let (read, size) = read_operands(&op.definition(), &bytes[1..]).unwrap();
+            assert_eq!((read, size), (operands, bytes.len() - 1));
+        }
+
+        assert!(make(Opcode::GetLocal, &[256]).is_err());
+        assert!(make(Opcode::Jump, &[]).is_err());
+        assert!(read_operands(&Opcode::LoadConstant.definition(), &[1]).is_err());
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let instructions = vec![
+            Instruction::GetGlobal(0),
+            Instruction::JumpTable { low: -1, targets: vec![2, 4], default: 5 },
+            Instruction::True,
+            Instruction::JumpNotTruthy(5),
+            Instruction::LoadConstant(300),
+            Instruction::Pop,
+        ];
+        let bytes = encode(&instructions).unwrap();
+        // targets are byte offsets now: GetGlobal takes 3 bytes and the table 9
+        assert_eq!(&bytes[3..12], &[Opcode::JumpTable as u8, 1, 0, 19, 2, 0, 12, 0, 16]);
+        assert_eq!(decode(&bytes).unwrap(), instructions);
+
+        assert!(decode(&[Opcode::Jump as u8, 0, 1]).is_err());
+        assert!(decode(&[200]).is_err());
+    }
+}
@@ -1,14 +1,66 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, fmt::Display, rc::Rc};
 
-use crate::ast::Statement;
+use crate::{builtins::BUILTINS, ast::{BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Pattern, PrefixOp, Span, Statement}, const_fold::ConstantFolder, deadcode::{DeadCode, Warning}, definite::{AssignError, DefiniteAssignment}, resolver::{NodeId, ResolveError, Resolver}};
 
 pub struct CodeGen {
     instructions: Vec<Instruction>,
-    constants: Vec<Object>,
-    symbol_table: SymbolTable,
+    constants: ConstantPool,
+    // from the resolver, only valid while the program they were made from is being compiled
+    symbols: HashMap<NodeId, Symbol>,
+    num_locals: HashMap<NodeId, usize>,
+    hidden: HashMap<NodeId, Vec<Symbol>>,
+    num_globals: usize,
+    warnings: Vec<Warning>,
+    loops: Vec<LoopContext>, // innermost last
+    global_names: Vec<String>, // the first name stored in each global slot
+    lines: LineTable,          // where the instructions being emitted came from
+    span: Span,                // the innermost statement or expression being compiled
 }
 
-#[derive(Debug, Clone)]
+// why compile rejected a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    Unassigned(Vec<AssignError>), // every read of a variable that may have no value, in source order
+    Unresolved(Vec<ResolveError>), // every name the resolver couldn't place
+    Message(String),              // the first thing codegen itself couldn't compile
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Unassigned(errors) => {
+                let lines = errors.iter().map(|error| error.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CompileError::Unresolved(errors) => {
+                let lines = errors.iter().map(|error| error.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", lines.join("\n"))
+            }
+            CompileError::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<String> for CompileError {
+    fn from(message: String) -> Self {
+        CompileError::Message(message)
+    }
+}
+
+impl From<CompileError> for String {
+    fn from(error: CompileError) -> Self {
+        error.to_string()
+    }
+}
+
+// the jumps out of a loop being compiled, patched once the loop's end is known
+struct LoopContext {
+    label: Option<String>,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Instruction {
     LoadConstant(usize),
     Add,
@@ -16,61 +68,881 @@ pub enum Instruction {
     Mul,
     Div,
     Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     Equal,
     NotEqual,
     LessThan, 
     GreaterThan,
+    LessEqual,
+    GreaterEqual,
     And,
     Or,
     Not,
+    Neg,
+    True,
+    False,
+    Null,
+    Dup,
     Jump(usize),
     JumpNotTruthy(usize),
+    JumpTruthy(usize),
     SetGlobal(usize),
     GetGlobal(usize),
     SetLocal(usize),
     GetLocal(usize),
     Call(usize),
     Return,
+    Pop,
+    Array(usize), // collects the top n values into an array
+    Index,        // array, index -> element
+    SetIndex,     // array, index, value -> copy of the array with the element replaced
+    Length,       // array -> number of elements
+    JumpTable { low: i64, targets: Vec<usize>, default: usize }, // jumps to targets[value - low], default when out of range
+    CurrentFunction, // the function that is running, so a nested function can call itself
+    GetBuiltin(usize), // an index into builtins::BUILTINS
+}
+
+impl Instruction {
+    // the instructions a jump can go to, empty for everything else
+    pub fn jump_targets(&self) -> Vec<usize> {
+        match self {
+            Instruction::Jump(target) | Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => vec![*target],
+            Instruction::JumpTable { targets, default, .. } => targets.iter().chain([default]).copied().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// fewer cases than this are cheaper to compare one by one
+const MIN_TABLE_CASES: usize = 3;
+
+// what the vm needs to run a compiled program
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    pub instructions: Vec<Instruction>,
+    pub constants: Vec<Object>,
+    pub globals: Vec<String>, // the name of each global slot, only used to report things
+    pub lines: LineTable,     // main's instructions back to the source
 }
 
 impl CodeGen {
     pub fn new() -> Self {
         CodeGen {
             instructions: Vec::new(),
-            constants: Vec::new(),
-            symbol_table: SymbolTable::new(),
+            constants: ConstantPool::default(),
+            symbols: HashMap::new(),
+            num_locals: HashMap::new(),
+            hidden: HashMap::new(),
+            num_globals: 0,
+            warnings: Vec::new(),
+            loops: Vec::new(),
+            global_names: Vec::new(),
+            lines: LineTable::default(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn compile(&mut self, program: Vec<Statement>) -> Result<(), CompileError> {
+        // drop what can never run before emitting anything for it
+        let program = ConstantFolder::fold(program);
+        let (program, warnings) = DeadCode::eliminate(program);
+        self.warnings.extend(warnings);
+        DefiniteAssignment::check(&program).map_err(CompileError::Unassigned)?;
+        // every slot comes from the resolver, codegen only looks them up
+        let resolution = Resolver::new().resolve(&program).map_err(CompileError::Unresolved)?;
+        self.symbols = resolution.symbols;
+        self.num_locals = resolution.num_locals;
+        self.hidden = resolution.hidden;
+        self.num_globals = resolution.num_globals;
+
+        let result = program.iter().try_for_each(|stmt| self.compile_statement(stmt));
+        // the ids point into `program`, which is about to be freed
+        self.symbols.clear();
+        self.num_locals.clear();
+        self.hidden.clear();
+        Ok(result?)
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn bytecode(&self) -> Bytecode {
+        Bytecode {
+            instructions: self.instructions.clone(),
+            constants: self.constants.objects().to_vec(),
+            globals: (0..self.num_globals)
+                .map(|i| self.global_names.get(i).cloned().unwrap_or_default())
+                .collect(),
+            lines: self.lines.clone(),
+        }
+    }
+
+    pub fn compile_statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        let outer = std::mem::replace(&mut self.span, stmt.span().clone());
+        let result = self.lower_statement(stmt);
+        self.span = outer;
+        result
+    }
+
+    fn lower_statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        match stmt {
+            Statement::Block { statements, .. } => {
+                for stmt in statements {
+                    self.compile_statement(stmt)?;
+                }
+            }
+            Statement::If { condition, then_branch, else_branch, .. } => {
+                self.compile_expression(condition)?;
+                let jump_not_truthy = self.emit(Instruction::JumpNotTruthy(0));
+                self.compile_statement(then_branch)?;
+
+                match else_branch {
+                    Some(else_branch) => {
+                        let jump = self.emit(Instruction::Jump(0));
+                        self.patch_jump(jump_not_truthy)?;
+                        self.compile_statement(else_branch)?;
+                        self.patch_jump(jump)?;
+                    }
+                    None => self.patch_jump(jump_not_truthy)?,
+                }
+            }
+            Statement::While { label, condition, body, .. } => {
+                let start = self.instructions.len();
+                self.compile_expression(condition)?;
+                let exit = self.emit(Instruction::JumpNotTruthy(0));
+                let context = self.compile_loop_body(label, body)?;
+                self.emit(Instruction::Jump(start));
+                self.patch_jump(exit)?;
+                self.patch_loop(context, start)?;
+            }
+            Statement::For { label, init, condition, increment, body, .. } => {
+                if let Some(init) = init {
+                    self.compile_statement(init)?;
+                }
+                let start = self.instructions.len();
+                self.compile_expression(condition)?;
+                let exit = self.emit(Instruction::JumpNotTruthy(0));
+                let context = self.compile_loop_body(label, body)?;
+                // continue runs the increment before checking the condition again
+                let next = self.instructions.len();
+                if let Some(increment) = increment {
+                    self.compile_statement(increment)?;
+                }
+                self.emit(Instruction::Jump(start));
+                self.patch_jump(exit)?;
+                self.patch_loop(context, next)?;
+            }
+            Statement::ForIn { label, variable, iterable, body, span } => {
+                // a hidden counter walks the range or the array indices, ranges never build an array
+                let hidden = self.hidden_slots(span)?;
+                let (limit, array) = match (iterable, hidden.as_slice()) {
+                    (Iterable::Range { start, end, .. }, [counter, limit]) => {
+                        self.compile_expression(start)?;
+                        self.emit_set(counter);
+                        self.compile_expression(end)?;
+                        (limit, None)
+                    }
+                    (Iterable::Array(array), [counter, slot, length]) => {
+                        self.compile_expression(array)?;
+                        self.emit_set(slot);
+                        self.emit_constant(Object::Integer(0));
+                        self.emit_set(counter);
+                        self.emit_get(slot)?;
+                        self.emit(Instruction::Length);
+                        (length, Some(slot))
+                    }
+                    _ => return Err(format!("the loop over `{}` has no hidden slots", variable)),
+                };
+                let counter = &hidden[0];
+                self.emit_set(limit);
+                let element = self.symbol(variable, span)?;
+
+                let start = self.instructions.len();
+                self.emit_get(counter)?;
+                self.emit_get(limit)?;
+                match iterable {
+                    Iterable::Range { inclusive: true, .. } => self.emit(Instruction::LessEqual),
+                    _ => self.emit(Instruction::LessThan),
+                };
+                let exit = self.emit(Instruction::JumpNotTruthy(0));
+                if let Some(array) = array {
+                    self.emit_get(array)?;
+                    self.emit_get(counter)?;
+                    self.emit(Instruction::Index);
+                } else {
+                    self.emit_get(counter)?;
+                }
+                self.emit_set(&element);
+
+                let context = self.compile_loop_body(label, body)?;
+                let next = self.instructions.len();
+                // an inclusive range stops at its bound instead of counting past it, `..=i64::MAX` would overflow
+                let last = match iterable {
+                    Iterable::Range { inclusive: true, .. } => {
+                        self.emit_get(counter)?;
+                        self.emit_get(limit)?;
+                        self.emit(Instruction::NotEqual);
+                        Some(self.emit(Instruction::JumpNotTruthy(0)))
+                    }
+                    _ => None,
+                };
+                self.emit_get(counter)?;
+                self.emit_constant(Object::Integer(1));
+                self.emit(Instruction::Add);
+                self.emit_set(counter);
+                self.emit(Instruction::Jump(start));
+                self.patch_jump(exit)?;
+                if let Some(last) = last {
+                    self.patch_jump(last)?;
+                }
+                self.patch_loop(context, next)?;
+            }
+            Statement::Break { label, .. } => {
+                let jump = self.emit(Instruction::Jump(0));
+                self.enclosing_loop("break", label.as_deref())?.breaks.push(jump);
+            }
+            Statement::Continue { label, .. } => {
+                let jump = self.emit(Instruction::Jump(0));
+                self.enclosing_loop("continue", label.as_deref())?.continues.push(jump);
+            }
+            Statement::Return { value, .. } => {
+                match value {
+                    Some(value) => self.compile_expression(value)?,
+                    None => self.emit_constant(Object::Null),
+                }
+                self.emit(Instruction::Return);
+            }
+            Statement::Expression { expression, .. } => {
+                self.compile_expression(expression)?;
+                self.emit(Instruction::Pop);
+            }
+            Statement::VariableDeclaration { name, initializer, span, .. } => {
+                match initializer {
+                    Some(initializer) => self.compile_expression(initializer)?,
+                    None => self.emit_constant(Object::Null),
+                }
+                let symbol = self.symbol(name, span)?;
+                self.emit_set(&symbol);
+            }
+            Statement::FunctionDeclaration { name, parameters, body, span, .. } => {
+                let symbol = self.symbol(name, span)?;
+                let num_locals = self.num_locals.get(&NodeId::of(span)).copied().ok_or_else(|| format!("`{}` has no locals", name))?;
+
+                // a break in the body can't leave a loop around the declaration
+                let outer = std::mem::take(&mut self.instructions);
+                let outer_lines = std::mem::take(&mut self.lines);
+                let outer_loops = std::mem::take(&mut self.loops);
+                // the parameters are the first locals, the arguments are already in them
+                let body = self.compile_statement(body);
+                // falling off the end returns null
+                self.emit_constant(Object::Null);
+                self.emit(Instruction::Return);
+                let instructions = std::mem::replace(&mut self.instructions, outer);
+                let lines = std::mem::replace(&mut self.lines, outer_lines);
+                self.loops = outer_loops;
+                body?;
+
+                self.emit_constant(Object::Function(Rc::new(Function {
+                    instructions,
+                    num_locals,
+                    num_parameters: parameters.len(),
+                    lines,
+                })));
+                self.emit_set(&symbol);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compile_expression(&mut self, expr: &Expression) -> Result<(), String> {
+        let outer = std::mem::replace(&mut self.span, expr.span().clone());
+        let result = self.lower_expression(expr);
+        self.span = outer;
+        result
+    }
+
+    fn lower_expression(&mut self, expr: &Expression) -> Result<(), String> {
+        match expr {
+            // short circuit, the right side only runs when it decides the result
+            Expression::Binary { left, operator: BinaryOp::And, right, .. } => {
+                self.compile_expression(left)?;
+                let short = self.emit(Instruction::JumpNotTruthy(0));
+                self.compile_expression(right)?;
+                let end = self.emit(Instruction::Jump(0));
+                self.patch_jump(short)?;
+                self.emit_constant(Object::Boolean(false));
+                self.patch_jump(end)?;
+            }
+            Expression::Binary { left, operator: BinaryOp::Or, right, .. } => {
+                self.compile_expression(left)?;
+                let next = self.emit(Instruction::JumpNotTruthy(0));
+                self.emit_constant(Object::Boolean(true));
+                let end = self.emit(Instruction::Jump(0));
+                self.patch_jump(next)?;
+                self.compile_expression(right)?;
+                self.patch_jump(end)?;
+            }
+            Expression::Binary { left, operator, right, .. } => {
+                self.compile_expression(left)?;
+                self.compile_expression(right)?;
+                self.emit_binary(operator);
+            }
+            Expression::Unary { operator: PrefixOp::Not, operand, .. } => {
+                self.compile_expression(operand)?;
+                self.emit(Instruction::Not);
+            }
+            Expression::Unary { operator: PrefixOp::Neg, operand, .. } => {
+                self.compile_expression(operand)?;
+                self.emit(Instruction::Neg);
+            }
+            Expression::Literal { value, .. } => self.emit_constant(literal_object(value)),
+            Expression::Variable { name, span } => {
+                let symbol = self.symbol(name, span)?;
+                self.emit_get(&symbol)?;
+            }
+            Expression::Call { callee, arguments, .. } => {
+                self.compile_expression(callee)?;
+                for arg in arguments {
+                    self.compile_expression(arg)?;
+                }
+                self.emit(Instruction::Call(arguments.len()));
+            }
+            Expression::Assign { target, value, span } => self.compile_assign(target, None, value, span)?,
+            Expression::CompoundAssign { target, operator, value, span } => self.compile_assign(target, Some(&operator.binary()), value, span)?,
+            Expression::Increment { target, operator, span } => {
+                let (operator, one) = operator.lower(span);
+                self.compile_assign(target, Some(&operator.binary()), &one, span)?;
+            }
+            Expression::Index { array, index, .. } => {
+                self.compile_expression(array)?;
+                self.compile_expression(index)?;
+                self.emit(Instruction::Index);
+            }
+            Expression::Array { elements, .. } => {
+                for element in elements {
+                    self.compile_expression(element)?;
+                }
+                self.emit(Instruction::Array(elements.len()));
+            }
+            Expression::Match { scrutinee, arms, span } => self.compile_match(scrutinee, arms, span)?,
+        }
+        Ok(())
+    }
+
+    fn emit_binary(&mut self, operator: &BinaryOp) {
+        match operator {
+            BinaryOp::Plus => self.emit(Instruction::Add),
+            BinaryOp::Minus => self.emit(Instruction::Sub),
+            BinaryOp::Multiply => self.emit(Instruction::Mul),
+            BinaryOp::Divide => self.emit(Instruction::Div),
+            BinaryOp::Modulo => self.emit(Instruction::Mod),
+            BinaryOp::BitAnd => self.emit(Instruction::BitAnd),
+            BinaryOp::BitOr => self.emit(Instruction::BitOr),
+            BinaryOp::BitXor => self.emit(Instruction::BitXor),
+            BinaryOp::ShiftLeft => self.emit(Instruction::ShiftLeft),
+            BinaryOp::ShiftRight => self.emit(Instruction::ShiftRight),
+            BinaryOp::Equal => self.emit(Instruction::Equal),
+            BinaryOp::NotEqual => self.emit(Instruction::NotEqual),
+            BinaryOp::LessThan => self.emit(Instruction::LessThan),
+            BinaryOp::GreaterThan => self.emit(Instruction::GreaterThan),
+            BinaryOp::LessEqual => self.emit(Instruction::LessEqual),
+            BinaryOp::GreaterEqual => self.emit(Instruction::GreaterEqual),
+            BinaryOp::And | BinaryOp::Or => unreachable!("short circuits are compiled as jumps"),
+        };
+    }
+
+    // `a[f()][j] op= v` evaluates f() and j once into hidden slots, then rebuilds a
+    // from the inside out with SetIndex since arrays are values
+    // an assignment is an expression, the assigned value stays on the stack
+    fn compile_assign(&mut self, target: &Expression, operator: Option<&BinaryOp>, value: &Expression, span: &Span) -> Result<(), String> {
+        let mut indices = Vec::new();
+        let mut base = target;
+        while let Expression::Index { array, index, .. } = base {
+            indices.push(index.as_ref());
+            base = array;
+        }
+        indices.reverse();
+        let Expression::Variable { name, span: variable_span } = base else {
+            return Err("invalid assignment target".to_string());
+        };
+        let variable = self.symbol(name, variable_span)?;
+        match variable.scope {
+            SymbolScope::Function => return Err(format!("cannot assign to `{}` inside its own body", name)),
+            SymbolScope::Builtin => return Err(format!("cannot assign to the builtin `{}`", name)),
+            SymbolScope::Global | SymbolScope::Local => {}
+        }
+
+        if indices.is_empty() {
+            return self.compile_variable_assign(&variable, operator, value);
+        }
+        // one slot per index, then one for the assigned value
+        let hidden = self.hidden_slots(span)?;
+        let Some((assigned, slots)) = hidden.split_last().filter(|(_, slots)| slots.len() == indices.len()) else {
+            return Err(format!("the assignment to `{}` has no hidden slots", name));
+        };
+        for (index, slot) in indices.iter().zip(slots) {
+            self.compile_expression(index)?;
+            self.emit_set(slot);
+        }
+
+        self.compile_value(&variable, slots, operator, value)?;
+        self.emit_set(assigned);
+        // every enclosing array and its index, then the new element on top
+        for depth in 0..slots.len() {
+            self.emit_element(&variable, &slots[..depth])?;
+            self.emit_get(&slots[depth])?;
+        }
+        self.emit_get(assigned)?;
+        for _ in slots {
+            self.emit(Instruction::SetIndex);
+        }
+        self.emit_set(&variable);
+        self.emit_get(assigned)
+    }
+
+    fn compile_variable_assign(&mut self, variable: &Symbol, operator: Option<&BinaryOp>, value: &Expression) -> Result<(), String> {
+        self.compile_value(variable, &[], operator, value)?;
+        self.emit(Instruction::Dup);
+        self.emit_set(variable);
+        Ok(())
+    }
+
+    // the new value of variable[slot0][slot1]...
+    fn compile_value(&mut self, variable: &Symbol, slots: &[Symbol], operator: Option<&BinaryOp>, value: &Expression) -> Result<(), String> {
+        if let Some(operator) = operator {
+            self.emit_element(variable, slots)?;
+            self.compile_expression(value)?;
+            self.emit_binary(operator);
+        } else {
+            self.compile_expression(value)?;
+        }
+        Ok(())
+    }
+
+    // variable[slot0][slot1]...
+    fn emit_element(&mut self, variable: &Symbol, slots: &[Symbol]) -> Result<(), String> {
+        self.emit_get(variable)?;
+        for slot in slots {
+            self.emit_get(slot)?;
+            self.emit(Instruction::Index);
+        }
+        Ok(())
+    }
+
+    fn compile_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], span: &Span) -> Result<(), String> {
+        // the value is kept in a hidden slot so every arm can test it again
+        self.compile_expression(scrutinee)?;
+        let Some(value) = self.hidden_slots(span)?.pop() else {
+            return Err("the match has no hidden slot".to_string());
+        };
+        self.emit_set(&value);
+
+        let mut ends = Vec::new();
+        let rest = match jump_table(arms) {
+            Some((count, low, slots)) => {
+                self.emit_get(&value)?;
+                let table = self.emit(Instruction::JumpTable { low, targets: Vec::new(), default: 0 });
+                let mut starts = Vec::new();
+                for arm in &arms[..count] {
+                    starts.push(self.instructions.len());
+                    self.compile_expression(&arm.body)?;
+                    ends.push(self.emit(Instruction::Jump(0)));
+                }
+                // values the table doesn't list go on to the remaining arms
+                let default = self.instructions.len();
+                let targets = slots.iter().map(|slot| slot.map_or(default, |arm| starts[arm])).collect();
+                self.instructions[table] = Instruction::JumpTable { low, targets, default };
+                &arms[count..]
+            }
+            None => arms,
+        };
+
+        for arm in rest {
+            ends.push(self.compile_arm(&value, arm)?);
+        }
+        // no arm matched, the type checker rules this out for exhaustive matches
+        self.emit_constant(Object::Null);
+        for end in ends {
+            self.patch_jump(end)?;
+        }
+        Ok(())
+    }
+
+    // returns the jump taken after the body ran
+    fn compile_arm(&mut self, value: &Symbol, arm: &MatchArm) -> Result<usize, String> {
+        let mut fails = self.compile_pattern(&arm.pattern, value, &mut Vec::new())?;
+        if let Some(guard) = &arm.guard {
+            self.compile_expression(guard)?;
+            fails.push(self.emit(Instruction::JumpNotTruthy(0)));
+        }
+        self.compile_expression(&arm.body)?;
+        let end = self.emit(Instruction::Jump(0));
+        for fail in fails {
+            self.patch_jump(fail)?;
+        }
+        Ok(end)
+    }
+
+    // tests the part of the value at `path` (array indices from the top) and binds names on the way
+    // returns the jumps taken when the pattern doesn't match
+    fn compile_pattern(&mut self, pattern: &Pattern, value: &Symbol, path: &mut Vec<usize>) -> Result<Vec<usize>, String> {
+        let outer = std::mem::replace(&mut self.span, pattern.span().clone());
+        let result = self.lower_pattern(pattern, value, path);
+        self.span = outer;
+        result
+    }
+
+    fn lower_pattern(&mut self, pattern: &Pattern, value: &Symbol, path: &mut Vec<usize>) -> Result<Vec<usize>, String> {
+        let mut fails = Vec::new();
+        match pattern {
+            Pattern::Wildcard { .. } => {}
+            Pattern::Binding { name, span } => {
+                self.emit_path(value, path)?;
+                let symbol = self.symbol(name, span)?;
+                self.emit_set(&symbol);
+            }
+            Pattern::Literal { value: literal, .. } => {
+                self.emit_path(value, path)?;
+                self.emit_constant(literal_object(literal));
+                self.emit(Instruction::Equal);
+                fails.push(self.emit(Instruction::JumpNotTruthy(0)));
+            }
+            Pattern::Array { elements, rest, .. } => {
+                self.emit_path(value, path)?;
+                self.emit(Instruction::Length);
+                self.emit_constant(Object::Integer(elements.len() as i64));
+                if *rest {
+                    // at least that many elements
+                    self.emit(Instruction::GreaterEqual);
+                } else {
+                    self.emit(Instruction::Equal);
+                }
+                fails.push(self.emit(Instruction::JumpNotTruthy(0)));
+                for (i, element) in elements.iter().enumerate() {
+                    path.push(i);
+                    fails.extend(self.compile_pattern(element, value, path)?);
+                    path.pop();
+                }
+            }
+            Pattern::Or { alternatives, .. } => {
+                let Some((last, others)) = alternatives.split_last() else {
+                    fails.push(self.emit(Instruction::Jump(0)));
+                    return Ok(fails);
+                };
+                // an alternative that fails falls through to the next one
+                let mut matched = Vec::new();
+                for alternative in others {
+                    let alternative_fails = self.compile_pattern(alternative, value, path)?;
+                    matched.push(self.emit(Instruction::Jump(0)));
+                    for fail in alternative_fails {
+                        self.patch_jump(fail)?;
+                    }
+                }
+                fails = self.compile_pattern(last, value, path)?;
+                for jump in matched {
+                    self.patch_jump(jump)?;
+                }
+            }
+        }
+        Ok(fails)
+    }
+
+    fn emit_path(&mut self, value: &Symbol, path: &[usize]) -> Result<(), String> {
+        self.emit_get(value)?;
+        for &index in path {
+            self.emit_constant(Object::Integer(index as i64));
+            self.emit(Instruction::Index);
+        }
+        Ok(())
+    }
+
+    // what the resolver recorded for the name at `span`
+    fn symbol(&self, name: &str, span: &Span) -> Result<Symbol, String> {
+        self.symbols.get(&NodeId::of(span)).cloned().ok_or_else(|| format!("`{}` was never resolved", name))
+    }
+
+    fn hidden_slots(&self, span: &Span) -> Result<Vec<Symbol>, String> {
+        self.hidden.get(&NodeId::of(span)).cloned().ok_or_else(|| "a hidden slot was never resolved".to_string())
+    }
+
+    // returns the position of the instruction so jumps can be patched later
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.lines.add(self.instructions.len(), &self.span);
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    // booleans and null have their own opcodes and never take a constant slot
+    fn emit_constant(&mut self, object: Object) {
+        match object {
+            Object::Boolean(true) => self.emit(Instruction::True),
+            Object::Boolean(false) => self.emit(Instruction::False),
+            Object::Null => self.emit(Instruction::Null),
+            object => {
+                let index = self.constants.add(object);
+                self.emit(Instruction::LoadConstant(index))
+            }
+        };
+    }
+
+    fn emit_set(&mut self, symbol: &Symbol) {
+        match symbol.scope {
+            SymbolScope::Global => {
+                if self.global_names.len() <= symbol.index {
+                    self.global_names.resize(symbol.index + 1, String::new());
+                }
+                if self.global_names[symbol.index].is_empty() {
+                    self.global_names[symbol.index] = symbol.name.clone();
+                }
+                self.emit(Instruction::SetGlobal(symbol.index))
+            }
+            _ => self.emit(Instruction::SetLocal(symbol.index)),
+        };
+    }
+
+    fn emit_get(&mut self, symbol: &Symbol) -> Result<(), String> {
+        match symbol.scope {
+            SymbolScope::Global => self.emit(Instruction::GetGlobal(symbol.index)),
+            SymbolScope::Local => self.emit(Instruction::GetLocal(symbol.index)),
+            SymbolScope::Function => self.emit(Instruction::CurrentFunction),
+            SymbolScope::Builtin => self.emit(Instruction::GetBuiltin(symbol.index)),
+        };
+        Ok(())
+    }
+
+    fn compile_loop_body(&mut self, label: &Option<String>, body: &Statement) -> Result<LoopContext, String> {
+        self.loops.push(LoopContext { label: label.clone(), breaks: Vec::new(), continues: Vec::new() });
+        let body = self.compile_statement(body);
+        let context = self.loops.pop().unwrap();
+        body.map(|_| context)
+    }
+
+    // breaks land right after the loop, so call this once the loop is fully emitted
+    fn patch_loop(&mut self, context: LoopContext, continue_target: usize) -> Result<(), String> {
+        for jump in context.breaks {
+            self.patch_jump(jump)?;
+        }
+        for jump in context.continues {
+            self.patch_jump_to(jump, continue_target)?;
+        }
+        Ok(())
+    }
+
+    fn enclosing_loop(&mut self, keyword: &str, label: Option<&str>) -> Result<&mut LoopContext, String> {
+        let found = match label {
+            Some(label) => self.loops.iter().rposition(|l| l.label.as_deref() == Some(label)),
+            None => self.loops.len().checked_sub(1),
+        };
+        match (found, label) {
+            (Some(index), _) => Ok(&mut self.loops[index]),
+            (None, Some(label)) => Err(format!("`{} {}` doesn't match any enclosing loop", keyword, label)),
+            (None, None) => Err(format!("`{}` outside of a loop", keyword)),
         }
     }
 
-    // pub fn compile_statement(&mut self, stmt: Statement) -> Result<(), String> {
-        
-    // }
+    // point the jump at `pos` to the next instruction to be emitted
+    fn patch_jump(&mut self, pos: usize) -> Result<(), String> {
+        self.patch_jump_to(pos, self.instructions.len())
+    }
+
+    fn patch_jump_to(&mut self, pos: usize, target: usize) -> Result<(), String> {
+        let Some(Instruction::Jump(to) | Instruction::JumpNotTruthy(to) | Instruction::JumpTruthy(to)) = self.instructions.get_mut(pos) else {
+            return Err(format!("instruction {} is not a jump", pos));
+        };
+        *to = target;
+        Ok(())
+    }
 }
 
 
 
-#[derive(Debug, Clone)]
+fn literal_object(value: &LiteralValue) -> Object {
+    match value {
+        LiteralValue::Integer(n) => Object::Integer(*n),
+        LiteralValue::String(s) => Object::String(s.as_str().into()),
+        LiteralValue::Char(c) => Object::Char(*c),
+        LiteralValue::Bool(b) => Object::Boolean(*b),
+    }
+}
+
+// the leading unguarded arms that only list integers, if they are dense enough that
+// indexing a table beats comparing arm by arm
+// returns how many arms the table covers, its lowest value and the arm for each slot
+fn jump_table(arms: &[MatchArm]) -> Option<(usize, i64, Vec<Option<usize>>)> {
+    let mut cases: Vec<(i64, usize)> = Vec::new();
+    let mut count = 0;
+    for (i, arm) in arms.iter().enumerate() {
+        let (None, Some(values)) = (&arm.guard, integer_values(&arm.pattern)) else {
+            break;
+        };
+        for value in values {
+            // an earlier arm listing the same value wins
+            if !cases.iter().any(|(case, _)| *case == value) {
+                cases.push((value, i));
+            }
+        }
+        count = i + 1;
+    }
+    if cases.len() < MIN_TABLE_CASES {
+        return None;
+    }
+
+    let low = cases.iter().map(|(value, _)| *value).min()?;
+    let high = cases.iter().map(|(value, _)| *value).max()?;
+    let size = usize::try_from(high.checked_sub(low)?).ok()?.checked_add(1)?;
+    if size > 2 * cases.len() {
+        return None;
+    }
+    let mut slots = vec![None; size];
+    for (value, arm) in cases {
+        slots[(value - low) as usize] = Some(arm);
+    }
+    Some((count, low, slots))
+}
+
+fn integer_values(pattern: &Pattern) -> Option<Vec<i64>> {
+    match pattern {
+        Pattern::Literal { value: LiteralValue::Integer(n), .. } => Some(vec![*n]),
+        Pattern::Or { alternatives, .. } => {
+            let values = alternatives.iter().map(integer_values).collect::<Option<Vec<_>>>()?;
+            Some(values.concat())
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Object {
     Integer(i64),
     Boolean(bool),
-    String(String),
+    String(Rc<str>),
+    Char(char),
     Array(Vec<Object>),
-    Function(Function),
+    Function(Rc<Function>), // shared, the vm finds a function's encoded code by its address
+    Builtin(usize),         // only made by GetBuiltin while running, never a constant
     Null,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Function {
     pub instructions: Vec<Instruction>,
     pub num_locals: usize,
-    pub num_parameters: usize
+    pub num_parameters: usize,
+    pub lines: LineTable,
+}
+
+// the source span each instruction was compiled from, stored only where it changes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct LineTable {
+    entries: Vec<(usize, Span)>, // (first instruction, span), in instruction order
+}
+
+impl LineTable {
+    pub fn add(&mut self, instruction: usize, span: &Span) {
+        match self.entries.last_mut() {
+            Some((_, last)) if last == span => {}
+            // nothing was emitted for the previous span
+            Some((at, _)) if *at == instruction => {
+                self.entries.pop();
+                self.add(instruction, span);
+            }
+            _ => self.entries.push((instruction, span.clone())),
+        }
+    }
+
+    pub fn lookup(&self, instruction: usize) -> Option<&Span> {
+        let after = self.entries.partition_point(|(at, _)| *at <= instruction);
+        after.checked_sub(1).map(|i| &self.entries[i].1)
+    }
+
+    pub fn entries(&self) -> &[(usize, Span)] {
+        &self.entries
+    }
+
+    // the same table keyed by positions[instruction], like the byte offsets of encoded code
+    // positions ends with the position of the end, entries that move there had no instructions left
+    pub fn remap(&self, positions: &[usize]) -> LineTable {
+        let end = positions[positions.len() - 1];
+        let mut lines = LineTable::default();
+        for (at, span) in &self.entries {
+            if positions[*at] < end {
+                lines.add(positions[*at], span);
+            }
+        }
+        lines
+    }
+}
+
+// every distinct constant is stored once, functions only match when their line tables do too
+// strings are interned, equal strings anywhere in the pool share one allocation
+#[derive(Debug, Clone, Default)]
+pub struct ConstantPool {
+    objects: Vec<Object>,
+    indices: HashMap<Object, usize>,
+    strings: HashSet<Rc<str>>,
+}
+
+// keeps every object at its index, a duplicate is found again under the first one
+impl From<Vec<Object>> for ConstantPool {
+    fn from(objects: Vec<Object>) -> Self {
+        let mut pool = ConstantPool::default();
+        for object in objects {
+            let object = pool.intern(object);
+            pool.indices.entry(object.clone()).or_insert(pool.objects.len());
+            pool.objects.push(object);
+        }
+        pool
+    }
+}
+
+impl ConstantPool {
+    // the index of an equal constant if there is one
+    pub fn add(&mut self, object: Object) -> usize {
+        let object = self.intern(object);
+        if let Some(&index) = self.indices.get(&object) {
+            return index;
+        }
+        self.objects.push(object.clone());
+        self.indices.insert(object, self.objects.len() - 1);
+        self.objects.len() - 1
+    }
+
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+
+    pub fn into_objects(self) -> Vec<Object> {
+        self.objects
+    }
+
+    fn intern(&mut self, object: Object) -> Object {
+        match object {
+            Object::String(s) => match self.strings.get(&s) {
+                Some(interned) => Object::String(interned.clone()),
+                None => {
+                    self.strings.insert(s.clone());
+                    Object::String(s)
+                }
+            },
+            Object::Array(elements) => Object::Array(elements.into_iter().map(|element| self.intern(element)).collect()),
+            object => object,
+        }
+    }
 }
 
 
 pub struct SymbolTable {
     store: HashMap<String, Symbol>,
     definitions: Vec<Symbol>,
-    scope_index: usize,
+    kind: ScopeKind,
+    symbol_scope: SymbolScope, // scope given to the symbols defined here
+    base: usize, // first free slot when the scope was entered
+    max_slots: usize, // high-water mark of slots used by this scope and its closed blocks
     outer: Option<Box<SymbolTable>>,
 }
 
@@ -85,53 +957,509 @@ pub struct Symbol {
 pub enum SymbolScope {
     Global,
     Local,
+    Builtin,
     Function
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScopeKind {
+    Function, // gets its own locals starting at slot 0
+    Block,    // keeps allocating slots of the scope around it
+}
+
 impl SymbolTable {
+    // the global scope, inside the one that holds the builtins so a global can hide a builtin's name
     pub fn new() -> Self {
+        let mut builtins = Self::empty(SymbolScope::Builtin);
+        for (index, builtin) in BUILTINS.iter().enumerate() {
+            let symbol = Symbol { name: builtin.name.to_string(), scope: SymbolScope::Builtin, index };
+            builtins.store.insert(symbol.name.clone(), symbol);
+        }
+        let mut global = Self::empty(SymbolScope::Global);
+        global.outer = Some(Box::new(builtins));
+        global
+    }
+
+    fn empty(symbol_scope: SymbolScope) -> Self {
         SymbolTable {
             store: HashMap::new(),
             definitions: Vec::new(),
-            scope_index: 0,
+            kind: ScopeKind::Function,
+            symbol_scope,
+            base: 0,
+            max_slots: 0,
             outer: None
         }
     }
 
-    pub fn new_enclosed(outer: SymbolTable) -> Self {
-        let mut enclosed = Self::new();
+    fn new_scope(outer: SymbolTable, kind: ScopeKind) -> Self {
+        let mut enclosed = Self::empty(SymbolScope::Local);
+        enclosed.kind = kind;
+        match kind {
+            ScopeKind::Function => {}
+            ScopeKind::Block => {
+                enclosed.symbol_scope = outer.symbol_scope.clone();
+                enclosed.base = outer.next_slot();
+                enclosed.max_slots = enclosed.base;
+            }
+        }
         enclosed.outer = Some(Box::new(outer));
         enclosed
     }
 
-    pub fn define(&mut self, name: String) -> Symbol {
-        let scope = if self.outer.is_none() {
-            SymbolScope::Global
-        } else {
-            SymbolScope::Local
-        };
+    pub fn enter_scope(&mut self, kind: ScopeKind) {
+        let outer = std::mem::replace(self, SymbolTable::empty(SymbolScope::Global));
+        *self = Self::new_scope(outer, kind);
+    }
+
+    // returns how many slots the scope needed, for a function that is its num_locals
+    pub fn leave_scope(&mut self) -> usize {
+        assert!(self.kind == ScopeKind::Block || self.symbol_scope == SymbolScope::Local, "cannot leave the global scope");
+        let inner = std::mem::replace(self, SymbolTable::empty(SymbolScope::Global));
+        *self = *inner.outer.expect("every scope but the builtins has one around it");
+
+        // slots of a closed block can be reused, but the function still has to reserve them
+        if inner.kind == ScopeKind::Block {
+            self.max_slots = self.max_slots.max(inner.max_slots);
+        }
+        inner.max_slots
+    }
+
+    // slots the innermost function (or the global scope) needs so far
+    pub fn num_locals(&self) -> usize {
+        match (&self.outer, self.kind) {
+            (Some(outer), ScopeKind::Block) => self.max_slots.max(outer.num_locals()),
+            _ => self.max_slots,
+        }
+    }
+
+    fn next_slot(&self) -> usize {
+        self.base + self.definitions.len()
+    }
 
+    pub fn define(&mut self, name: String) -> Symbol {
         let symbol = Symbol {
             name: name.clone(),
-            scope,
-            index: self.definitions.len(),
+            scope: self.symbol_scope.clone(),
+            index: self.next_slot(),
         };
 
         self.store.insert(name, symbol.clone());
         self.definitions.push(symbol.clone());
+        self.max_slots = self.max_slots.max(self.next_slot());
 
         symbol
     }
 
-    pub fn resolve(&self, name: &str) -> Option<Symbol> {
-        match self.store.get(name) {
-            Some(symbol) => Some(symbol.clone()),
-            None => {
-                match &self.outer {
-                    Some(outer) => outer.resolve(name),
-                    None => None,
-                }
+    // a function's own name inside its body, it doesn't take a slot either
+    pub fn define_function_name(&mut self, name: String) -> Symbol {
+        let symbol = Symbol {
+            name: name.clone(),
+            scope: SymbolScope::Function,
+            index: 0,
+        };
+        self.store.insert(name, symbol.clone());
+        symbol
+    }
+
+    // only looks at the innermost scope, used to catch redefinitions
+    pub fn resolve_local(&self, name: &str) -> Option<Symbol> {
+        self.store.get(name).cloned()
+    }
+
+    // None when the name isn't defined anywhere
+    // a frame only holds its own function's locals, so one of an enclosing function is an error until there are upvalues
+    pub fn resolve(&self, name: &str) -> Result<Option<Symbol>, String> {
+        if let Some(symbol) = self.store.get(name) {
+            return Ok(Some(symbol.clone()));
+        }
+        let Some(outer) = &self.outer else {
+            return Ok(None);
+        };
+        match outer.resolve(name)? {
+            Some(symbol) if self.kind == ScopeKind::Function && matches!(symbol.scope, SymbolScope::Local | SymbolScope::Function) => {
+                Err(format!("`{}` belongs to an enclosing function, closures aren't supported yet", name))
             }
+            symbol => Ok(symbol),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::{build::*, AssignOp}, vm::VM};
+
+    use super::*;
+
+    #[test]
+    fn test_compile_globals_and_if() {
+        // def x = 1; if (x <= 2) { x = 3; } ret x;
+        let program = vec![
+            decl("x", int(1)),
+            Statement::If {
+                condition: bin(var("x"), BinaryOp::LessEqual, int(2)),
+                then_branch: Box::new(expr(assign(var("x"), int(3)))),
+                else_branch: None,
+                span: Span::default(),
+            },
+            ret(var("x")),
+        ];
+
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        let bytecode = codegen.bytecode();
+        assert_eq!(bytecode.instructions, vec![
+            Instruction::LoadConstant(0),
+            Instruction::SetGlobal(0),
+            Instruction::GetGlobal(0),
+            Instruction::LoadConstant(1),
+            Instruction::LessEqual,
+            Instruction::JumpNotTruthy(10),
+            Instruction::LoadConstant(2),
+            Instruction::Dup,
+            Instruction::SetGlobal(0),
+            Instruction::Pop,
+            Instruction::GetGlobal(0),
+            Instruction::Return,
+        ]);
+        assert_eq!(bytecode.constants, vec![Object::Integer(1), Object::Integer(2), Object::Integer(3)]);
+        assert_eq!(bytecode.globals, vec!["x".to_string()]);
+    }
+
+    #[test]
+    fn test_compile_function_drops_dead_code() {
+        // fun f(a) { def b = a; ret b; b; }
+        let program = vec![function("f", &["a"], vec![decl("b", var("a")), ret(var("b")), expr(var("b"))])];
+
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        assert_eq!(codegen.warnings().len(), 1);
+
+        let bytecode = codegen.bytecode();
+        assert_eq!(bytecode.instructions, vec![Instruction::LoadConstant(0), Instruction::SetGlobal(0)]);
+        let Object::Function(function) = &bytecode.constants[0] else { panic!("expected a function") };
+        assert_eq!(function.instructions, vec![
+            Instruction::GetLocal(0),
+            Instruction::SetLocal(1),
+            Instruction::GetLocal(1),
+            Instruction::Return,
+            Instruction::Null,
+            Instruction::Return,
+        ]);
+        assert_eq!((function.num_locals, function.num_parameters), (2, 1));
+    }
+
+    #[test]
+    fn test_compile_undefined_variable() {
+        let mut codegen = CodeGen::new();
+        let program = vec![expr(var("nope"))];
+        let Err(CompileError::Unresolved(errors)) = codegen.compile(program) else { panic!("expected an unresolved name") };
+        assert_eq!(errors.iter().map(|error| error.to_string()).collect::<Vec<_>>(), vec!["0:0: undefined variable `nope`"]);
+    }
+
+    #[test]
+    fn test_compile_break_and_continue() {
+        let jump = |is_break: bool, label: Option<&str>| {
+            let label = label.map(str::to_string);
+            if is_break { Statement::Break { label, span: Span::default() } } else { Statement::Continue { label, span: Span::default() } }
+        };
+        let while_loop = |label: Option<&str>, body: Vec<Statement>| Statement::While {
+            label: label.map(str::to_string),
+            condition: var("c"),
+            body: block(body),
+            span: Span::default(),
+        };
+
+        // def c = true; outer: while (c) { while (c) { continue outer; } break; }
+        let program = vec![
+            decl("c", boolean(true)),
+            while_loop(Some("outer"), vec![while_loop(None, vec![jump(false, Some("outer"))]), jump(true, None)]),
+        ];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        assert_eq!(codegen.bytecode().instructions, vec![
+            Instruction::True,
+            Instruction::SetGlobal(0),
+            Instruction::GetGlobal(0),
+            Instruction::JumpNotTruthy(10),
+            Instruction::GetGlobal(0),
+            Instruction::JumpNotTruthy(8),
+            Instruction::Jump(2), // continue outer
+            Instruction::Jump(4),
+            Instruction::Jump(10), // break
+            Instruction::Jump(2),
+        ]);
+
+        assert_eq!(CodeGen::new().compile(vec![jump(true, None)]).map_err(String::from), Err("`break` outside of a loop".to_string()));
+        let program = vec![decl("c", int(1)), while_loop(None, vec![jump(false, Some("outer"))])];
+        assert_eq!(CodeGen::new().compile(program).map_err(String::from), Err("`continue outer` doesn't match any enclosing loop".to_string()));
+    }
+
+    // def sum = 0; for i in iterable { sum += add(i); } sum;
+    fn sum_loop(iterable: Iterable, add: fn(Box<Expression>) -> Box<Expression>) -> Vec<Statement> {
+        let add = expr(compound(var("sum"), AssignOp::Plus, add(var("i"))));
+        vec![
+            decl("sum", int(0)),
+            Statement::ForIn { label: None, variable: "i".to_string(), iterable, body: Box::new(add), span: Span::default() },
+            expr(var("sum")),
+        ]
+    }
+
+    fn run(program: Vec<Statement>) -> Result<Object, String> {
+        let mut codegen = CodeGen::new();
+        codegen.compile(program)?;
+        let mut vm = VM::new(codegen.bytecode())?;
+        vm.run().map_err(|e| e.message)?;
+        Ok(vm.last_popped().clone())
+    }
+
+    #[test]
+    fn test_compile_for_in_range() {
+        // for i in 0..=n { sum += i; }
+        let range = |start, end| Iterable::Range { start, end, inclusive: true };
+        let program = [vec![decl("n", int(3))], sum_loop(range(int(0), var("n")), |i| i)].concat();
+        let mut codegen = CodeGen::new();
+        codegen.compile(program.clone()).unwrap();
+        let instructions = &codegen.bytecode().instructions;
+        // the range is a counter and a bound, it never becomes an array
+        assert!(!instructions.iter().any(|inst| matches!(inst, Instruction::Array(_) | Instruction::Index)));
+        assert!(instructions.contains(&Instruction::LessEqual));
+        assert_eq!(run(program), Ok(Object::Integer(1 + 2 + 3)));
+
+        let program = sum_loop(Iterable::Range { start: int(1), end: int(4), inclusive: false }, |i| i);
+        assert_eq!(run(program), Ok(Object::Integer(1 + 2 + 3)));
+        // the last element is i64::MAX itself, the loop ends there instead of overflowing
+        let program = sum_loop(range(int(i64::MAX - 2), int(i64::MAX)), |_| int(1));
+        assert_eq!(run(program), Ok(Object::Integer(3)));
+    }
+
+    #[test]
+    fn test_compile_for_in_array() {
+        assert_eq!(run(sum_loop(Iterable::Array(array(vec![*int(4), *int(5), *int(6)])), |i| i)), Ok(Object::Integer(4 + 5 + 6)));
+        assert_eq!(run(sum_loop(Iterable::Array(array(vec![])), |i| i)), Ok(Object::Integer(0)));
+    }
+
+    #[test]
+    fn test_compile_compound_assign_to_element() {
+        // a[i] += 3
+        let program = vec![
+            decl("a", array(vec![*int(1), *int(2)])),
+            decl("i", int(1)),
+            expr(compound(index(var("a"), var("i")), AssignOp::Plus, int(3))),
+        ];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        // the index goes into a hidden slot once, the 1 in `i = 1` reuses the array's constant and is read back for the load and the store
+        assert_eq!(codegen.bytecode().instructions[6..], [
+            Instruction::GetGlobal(1),
+            Instruction::SetGlobal(2),
+            Instruction::GetGlobal(0),
+            Instruction::GetGlobal(2),
+            Instruction::Index,
+            Instruction::LoadConstant(2),
+            Instruction::Add,
+            Instruction::SetGlobal(3),
+            Instruction::GetGlobal(0),
+            Instruction::GetGlobal(2),
+            Instruction::GetGlobal(3),
+            Instruction::SetIndex,
+            Instruction::SetGlobal(0),
+            Instruction::GetGlobal(3),
+            Instruction::Pop,
+        ]);
+    }
+
+    #[test]
+    fn test_constant_pool_dedup() {
+        let string = |s: &str| Object::String(s.into());
+        let mut pool = ConstantPool::default();
+        assert_eq!(pool.add(Object::Integer(1)), 0);
+        assert_eq!(pool.add(string("ok")), 1);
+        assert_eq!(pool.add(Object::Integer(1)), 0);
+        assert_eq!(pool.add(string("ok")), 1);
+        assert_eq!(pool.add(Object::Array(vec![string("ok")])), 2);
+        // the string inside the array is the one already in the pool
+        match (&pool.objects()[1], &pool.objects()[2]) {
+            (Object::String(a), Object::Array(elements)) => assert!(matches!(&elements[0], Object::String(b) if Rc::ptr_eq(a, b))),
+            other => panic!("unexpected constants {:?}", other),
         }
+
+        let function = || Object::Function(Rc::new(Function { instructions: vec![Instruction::Null, Instruction::Return], num_locals: 0, num_parameters: 0, lines: LineTable::default() }));
+        assert_eq!(pool.add(function()), 3);
+        assert_eq!(pool.add(function()), 3);
+        assert_eq!(pool.objects().len(), 4);
+    }
+
+    #[test]
+    fn test_compile_match_jump_table() {
+        // match x { 1 => 10, 2 | 4 => 20, 5 => 30, _ => 0 }
+        let arms = vec![
+            arm(int_pattern(1), None, int(10)),
+            arm(or(vec![int_pattern(2), int_pattern(4)]), None, int(20)),
+            arm(int_pattern(5), None, int(30)),
+            arm(wildcard(), None, int(0)),
+        ];
+        let program = vec![decl("x", int(4)), expr(match_on(var("x"), arms))];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        assert_eq!(codegen.bytecode().instructions[2..], [
+            Instruction::GetGlobal(0),
+            Instruction::SetGlobal(1), // $match
+            Instruction::GetGlobal(1),
+            // 3 isn't listed and goes to the wildcard arm
+            Instruction::JumpTable { low: 1, targets: vec![6, 8, 12, 8, 10], default: 12 },
+            Instruction::LoadConstant(1),
+            Instruction::Jump(15),
+            Instruction::LoadConstant(2),
+            Instruction::Jump(15),
+            Instruction::LoadConstant(3),
+            Instruction::Jump(15),
+            Instruction::LoadConstant(4),
+            Instruction::Jump(15),
+            Instruction::Null, // no arm matched
+            Instruction::Pop,
+        ]);
+    }
+
+    #[test]
+    fn test_function_scope_round_trip() {
+        let mut table = SymbolTable::new();
+        table.define("a".to_string());
+
+        table.enter_scope(ScopeKind::Function);
+        let b = table.define("b".to_string());
+        assert_eq!((b.scope, b.index), (SymbolScope::Local, 0));
+        assert_eq!(table.resolve("a").unwrap().unwrap().scope, SymbolScope::Global);
+        assert_eq!(table.leave_scope(), 1);
+
+        assert!(table.resolve("b").unwrap().is_none());
+        assert_eq!(table.define("c".to_string()).index, 1);
+    }
+
+    #[test]
+    fn test_block_scopes_share_function_slots() {
+        let mut table = SymbolTable::new();
+        table.enter_scope(ScopeKind::Function);
+        table.define("x".to_string());
+
+        table.enter_scope(ScopeKind::Block);
+        let y = table.define("y".to_string());
+        let shadow = table.define("x".to_string());
+        assert_eq!((y.index, shadow.index), (1, 2));
+        assert_eq!(table.resolve("x").unwrap().unwrap().index, 2);
+        table.leave_scope();
+
+        // the block's slots are free again but still counted
+        assert_eq!(table.resolve("x").unwrap().unwrap().index, 0);
+        table.enter_scope(ScopeKind::Block);
+        assert_eq!(table.define("z".to_string()).index, 1);
+        table.leave_scope();
+
+        assert_eq!(table.num_locals(), 3);
+        assert_eq!(table.leave_scope(), 3);
+    }
+
+    #[test]
+    fn test_builtins_resolve_from_any_scope() {
+        let mut table = SymbolTable::new();
+        table.define("g".to_string());
+
+        table.enter_scope(ScopeKind::Function);
+        table.enter_scope(ScopeKind::Block);
+        let len = table.resolve("len").unwrap().unwrap();
+        assert_eq!((len.scope, len.index), (SymbolScope::Builtin, 0));
+        assert_eq!(table.define("l".to_string()).index, 0);
+        table.leave_scope();
+        table.leave_scope();
+
+        // a global hides the builtin instead of replacing it
+        assert_eq!(table.define("len".to_string()).scope, SymbolScope::Global);
+        table.enter_scope(ScopeKind::Function);
+        assert_eq!(table.resolve("len").unwrap().unwrap().scope, SymbolScope::Global);
+        assert_eq!(table.resolve("push").unwrap().unwrap().scope, SymbolScope::Builtin);
+    }
+
+    #[test]
+    fn test_call_builtins() {
+        // def a = push([1, 2], 3); len(a);
+        let program = vec![
+            decl("a", call("push", vec![*array(vec![*int(1), *int(2)]), *int(3)])),
+            expr(call("len", vec![*var("a")])),
+        ];
+        assert_eq!(run(program), Ok(Object::Integer(3)));
+        assert_eq!(run(vec![expr(call("len", vec![*int(1)]))]), Err("len cannot be applied to [Integer(1)]".to_string()));
+        let error = CodeGen::new().compile(vec![expr(assign(var("len"), int(1)))]).unwrap_err();
+        assert_eq!(error, CompileError::Message("cannot assign to the builtin `len`".to_string()));
+    }
+
+    #[test]
+    fn test_unassigned_errors_keep_their_spans() {
+        let at = |line: usize| Span { start: 0, end: 0, line, column: 1 };
+        let read = |name: &str, line: usize| Statement::Expression {
+            expression: Box::new(Expression::Variable { name: name.to_string(), span: at(line) }),
+            span: at(line),
+        };
+        // def x; def y; x; y;, one statement per line
+        let program = vec![
+            Statement::VariableDeclaration { name: "x".to_string(), type_ann: None, initializer: None, span: at(1) },
+            Statement::VariableDeclaration { name: "y".to_string(), type_ann: None, initializer: None, span: at(2) },
+            read("x", 3),
+            read("y", 4),
+        ];
+
+        let Err(CompileError::Unassigned(errors)) = CodeGen::new().compile(program.clone()) else { panic!("expected unassigned reads") };
+        let spans = errors.iter().map(|error| (error.span.line, error.unset_by.line)).collect::<Vec<_>>();
+        assert_eq!(spans, [(3, 1), (4, 2)]);
+        assert_eq!(CodeGen::new().compile(program).unwrap_err().to_string(), [
+            "3:1: `x` may be used before it is assigned, it is declared without a value (see 1:1)",
+            "4:1: `y` may be used before it is assigned, it is declared without a value (see 2:1)",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_patch_jump_needs_a_jump() {
+        let mut codegen = CodeGen::new();
+        codegen.emit(Instruction::Pop);
+        assert_eq!(codegen.patch_jump(0), Err("instruction 0 is not a jump".to_string()));
+        assert!(codegen.patch_jump(1).is_err());
+    }
+
+    #[test]
+    fn test_locals_of_enclosing_functions() {
+        let mut table = SymbolTable::new();
+        table.enter_scope(ScopeKind::Function);
+        table.define("a".to_string());
+        table.define("inner".to_string());
+
+        table.enter_scope(ScopeKind::Function);
+        table.define_function_name("inner".to_string());
+        table.enter_scope(ScopeKind::Block);
+        assert_eq!(table.resolve("inner").unwrap().unwrap().scope, SymbolScope::Function);
+        assert_eq!(table.resolve("a").unwrap_err(), "`a` belongs to an enclosing function, closures aren't supported yet");
+
+        // a third level can't reach the function around it either
+        table.enter_scope(ScopeKind::Function);
+        assert!(table.resolve("inner").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compile_nested_function() {
+        // fun outer(a, b) { fun inner(x) { ret inner(x); } ret inner(7); }, inner calls itself
+        let recursive = function("inner", &["x"], vec![ret(call("inner", vec![*var("x")]))]);
+        let program = vec![function("outer", &["a", "b"], vec![recursive, ret(call("inner", vec![*int(7)]))])];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        let constants = codegen.bytecode().constants;
+        let Some(Object::Function(inner)) = constants.iter().find(|constant| matches!(constant, Object::Function(f) if f.num_parameters == 1)) else {
+            panic!("expected inner to be compiled")
+        };
+        assert_eq!(inner.instructions[..3], [Instruction::CurrentFunction, Instruction::GetLocal(0), Instruction::Call(1)]);
+
+        // fun outer(a, b) { fun inner(x) { def y = x; ret b; } ret inner(7); }, b is one of outer's slots
+        let reads_outer = function("inner", &["x"], vec![decl("y", var("x")), ret(var("b"))]);
+        let program = vec![function("outer", &["a", "b"], vec![reads_outer, ret(call("inner", vec![*int(7)]))])];
+        let error = CodeGen::new().compile(program).unwrap_err();
+        assert_eq!(error.to_string(), "0:0: `b` belongs to an enclosing function, closures aren't supported yet");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{ast::{BinaryOp, Expression, LiteralValue, PrefixOp, Span, Statement}, visitor::{fold_expr_children, Folder}};
+
+// folds operators whose operands are literals and drops identity operations
+// anything that would fail at runtime (division by zero, overflow) is left for the runtime to report
+// the identities only drop an operation when the operand that stays can only be an Int,
+// `"a" + 0` is an error at runtime and has to stay one
+pub struct ConstantFolder;
+
+impl ConstantFolder {
+    pub fn fold(program: Vec<Statement>) -> Vec<Statement> {
+        ConstantFolder.fold_program(program)
+    }
+}
+
+impl Folder for ConstantFolder {
+    fn fold_expr(&mut self, expr: Expression) -> Expression {
+        // children first so `2 * 3 + 1` sees `6 + 1`
+        match fold_expr_children(self, expr) {
+            Expression::Binary { left, operator, right, span } => fold_binary(*left, operator, *right, span),
+            Expression::Unary { operator, operand, span } => fold_unary(operator, *operand, span),
+            expr => expr,
+        }
+    }
+}
+
+fn fold_binary(left: Expression, operator: BinaryOp, right: Expression, span: Span) -> Expression {
+    let folded = match (&left, &right) {
+        (Expression::Literal { value: l, .. }, Expression::Literal { value: r, .. }) => eval_binary(l, &operator, r),
+        // the right side of a short circuit is never evaluated, so it can go
+        (Expression::Literal { value: LiteralValue::Bool(true), .. }, _) if matches!(operator, BinaryOp::Or) => Some(LiteralValue::Bool(true)),
+        (Expression::Literal { value: LiteralValue::Bool(false), .. }, _) if matches!(operator, BinaryOp::And) => Some(LiteralValue::Bool(false)),
+        _ => None,
+    };
+    if let Some(value) = folded {
+        return Expression::Literal { value, span };
+    }
+
+    match (operator, is_int(&left, 0), is_int(&left, 1), is_int(&right, 0), is_int(&right, 1)) {
+        (BinaryOp::Plus, true, _, _, _) if known_int(&right) => right,
+        (BinaryOp::Plus | BinaryOp::Minus, _, _, true, _) if known_int(&left) => left,
+        (BinaryOp::Multiply, _, true, _, _) if known_int(&right) => right,
+        (BinaryOp::Multiply | BinaryOp::Divide, _, _, _, true) if known_int(&left) => left,
+        (operator, ..) => Expression::Binary { left: Box::new(left), operator, right: Box::new(right), span },
+    }
+}
+
+fn fold_unary(operator: PrefixOp, operand: Expression, span: Span) -> Expression {
+    let folded = match (&operator, &operand) {
+        (PrefixOp::Not, Expression::Literal { value: LiteralValue::Bool(b), .. }) => Some(LiteralValue::Bool(!b)),
+        (PrefixOp::Neg, Expression::Literal { value: LiteralValue::Integer(n), .. }) => n.checked_neg().map(LiteralValue::Integer),
+        _ => None,
+    };
+
+    match folded {
+        Some(value) => Expression::Literal { value, span },
+        None => Expression::Unary { operator, operand: Box::new(operand), span },
+    }
+}
+
+fn eval_binary(left: &LiteralValue, operator: &BinaryOp, right: &LiteralValue) -> Option<LiteralValue> {
+    use LiteralValue::*;
+
+    match (left, right) {
+        (Integer(l), Integer(r)) => {
+            let (l, r) = (*l, *r);
+            Some(match operator {
+                // checked ops give None on overflow and division by zero
+                BinaryOp::Plus => Integer(l.checked_add(r)?),
+                BinaryOp::Minus => Integer(l.checked_sub(r)?),
+                BinaryOp::Multiply => Integer(l.checked_mul(r)?),
+                BinaryOp::Divide => Integer(l.checked_div(r)?),
+                BinaryOp::Modulo => Integer(l.checked_rem(r)?),
+                BinaryOp::Equal => Bool(l == r),
+                BinaryOp::NotEqual => Bool(l != r),
+                BinaryOp::LessThan => Bool(l < r),
+                BinaryOp::GreaterThan => Bool(l > r),
+                BinaryOp::LessEqual => Bool(l <= r),
+                BinaryOp::GreaterEqual => Bool(l >= r),
+                BinaryOp::BitAnd => Integer(l & r),
+                BinaryOp::BitOr => Integer(l | r),
+                BinaryOp::BitXor => Integer(l ^ r),
+                BinaryOp::ShiftLeft => Integer(l.checked_shl(u32::try_from(r).ok()?)?),
+                BinaryOp::ShiftRight => Integer(l.checked_shr(u32::try_from(r).ok()?)?),
+                BinaryOp::And | BinaryOp::Or => return None,
+            })
+        }
+        (Bool(l), Bool(r)) => match operator {
+            BinaryOp::And => Some(Bool(*l && *r)),
+            BinaryOp::Or => Some(Bool(*l || *r)),
+            BinaryOp::Equal => Some(Bool(l == r)),
+            BinaryOp::NotEqual => Some(Bool(l != r)),
+            _ => None,
+        },
+        (String(l), String(r)) => match operator {
+            BinaryOp::Plus => Some(String(format!("{}{}", l, r))),
+            BinaryOp::Equal => Some(Bool(l == r)),
+            BinaryOp::NotEqual => Some(Bool(l != r)),
+            _ => None,
+        },
+        (Char(l), Char(r)) => match operator {
+            BinaryOp::Equal => Some(Bool(l == r)),
+            BinaryOp::NotEqual => Some(Bool(l != r)),
+            BinaryOp::LessThan => Some(Bool(l < r)),
+            BinaryOp::GreaterThan => Some(Bool(l > r)),
+            BinaryOp::LessEqual => Some(Bool(l <= r)),
+            BinaryOp::GreaterEqual => Some(Bool(l >= r)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_int(expr: &Expression, n: i64) -> bool {
+    matches!(expr, Expression::Literal { value: LiteralValue::Integer(value), .. } if *value == n)
+}
+
+// true when the expression gives an Int or fails, without knowing the type of any variable
+// negation and the operators other than + are only defined on Ints
+fn known_int(expr: &Expression) -> bool {
+    match expr {
+        Expression::Literal { value, .. } => matches!(value, LiteralValue::Integer(_)),
+        Expression::Unary { operator, .. } => matches!(operator, PrefixOp::Neg),
+        Expression::Binary { left, operator: BinaryOp::Plus, right, .. } => known_int(left) && known_int(right),
+        Expression::Binary { operator, .. } => matches!(
+            operator,
+            BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo
+                | BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor | BinaryOp::ShiftLeft | BinaryOp::ShiftRight
+        ),
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize) -> Span {
+        Span { start, end: start + 1, line: 1, column: start + 1 }
+    }
+
+    fn int(n: i64) -> Box<Expression> {
+        Box::new(Expression::Literal { value: LiteralValue::Integer(n), span: span(0) })
+    }
+
+    fn bin(left: Box<Expression>, operator: BinaryOp, right: Box<Expression>, at: usize) -> Box<Expression> {
+        Box::new(Expression::Binary { left, operator, right, span: span(at) })
+    }
+
+    fn fold(expr: Box<Expression>) -> Expression {
+        let program = vec![Statement::Expression { expression: expr, span: span(99) }];
+        match ConstantFolder::fold(program).remove(0) {
+            Statement::Expression { expression, .. } => *expression,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_folds_arithmetic_and_keeps_span() {
+        // 2 * 3 + 1
+        let expr = fold(bin(bin(int(2), BinaryOp::Multiply, int(3), 2), BinaryOp::Plus, int(1), 4));
+        assert!(matches!(expr, Expression::Literal { value: LiteralValue::Integer(7), ref span } if *span == self::span(4)));
+
+        let expr = fold(Box::new(Expression::Unary {
+            operator: PrefixOp::Not,
+            operand: Box::new(Expression::Literal { value: LiteralValue::Bool(true), span: span(1) }),
+            span: span(0),
+        }));
+        assert!(matches!(expr, Expression::Literal { value: LiteralValue::Bool(false), .. }));
+    }
+
+    #[test]
+    fn test_applies_identities() {
+        let x = || Box::new(Expression::Variable { name: "x".to_string(), span: span(7) });
+
+        // ((x - y) * 1) + 0 and 0 + (1 * (x - y)), x - y can only be an Int
+        let difference = || bin(x(), BinaryOp::Minus, Box::new(Expression::Variable { name: "y".to_string(), span: span(8) }), 9);
+        let expr = fold(bin(bin(difference(), BinaryOp::Multiply, int(1), 1), BinaryOp::Plus, int(0), 2));
+        assert!(matches!(expr, Expression::Binary { operator: BinaryOp::Minus, ref span, .. } if *span == self::span(9)));
+        let expr = fold(bin(int(0), BinaryOp::Plus, bin(int(1), BinaryOp::Multiply, difference(), 1), 2));
+        assert!(matches!(expr, Expression::Binary { operator: BinaryOp::Minus, .. }));
+
+        // x * 0 still evaluates x
+        let expr = fold(bin(x(), BinaryOp::Multiply, int(0), 1));
+        assert!(matches!(expr, Expression::Binary { .. }));
+
+        // x might not be an Int, and "a" + 0 has to stay an error
+        assert!(matches!(fold(bin(x(), BinaryOp::Plus, int(0), 1)), Expression::Binary { .. }));
+        let a = Box::new(Expression::Literal { value: LiteralValue::String("a".to_string()), span: span(3) });
+        assert!(matches!(fold(bin(a, BinaryOp::Plus, int(0), 1)), Expression::Binary { .. }));
+    }
+
+    #[test]
+    fn test_preserves_runtime_errors() {
+        assert!(matches!(fold(bin(int(1), BinaryOp::Divide, int(0), 1)), Expression::Binary { .. }));
+        assert!(matches!(fold(bin(int(1), BinaryOp::Modulo, bin(int(2), BinaryOp::Minus, int(2), 2), 1)), Expression::Binary { .. }));
+        assert!(matches!(fold(bin(int(i64::MAX), BinaryOp::Plus, int(1), 1)), Expression::Binary { .. }));
+    }
+}
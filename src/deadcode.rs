@@ -0,0 +1,198 @@
+use crate::{ast::{Expression, LiteralValue, Span, Statement}, visitor::{fold_stmt_children, Folder}};
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub message: String,
+    pub span: Span,
+}
+
+// finds statements that can never run, warns about them and drops them from the tree
+// that is anything after a ret, break or continue in the same block and branches behind a literal condition,
+// run ConstantFolder first so conditions like `1 > 2` are literals by the time we get here
+pub struct DeadCode {
+    warnings: Vec<Warning>,
+}
+
+impl DeadCode {
+    pub fn eliminate(program: Vec<Statement>) -> (Vec<Statement>, Vec<Warning>) {
+        let mut pass = DeadCode { warnings: Vec::new() };
+        let program = pass.fold_program(program);
+        (program, pass.warnings)
+    }
+
+    fn warn(&mut self, message: &str, span: &Span) {
+        self.warnings.push(Warning { message: message.to_string(), span: span.clone() });
+    }
+}
+
+impl Folder for DeadCode {
+    fn fold_program(&mut self, program: Vec<Statement>) -> Vec<Statement> {
+        let mut live = Vec::new();
+        let mut statements = program.into_iter();
+
+        while let Some(stmt) = statements.next() {
+            let stmt = self.fold_stmt(stmt);
+            if matches!(&stmt, Statement::Block { statements, .. } if statements.is_empty()) {
+                continue;
+            }
+
+            let diverges = diverges(&stmt);
+            live.push(stmt);
+            if diverges {
+                // one warning for the whole unreachable tail
+                if let Some(next) = statements.next() {
+                    self.warn("unreachable statement", next.span());
+                }
+                break;
+            }
+        }
+
+        live
+    }
+
+    fn fold_stmt(&mut self, stmt: Statement) -> Statement {
+        match fold_stmt_children(self, stmt) {
+            Statement::If { condition, then_branch, else_branch, span } => match literal_bool(&condition) {
+                Some(true) => {
+                    if let Some(else_branch) = &else_branch {
+                        self.warn("unreachable else branch, the condition is always true", else_branch.span());
+                    }
+                    scoped(*then_branch, span)
+                }
+                Some(false) => {
+                    self.warn("unreachable code, the condition is always false", then_branch.span());
+                    else_branch.map(|stmt| scoped(*stmt, span.clone())).unwrap_or(empty_block(span))
+                }
+                None => Statement::If { condition, then_branch, else_branch, span },
+            },
+            Statement::While { label, condition, body, span } => match literal_bool(&condition) {
+                Some(false) => {
+                    self.warn("unreachable loop body, the condition is always false", body.span());
+                    empty_block(span)
+                }
+                _ => Statement::While { label, condition, body, span },
+            },
+            Statement::For { label, init, condition, increment, body, span } => match literal_bool(&condition) {
+                Some(false) => {
+                    self.warn("unreachable loop body, the condition is always false", body.span());
+                    // the init still runs once, kept in a block so its variable stays scoped
+                    Statement::Block { statements: init.map(|stmt| vec![*stmt]).unwrap_or_default(), span }
+                }
+                _ => Statement::For { label, init, condition, increment, body, span },
+            },
+            stmt => stmt,
+        }
+    }
+}
+
+// true when control can't fall through to the next statement, it leaves through ret, break or continue
+pub fn diverges(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Return { .. } | Statement::Break { .. } | Statement::Continue { .. } => true,
+        Statement::Block { statements, .. } => statements.iter().any(diverges),
+        Statement::If { then_branch, else_branch: Some(else_branch), .. } => diverges(then_branch) && diverges(else_branch),
+        _ => false,
+    }
+}
+
+fn literal_bool(expr: &Expression) -> Option<bool> {
+    match expr {
+        Expression::Literal { value: LiteralValue::Bool(b), .. } => Some(*b),
+        _ => None,
+    }
+}
+
+// a branch is a scope of its own, on its own a `def x` in it would leak into the enclosing block
+fn scoped(branch: Statement, span: Span) -> Statement {
+    match branch {
+        Statement::Block { .. } => branch,
+        branch => Statement::Block { statements: vec![branch], span },
+    }
+}
+
+fn empty_block(span: Span) -> Statement {
+    Statement::Block { statements: Vec::new(), span }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize) -> Span {
+        Span { start: line * 10, end: line * 10 + 5, line, column: 1 }
+    }
+
+    fn ret(line: usize) -> Statement {
+        Statement::Return { value: None, span: span(line) }
+    }
+
+    fn expr(line: usize) -> Statement {
+        let value = Expression::Literal { value: LiteralValue::Integer(line as i64), span: span(line) };
+        Statement::Expression { expression: Box::new(value), span: span(line) }
+    }
+
+    fn block(statements: Vec<Statement>, line: usize) -> Box<Statement> {
+        Box::new(Statement::Block { statements, span: span(line) })
+    }
+
+    fn literal(b: bool) -> Box<Expression> {
+        Box::new(Expression::Literal { value: LiteralValue::Bool(b), span: Span::default() })
+    }
+
+    #[test]
+    fn test_drops_statements_after_return() {
+        // 1; if (c) { ret; } else { ret; } 3; 4;
+        let program = vec![
+            expr(1),
+            Statement::If {
+                condition: Box::new(Expression::Variable { name: "c".to_string(), span: span(2) }),
+                then_branch: block(vec![ret(2)], 2),
+                else_branch: Some(block(vec![ret(2), expr(2)], 2)),
+                span: span(2),
+            },
+            expr(3),
+            expr(4),
+        ];
+
+        let (program, warnings) = DeadCode::eliminate(program);
+        assert_eq!(program.len(), 2);
+        let lines = warnings.iter().map(|w| w.span.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 3]);
+        assert_eq!(warnings[1].message, "unreachable statement");
+    }
+
+    #[test]
+    fn test_removes_constant_false_branches() {
+        // if (false) { 1; } else { 2; }  while (false) { 3; }  if (true) { 4; }
+        let program = vec![
+            Statement::If { condition: literal(false), then_branch: block(vec![expr(1)], 1), else_branch: Some(block(vec![expr(2)], 2)), span: span(1) },
+            Statement::While { label: None, condition: literal(false), body: block(vec![expr(3)], 3), span: span(3) },
+            Statement::If { condition: literal(true), then_branch: block(vec![expr(4)], 4), else_branch: None, span: span(4) },
+        ];
+
+        let (program, warnings) = DeadCode::eliminate(program);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].span, span(1));
+        assert_eq!(warnings[1].span, span(3));
+
+        let lines = program.iter().map(|stmt| stmt.span().line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![2, 4]);
+    }
+
+    #[test]
+    fn test_kept_branch_stays_scoped() {
+        // if (true) def x = 1; if (false) 2; else def y = 3;
+        let define = |name: &str, line: usize| Statement::VariableDeclaration { name: name.to_string(), type_ann: None, initializer: None, span: span(line) };
+        let program = vec![
+            Statement::If { condition: literal(true), then_branch: Box::new(define("x", 1)), else_branch: None, span: span(1) },
+            Statement::If { condition: literal(false), then_branch: Box::new(expr(2)), else_branch: Some(Box::new(define("y", 3))), span: span(2) },
+        ];
+
+        let (program, _) = DeadCode::eliminate(program);
+        for (stmt, line) in program.iter().zip([1, 3]) {
+            let Statement::Block { statements, .. } = stmt else { panic!("expected a block, got {:?}", stmt) };
+            assert!(matches!(&statements[..], [Statement::VariableDeclaration { span, .. }] if span.line == line));
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::ast::{BinaryOp, Expression, Iterable, Span, Statement};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssignError {
+    pub message: String,
+    pub span: Span,
+    pub unset_by: Span, // the declaration or branch that leaves the variable without a value
+}
+
+impl Display for AssignError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {} (see {}:{})", self.span.line, self.span.column, self.message, self.unset_by.line, self.unset_by.column)
+    }
+}
+
+// why a variable may have no value at some point
+#[derive(Debug, Clone)]
+struct Unset {
+    name: String,
+    reason: &'static str,
+    span: Span,
+}
+
+// variable id -> why it may be unset, None when the point can't be reached
+type State = Option<HashMap<usize, Unset>>;
+
+// flow-sensitive check that every variable declared without an initializer
+// is assigned on every path before it is read
+// only tracks variables of the function being checked, a function body can run
+// at any time so it can't say anything about the globals it reads
+pub struct DefiniteAssignment {
+    scopes: Vec<HashMap<String, usize>>,
+    num_vars: usize,
+    errors: Vec<AssignError>,
+}
+
+impl DefiniteAssignment {
+    pub fn check(program: &[Statement]) -> Result<(), Vec<AssignError>> {
+        let mut pass = DefiniteAssignment { scopes: vec![HashMap::new()], num_vars: 0, errors: Vec::new() };
+        let mut state = Some(HashMap::new());
+        for stmt in program {
+            state = pass.statement(stmt, state);
+        }
+
+        if pass.errors.is_empty() { Ok(()) } else { Err(pass.errors) }
+    }
+
+    fn declare(&mut self, name: &str) -> usize {
+        self.num_vars += 1;
+        self.scopes.last_mut().unwrap().insert(name.to_string(), self.num_vars);
+        self.num_vars
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).copied()
+    }
+
+    fn scoped(&mut self, stmt: &Statement, state: State) -> State {
+        self.scopes.push(HashMap::new());
+        let state = self.statement(stmt, state);
+        self.scopes.pop();
+        state
+    }
+
+    fn statement(&mut self, stmt: &Statement, mut state: State) -> State {
+        // nothing to report in code that never runs
+        let vars = state.as_mut()?;
+
+        match stmt {
+            Statement::Block { statements, .. } => {
+                self.scopes.push(HashMap::new());
+                let state = statements.iter().fold(state, |state, stmt| self.statement(stmt, state));
+                self.scopes.pop();
+                state
+            }
+            Statement::If { condition, then_branch, else_branch, .. } => {
+                self.expression(condition, vars);
+                let then_state = self.scoped(then_branch, state.clone());
+                match else_branch {
+                    Some(else_branch) => {
+                        let else_state = self.scoped(else_branch, state);
+                        merge(
+                            (then_state, "the then branch doesn't assign it", then_branch.span()),
+                            (else_state, "the else branch doesn't assign it", else_branch.span()),
+                        )
+                    }
+                    None => merge(
+                        (then_state, "the then branch doesn't assign it", then_branch.span()),
+                        (state, "nothing assigns it when the condition is false", condition.span()),
+                    ),
+                }
+            }
+            Statement::While { condition, body, .. } => {
+                self.expression(condition, vars);
+                let body_state = self.scoped(body, state.clone());
+                skip_loop(state, body_state, body.span())
+            }
+            Statement::For { init, condition, increment, body, .. } => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = init {
+                    state = self.statement(init, state);
+                }
+                if let Some(vars) = state.as_mut() {
+                    self.expression(condition, vars);
+                }
+                let mut body_state = self.scoped(body, state.clone());
+                if let Some(increment) = increment {
+                    body_state = self.statement(increment, body_state);
+                }
+                self.scopes.pop();
+                skip_loop(state, body_state, body.span())
+            }
+            Statement::ForIn { variable, iterable, body, .. } => {
+                match iterable {
+                    Iterable::Range { start, end, .. } => {
+                        self.expression(start, vars);
+                        self.expression(end, vars);
+                    }
+                    Iterable::Array(array) => self.expression(array, vars),
+                }
+                self.scopes.push(HashMap::new());
+                self.declare(variable);
+                let body_state = self.scoped(body, state.clone());
+                self.scopes.pop();
+                skip_loop(state, body_state, body.span())
+            }
+            Statement::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value, vars);
+                }
+                None
+            }
+            // what reaches the loop exit through a break is a subset of what skipping the loop leaves unset
+            Statement::Break { .. } | Statement::Continue { .. } => None,
+            Statement::Expression { expression, .. } => {
+                self.expression(expression, vars);
+                state
+            }
+            Statement::VariableDeclaration { name, initializer, span, .. } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer, vars);
+                }
+                let var = self.declare(name);
+                if initializer.is_none() {
+                    vars.insert(var, Unset { name: name.clone(), reason: "it is declared without a value", span: span.clone() });
+                }
+                state
+            }
+            Statement::FunctionDeclaration { name, parameters, body, .. } => {
+                self.declare(name);
+
+                let outer = std::mem::replace(&mut self.scopes, vec![HashMap::new()]);
+                for param in parameters {
+                    self.declare(&param.name);
+                }
+                self.statement(body, Some(HashMap::new()));
+                self.scopes = outer;
+                state
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression, vars: &mut HashMap<usize, Unset>) {
+        match expr {
+            Expression::Binary { left, operator: BinaryOp::And | BinaryOp::Or, right, .. } => {
+                // the right side may not run, whatever it assigns doesn't count afterwards
+                self.expression(left, vars);
+                self.expression(right, &mut vars.clone());
+            }
+            Expression::Binary { left, right, .. } => {
+                self.expression(left, vars);
+                self.expression(right, vars);
+            }
+            Expression::Unary { operand, .. } => self.expression(operand, vars),
+            Expression::Literal { .. } => {}
+            Expression::Variable { name, span } => {
+                // reported once, later reads would only repeat the same error
+                if let Some(unset) = self.lookup(name).and_then(|var| vars.remove(&var)) {
+                    self.errors.push(AssignError {
+                        message: format!("`{}` may be used before it is assigned, {}", unset.name, unset.reason),
+                        span: span.clone(),
+                        unset_by: unset.span,
+                    });
+                }
+            }
+            Expression::Call { callee, arguments, .. } => {
+                self.expression(callee, vars);
+                for arg in arguments {
+                    self.expression(arg, vars);
+                }
+            }
+            Expression::Array { elements, .. } => {
+                for element in elements {
+                    self.expression(element, vars);
+                }
+            }
+            Expression::Match { scrutinee, arms, .. } => {
+                // any arm may be the one that runs, so like the right side of && nothing
+                // assigned inside an arm counts afterwards
+                self.expression(scrutinee, vars);
+                for arm in arms {
+                    self.scopes.push(HashMap::new());
+                    for name in arm.pattern.bindings() {
+                        self.declare(name);
+                    }
+                    let mut arm_vars = vars.clone();
+                    if let Some(guard) = &arm.guard {
+                        self.expression(guard, &mut arm_vars);
+                    }
+                    self.expression(&arm.body, &mut arm_vars);
+                    self.scopes.pop();
+                }
+            }
+            Expression::Index { array, index, .. } => {
+                self.expression(array, vars);
+                self.expression(index, vars);
+            }
+            // reads the target before assigning it
+            Expression::CompoundAssign { target, value, .. } => {
+                self.expression(target, vars);
+                self.expression(value, vars);
+            }
+            Expression::Increment { target, .. } => self.expression(target, vars),
+            Expression::Assign { target, value, .. } => {
+                self.expression(value, vars);
+                match target.as_ref() {
+                    Expression::Variable { name, .. } => {
+                        if let Some(var) = self.lookup(name) {
+                            vars.remove(&var);
+                        }
+                    }
+                    target => self.expression(target, vars),
+                }
+            }
+        }
+    }
+}
+
+// a variable is unset after a join when either side leaves it unset
+// when only one side does, that side is the reason
+fn merge(a: (State, &'static str, &Span), b: (State, &'static str, &Span)) -> State {
+    let (Some(a_vars), Some(b_vars)) = (&a.0, &b.0) else {
+        return a.0.or(b.0);
+    };
+
+    let mut merged = HashMap::new();
+    for (vars, other, reason, span) in [(a_vars, b_vars, a.1, a.2), (b_vars, a_vars, b.1, b.2)] {
+        for (var, unset) in vars {
+            let unset = match other.contains_key(var) {
+                true => unset.clone(),
+                false => Unset { name: unset.name.clone(), reason, span: span.clone() },
+            };
+            merged.entry(*var).or_insert(unset);
+        }
+    }
+    Some(merged)
+}
+
+// the body of a loop may not run at all, so what it assigns doesn't count after the loop
+fn skip_loop(before: State, body: State, body_span: &Span) -> State {
+    let mut vars = before?;
+    for (var, unset) in vars.iter_mut() {
+        if body.as_ref().is_some_and(|body| !body.contains_key(var)) {
+            *unset = Unset { name: unset.name.clone(), reason: "the loop body may not run", span: body_span.clone() };
+        }
+    }
+    Some(vars)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::LiteralValue;
+
+    use super::*;
+
+    fn span(line: usize) -> Span {
+        Span { start: line * 10, end: line * 10 + 5, line, column: 1 }
+    }
+
+    fn var(name: &str, line: usize) -> Box<Expression> {
+        Box::new(Expression::Variable { name: name.to_string(), span: span(line) })
+    }
+
+    fn decl(name: &str, line: usize) -> Statement {
+        Statement::VariableDeclaration { name: name.to_string(), type_ann: None, initializer: None, span: span(line) }
+    }
+
+    fn assign(name: &str, line: usize) -> Statement {
+        let value = Box::new(Expression::Literal { value: LiteralValue::Integer(1), span: span(line) });
+        let expression = Box::new(Expression::Assign { target: var(name, line), value, span: span(line) });
+        Statement::Expression { expression, span: span(line) }
+    }
+
+    fn read(name: &str, line: usize) -> Statement {
+        Statement::Expression { expression: var(name, line), span: span(line) }
+    }
+
+    fn if_stmt(then_branch: Statement, else_branch: Option<Statement>, line: usize) -> Statement {
+        Statement::If { condition: var("c", line), then_branch: Box::new(then_branch), else_branch: else_branch.map(Box::new), span: span(line) }
+    }
+
+    #[test]
+    fn test_straight_line() {
+        // def x; x = 1; x; def y; y;
+        let program = vec![decl("x", 1), assign("x", 2), read("x", 3), decl("y", 4), read("y", 5)];
+
+        let errors = DefiniteAssignment::check(&program).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "`y` may be used before it is assigned, it is declared without a value");
+        assert_eq!(errors[0].span, span(5));
+        assert_eq!(errors[0].unset_by, span(4));
+    }
+
+    #[test]
+    fn test_names_the_branch() {
+        // def x; if (c) x = 1; else { } x;
+        let program = vec![
+            decl("x", 1),
+            if_stmt(assign("x", 2), Some(Statement::Block { statements: vec![], span: span(3) }), 2),
+            read("x", 4),
+        ];
+        let errors = DefiniteAssignment::check(&program).unwrap_err();
+        assert_eq!(errors[0].message, "`x` may be used before it is assigned, the else branch doesn't assign it");
+        assert_eq!(errors[0].unset_by, span(3));
+
+        // def x; if (c) x = 1; x;
+        let program = vec![decl("x", 1), if_stmt(assign("x", 2), None, 2), read("x", 4)];
+        let errors = DefiniteAssignment::check(&program).unwrap_err();
+        assert_eq!(errors[0].message, "`x` may be used before it is assigned, nothing assigns it when the condition is false");
+        assert_eq!(errors[0].unset_by, span(2));
+    }
+
+    #[test]
+    fn test_accepts_assignment_on_every_path() {
+        // def x; if (c) x = 1; else ret; x;  def y; while (c) y = 1; y;
+        let program = vec![
+            decl("x", 1),
+            if_stmt(assign("x", 2), Some(Statement::Return { value: None, span: span(3) }), 2),
+            read("x", 4),
+            decl("y", 5),
+            Statement::While { label: None, condition: var("c", 6), body: Box::new(assign("y", 6)), span: span(6) },
+            read("y", 7),
+        ];
+
+        let errors = DefiniteAssignment::check(&program).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "`y` may be used before it is assigned, the loop body may not run");
+        assert_eq!(errors[0].span, span(7));
+    }
+}
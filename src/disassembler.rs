@@ -0,0 +1,163 @@
+use crate::{builtins::BUILTINS, code, codegen::{Bytecode, Function, Instruction, Object}, verifier};
+
+// prints compiled code the way it gets encoded:
+//   0003  LoadConstant 1          ; 42
+//   0006  JumpNotTruthy L0
+// L0:
+// jump targets become labels, constants and globals are shown by value and name,
+// and every function in the constant pool is printed after main
+// with the source text, each source line is shown above the first instruction compiled from it
+pub fn disassemble(bytecode: &Bytecode, source: Option<&str>) -> Result<String, String> {
+    let mut lines = Vec::new();
+    let main = Function {
+        instructions: bytecode.instructions.clone(),
+        num_locals: 0,
+        num_parameters: 0,
+        lines: bytecode.lines.clone(),
+    };
+    let mut pending = vec![("main".to_string(), &main)];
+    // every function, nested ones too, sits in the one constant pool, some inside constant arrays
+    pending.extend(verifier::functions(&bytecode.constants).into_iter().map(|(name, function)| (name, function.as_ref())));
+
+    for (i, (name, function)) in pending.into_iter().enumerate() {
+        if i > 0 {
+            lines.push(String::new());
+            lines.push(format!("== {}: function, {} parameters, {} locals ==", name, function.num_parameters, function.num_locals));
+        } else {
+            lines.push(format!("== {} ==", name));
+        }
+        code_listing(&mut lines, function, bytecode, source)?;
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+fn code_listing(lines: &mut Vec<String>, function: &Function, bytecode: &Bytecode, source: Option<&str>) -> Result<(), String> {
+    let instructions = &function.instructions;
+    let offsets = code::offsets(instructions)?;
+    let mut targets = instructions.iter().flat_map(Instruction::jump_targets).collect::<Vec<_>>();
+    targets.sort();
+    targets.dedup();
+    let label = |target: usize| format!("L{}", targets.binary_search(&target).unwrap_or_default());
+
+    let mut source_line = 0;
+    for (i, instruction) in instructions.iter().enumerate() {
+        if targets.binary_search(&i).is_ok() {
+            lines.push(format!("{}:", label(i)));
+        }
+        // line 0 is code that came from no particular place
+        if let Some(span) = function.lines.lookup(i)
+            && span.line != 0
+            && span.line != source_line
+        {
+            source_line = span.line;
+            match source.and_then(|source| source.lines().nth(span.line - 1)) {
+                Some(text) => lines.push(format!("; {}: {}", span.line, text.trim())),
+                None => lines.push(format!("; line {}", span.line)),
+            }
+        }
+        let (op, _) = code::split(instruction);
+        let name = op.definition().name;
+        let (operands, comment) = match instruction {
+            Instruction::LoadConstant(index) => (index.to_string(), bytecode.constants.get(*index).map(describe)),
+            Instruction::GetGlobal(index) | Instruction::SetGlobal(index) => {
+                (index.to_string(), bytecode.globals.get(*index).filter(|name| !name.is_empty()).cloned())
+            }
+            Instruction::GetLocal(n) | Instruction::SetLocal(n) | Instruction::Call(n) | Instruction::Array(n) => (n.to_string(), None),
+            Instruction::GetBuiltin(index) => (index.to_string(), BUILTINS.get(*index).map(|builtin| builtin.name.to_string())),
+            Instruction::Jump(target) | Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => (label(*target), None),
+            Instruction::JumpTable { low, targets, default } => {
+                let cases = targets.iter().enumerate().map(|(i, target)| format!("{}: {}", low + i as i64, label(*target)));
+                (format!("[{}] else {}", cases.collect::<Vec<_>>().join(", "), label(*default)), None)
+            }
+            _ => (String::new(), None),
+        };
+        let line = format!("{:04}  {} {}", offsets[i], name, operands);
+        match comment {
+            Some(comment) => lines.push(format!("{:<32}; {}", line.trim_end(), comment)),
+            None => lines.push(line.trim_end().to_string()),
+        }
+    }
+    // a jump past the last instruction still needs its label
+    if targets.last() == Some(&instructions.len()) {
+        lines.push(format!("{}:", label(instructions.len())));
+        lines.push(format!("{:04}  <end>", offsets[instructions.len()]));
+    }
+    Ok(())
+}
+
+// a constant the way it would be written in source
+fn describe(object: &Object) -> String {
+    match object {
+        Object::Integer(n) => n.to_string(),
+        Object::Boolean(b) => b.to_string(),
+        Object::String(s) => format!("{:?}", s),
+        Object::Char(c) => format!("{:?}", c),
+        Object::Array(elements) => format!("[{}]", elements.iter().map(describe).collect::<Vec<_>>().join(", ")),
+        Object::Function(function) => format!("<function/{}>", function.num_parameters),
+        Object::Builtin(index) => format!("<builtin {}>", index),
+        Object::Null => "null".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{ast::Span, codegen::LineTable};
+
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let function = Function {
+            instructions: vec![Instruction::GetLocal(0), Instruction::JumpNotTruthy(3), Instruction::Jump(0), Instruction::Return],
+            num_locals: 1,
+            num_parameters: 1,
+            lines: LineTable::default(),
+        };
+        let mut lines = LineTable::default();
+        lines.add(0, &Span { start: 0, end: 10, line: 1, column: 1 });
+        lines.add(2, &Span { start: 12, end: 30, line: 3, column: 1 });
+        let bytecode = Bytecode {
+            instructions: vec![
+                Instruction::LoadConstant(0),
+                Instruction::SetGlobal(0),
+                Instruction::LoadConstant(1),
+                Instruction::JumpTable { low: 1, targets: vec![4, 5], default: 5 },
+                Instruction::Pop,
+            ],
+            constants: vec![Object::Function(Rc::new(function)), Object::Array(vec![Object::Integer(1), Object::String("a".into())])],
+            globals: vec!["f".to_string()],
+            lines,
+        };
+        assert_eq!(disassemble(&bytecode, Some("fn f(x) {}\n\n  match [1, \"a\"] {}")).unwrap(), "\
+== main ==
+; 1: fn f(x) {}
+0000  LoadConstant 0            ; <function/1>
+0003  SetGlobal 0               ; f
+; 3: match [1, \"a\"] {}
+0006  LoadConstant 1            ; [1, \"a\"]
+0009  JumpTable [1: L0, 2: L1] else L1
+L0:
+0018  Pop
+L1:
+0019  <end>
+
+== constant 0: function, 1 parameters, 1 locals ==
+L0:
+0000  GetLocal 0
+0002  JumpNotTruthy L1
+0005  Jump L0
+L1:
+0008  Return
+");
+        // a function inside a constant array is listed too
+        let mut in_array = bytecode.clone();
+        let Object::Function(function) = in_array.constants.remove(0) else { unreachable!() };
+        in_array.constants = vec![Object::Array(vec![Object::Integer(1), Object::Function(function)])];
+        assert!(disassemble(&in_array, None).unwrap().contains("\n== constant 0, element 1: function, 1 parameters, 1 locals ==\nL0:\n0000  GetLocal 0\n"));
+
+        // without the source only the line numbers are known
+        assert!(disassemble(&bytecode, None).unwrap().starts_with("== main ==\n; line 1\n0000"));
+    }
+}
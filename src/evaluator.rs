@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
-use crate::{ast::{BinaryOp, Expression, LiteralValue, Span, Statement}, visitor::{ExprVisitor, StmtVisitor}};
+use crate::{ast::{AssignOp, BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Parameter, Pattern, PrefixOp, Span, Statement, Type}, codegen::CompileError, definite::DefiniteAssignment, visitor::{ExprVisitor, StmtVisitor, Walked}};
 
 
 // 假设我们有以下代码：
@@ -89,56 +89,702 @@ fn create_sample_ast() -> Statement {
 }
 
 
+#[derive(Debug, Clone)]
+pub enum Value {
+    Integer(i64),
+    Bool(bool),
+    String(String),
+    Char(char),
+    Function(Rc<FunctionValue>),
+    Array(Rc<Vec<Value>>),
+    Null,
+}
+
+#[derive(Debug)]
+pub struct FunctionValue {
+    name: Option<String>, // only for a function declared inside another, whose body can't see the enclosing scopes
+    parameters: Vec<String>,
+    body: Statement,
+}
+
+// 语句执行完之后控制流往哪里走
+#[derive(Debug)]
+enum Flow {
+    Next,
+    Return(Value),
+    Break(Option<String>),    // 带标签就跳出那个循环，不带就是最里层的
+    Continue(Option<String>),
+}
+
+impl Walked for Flow {
+    fn walked() -> Self {
+        Flow::Next
+    }
+}
+
+impl Walked for Value {
+    fn walked() -> Self {
+        Value::Null
+    }
+}
+
 // 一个简单的Evaluator
 // 这个Evaluator会遍历AST并计算表达式的值
-struct Evaluator {
-    variables: HashMap<String, LiteralValue>,
+pub struct Evaluator {
+    scopes: Vec<HashMap<String, Value>>, // scopes[0] 是全局变量
+    calls: usize,                        // function calls in progress
 }
 
 impl Evaluator {
-    fn new() -> Self {
-        Self { variables: HashMap::new() }
+    pub fn new() -> Self {
+        Self { scopes: vec![HashMap::new()], calls: 0 }
+    }
+
+    // 顶层的 ret 结束整个程序，返回它的值
+    // like codegen it first rejects reads of variables that may not have a value yet
+    pub fn evaluate(&mut self, program: &[Statement]) -> Result<Option<Value>, String> {
+        DefiniteAssignment::check(program).map_err(|errors| CompileError::Unassigned(errors).to_string())?;
+        for stmt in program {
+            match self.visit_stmt(stmt)? {
+                Flow::Next => {}
+                Flow::Return(value) => return Ok(Some(value)),
+                flow => return Err(stray_jump(&flow)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn define(&mut self, name: &str, value: Value) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), value);
+    }
+
+    fn scoped(&mut self, stmt: &Statement) -> Result<Flow, String> {
+        self.scopes.push(HashMap::new());
+        let flow = self.visit_stmt(stmt);
+        self.scopes.pop();
+        flow
     }
 
-    fn evaluate(&mut self, stmt: &Statement) -> Option<LiteralValue> {
-        self.visit_stmt(stmt)
+    fn truthy(&mut self, condition: &Expression) -> Result<bool, String> {
+        Ok(match self.visit_expr(condition)? {
+            Value::Bool(b) => b,
+            Value::Null => false,
+            _ => true,
+        })
+    }
+
+    fn index(&mut self, index: &Expression) -> Result<usize, String> {
+        match self.visit_expr(index)? {
+            Value::Integer(i) => usize::try_from(i).map_err(|_| format!("index {} out of bounds", i)),
+            other => Err(format!("cannot index with {:?}", other)),
+        }
+    }
+
+    // a[f()][j] 变成变量名 a 和已经算好的下标，每个子表达式只算一次
+    fn place(&mut self, target: &Expression) -> Result<(String, Vec<usize>), String> {
+        match target {
+            Expression::Variable { name, .. } => Ok((name.clone(), Vec::new())),
+            Expression::Index { array, index, .. } => {
+                let (name, mut indices) = self.place(array)?;
+                indices.push(self.index(index)?);
+                Ok((name, indices))
+            }
+            _ => Err("invalid assignment target".to_string()),
+        }
+    }
+
+    // operator 不为空就是 x op= value
+    fn assign(&mut self, target: &Expression, operator: Option<&BinaryOp>, value: &Expression) -> Result<Value, String> {
+        let (name, indices) = self.place(target)?;
+        let value = match operator {
+            Some(operator) => {
+                let mut current = self.visit_variable(&name, target.span())?;
+                for &index in &indices {
+                    current = element(&current, index)?;
+                }
+                let value = self.visit_expr(value)?;
+                binary(operator, &current, &value)?
+            }
+            None => self.visit_expr(value)?,
+        };
+
+        // 数组是值语义，改之前先复制一份共享的
+        let mut slot = self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(&name)).ok_or_else(|| format!("undefined variable `{}`", name))?;
+        for &index in &indices {
+            slot = match slot {
+                Value::Array(elements) => {
+                    let elements = Rc::make_mut(elements);
+                    let len = elements.len();
+                    elements.get_mut(index).ok_or_else(|| format!("index {} out of bounds for length {}", index, len))?
+                }
+                other => return Err(format!("cannot index into {:?}", other)),
+            };
+        }
+        *slot = value.clone();
+        Ok(value)
+    }
+
+    fn call(&mut self, function: &Rc<FunctionValue>, arguments: Vec<Value>) -> Result<Value, String> {
+        if function.parameters.len() != arguments.len() {
+            return Err(format!("expected {} arguments, found {}", function.parameters.len(), arguments.len()));
+        }
+
+        // 函数体只能看到全局变量和自己的参数
+        // a nested function still sees its own name so it can call itself, same as codegen's CurrentFunction
+        let mut locals = HashMap::new();
+        if let Some(name) = &function.name {
+            locals.insert(name.clone(), Value::Function(function.clone()));
+        }
+        locals.extend(function.parameters.iter().cloned().zip(arguments));
+        let callers = self.scopes.split_off(1);
+        self.scopes.push(locals);
+        self.calls += 1;
+        let flow = self.visit_stmt(&function.body);
+        self.calls -= 1;
+        self.scopes.truncate(1);
+        self.scopes.extend(callers);
+
+        match flow? {
+            Flow::Next => Ok(Value::Null),
+            Flow::Return(value) => Ok(value),
+            flow => Err(stray_jump(&flow)),
+        }
+    }
+}
+
+// a break or continue that no loop caught
+fn stray_jump(flow: &Flow) -> String {
+    match flow {
+        Flow::Break(Some(label)) => format!("`break {}` doesn't match any enclosing loop", label),
+        Flow::Continue(Some(label)) => format!("`continue {}` doesn't match any enclosing loop", label),
+        Flow::Break(None) => "`break` outside of a loop".to_string(),
+        _ => "`continue` outside of a loop".to_string(),
+    }
+}
+
+// 匹配的时候顺便把绑定的名字放进 bindings，只有整个模式匹配上才会用到
+fn matches(pattern: &Pattern, value: &Value, bindings: &mut HashMap<String, Value>) -> bool {
+    match (pattern, value) {
+        (Pattern::Wildcard { .. }, _) => true,
+        (Pattern::Binding { name, .. }, value) => {
+            bindings.insert(name.clone(), value.clone());
+            true
+        }
+        (Pattern::Literal { value: literal, .. }, value) => match (literal, value) {
+            (LiteralValue::Integer(l), Value::Integer(v)) => l == v,
+            (LiteralValue::String(l), Value::String(v)) => l == v,
+            (LiteralValue::Char(l), Value::Char(v)) => l == v,
+            (LiteralValue::Bool(l), Value::Bool(v)) => l == v,
+            _ => false,
+        },
+        (Pattern::Array { elements, rest, .. }, Value::Array(values)) => {
+            let shape = values.len() == elements.len() || (*rest && values.len() > elements.len());
+            shape && elements.iter().zip(values.iter()).all(|(element, value)| matches(element, value, bindings))
+        }
+        (Pattern::Or { alternatives, .. }, value) => alternatives.iter().any(|alternative| matches(alternative, value, bindings)),
+        _ => false,
+    }
+}
+
+fn binary(operator: &BinaryOp, left_val: &Value, right_val: &Value) -> Result<Value, String> {
+    let result = match (left_val, right_val) {
+        (Value::Integer(l), Value::Integer(r)) => {
+            let (l, r) = (*l, *r);
+            match operator {
+                BinaryOp::Plus => l.checked_add(r).map(Value::Integer),
+                BinaryOp::Minus => l.checked_sub(r).map(Value::Integer),
+                BinaryOp::Multiply => l.checked_mul(r).map(Value::Integer),
+                BinaryOp::Divide if r == 0 => return Err("division by zero".to_string()),
+                BinaryOp::Modulo if r == 0 => return Err("division by zero".to_string()),
+                BinaryOp::Divide => l.checked_div(r).map(Value::Integer),
+                BinaryOp::Modulo => l.checked_rem(r).map(Value::Integer),
+                BinaryOp::Equal => Some(Value::Bool(l == r)),
+                BinaryOp::NotEqual => Some(Value::Bool(l != r)),
+                BinaryOp::LessThan => Some(Value::Bool(l < r)),
+                BinaryOp::GreaterThan => Some(Value::Bool(l > r)),
+                BinaryOp::LessEqual => Some(Value::Bool(l <= r)),
+                BinaryOp::GreaterEqual => Some(Value::Bool(l >= r)),
+                BinaryOp::BitAnd => Some(Value::Integer(l & r)),
+                BinaryOp::BitOr => Some(Value::Integer(l | r)),
+                BinaryOp::BitXor => Some(Value::Integer(l ^ r)),
+                BinaryOp::ShiftLeft | BinaryOp::ShiftRight if !(0..64).contains(&r) => return Err(format!("cannot shift by {}", r)),
+                BinaryOp::ShiftLeft => Some(Value::Integer(l << r)),
+                BinaryOp::ShiftRight => Some(Value::Integer(l >> r)),
+                BinaryOp::And | BinaryOp::Or => unreachable!(),
+            }
+            .ok_or_else(|| "integer overflow".to_string())?
+        }
+        (Value::String(l), Value::String(r)) => match operator {
+            BinaryOp::Plus => Value::String(format!("{}{}", l, r)),
+            BinaryOp::Equal => Value::Bool(l == r),
+            BinaryOp::NotEqual => Value::Bool(l != r),
+            _ => return Err(format!("operator {:?} cannot be applied to strings", operator)),
+        },
+        (Value::Char(l), Value::Char(r)) => match operator {
+            BinaryOp::Equal => Value::Bool(l == r),
+            BinaryOp::NotEqual => Value::Bool(l != r),
+            BinaryOp::LessThan => Value::Bool(l < r),
+            BinaryOp::GreaterThan => Value::Bool(l > r),
+            BinaryOp::LessEqual => Value::Bool(l <= r),
+            BinaryOp::GreaterEqual => Value::Bool(l >= r),
+            _ => return Err(format!("operator {:?} cannot be applied to chars", operator)),
+        },
+        (Value::Bool(l), Value::Bool(r)) => match operator {
+            BinaryOp::Equal => Value::Bool(l == r),
+            BinaryOp::NotEqual => Value::Bool(l != r),
+            _ => return Err(format!("operator {:?} cannot be applied to bools", operator)),
+        },
+        _ => return Err(format!("operator {:?} cannot be applied to {:?} and {:?}", operator, left_val, right_val)),
+    };
+    Ok(result)
+}
+
+// 数组取下标，越界报错
+fn element(array: &Value, index: usize) -> Result<Value, String> {
+    match array {
+        Value::Array(elements) => elements.get(index).cloned().ok_or_else(|| format!("index {} out of bounds for length {}", index, elements.len())),
+        other => Err(format!("cannot index into {:?}", other)),
     }
 }
 
-impl StmtVisitor<Option<LiteralValue>> for Evaluator { // 访问者相当于把所有的ast转化成了想要的结果 T 这就是为什么要叫计算器
-    fn visit_block(&mut self, statements: &[Statement], _span: &Span) -> Option<LiteralValue> {
-        let mut result = None;
+// true when a break or continue with this target belongs to the loop with `label`
+fn targets(label: Option<&str>, target: &Option<String>) -> bool {
+    target.is_none() || target.as_deref() == label
+}
+
+impl StmtVisitor<Result<Flow, String>, Result<Value, String>> for Evaluator { // 访问者相当于把所有的ast转化成了想要的结果 T 这就是为什么要叫计算器
+    fn visit_block(&mut self, statements: &[Statement], _span: &Span) -> Result<Flow, String> {
+        self.scopes.push(HashMap::new());
+        let mut flow = Ok(Flow::Next);
         for stmt in statements {
-            result = self.visit_stmt(stmt);
-            // 如果遇到return语句，直接返回结果
-            if result.is_some() {
-                return result;
+            flow = self.visit_stmt(stmt);
+            // 遇到return、break、continue，后面的语句都不执行了
+            if !matches!(flow, Ok(Flow::Next)) {
+                break;
+            }
+        }
+        self.scopes.pop();
+        flow
+    }
+
+    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, _span: &Span) -> Result<Flow, String> {
+        if self.truthy(condition)? {
+            self.scoped(then_branch)
+        } else if let Some(else_branch) = else_branch {
+            self.scoped(else_branch)
+        } else {
+            Ok(Flow::Next)
+        }
+    }
+
+    fn visit_while(&mut self, label: Option<&str>, condition: &Expression, body: &Statement, _span: &Span) -> Result<Flow, String> {
+        while self.truthy(condition)? {
+            match self.scoped(body)? {
+                Flow::Break(target) if targets(label, &target) => break,
+                Flow::Continue(target) if targets(label, &target) => continue,
+                Flow::Next => {}
+                // ret, or a jump out of some outer loop
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn visit_for(&mut self, label: Option<&str>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement, _span: &Span) -> Result<Flow, String> {
+        self.scopes.push(HashMap::new());
+        let flow = (|| {
+            if let Some(init) = init {
+                self.visit_stmt(init)?;
+            }
+            while self.truthy(condition)? {
+                match self.scoped(body)? {
+                    Flow::Break(target) if targets(label, &target) => break,
+                    Flow::Continue(target) if targets(label, &target) => {}
+                    Flow::Next => {}
+                    flow => return Ok(flow),
+                }
+                if let Some(increment) = increment {
+                    self.visit_stmt(increment)?;
+                }
+            }
+            Ok(Flow::Next)
+        })();
+        self.scopes.pop();
+        flow
+    }
+
+    fn visit_for_in(&mut self, label: Option<&str>, variable: &str, iterable: &Iterable, body: &Statement, _span: &Span) -> Result<Flow, String> {
+        // 范围不会生成数组，直接数下去
+        let elements: Box<dyn Iterator<Item = Value>> = match iterable {
+            Iterable::Range { start, end, inclusive } => {
+                let (Value::Integer(start), Value::Integer(end)) = (self.visit_expr(start)?, self.visit_expr(end)?) else {
+                    return Err("range bounds must be integers".to_string());
+                };
+                match inclusive {
+                    true => Box::new((start..=end).map(Value::Integer)),
+                    false => Box::new((start..end).map(Value::Integer)),
+                }
+            }
+            Iterable::Array(array) => match self.visit_expr(array)? {
+                Value::Array(elements) => Box::new((0..elements.len()).map(move |i| elements[i].clone())),
+                other => return Err(format!("cannot iterate over {:?}", other)),
+            },
+        };
+
+        for element in elements {
+            self.scopes.push(HashMap::from([(variable.to_string(), element)]));
+            let flow = self.scoped(body);
+            self.scopes.pop();
+            match flow? {
+                Flow::Break(target) if targets(label, &target) => break,
+                Flow::Continue(target) if targets(label, &target) => continue,
+                Flow::Next => {}
+                flow => return Ok(flow),
             }
         }
-        result
+        Ok(Flow::Next)
     }
 
-    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, span: &Span) -> Option<LiteralValue> {
-        let cond_value = 
+    fn visit_return(&mut self, value: Option<&Expression>, _span: &Span) -> Result<Flow, String> {
+        let value = match value {
+            Some(value) => self.visit_expr(value)?,
+            None => Value::Null,
+        };
+        Ok(Flow::Return(value))
+    }
+
+    fn visit_break(&mut self, label: Option<&str>, _span: &Span) -> Result<Flow, String> {
+        Ok(Flow::Break(label.map(str::to_string)))
+    }
+
+    fn visit_continue(&mut self, label: Option<&str>, _span: &Span) -> Result<Flow, String> {
+        Ok(Flow::Continue(label.map(str::to_string)))
+    }
+
+    fn visit_expression(&mut self, expression: &Expression, _span: &Span) -> Result<Flow, String> {
+        self.visit_expr(expression)?;
+        Ok(Flow::Next)
+    }
+
+    fn visit_variable_declaration(&mut self, name: &str, _type_ann: &Option<Type>, initializer: Option<&Expression>, _span: &Span) -> Result<Flow, String> {
+        let value = match initializer {
+            Some(initializer) => self.visit_expr(initializer)?,
+            None => Value::Null,
+        };
+        self.define(name, value);
+        Ok(Flow::Next)
+    }
+
+    fn visit_function_declaration(&mut self, name: &str, parameters: &[Parameter], _return_type: &Option<Type>, body: &Statement, _span: &Span) -> Result<Flow, String> {
+        let function = FunctionValue {
+            name: (self.calls > 0).then(|| name.to_string()),
+            parameters: parameters.iter().map(|p| p.name.clone()).collect(),
+            body: body.clone(),
+        };
+        self.define(name, Value::Function(Rc::new(function)));
+        Ok(Flow::Next)
     }
 }
 
-impl ExprVisitor<Option<LiteralValue>> for Evaluator {
-    fn visit_binary(&mut self, left: &Expression, operator: &BinaryOp, right: &Expression, span: &Span) -> Option<LiteralValue> {
-        let left_val = self.visit_expr(left);
-        let right_val = self.visit_expr(right);
-        match (left_val, right_val) {
-            (Some(LiteralValue::Integer(l)), Some(LiteralValue::Integer(r))) => {
-                match operator {
-                    BinaryOp::Plus => Some(LiteralValue::Integer(l + r)),
-                    BinaryOp::Minus => Some(LiteralValue::Integer(l - r)),
-                    BinaryOp::Multiply => Some(LiteralValue::Integer(l * r)),
-                    BinaryOp::Divide => Some(LiteralValue::Integer(l / r)),
-                    BinaryOp::GreaterThan => Some(LiteralValue::Bool(l > r)),
-                    _ => None,
+impl ExprVisitor<Result<Value, String>> for Evaluator {
+    fn visit_binary(&mut self, left: &Expression, operator: &BinaryOp, right: &Expression, _span: &Span) -> Result<Value, String> {
+        // && 和 || 短路，右边不一定执行；没短路时结果就是右边的值，跟 codegen 一致
+        if matches!(operator, BinaryOp::And | BinaryOp::Or) {
+            let left = self.truthy(left)?;
+            return match (operator, left) {
+                (BinaryOp::And, false) => Ok(Value::Bool(false)),
+                (BinaryOp::Or, true) => Ok(Value::Bool(true)),
+                _ => self.visit_expr(right),
+            };
+        }
+
+        let left_val = self.visit_expr(left)?;
+        let right_val = self.visit_expr(right)?;
+        binary(operator, &left_val, &right_val)
+    }
+
+    fn visit_unary(&mut self, operator: &PrefixOp, operand: &Expression, _span: &Span) -> Result<Value, String> {
+        match (operator, self.visit_expr(operand)?) {
+            (PrefixOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            (PrefixOp::Neg, Value::Integer(n)) => n.checked_neg().map(Value::Integer).ok_or_else(|| "integer overflow".to_string()),
+            (operator, value) => Err(format!("operator {:?} cannot be applied to {:?}", operator, value)),
+        }
+    }
+
+    fn visit_literal(&mut self, value: &LiteralValue, _span: &Span) -> Result<Value, String> {
+        Ok(match value {
+            LiteralValue::Integer(n) => Value::Integer(*n),
+            LiteralValue::String(s) => Value::String(s.clone()),
+            LiteralValue::Char(c) => Value::Char(*c),
+            LiteralValue::Bool(b) => Value::Bool(*b),
+        })
+    }
+
+    fn visit_assign(&mut self, target: &Expression, value: &Expression, _span: &Span) -> Result<Value, String> {
+        self.assign(target, None, value)
+    }
+
+    fn visit_compound_assign(&mut self, target: &Expression, operator: &AssignOp, value: &Expression, _span: &Span) -> Result<Value, String> {
+        self.assign(target, Some(&operator.binary()), value)
+    }
+
+    fn visit_index(&mut self, array: &Expression, index: &Expression, _span: &Span) -> Result<Value, String> {
+        let array = self.visit_expr(array)?;
+        let index = self.index(index)?;
+        element(&array, index)
+    }
+
+    fn visit_call(&mut self, callee: &Expression, arguments: &[Expression], _span: &Span) -> Result<Value, String> {
+        let callee = self.visit_expr(callee)?;
+        let arguments = arguments.iter().map(|arg| self.visit_expr(arg)).collect::<Result<Vec<_>, _>>()?;
+        match callee {
+            Value::Function(function) => self.call(&function, arguments),
+            other => Err(format!("{:?} is not callable", other)),
+        }
+    }
+
+    fn visit_array(&mut self, elements: &[Expression], _span: &Span) -> Result<Value, String> {
+        let elements = elements.iter().map(|element| self.visit_expr(element)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Value::Array(Rc::new(elements)))
+    }
+
+    fn visit_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], _span: &Span) -> Result<Value, String> {
+        let value = self.visit_expr(scrutinee)?;
+        for arm in arms {
+            let mut bindings = HashMap::new();
+            if !matches(&arm.pattern, &value, &mut bindings) {
+                continue;
+            }
+
+            // guard 不成立就接着试下一个分支
+            self.scopes.push(bindings);
+            let result = (|| {
+                if let Some(guard) = &arm.guard && !self.truthy(guard)? {
+                    return Ok(None);
                 }
+                self.visit_expr(&arm.body).map(Some)
+            })();
+            self.scopes.pop();
+            if let Some(result) = result? {
+                return Ok(result);
             }
-            _ => None,
         }
+        // like the compiled code, a match that no arm takes is null
+        Ok(Value::Null)
+    }
+
+    fn visit_variable(&mut self, name: &str, _span: &Span) -> Result<Value, String> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned().ok_or_else(|| format!("undefined variable `{}`", name))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::{build::*, IncrementOp}, codegen::{CodeGen, Object}, vm::VM};
+
+    use super::*;
+
+    fn jump(is_break: bool, label: Option<&str>) -> Statement {
+        let label = label.map(str::to_string);
+        match is_break {
+            true => Statement::Break { label, span: Span::default() },
+            false => Statement::Continue { label, span: Span::default() },
+        }
+    }
+
+    // for (def i = 0; i < n; i = i + 1) { ... }
+    fn count(label: Option<&str>, i: &str, n: i64, body: Vec<Statement>) -> Statement {
+        Statement::For {
+            label: label.map(str::to_string),
+            init: Some(Box::new(decl(i, int(0)))),
+            condition: bin(var(i), BinaryOp::LessThan, int(n)),
+            increment: Some(Box::new(expr(assign(var(i), bin(var(i), BinaryOp::Plus, int(1)))))),
+            body: block(body),
+            span: Span::default(),
+        }
+    }
+
+    fn result(program: &[Statement]) -> i64 {
+        match Evaluator::new().evaluate(program) {
+            Ok(Some(Value::Integer(n))) => n,
+            other => panic!("expected an integer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sample_ast() {
+        let program = vec![decl("a", int(3)), decl("b", int(7)), create_sample_ast()];
+        assert_eq!(result(&program), 7);
+    }
+
+    // the same tree run here and compiled for the vm gives the same value
+    #[test]
+    fn test_agrees_with_the_vm() {
+        let on_vm = |program: &[Statement]| {
+            let mut codegen = CodeGen::new();
+            codegen.compile(program.to_vec()).unwrap();
+            let mut vm = VM::new(codegen.bytecode()).unwrap();
+            vm.run().unwrap();
+            vm.last_popped().clone()
+        };
+        let evaluated = |program: &[Statement]| match Evaluator::new().evaluate(program) {
+            Ok(Some(Value::Integer(n))) => Object::Integer(n),
+            Ok(Some(Value::Null)) => Object::Null,
+            other => panic!("expected an integer or null, got {:?}", other),
+        };
+
+        // fun outer(n) { fun down(k) { if (k == 0) { ret 100; } ret down(k - 1) + 1; } ret down(n); } ret outer(3);
+        let down = function("down", &["k"], vec![
+            if_then(bin(var("k"), BinaryOp::Equal, int(0)), vec![ret(int(100))]),
+            ret(bin(call("down", vec![*bin(var("k"), BinaryOp::Minus, int(1))]), BinaryOp::Plus, int(1))),
+        ]);
+        let nested = vec![function("outer", &["n"], vec![down, ret(call("down", vec![*var("n")]))]), ret(call("outer", vec![*int(3)]))];
+        // ret match 3 { 1 => 1 };
+        let no_arm = vec![ret(match_on(int(3), vec![arm(int_pattern(1), None, int(1))]))];
+
+        // if ('a' != 'b') { ret 1; } ret 0;
+        let character = |c| Box::new(Expression::Literal { value: LiteralValue::Char(c), span: Span::default() });
+        let chars = vec![if_then(bin(character('a'), BinaryOp::NotEqual, character('b')), vec![ret(int(1))]), ret(int(0))];
+        let mut cases = vec![(nested, Object::Integer(103)), (no_arm, Object::Null), (chars, Object::Integer(1))];
+
+        // def x = 12; x op= 5; ret x; for every compound operator
+        let operators = [
+            (AssignOp::Plus, 17), (AssignOp::Minus, 7), (AssignOp::Multiply, 60), (AssignOp::Divide, 2), (AssignOp::Modulo, 2),
+            (AssignOp::BitAnd, 4), (AssignOp::BitOr, 13), (AssignOp::BitXor, 9), (AssignOp::ShiftLeft, 384), (AssignOp::ShiftRight, 0),
+        ];
+        for (operator, expected) in operators {
+            let program = vec![decl("x", int(12)), expr(compound(var("x"), operator, int(5))), ret(var("x"))];
+            cases.push((program, Object::Integer(expected)));
+        }
+
+        for (program, expected) in cases {
+            assert_eq!(evaluated(&program), expected);
+            assert_eq!(on_vm(&program), expected);
+        }
+    }
+
+    #[test]
+    fn test_rejects_unassigned_reads() {
+        // def x; if (c) { x = 1; } ret x; fails before anything runs, even with c true
+        let program = vec![
+            decl("c", boolean(true)),
+            Statement::VariableDeclaration { name: "x".to_string(), type_ann: None, initializer: None, span: Span::default() },
+            if_then(var("c"), vec![expr(assign(var("x"), int(1)))]),
+            ret(var("x")),
+        ];
+        let error = Evaluator::new().evaluate(&program).unwrap_err();
+        assert_eq!(error, "0:0: `x` may be used before it is assigned, nothing assigns it when the condition is false (see 0:0)");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_short_circuit_keeps_the_right_value() {
+        let returning = |value| vec![ret(value)];
+        assert_eq!(result(&returning(bin(boolean(true), BinaryOp::And, int(5)))), 5);
+        assert_eq!(result(&returning(bin(boolean(false), BinaryOp::Or, int(5)))), 5);
+        assert!(matches!(Evaluator::new().evaluate(&returning(bin(boolean(false), BinaryOp::And, int(5)))), Ok(Some(Value::Bool(false)))));
+        assert!(matches!(Evaluator::new().evaluate(&returning(bin(int(0), BinaryOp::Or, int(5)))), Ok(Some(Value::Bool(true)))));
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        // def sum = 0; for (i) { if (i == 5) break; if (i % 2 == 0) continue; sum = sum + i; } ret sum;
+        let program = vec![
+            decl("sum", int(0)),
+            count(None, "i", 100, vec![
+                if_then(bin(var("i"), BinaryOp::Equal, int(5)), vec![jump(true, None)]),
+                if_then(bin(bin(var("i"), BinaryOp::Modulo, int(2)), BinaryOp::Equal, int(0)), vec![jump(false, None)]),
+                expr(assign(var("sum"), bin(var("sum"), BinaryOp::Plus, var("i")))),
+            ]),
+            ret(var("sum")),
+        ];
+        assert_eq!(result(&program), 1 + 3);
+    }
+
+    #[test]
+    fn test_labeled_jumps() {
+        // def n = 0; outer: for (i < 3) { for (j < 3) { if (j == 1) continue outer; if (i == 2) break outer; n = n + 1; } } ret n;
+        let program = vec![
+            decl("n", int(0)),
+            count(Some("outer"), "i", 3, vec![
+                count(None, "j", 3, vec![
+                    if_then(bin(var("j"), BinaryOp::Equal, int(1)), vec![jump(false, Some("outer"))]),
+                    if_then(bin(var("i"), BinaryOp::Equal, int(2)), vec![jump(true, Some("outer"))]),
+                    expr(assign(var("n"), bin(var("n"), BinaryOp::Plus, int(1)))),
+                ]),
+            ]),
+            ret(var("n")),
+        ];
+        assert_eq!(result(&program), 2);
+
+        let err = Evaluator::new().evaluate(&[jump(true, None)]).unwrap_err();
+        assert_eq!(err, "`break` outside of a loop");
+    }
+
+    #[test]
+    fn test_for_in() {
+        let for_in = |iterable: Iterable| Statement::ForIn {
+            label: None,
+            variable: "x".to_string(),
+            iterable,
+            body: Box::new(expr(assign(var("sum"), bin(var("sum"), BinaryOp::Plus, var("x"))))),
+            span: Span::default(),
+        };
+        let sum = |iterable: Iterable| result(&[decl("sum", int(0)), for_in(iterable), ret(var("sum"))]);
+
+        assert_eq!(sum(Iterable::Range { start: int(1), end: int(4), inclusive: false }), 1 + 2 + 3);
+        assert_eq!(sum(Iterable::Range { start: int(1), end: int(4), inclusive: true }), 1 + 2 + 3 + 4);
+        assert_eq!(sum(Iterable::Array(array(vec![*int(5), *int(7)]))), 12);
+    }
+
+    #[test]
+    fn test_match() {
+        // match xs { [] => 0, [1 | 2, ..] => 1, [x, y] if x > y => x, [_, y, ..] => y }
+        let classify = |elements: Vec<Expression>| {
+            let arms = vec![
+                arm(array_pattern(vec![], false), None, int(0)),
+                arm(array_pattern(vec![or(vec![int_pattern(1), int_pattern(2)])], true), None, int(1)),
+                arm(array_pattern(vec![binding("x"), binding("y")], false), Some(bin(var("x"), BinaryOp::GreaterThan, var("y"))), var("x")),
+                arm(array_pattern(vec![wildcard(), binding("y")], true), None, var("y")),
+            ];
+            result(&[ret(match_on(array(elements), arms))])
+        };
+
+        assert_eq!(classify(vec![]), 0);
+        assert_eq!(classify(vec![*int(2), *int(9)]), 1);
+        assert_eq!(classify(vec![*int(9), *int(5)]), 9);
+        assert_eq!(classify(vec![*int(5), *int(9), *int(0)]), 9);
+
+        let program = vec![ret(match_on(int(3), vec![arm(int_pattern(1), None, int(1))]))];
+        assert!(matches!(Evaluator::new().evaluate(&program), Ok(Some(Value::Null))));
+    }
+
+    #[test]
+    fn test_compound_assign_evaluates_the_index_once() {
+        // fun f() { calls = calls + 1; ret 1; }
+        let f = function("f", &[], vec![expr(assign(var("calls"), bin(var("calls"), BinaryOp::Plus, int(1)))), ret(int(1))]);
+        let program = vec![
+            decl("calls", int(0)),
+            decl("a", array(vec![*int(10), *int(20)])),
+            decl("bits", int(6)),
+            f,
+            expr(compound(index(var("a"), call("f", vec![])), AssignOp::Plus, int(5))),
+            expr(compound(var("bits"), AssignOp::ShiftLeft, int(2))),
+            ret(bin(bin(index(var("a"), int(1)), BinaryOp::Multiply, int(100)), BinaryOp::Plus, bin(var("bits"), BinaryOp::Plus, var("calls")))),
+        ];
+        assert_eq!(result(&program), 25 * 100 + 24 + 1);
+    }
+
+    #[test]
+    fn test_increment() {
+        // def x = 5; def a = [1, 2]; def y = --x; ++a[1]; ret y * 100 + a[1] * 10 + x;
+        let program = vec![
+            decl("x", int(5)),
+            decl("a", array(vec![*int(1), *int(2)])),
+            decl("y", increment(var("x"), IncrementOp::Decrement)),
+            expr(increment(index(var("a"), int(1)), IncrementOp::Increment)),
+            ret(bin(bin(bin(var("y"), BinaryOp::Multiply, int(100)), BinaryOp::Plus, bin(index(var("a"), int(1)), BinaryOp::Multiply, int(10))), BinaryOp::Plus, var("x"))),
+        ];
+        assert_eq!(result(&program), 4 * 100 + 3 * 10 + 4);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::ast::{LiteralValue, Pattern, Type};
+
+// a row of patterns still to be matched, None matches anything
+type Row<'a> = Vec<Option<&'a Pattern>>;
+
+// what the values in a column look like
+enum Column {
+    Bool,
+    Array(Option<Type>), // element type when known
+    Open,                // ints, strings, chars: too many values to list, only a catch-all covers them
+}
+
+// returns a value none of the patterns match, None when every value of `ty` is covered
+// based on the usefulness check from Maranget's "Warnings for pattern matching"
+pub fn uncovered(patterns: &[&Pattern], ty: Option<&Type>) -> Option<String> {
+    let rows = patterns.iter().map(|pattern| vec![Some(*pattern)]).collect::<Vec<_>>();
+    missing(&rows, &[ty.cloned()]).map(|mut witness| witness.remove(0))
+}
+
+// one witness per column for a value vector no row matches
+fn missing(rows: &[Row], tys: &[Option<Type>]) -> Option<Vec<String>> {
+    let Some((ty, rest_tys)) = tys.split_first() else {
+        return rows.is_empty().then(Vec::new);
+    };
+    let rows = rows.iter().flat_map(expand).collect::<Vec<_>>();
+    let heads = rows.iter().filter_map(|row| row[0]).collect::<Vec<_>>();
+
+    let column = match ty {
+        Some(Type::Bool) => Column::Bool,
+        Some(Type::Array(element)) => Column::Array(Some(*element.clone())),
+        Some(_) => Column::Open,
+        // unknown types are guessed from the patterns
+        None => match heads.first() {
+            Some(Pattern::Literal { value: LiteralValue::Bool(_), .. }) => Column::Bool,
+            Some(Pattern::Array { .. }) => Column::Array(None),
+            _ => Column::Open,
+        },
+    };
+
+    match column {
+        Column::Bool => [true, false].into_iter().find_map(|b| {
+            let specialized = rows.iter().filter_map(|row| match row[0] {
+                None => Some(row[1..].to_vec()),
+                Some(Pattern::Literal { value: LiteralValue::Bool(v), .. }) if *v == b => Some(row[1..].to_vec()),
+                Some(_) => None,
+            }).collect::<Vec<_>>();
+            missing(&specialized, rest_tys).map(|witness| prepend(b.to_string(), witness))
+        }),
+        Column::Array(element) => {
+            // past the longest pattern every length behaves the same, so one more is enough
+            let longest = heads.iter().filter_map(|head| match head {
+                Pattern::Array { elements, .. } => Some(elements.len()),
+                _ => None,
+            }).max().unwrap_or(0);
+
+            (0..=longest + 1).find_map(|len| {
+                let specialized = rows.iter().filter_map(|row| {
+                    let mut columns = match row[0] {
+                        None => vec![None; len],
+                        Some(Pattern::Array { elements, rest, .. }) if elements.len() == len || (*rest && elements.len() < len) => {
+                            let mut columns = elements.iter().map(Some).collect::<Vec<_>>();
+                            columns.resize(len, None);
+                            columns
+                        }
+                        Some(_) => return None,
+                    };
+                    columns.extend_from_slice(&row[1..]);
+                    Some(columns)
+                }).collect::<Vec<_>>();
+
+                let mut tys = vec![element.clone(); len];
+                tys.extend_from_slice(rest_tys);
+                missing(&specialized, &tys).map(|mut witness| {
+                    let rest = witness.split_off(len);
+                    prepend(format!("[{}]", witness.join(", ")), rest)
+                })
+            })
+        }
+        Column::Open => {
+            let defaults = rows.iter().filter(|row| row[0].is_none()).map(|row| row[1..].to_vec()).collect::<Vec<_>>();
+            missing(&defaults, rest_tys).map(|witness| prepend("_".to_string(), witness))
+        }
+    }
+}
+
+// or-patterns become one row per alternative, bindings match anything like `_`
+fn expand<'a>(row: &Row<'a>) -> Vec<Row<'a>> {
+    match row[0] {
+        Some(Pattern::Or { alternatives, .. }) => alternatives
+            .iter()
+            .flat_map(|alternative| {
+                let mut row = row.clone();
+                row[0] = Some(alternative);
+                expand(&row)
+            })
+            .collect(),
+        Some(Pattern::Wildcard { .. } | Pattern::Binding { .. }) => {
+            let mut row = row.clone();
+            row[0] = None;
+            vec![row]
+        }
+        _ => vec![row.clone()],
+    }
+}
+
+fn prepend(first: String, mut rest: Vec<String>) -> Vec<String> {
+    rest.insert(0, first);
+    rest
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::build::*;
+
+    use super::*;
+
+    fn check(patterns: &[Pattern], ty: Type) -> Option<String> {
+        uncovered(&patterns.iter().collect::<Vec<_>>(), Some(&ty))
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(check(&[bool_pattern(true)], Type::Bool), Some("false".to_string()));
+        let or = or(vec![bool_pattern(true), bool_pattern(false)]);
+        assert_eq!(check(&[or], Type::Bool), None);
+
+        assert_eq!(check(&[int_pattern(0), int_pattern(1)], Type::Int), Some("_".to_string()));
+        let binding = binding("n");
+        assert_eq!(check(&[int_pattern(0), binding], Type::Int), None);
+    }
+
+    #[test]
+    fn test_array_shapes() {
+        let ty = Type::Array(Box::new(Type::Bool));
+        // [] and [_, ..] cover every length
+        assert_eq!(check(&[array_pattern(vec![], false), array_pattern(vec![wildcard()], true)], ty.clone()), None);
+
+        // [] [true, ..] leaves arrays starting with false
+        let patterns = [array_pattern(vec![], false), array_pattern(vec![bool_pattern(true)], true)];
+        assert_eq!(check(&patterns, ty), Some("[false]".to_string()));
+
+        // fixed lengths never cover every length
+        let patterns = [array_pattern(vec![], false), array_pattern(vec![wildcard()], false)];
+        assert_eq!(check(&patterns, Type::Array(Box::new(Type::Int))), Some("[_, _]".to_string()));
+    }
+}
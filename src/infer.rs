@@ -0,0 +1,789 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display};
+
+use crate::{ast::{AssignOp, BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Parameter, Pattern, PrefixOp, Span, Statement, Type}, resolver::NodeId, typechecker::literal_type, visitor::{ExprVisitor, StmtVisitor, Walked}};
+
+// a type while inference is running, Var is a not yet known type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Var(usize),
+    Int,
+    Float,
+    String,
+    Char,
+    Bool,
+    Void,
+    Array(Box<Ty>),
+    Object(String),
+    Function(Vec<Ty>, Box<Ty>),
+}
+
+impl Ty {
+    // None while the type still contains variables, e.g. a generic function
+    pub fn to_type(&self) -> Option<Type> {
+        Some(match self {
+            Ty::Var(_) => return None,
+            Ty::Int => Type::Int,
+            Ty::Float => Type::Float,
+            Ty::String => Type::String,
+            Ty::Char => Type::Char,
+            Ty::Bool => Type::Bool,
+            Ty::Void => Type::Void,
+            Ty::Array(elem) => Type::Array(Box::new(elem.to_type()?)),
+            Ty::Object(name) => Type::Object(name.clone()),
+            Ty::Function(params, ret) => Type::Function(
+                params.iter().map(Ty::to_type).collect::<Option<Vec<_>>>()?,
+                Box::new(ret.to_type()?),
+            ),
+        })
+    }
+
+    fn free_vars(&self, out: &mut Vec<usize>) {
+        match self {
+            Ty::Var(v) if !out.contains(v) => out.push(*v),
+            Ty::Array(elem) => elem.free_vars(out),
+            Ty::Function(params, ret) => {
+                params.iter().for_each(|p| p.free_vars(out));
+                ret.free_vars(out);
+            }
+            _ => {}
+        }
+    }
+
+    fn write(&self, f: &mut std::fmt::Formatter<'_>, names: &[usize]) -> std::fmt::Result {
+        match self {
+            // variables are renamed 'a, 'b, ... in order of appearance
+            Ty::Var(v) => {
+                let n = names.iter().position(|name| name == v).unwrap_or(*v);
+                match n {
+                    0..26 => write!(f, "'{}", (b'a' + n as u8) as char),
+                    _ => write!(f, "'t{}", n),
+                }
+            }
+            Ty::Array(elem) => {
+                write!(f, "[")?;
+                elem.write(f, names)?;
+                write!(f, "]")
+            }
+            Ty::Function(params, ret) => {
+                write!(f, "fun(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    param.write(f, names)?;
+                }
+                write!(f, "): ")?;
+                ret.write(f, names)
+            }
+            other => write!(f, "{}", other.to_type().unwrap()),
+        }
+    }
+}
+
+impl From<&Type> for Ty {
+    fn from(ty: &Type) -> Self {
+        match ty {
+            Type::Int => Ty::Int,
+            Type::Float => Ty::Float,
+            Type::String => Ty::String,
+            Type::Char => Ty::Char,
+            Type::Bool => Ty::Bool,
+            Type::Void => Ty::Void,
+            Type::Array(elem) => Ty::Array(Box::new(elem.as_ref().into())),
+            Type::Object(name) => Ty::Object(name.clone()),
+            Type::Function(params, ret) => Ty::Function(
+                params.iter().map(Ty::from).collect(),
+                Box::new(ret.as_ref().into()),
+            ),
+        }
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = Vec::new();
+        self.free_vars(&mut names);
+        self.write(f, &names)
+    }
+}
+
+impl Walked for Ty {
+    fn walked() -> Self {
+        Ty::Void
+    }
+}
+
+// forall vars. ty
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Ty,
+}
+
+impl Scheme {
+    fn mono(ty: Ty) -> Self {
+        Self { vars: Vec::new(), ty }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InferError {
+    pub message: String,
+    pub span: Span,
+}
+
+struct FunctionFrame {
+    ret: Ty,
+    has_return: bool,
+}
+
+// hindley-milner inference for the whole program
+// function declarations are generalized so generic helpers can be called at different types
+pub struct TypeInference {
+    bindings: Vec<Option<Ty>>, // substitution, indexed by type variable
+    scopes: Vec<HashMap<String, Scheme>>,
+    functions: Vec<FunctionFrame>,
+    types: HashMap<NodeId, Ty>, // every expression, declaration and parameter
+    hover: HashMap<Span, NodeId>, // the last node recorded at each span, spans of different nodes can be equal
+    errors: Vec<InferError>,
+}
+
+impl TypeInference {
+    pub fn new() -> Self {
+        Self {
+            bindings: Vec::new(),
+            scopes: vec![HashMap::new()],
+            functions: Vec::new(),
+            types: HashMap::new(),
+            hover: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn infer(&mut self, program: &[Statement]) -> Result<(), Vec<InferError>> {
+        for stmt in program {
+            self.visit_stmt(stmt);
+        }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // the inferred type of the node at span, used for hover
+    pub fn type_at(&self, span: &Span) -> Option<Ty> {
+        self.hover.get(span).and_then(|node| self.type_of(node))
+    }
+
+    fn type_of(&self, node: &NodeId) -> Option<Ty> {
+        self.types.get(node).map(|ty| self.resolve(ty))
+    }
+
+    // fill every missing annotation whose inferred type is fully known
+    // the program has to be the one `infer` saw, the nodes are found by their address
+    pub fn annotate(&self, program: &mut [Statement]) {
+        for stmt in program {
+            self.annotate_stmt(stmt);
+        }
+    }
+
+    fn annotate_stmt(&self, stmt: &mut Statement) {
+        match stmt {
+            Statement::Block { statements, .. } => self.annotate(statements),
+            Statement::If { then_branch, else_branch, .. } => {
+                self.annotate_stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.annotate_stmt(else_branch);
+                }
+            }
+            Statement::While { body, .. } | Statement::ForIn { body, .. } => self.annotate_stmt(body),
+            Statement::For { init, increment, body, .. } => {
+                if let Some(init) = init {
+                    self.annotate_stmt(init);
+                }
+                if let Some(increment) = increment {
+                    self.annotate_stmt(increment);
+                }
+                self.annotate_stmt(body);
+            }
+            Statement::VariableDeclaration { type_ann, span, .. } => {
+                if type_ann.is_none() {
+                    *type_ann = self.type_of(&NodeId::of(span)).and_then(|ty| ty.to_type());
+                }
+            }
+            Statement::FunctionDeclaration { parameters, return_type, body, span, .. } => {
+                for param in parameters.iter_mut() {
+                    if param.type_ann.is_none() {
+                        param.type_ann = self.type_of(&NodeId::of(&param.span)).and_then(|ty| ty.to_type());
+                    }
+                }
+                if return_type.is_none()
+                    && let Some(Ty::Function(_, ret)) = self.type_of(&NodeId::of(span))
+                {
+                    *return_type = ret.to_type();
+                }
+                self.annotate_stmt(body);
+            }
+            Statement::Return { .. } | Statement::Break { .. } | Statement::Continue { .. } | Statement::Expression { .. } => {}
+        }
+    }
+
+    fn error(&mut self, message: String, span: &Span) {
+        self.errors.push(InferError { message, span: span.clone() });
+    }
+
+    fn fresh(&mut self) -> Ty {
+        self.bindings.push(None);
+        Ty::Var(self.bindings.len() - 1)
+    }
+
+    fn record(&mut self, span: &Span, ty: &Ty) {
+        self.types.insert(NodeId::of(span), ty.clone());
+        self.hover.insert(span.clone(), NodeId::of(span));
+    }
+
+    // follow bound variables one level deep
+    fn shallow(&self, ty: &Ty) -> Ty {
+        let mut ty = ty.clone();
+        while let Ty::Var(v) = ty {
+            match &self.bindings[v] {
+                Some(bound) => ty = bound.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    fn resolve(&self, ty: &Ty) -> Ty {
+        match self.shallow(ty) {
+            Ty::Array(elem) => Ty::Array(Box::new(self.resolve(&elem))),
+            Ty::Function(params, ret) => Ty::Function(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(&ret)),
+            ),
+            other => other,
+        }
+    }
+
+    fn occurs(&self, var: usize, ty: &Ty) -> bool {
+        match self.shallow(ty) {
+            Ty::Var(v) => v == var,
+            Ty::Array(elem) => self.occurs(var, &elem),
+            Ty::Function(params, ret) => params.iter().any(|p| self.occurs(var, p)) || self.occurs(var, &ret),
+            _ => false,
+        }
+    }
+
+    fn unify(&mut self, a: &Ty, b: &Ty, span: &Span) {
+        if let Err(message) = self.try_unify(a, b) {
+            let message = format!("{}: expected {}, found {}", message, self.resolve(a), self.resolve(b));
+            self.error(message, span);
+        }
+    }
+
+    fn try_unify(&mut self, a: &Ty, b: &Ty) -> Result<(), &'static str> {
+        match (self.shallow(a), self.shallow(b)) {
+            (Ty::Var(x), Ty::Var(y)) if x == y => Ok(()),
+            (Ty::Var(v), other) | (other, Ty::Var(v)) => {
+                if self.occurs(v, &other) {
+                    return Err("infinite type");
+                }
+                self.bindings[v] = Some(other);
+                Ok(())
+            }
+            (Ty::Array(x), Ty::Array(y)) => self.try_unify(&x, &y),
+            (Ty::Function(xp, xr), Ty::Function(yp, yr)) => {
+                if xp.len() != yp.len() {
+                    return Err("wrong number of arguments");
+                }
+                for (x, y) in xp.iter().zip(&yp) {
+                    self.try_unify(x, y)?;
+                }
+                self.try_unify(&xr, &yr)
+            }
+            (x, y) if x == y => Ok(()),
+            _ => Err("mismatched types"),
+        }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Ty {
+        let mapping = scheme.vars.iter().map(|v| (*v, self.fresh())).collect::<HashMap<_, _>>();
+        Self::substitute(&scheme.ty, &mapping)
+    }
+
+    fn substitute(ty: &Ty, mapping: &HashMap<usize, Ty>) -> Ty {
+        match ty {
+            Ty::Var(v) => mapping.get(v).cloned().unwrap_or(Ty::Var(*v)),
+            Ty::Array(elem) => Ty::Array(Box::new(Self::substitute(elem, mapping))),
+            Ty::Function(params, ret) => Ty::Function(
+                params.iter().map(|p| Self::substitute(p, mapping)).collect(),
+                Box::new(Self::substitute(ret, mapping)),
+            ),
+            other => other.clone(),
+        }
+    }
+
+    // quantify over the variables that don't appear anywhere in the environment
+    fn generalize(&self, ty: &Ty) -> Scheme {
+        let ty = self.resolve(ty);
+        let mut env_vars = Vec::new();
+        for scheme in self.scopes.iter().flat_map(|scope| scope.values()) {
+            let mut vars = Vec::new();
+            self.resolve(&scheme.ty).free_vars(&mut vars);
+            env_vars.extend(vars.into_iter().filter(|v| !scheme.vars.contains(v)));
+        }
+        let env_vars = env_vars.into_iter().collect::<HashSet<_>>();
+
+        let mut vars = Vec::new();
+        ty.free_vars(&mut vars);
+        vars.retain(|v| !env_vars.contains(v));
+        Scheme { vars, ty }
+    }
+
+    fn define(&mut self, name: &str, scheme: Scheme) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), scheme);
+    }
+
+    fn lookup(&self, name: &str) -> Option<Scheme> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned()
+    }
+
+    fn infer_condition(&mut self, condition: &Expression) {
+        let ty = self.visit_expr(condition);
+        self.unify(&Ty::Bool, &ty, condition.span());
+    }
+
+    fn infer_scoped(&mut self, stmt: &Statement) {
+        self.scopes.push(HashMap::new());
+        self.visit_stmt(stmt);
+        self.scopes.pop();
+    }
+
+    // ties the pattern to the type of the value it matches and defines its bindings
+    fn infer_pattern(&mut self, pattern: &Pattern, ty: &Ty) {
+        match pattern {
+            Pattern::Wildcard { .. } => {}
+            Pattern::Literal { value, span } => self.unify(ty, &Ty::from(&literal_type(value)), span),
+            Pattern::Binding { name, span } => {
+                self.record(span, ty);
+                self.define(name, Scheme::mono(ty.clone()));
+            }
+            Pattern::Array { elements, span, .. } => {
+                let element = self.fresh();
+                self.unify(ty, &Ty::Array(Box::new(element.clone())), span);
+                for e in elements {
+                    self.infer_pattern(e, &element);
+                }
+            }
+            Pattern::Or { alternatives, .. } => {
+                for alternative in alternatives {
+                    self.infer_pattern(alternative, ty);
+                }
+            }
+        }
+    }
+}
+
+impl StmtVisitor<(), Ty> for TypeInference {
+    fn visit_block(&mut self, statements: &[Statement], _span: &Span) {
+        self.scopes.push(HashMap::new());
+        for stmt in statements {
+            self.visit_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, _span: &Span) {
+        self.infer_condition(condition);
+        self.infer_scoped(then_branch);
+        if let Some(else_branch) = else_branch {
+            self.infer_scoped(else_branch);
+        }
+    }
+
+    fn visit_while(&mut self, _label: Option<&str>, condition: &Expression, body: &Statement, _span: &Span) {
+        self.infer_condition(condition);
+        self.infer_scoped(body);
+    }
+
+    fn visit_for(&mut self, _label: Option<&str>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement, _span: &Span) {
+        self.scopes.push(HashMap::new());
+        if let Some(init) = init {
+            self.visit_stmt(init);
+        }
+        self.infer_condition(condition);
+        if let Some(increment) = increment {
+            self.visit_stmt(increment);
+        }
+        self.infer_scoped(body);
+        self.scopes.pop();
+    }
+
+    fn visit_return(&mut self, value: Option<&Expression>, span: &Span) {
+        let ty = match value {
+            Some(value) => self.visit_expr(value),
+            None => Ty::Void,
+        };
+
+        if let Some(frame) = self.functions.last_mut() {
+            frame.has_return = true;
+            let ret = frame.ret.clone();
+            self.unify(&ret, &ty, value.map(Expression::span).unwrap_or(span));
+        }
+    }
+
+    fn visit_for_in(&mut self, _label: Option<&str>, variable: &str, iterable: &Iterable, body: &Statement, _span: &Span) {
+        let element = match iterable {
+            Iterable::Range { start, end, .. } => {
+                for bound in [start, end] {
+                    let ty = self.visit_expr(bound);
+                    self.unify(&Ty::Int, &ty, bound.span());
+                }
+                Ty::Int
+            }
+            Iterable::Array(array) => {
+                let element = self.fresh();
+                let ty = self.visit_expr(array);
+                self.unify(&Ty::Array(Box::new(element.clone())), &ty, array.span());
+                element
+            }
+        };
+
+        self.scopes.push(HashMap::new());
+        self.define(variable, Scheme::mono(element));
+        self.infer_scoped(body);
+        self.scopes.pop();
+    }
+
+    fn visit_variable_declaration(&mut self, name: &str, type_ann: &Option<Type>, initializer: Option<&Expression>, span: &Span) {
+        let ty = match type_ann {
+            Some(ann) => Ty::from(ann),
+            None => self.fresh(),
+        };
+
+        if let Some(init) = initializer {
+            let init_ty = self.visit_expr(init);
+            self.unify(&ty, &init_ty, init.span());
+        }
+
+        self.record(span, &ty);
+        // variables stay monomorphic, a later assignment may still pin them down
+        self.define(name, Scheme::mono(ty));
+    }
+
+    fn visit_function_declaration(&mut self, name: &str, parameters: &[Parameter], return_type: &Option<Type>, body: &Statement, span: &Span) {
+        let param_tys = parameters
+            .iter()
+            .map(|p| p.type_ann.as_ref().map(Ty::from).unwrap_or_else(|| self.fresh()))
+            .collect::<Vec<_>>();
+        let ret = return_type.as_ref().map(Ty::from).unwrap_or_else(|| self.fresh());
+        let fn_ty = Ty::Function(param_tys.clone(), Box::new(ret.clone()));
+
+        // monomorphic inside its own body so recursion constrains it
+        self.define(name, Scheme::mono(fn_ty.clone()));
+
+        self.scopes.push(HashMap::new());
+        for (param, ty) in parameters.iter().zip(&param_tys) {
+            self.record(&param.span, ty);
+            self.define(&param.name, Scheme::mono(ty.clone()));
+        }
+        self.functions.push(FunctionFrame { ret, has_return: false });
+        self.visit_stmt(body);
+        let frame = self.functions.pop().unwrap();
+        self.scopes.pop();
+
+        // a body without any ret returns nothing
+        if !frame.has_return {
+            self.unify(&frame.ret, &Ty::Void, span);
+        }
+
+        self.record(span, &fn_ty);
+        self.scopes.last_mut().unwrap().remove(name);
+        let scheme = self.generalize(&fn_ty);
+        self.define(name, scheme);
+    }
+}
+
+impl ExprVisitor<Ty> for TypeInference {
+    fn visit_binary(&mut self, left: &Expression, operator: &BinaryOp, right: &Expression, span: &Span) -> Ty {
+        let left_ty = self.visit_expr(left);
+        let right_ty = self.visit_expr(right);
+
+        let ty = match operator {
+            // both operands share one type, the checker decides if the operator supports it
+            BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => {
+                self.unify(&left_ty, &right_ty, right.span());
+                left_ty
+            }
+            BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor | BinaryOp::ShiftLeft | BinaryOp::ShiftRight => {
+                self.unify(&Ty::Int, &left_ty, left.span());
+                self.unify(&Ty::Int, &right_ty, right.span());
+                Ty::Int
+            }
+            BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::LessThan | BinaryOp::GreaterThan | BinaryOp::LessEqual | BinaryOp::GreaterEqual => {
+                self.unify(&left_ty, &right_ty, right.span());
+                Ty::Bool
+            }
+            BinaryOp::And | BinaryOp::Or => {
+                self.unify(&Ty::Bool, &left_ty, left.span());
+                self.unify(&Ty::Bool, &right_ty, right.span());
+                Ty::Bool
+            }
+        };
+        self.record(span, &ty);
+        ty
+    }
+
+    fn visit_unary(&mut self, operator: &PrefixOp, operand: &Expression, span: &Span) -> Ty {
+        let operand_ty = self.visit_expr(operand);
+        let ty = match operator {
+            PrefixOp::Not => {
+                self.unify(&Ty::Bool, &operand_ty, operand.span());
+                Ty::Bool
+            }
+            PrefixOp::Neg => operand_ty,
+        };
+        self.record(span, &ty);
+        ty
+    }
+
+    fn visit_literal(&mut self, value: &LiteralValue, span: &Span) -> Ty {
+        let ty = Ty::from(&literal_type(value));
+        self.record(span, &ty);
+        ty
+    }
+
+    fn visit_assign(&mut self, target: &Expression, value: &Expression, span: &Span) -> Ty {
+        let target_ty = self.visit_expr(target);
+        let value_ty = self.visit_expr(value);
+        self.unify(&target_ty, &value_ty, value.span());
+        self.record(span, &target_ty);
+        target_ty
+    }
+
+    fn visit_compound_assign(&mut self, target: &Expression, operator: &AssignOp, value: &Expression, span: &Span) -> Ty {
+        // x += v has the type of x = x + v
+        let target_ty = self.visit_binary(target, &operator.binary(), value, span);
+        self.record(span, &target_ty);
+        target_ty
+    }
+
+    fn visit_index(&mut self, array: &Expression, index: &Expression, span: &Span) -> Ty {
+        let element = self.fresh();
+        let array_ty = self.visit_expr(array);
+        self.unify(&Ty::Array(Box::new(element.clone())), &array_ty, array.span());
+        let index_ty = self.visit_expr(index);
+        self.unify(&Ty::Int, &index_ty, index.span());
+        self.record(span, &element);
+        element
+    }
+
+    fn visit_call(&mut self, callee: &Expression, arguments: &[Expression], span: &Span) -> Ty {
+        let callee_ty = self.visit_expr(callee);
+        let arg_tys = arguments.iter().map(|arg| self.visit_expr(arg)).collect();
+        let ret = self.fresh();
+        self.unify(&callee_ty, &Ty::Function(arg_tys, Box::new(ret.clone())), span);
+        self.record(span, &ret);
+        ret
+    }
+
+    fn visit_variable(&mut self, name: &str, span: &Span) -> Ty {
+        let ty = match self.lookup(name) {
+            Some(scheme) => self.instantiate(&scheme),
+            None => {
+                self.error(format!("undefined variable `{}`", name), span);
+                self.fresh()
+            }
+        };
+        self.record(span, &ty);
+        ty
+    }
+
+    fn visit_array(&mut self, elements: &[Expression], span: &Span) -> Ty {
+        let element = self.fresh();
+        for e in elements {
+            let ty = self.visit_expr(e);
+            self.unify(&element, &ty, e.span());
+        }
+        let ty = Ty::Array(Box::new(element));
+        self.record(span, &ty);
+        ty
+    }
+
+    fn visit_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], span: &Span) -> Ty {
+        let scrutinee_ty = self.visit_expr(scrutinee);
+        let result = self.fresh();
+        for arm in arms {
+            self.scopes.push(HashMap::new());
+            self.infer_pattern(&arm.pattern, &scrutinee_ty);
+            if let Some(guard) = &arm.guard {
+                let ty = self.visit_expr(guard);
+                self.unify(&Ty::Bool, &ty, guard.span());
+            }
+            let ty = self.visit_expr(&arm.body);
+            self.unify(&result, &ty, arm.body.span());
+            self.scopes.pop();
+        }
+        self.record(span, &result);
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::build;
+
+    use super::*;
+
+    fn span(start: usize) -> Span {
+        Span { start, end: start + 1, line: 1, column: start + 1 }
+    }
+
+    fn lit(value: i64, at: usize) -> Expression {
+        Expression::Literal { value: LiteralValue::Integer(value), span: span(at) }
+    }
+
+    fn var(name: &str, at: usize) -> Expression {
+        Expression::Variable { name: name.to_string(), span: span(at) }
+    }
+
+    fn param(name: &str, at: usize) -> Parameter {
+        Parameter { name: name.to_string(), type_ann: None, span: span(at) }
+    }
+
+    fn call(callee: &str, arguments: Vec<Expression>, at: usize) -> Expression {
+        Expression::Call { callee: Box::new(var(callee, at)), arguments, span: span(at + 1) }
+    }
+
+    fn ret(value: Expression) -> Statement {
+        Statement::Block {
+            statements: vec![Statement::Return { value: Some(Box::new(value)), span: Span::default() }],
+            span: Span::default(),
+        }
+    }
+
+    #[test]
+    fn test_infers_declarations_and_annotates() {
+        // def x = 1; def y; y = x < 2;
+        let mut program = vec![
+            Statement::VariableDeclaration { name: "x".to_string(), type_ann: None, initializer: Some(Box::new(lit(1, 0))), span: span(10) },
+            Statement::VariableDeclaration { name: "y".to_string(), type_ann: None, initializer: None, span: span(20) },
+            Statement::Expression {
+                expression: Box::new(Expression::Assign {
+                    target: Box::new(var("y", 30)),
+                    value: Box::new(Expression::Binary {
+                        left: Box::new(var("x", 32)),
+                        operator: BinaryOp::LessThan,
+                        right: Box::new(lit(2, 34)),
+                        span: span(33),
+                    }),
+                    span: span(31),
+                }),
+                span: span(31),
+            },
+        ];
+
+        let mut inference = TypeInference::new();
+        inference.infer(&program).unwrap();
+        assert_eq!(inference.type_at(&span(32)), Some(Ty::Int));
+
+        inference.annotate(&mut program);
+        let anns = program.iter().filter_map(|stmt| match stmt {
+            Statement::VariableDeclaration { type_ann, .. } => type_ann.clone(),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(anns, vec![Type::Int, Type::Bool]);
+    }
+
+    #[test]
+    fn test_annotates_nodes_with_equal_spans() {
+        // def x = 1; def y = true; built without a source, so every span is the default one
+        let mut program = vec![build::decl("x", build::int(1)), build::decl("y", build::boolean(true))];
+
+        let mut inference = TypeInference::new();
+        inference.infer(&program).unwrap();
+        inference.annotate(&mut program);
+        let anns = program.iter().filter_map(|stmt| match stmt {
+            Statement::VariableDeclaration { type_ann, .. } => type_ann.clone(),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(anns, vec![Type::Int, Type::Bool]);
+    }
+
+    #[test]
+    fn test_infers_function_signature() {
+        // fun double(n) { ret n * 2; }
+        let mut program = vec![Statement::FunctionDeclaration {
+            name: "double".to_string(),
+            parameters: vec![param("n", 1)],
+            return_type: None,
+            body: Box::new(ret(Expression::Binary {
+                left: Box::new(var("n", 2)),
+                operator: BinaryOp::Multiply,
+                right: Box::new(lit(2, 4)),
+                span: span(3),
+            })),
+            span: span(0),
+        }];
+
+        let mut inference = TypeInference::new();
+        inference.infer(&program).unwrap();
+        assert_eq!(inference.type_at(&span(0)).unwrap().to_string(), "fun(Int): Int");
+
+        inference.annotate(&mut program);
+        let Statement::FunctionDeclaration { parameters, return_type, .. } = &program[0] else { unreachable!() };
+        assert_eq!(parameters[0].type_ann, Some(Type::Int));
+        assert_eq!(return_type, &Some(Type::Int));
+    }
+
+    #[test]
+    fn test_let_polymorphism() {
+        // fun id(x) { ret x; } def a = id(1); def b = id(true);
+        let program = vec![
+            Statement::FunctionDeclaration {
+                name: "id".to_string(),
+                parameters: vec![param("x", 1)],
+                return_type: None,
+                body: Box::new(ret(var("x", 2))),
+                span: span(0),
+            },
+            Statement::VariableDeclaration { name: "a".to_string(), type_ann: None, initializer: Some(Box::new(call("id", vec![lit(1, 12)], 10))), span: span(13) },
+            Statement::VariableDeclaration {
+                name: "b".to_string(),
+                type_ann: None,
+                initializer: Some(Box::new(call("id", vec![Expression::Literal { value: LiteralValue::Bool(true), span: span(22) }], 20))),
+                span: span(23),
+            },
+        ];
+
+        let mut inference = TypeInference::new();
+        inference.infer(&program).unwrap();
+        assert_eq!(inference.type_at(&span(0)).unwrap().to_string(), "fun('a): 'a");
+        assert_eq!(inference.type_at(&span(13)), Some(Ty::Int));
+        assert_eq!(inference.type_at(&span(23)), Some(Ty::Bool));
+    }
+
+    #[test]
+    fn test_reports_unification_errors() {
+        // def x = 1; x = true;
+        let program = vec![
+            Statement::VariableDeclaration { name: "x".to_string(), type_ann: None, initializer: Some(Box::new(lit(1, 0))), span: span(1) },
+            Statement::Expression {
+                expression: Box::new(Expression::Assign {
+                    target: Box::new(var("x", 2)),
+                    value: Box::new(Expression::Literal { value: LiteralValue::Bool(true), span: span(4) }),
+                    span: span(3),
+                }),
+                span: span(3),
+            },
+        ];
+
+        let errors = TypeInference::new().infer(&program).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "mismatched types: expected Int, found Bool");
+        assert_eq!(errors[0].span, span(4));
+    }
+}
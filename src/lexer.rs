@@ -15,6 +15,10 @@ pub enum TokenKind {
     If,            // if
     Else,          // else
     For,           // for
+    Break,         // break
+    Continue,      // continue
+    In,            // in
+    Match,         // match
 
     // punctuation
     Plus,          // +
@@ -22,6 +26,26 @@ pub enum TokenKind {
     Asterisk,      // *
     Slash,         // /
     Percent,       // %
+    Ampersand,     // &
+    Caret,         // ^
+    ShiftLeft,     // <<
+    ShiftRight,    // >>
+
+    // compound assignment
+    PlusEqual,       // +=
+    MinusEqual,      // -=
+    AsteriskEqual,   // *=
+    SlashEqual,      // /=
+    PercentEqual,    // %=
+    AmpersandEqual,  // &=
+    PipeEqual,       // |=
+    CaretEqual,      // ^=
+    ShiftLeftEqual,  // <<=
+    ShiftRightEqual, // >>=
+
+    // increment and decrement
+    PlusPlus,        // ++
+    MinusMinus,      // --
 
     LParen,        // (
     RParen,        // )
@@ -32,6 +56,10 @@ pub enum TokenKind {
     Semicolon,     // ;
     Colon,         // :
     Comma,         // ,
+    DotDot,        // ..
+    DotDotEqual,   // ..=
+    FatArrow,      // =>
+    Pipe,          // |
 
     // need judge
     Equal,         // =
@@ -62,11 +90,31 @@ impl Display for TokenKind {
             TokenKind::If => write!(f, "If"),
             TokenKind::Else => write!(f, "Else"),
             TokenKind::For => write!(f, "For"),
+            TokenKind::Break => write!(f, "Break"),
+            TokenKind::Continue => write!(f, "Continue"),
+            TokenKind::In => write!(f, "In"),
+            TokenKind::Match => write!(f, "Match"),
             TokenKind::Plus => write!(f, "Plus"),
             TokenKind::Minus => write!(f, "Minus"),
             TokenKind::Asterisk => write!(f, "Asterisk"),
             TokenKind::Slash => write!(f, "Slash"),
             TokenKind::Percent => write!(f, "Percent"), 
+            TokenKind::Ampersand => write!(f, "Ampersand"),
+            TokenKind::Caret => write!(f, "Caret"),
+            TokenKind::ShiftLeft => write!(f, "ShiftLeft"),
+            TokenKind::ShiftRight => write!(f, "ShiftRight"),
+            TokenKind::PlusPlus => write!(f, "PlusPlus"),
+            TokenKind::MinusMinus => write!(f, "MinusMinus"),
+            TokenKind::PlusEqual => write!(f, "PlusEqual"),
+            TokenKind::MinusEqual => write!(f, "MinusEqual"),
+            TokenKind::AsteriskEqual => write!(f, "AsteriskEqual"),
+            TokenKind::SlashEqual => write!(f, "SlashEqual"),
+            TokenKind::PercentEqual => write!(f, "PercentEqual"),
+            TokenKind::AmpersandEqual => write!(f, "AmpersandEqual"),
+            TokenKind::PipeEqual => write!(f, "PipeEqual"),
+            TokenKind::CaretEqual => write!(f, "CaretEqual"),
+            TokenKind::ShiftLeftEqual => write!(f, "ShiftLeftEqual"),
+            TokenKind::ShiftRightEqual => write!(f, "ShiftRightEqual"),
             TokenKind::LParen => write!(f, "LParen"),
             TokenKind::RParen => write!(f, "RParen"),
             TokenKind::LBrace => write!(f, "LBrace"),
@@ -76,6 +124,10 @@ impl Display for TokenKind {
             TokenKind::Semicolon => write!(f, "Semicolon"),
             TokenKind::Colon => write!(f, "Colon"),
             TokenKind::Comma => write!(f, "Comma"),
+            TokenKind::DotDot => write!(f, "DotDot"),
+            TokenKind::DotDotEqual => write!(f, "DotDotEqual"),
+            TokenKind::FatArrow => write!(f, "FatArrow"),
+            TokenKind::Pipe => write!(f, "Pipe"),
             TokenKind::Equal => write!(f, "Equal"),
             TokenKind::EqualEqual => write!(f, "EqualEqual"),
             TokenKind::Bang => write!(f, "Bang"),
@@ -143,6 +195,7 @@ pub struct Lexer<'a> {
     position: usize,
     read_position: usize,
     ch: char,
+    finished: bool, // EOF was handed out, there is nothing more to read
 }
 
 impl<'a> Lexer<'a> {
@@ -152,11 +205,15 @@ impl<'a> Lexer<'a> {
             position: 0,
             read_position: 0,
             ch: '\0',
+            finished: false,
         }
     }
 
     // the core of the lexer
     pub fn next_token(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
+        }
 
         self.skip_whitespace();
         let orinial_str = self.input.clone().collect::<String>();
@@ -176,11 +233,20 @@ impl<'a> Lexer<'a> {
         }
 
         let token_kind = match self.ch {
-            '+' => TokenKind::Plus,
-            '-' => TokenKind::Minus,
-            '*' => TokenKind::Asterisk,
-            '/' => TokenKind::Slash,
-            '%' => TokenKind::Percent,
+            '+' if self.input.peek() == Some(&'+') => {
+                self.consume_char();
+                TokenKind::PlusPlus
+            },
+            '+' => self.handle_double_char('=', TokenKind::Plus, TokenKind::PlusEqual),
+            '-' if self.input.peek() == Some(&'-') => {
+                self.consume_char();
+                TokenKind::MinusMinus
+            },
+            '-' => self.handle_double_char('=', TokenKind::Minus, TokenKind::MinusEqual),
+            '*' => self.handle_double_char('=', TokenKind::Asterisk, TokenKind::AsteriskEqual),
+            '/' => self.handle_double_char('=', TokenKind::Slash, TokenKind::SlashEqual),
+            '%' => self.handle_double_char('=', TokenKind::Percent, TokenKind::PercentEqual),
+            '^' => self.handle_double_char('=', TokenKind::Caret, TokenKind::CaretEqual),
 
             '(' => TokenKind::LParen,
             ')' => TokenKind::RParen,
@@ -191,31 +257,55 @@ impl<'a> Lexer<'a> {
             ';' => TokenKind::Semicolon,
             ':' => TokenKind::Colon,
             ',' => TokenKind::Comma,
+            // a single dot means nothing yet, only ranges use it
+            '.' if self.input.peek() == Some(&'.') => {
+                self.consume_char();
+                self.handle_double_char('=', TokenKind::DotDot, TokenKind::DotDotEqual)
+            },
 
+            '=' if self.input.peek() == Some(&'>') => {
+                self.consume_char();
+                TokenKind::FatArrow
+            },
             '=' => {
                 self.handle_double_char('=', TokenKind::Equal, TokenKind::EqualEqual)
             },// ! = how to detect the error and report it? and the way lsp works
             '!' => {
                 self.handle_double_char('=', TokenKind::Bang, TokenKind::BangEqual)
             },
+            '<' if self.input.peek() == Some(&'<') => {
+                self.consume_char();
+                self.handle_double_char('=', TokenKind::ShiftLeft, TokenKind::ShiftLeftEqual)
+            },
             '<' => {
                 self.handle_double_char('=', TokenKind::Less, TokenKind::LessEqual)
             },
+            '>' if self.input.peek() == Some(&'>') => {
+                self.consume_char();
+                self.handle_double_char('=', TokenKind::ShiftRight, TokenKind::ShiftRightEqual)
+            },
             '>' => {
                 self.handle_double_char('=', TokenKind::Greater, TokenKind::GreaterEqual)
             },
 
-            '&' => {
+            '&' if self.input.peek() == Some(&'&') => {
                 self.consume_char();
                 TokenKind::And
             },
-            '|' => {
+            '&' => {
+                self.handle_double_char('=', TokenKind::Ampersand, TokenKind::AmpersandEqual)
+            },
+            '|' if self.input.peek() == Some(&'|') => {
                 self.consume_char();
                 TokenKind::Or
             },
+            '|' => {
+                self.handle_double_char('=', TokenKind::Pipe, TokenKind::PipeEqual)
+            },
 
             _ => TokenKind::EOF,
         };
+        self.finished = token_kind == TokenKind::EOF;
 
         // generate the token, determine the exact start and end position of the token
         let end_pos = self.position;
@@ -274,6 +364,10 @@ impl<'a> Lexer<'a> {
             "if" => TokenKind::If,
             "else" => TokenKind::Else,
             "for" => TokenKind::For,
+            "break" => TokenKind::Break,
+            "continue" => TokenKind::Continue,
+            "in" => TokenKind::In,
+            "match" => TokenKind::Match,
             _ => TokenKind::Identifier(iden),
         }
     }
@@ -363,4 +457,109 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_loop_keywords() {
+        let mut lexer = Lexer::new("outer: for break continue outer;");
+        let mut kinds = Vec::new();
+        while let Some(token) = lexer.next_token() {
+            kinds.push(token.kind);
+        }
+        assert_eq!(kinds, vec![
+            TokenKind::Identifier("outer".to_string()),
+            TokenKind::Colon,
+            TokenKind::For,
+            TokenKind::Break,
+            TokenKind::Continue,
+            TokenKind::Identifier("outer".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut lexer = Lexer::new("for i in 0..10 0..=n");
+        let mut kinds = Vec::new();
+        while let Some(token) = lexer.next_token() {
+            kinds.push(token.kind);
+        }
+        assert_eq!(kinds, vec![
+            TokenKind::For,
+            TokenKind::Identifier("i".to_string()),
+            TokenKind::In,
+            TokenKind::Integer(0),
+            TokenKind::DotDot,
+            TokenKind::Integer(10),
+            TokenKind::Integer(0),
+            TokenKind::DotDotEqual,
+            TokenKind::Identifier("n".to_string()),
+            TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_match_tokens() {
+        let mut lexer = Lexer::new("match x { 1 | 2 => a, _ => b || c }");
+        let mut kinds = Vec::new();
+        while let Some(token) = lexer.next_token() {
+            kinds.push(token.kind);
+        }
+        assert_eq!(kinds, vec![
+            TokenKind::Match,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::LBrace,
+            TokenKind::Integer(1),
+            TokenKind::Pipe,
+            TokenKind::Integer(2),
+            TokenKind::FatArrow,
+            TokenKind::Identifier("a".to_string()),
+            TokenKind::Comma,
+            TokenKind::Identifier("_".to_string()),
+            TokenKind::FatArrow,
+            TokenKind::Identifier("b".to_string()),
+            TokenKind::Or,
+            TokenKind::Identifier("c".to_string()),
+            TokenKind::RBrace,
+            TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_compound_assignment_tokens() {
+        // like in C `m--1` is m, -- and 1 rather than m minus minus one
+        let mut lexer = Lexer::new("a[i] += 1; x <<= 2 >> y; n & m--1 ^ k |= 3; ++i");
+        let mut kinds = Vec::new();
+        while let Some(token) = lexer.next_token() {
+            kinds.push(token.kind);
+        }
+        assert_eq!(kinds, vec![
+            TokenKind::Identifier("a".to_string()),
+            TokenKind::LBracket,
+            TokenKind::Identifier("i".to_string()),
+            TokenKind::RBracket,
+            TokenKind::PlusEqual,
+            TokenKind::Integer(1),
+            TokenKind::Semicolon,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::ShiftLeftEqual,
+            TokenKind::Integer(2),
+            TokenKind::ShiftRight,
+            TokenKind::Identifier("y".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::Identifier("n".to_string()),
+            TokenKind::Ampersand,
+            TokenKind::Identifier("m".to_string()),
+            TokenKind::MinusMinus,
+            TokenKind::Integer(1),
+            TokenKind::Caret,
+            TokenKind::Identifier("k".to_string()),
+            TokenKind::PipeEqual,
+            TokenKind::Integer(3),
+            TokenKind::Semicolon,
+            TokenKind::PlusPlus,
+            TokenKind::Identifier("i".to_string()),
+            TokenKind::EOF,
+        ]);
+    }
+}
@@ -2,14 +2,37 @@
 mod lexer;
 // mod parser;
 mod ast;
-// mod codegen;
-// mod vm;
+mod codegen;
+mod code;
+mod vm;
 mod visitor;
-// mod evaluator;
+mod typechecker;
+mod exhaustive;
+mod infer;
+mod resolver;
+mod const_fold;
+mod deadcode;
+mod disassembler;
+mod definite;
+mod cfg;
+mod ssa;
+mod evaluator;
+mod rcb;
+mod peephole;
+mod regcodegen;
+mod regvm;
+mod bench;
+mod verifier;
+mod builtins;
 
 use std::{cell::RefCell, f32::consts::E, ops::{Deref, DerefMut}, rc::Rc};
 
+use std::path::Path;
+
+use codegen::CodeGen;
 use lexer::{Lexer, TokenKind};
+use peephole::Peephole;
+use vm::VM;
 
 
 // test for lexer
@@ -155,7 +178,23 @@ impl DerefMut for BoxedPerson {
 }
 
 
+const USAGE: &str = "usage: rust-compiler compile <program> [-o <out.rcb>] | run <script.rcb> | disasm <script.rcb> | bench";
+
 fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["compile", name] => compile_program(name, &Path::new(&name.replace(' ', "_")).with_extension("rcb")),
+        ["compile", name, "-o", output] => compile_program(name, Path::new(output)),
+        ["run", path] => run_file(Path::new(path)),
+        ["disasm", path] => disassemble_file(Path::new(path)),
+        ["bench"] => bench_suite(),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(message) = result {
+        eprintln!("error: {}", message);
+        std::process::exit(1);
+    }
+
     // tokenize("x = 2;");
     // let a = '2'.is_alphabetic();
     // println!("{}", a);
@@ -163,3 +202,56 @@ fn main() {
     // let a = Box::new(BoxedPerson(Employee { person: Person {} }));
     // a.work();
 }
+
+// the parser is a stub, so the programs to compile are the built-in ones from the bench suite
+fn compile_program(name: &str, output: &Path) -> Result<(), String> {
+    let names = bench::suite(0).iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+    let (_, program) = bench::suite(1000).into_iter().find(|(candidate, _)| *candidate == name)
+        .ok_or_else(|| format!("no built-in program named {}, try one of: {}", name, names))?;
+    let mut codegen = CodeGen::new();
+    codegen.compile(program)?;
+    let (bytecode, stats) = Peephole::optimize(codegen.bytecode());
+    println!("{} instructions, {} before the peephole pass", stats.after, stats.before);
+    // there is no source file to point the disassembler at
+    let bytes = rcb::write(&bytecode, None)?;
+    std::fs::write(output, bytes).map_err(|e| format!("cannot write {}: {}", output.display(), e))
+}
+
+fn load_file(path: &Path) -> Result<rcb::Script, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    rcb::read(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn run_file(path: &Path) -> Result<(), String> {
+    let script = load_file(path)?;
+    let mut vm = VM::new(script.bytecode)?;
+    if let Some(source) = &script.source {
+        vm.set_source(source);
+    }
+    vm.run().map_err(|e| e.to_string())?;
+    println!("{:?}", vm.last_popped());
+    Ok(())
+}
+
+fn disassemble_file(path: &Path) -> Result<(), String> {
+    let script = load_file(path)?;
+    // the source is only used to show its lines, it may have moved since compiling
+    let text = script.source.as_ref().and_then(|source| std::fs::read_to_string(source).ok());
+    print!("{}", disassembler::disassemble(&script.bytecode, text.as_deref())?);
+    Ok(())
+}
+
+// runs without a script, so it works while the parser is a stub
+fn bench_suite() -> Result<(), String> {
+    for (name, program) in bench::suite(1000) {
+        println!("{}", name);
+        print_comparison(&bench::compare(program, 10)?);
+    }
+    Ok(())
+}
+
+fn print_comparison(comparison: &bench::Comparison) {
+    println!("{:?}", comparison.result);
+    println!("stack vm     {:>6} instructions  {:?}", comparison.stack_instructions, comparison.stack);
+    println!("register vm  {:>6} instructions  {:?}", comparison.register_instructions, comparison.register);
+}
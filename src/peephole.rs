@@ -0,0 +1,386 @@
+use std::{collections::HashSet, rc::Rc};
+
+use crate::codegen::{Bytecode, ConstantPool, Function, Instruction, LineTable, Object};
+
+// rewrites short runs of instructions into cheaper ones, in main and every function in the constant pool
+//   a Jump to the next instruction goes away
+//   a jump to a Jump goes straight to where that one ends up
+//   LoadConstant, LoadConstant, Add of two integers or strings becomes one LoadConstant
+//   Not, JumpNotTruthy becomes JumpTruthy (and the other way around) when the operand is known to be a boolean
+//   a SetLocal to a local that is never read becomes a Pop, and Dup, Pop goes away
+// a run is only rewritten when no jump lands inside it, and it repeats until nothing changes
+// since one rewrite can make room for the next
+// constants nothing loads anymore, like the operands of a folded Add, are dropped at the end
+pub struct Peephole {
+    constants: ConstantPool,
+}
+
+// instruction counts over main and every function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub before: usize,
+    pub after: usize,
+}
+
+impl Peephole {
+    pub fn optimize(mut bytecode: Bytecode) -> (Bytecode, Stats) {
+        let mut stats = Stats { before: 0, after: 0 };
+        let mut pass = Peephole { constants: ConstantPool::from(std::mem::take(&mut bytecode.constants)) };
+        // folding only ever adds integers and strings, the functions stay where they are
+        let mut functions = pass.constants.objects().iter().enumerate().filter_map(|(index, constant)| match constant {
+            Object::Function(function) => Some((index, Function::clone(function))),
+            _ => None,
+        }).collect::<Vec<_>>();
+        for (_, function) in &mut functions {
+            stats.before += function.instructions.len();
+            (function.instructions, function.lines) = pass.run(std::mem::take(&mut function.instructions), &function.lines);
+            stats.after += function.instructions.len();
+        }
+        stats.before += bytecode.instructions.len();
+        let instructions = std::mem::take(&mut bytecode.instructions);
+        (bytecode.instructions, bytecode.lines) = pass.run(instructions, &bytecode.lines);
+        stats.after += bytecode.instructions.len();
+
+        // constants nothing loads anymore are dropped, main and the functions are renumbered to match
+        let constants = pass.constants.into_objects();
+        let code = std::iter::once(&bytecode.instructions).chain(functions.iter().map(|(_, function)| &function.instructions));
+        let positions = positions(constants.len(), code);
+        renumber(&mut bytecode.instructions, &positions);
+        for (_, function) in &mut functions {
+            renumber(&mut function.instructions, &positions);
+        }
+        bytecode.constants = constants.into_iter().zip(&positions).filter_map(|(constant, position)| position.map(|_| constant)).collect();
+        for (index, function) in functions {
+            if let Some(position) = positions[index] {
+                bytecode.constants[position] = Object::Function(Rc::new(function));
+            }
+        }
+        (bytecode, stats)
+    }
+
+    fn run(&mut self, mut instructions: Vec<Instruction>, lines: &LineTable) -> (Vec<Instruction>, LineTable) {
+        let mut lines = lines.clone();
+        loop {
+            let retargeted = collapse_jumps(&mut instructions);
+            let (rewritten, kept) = self.rewrite(&instructions);
+            if !retargeted && kept.iter().all(|&keep| keep) && rewritten == instructions {
+                return (instructions, lines);
+            }
+            (instructions, lines) = remove(rewritten, &kept, &lines);
+        }
+    }
+
+    // one pass left to right, returns the new instructions and which of them stay
+    fn rewrite(&mut self, instructions: &[Instruction]) -> (Vec<Instruction>, Vec<bool>) {
+        let targets = instructions.iter().flat_map(Instruction::jump_targets).collect::<HashSet<_>>();
+        let read = instructions.iter().filter_map(|instruction| match instruction {
+            Instruction::GetLocal(index) => Some(*index),
+            _ => None,
+        }).collect::<HashSet<_>>();
+        // nothing may jump into the middle of a run
+        let straight = |from: usize, to: usize| (from + 1..to).all(|i| !targets.contains(&i));
+
+        let mut rewritten = instructions.to_vec();
+        let mut kept = vec![true; instructions.len()];
+        let mut i = 0;
+        while i < instructions.len() {
+            let rest = &instructions[i..];
+            match rest {
+                [Instruction::Jump(target), ..] if *target == i + 1 => kept[i] = false,
+                [Instruction::LoadConstant(a), Instruction::LoadConstant(b), Instruction::Add, ..] if straight(i, i + 3) => {
+                    let constants = self.constants.objects();
+                    // the pool has no duplicates, so the sum may already be in there
+                    if let Some(sum) = constants.get(*a).zip(constants.get(*b)).and_then(|(a, b)| add(a, b)) {
+                        rewritten[i] = Instruction::LoadConstant(self.constants.add(sum));
+                        kept[i + 1] = false;
+                        kept[i + 2] = false;
+                        i += 3;
+                        continue;
+                    }
+                }
+                // the value has to come from right before the Not, not from a jump to it
+                [Instruction::Not, jump @ (Instruction::JumpNotTruthy(_) | Instruction::JumpTruthy(_)), ..]
+                    if i > 0 && kept[i - 1] && is_boolean(&rewritten[i - 1]) && straight(i - 1, i + 2) =>
+                {
+                    kept[i] = false;
+                    rewritten[i + 1] = match *jump {
+                        Instruction::JumpNotTruthy(target) => Instruction::JumpTruthy(target),
+                        Instruction::JumpTruthy(target) => Instruction::JumpNotTruthy(target),
+                        _ => unreachable!(),
+                    };
+                    i += 2;
+                    continue;
+                }
+                [Instruction::SetLocal(index), ..] if !read.contains(index) => rewritten[i] = Instruction::Pop,
+                // a jump to the Dup can just as well land after the Pop
+                [Instruction::Dup, Instruction::Pop, ..] if straight(i, i + 2) => {
+                    kept[i] = false;
+                    kept[i + 1] = false;
+                    i += 2;
+                    continue;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        (rewritten, kept)
+    }
+}
+
+// points every jump whose target is a Jump at where the chain ends, returns whether anything changed
+fn collapse_jumps(instructions: &mut [Instruction]) -> bool {
+    let follow = |instructions: &[Instruction], start: usize| {
+        let mut chain = vec![start];
+        while let Some(Instruction::Jump(next)) = instructions.get(chain[chain.len() - 1]) {
+            // jumps that go around in a circle are left alone
+            if chain.contains(next) {
+                return start;
+            }
+            chain.push(*next);
+        }
+        chain[chain.len() - 1]
+    };
+    let mut changed = false;
+    for i in 0..instructions.len() {
+        let mut instruction = instructions[i].clone();
+        match &mut instruction {
+            Instruction::Jump(target) | Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => {
+                *target = follow(instructions, *target);
+            }
+            Instruction::JumpTable { targets, default, .. } => {
+                for target in targets.iter_mut().chain([default]) {
+                    *target = follow(instructions, *target);
+                }
+            }
+            _ => {}
+        }
+        if instruction != instructions[i] {
+            changed = true;
+            instructions[i] = instruction;
+        }
+    }
+    changed
+}
+
+// where each constant ends up once the ones no LoadConstant reads are gone
+fn positions<'a>(count: usize, code: impl Iterator<Item = &'a Vec<Instruction>>) -> Vec<Option<usize>> {
+    let mut used = vec![false; count];
+    for instruction in code.flatten() {
+        if let Instruction::LoadConstant(index) = instruction && let Some(used) = used.get_mut(*index) {
+            *used = true;
+        }
+    }
+    let mut next = 0;
+    used.into_iter().map(|used| used.then(|| {
+        next += 1;
+        next - 1
+    })).collect()
+}
+
+// an index past the pool stays past it, for the verifier to reject
+fn renumber(instructions: &mut [Instruction], positions: &[Option<usize>]) {
+    let kept = positions.iter().flatten().count();
+    for instruction in instructions {
+        if let Instruction::LoadConstant(index) = instruction {
+            *index = match positions.get(*index) {
+                Some(Some(position)) => *position,
+                _ => *index - positions.len() + kept,
+            };
+        }
+    }
+}
+
+// drops what isn't kept, a jump to a dropped instruction goes to the next one that stays
+fn remove(instructions: Vec<Instruction>, kept: &[bool], lines: &LineTable) -> (Vec<Instruction>, LineTable) {
+    let mut positions = Vec::with_capacity(instructions.len() + 1);
+    let mut position = 0;
+    for &keep in kept {
+        positions.push(position);
+        position += keep as usize;
+    }
+    positions.push(position);
+
+    let moved = instructions.into_iter().zip(kept).filter(|(_, keep)| **keep).map(|(mut instruction, _)| {
+        match &mut instruction {
+            Instruction::Jump(target) | Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => *target = positions[*target],
+            Instruction::JumpTable { targets, default, .. } => {
+                for target in targets.iter_mut().chain([default]) {
+                    *target = positions[*target];
+                }
+            }
+            _ => {}
+        }
+        instruction
+    });
+    (moved.collect(), lines.remap(&positions))
+}
+
+// instructions that always leave a boolean, or fail
+fn is_boolean(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Equal | Instruction::NotEqual | Instruction::LessThan | Instruction::GreaterThan
+            | Instruction::LessEqual | Instruction::GreaterEqual | Instruction::And | Instruction::Or
+            | Instruction::Not | Instruction::True | Instruction::False
+    )
+}
+
+// what the vm's Add would leave, None when it would fail so the vm still reports it
+fn add(left: &Object, right: &Object) -> Option<Object> {
+    match (left, right) {
+        (Object::Integer(l), Object::Integer(r)) => l.checked_add(*r).map(Object::Integer),
+        (Object::String(l), Object::String(r)) => Some(Object::String(format!("{}{}", l, r).into())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::{build::*, BinaryOp, PrefixOp, Span, Statement}, codegen::CodeGen, vm::VM};
+
+    use super::*;
+
+    fn main(instructions: Vec<Instruction>, constants: Vec<Object>) -> Bytecode {
+        Bytecode { instructions, constants, globals: vec!["x".to_string()], lines: LineTable::default() }
+    }
+
+    #[test]
+    fn test_jumps() {
+        // the end of the then branch jumps to a jump to the next instruction
+        let bytecode = main(vec![
+            Instruction::GetGlobal(0),
+            Instruction::JumpNotTruthy(4),
+            Instruction::Null,
+            Instruction::Jump(5),
+            Instruction::True,
+            Instruction::Jump(6),
+            Instruction::Pop,
+        ], vec![]);
+        let (optimized, stats) = Peephole::optimize(bytecode);
+        assert_eq!(optimized.instructions, [
+            Instruction::GetGlobal(0),
+            Instruction::JumpNotTruthy(4),
+            Instruction::Null,
+            Instruction::Jump(5),
+            Instruction::True,
+            Instruction::Pop,
+        ]);
+        assert_eq!(stats, Stats { before: 7, after: 6 });
+
+        // a loop of jumps stays as it is
+        let spin = vec![Instruction::Jump(2), Instruction::Null, Instruction::Jump(0)];
+        assert_eq!(Peephole::optimize(main(spin.clone(), vec![])).0.instructions, spin);
+    }
+
+    #[test]
+    fn test_constants_and_conditions() {
+        let bytecode = main(vec![
+            Instruction::LoadConstant(0),
+            Instruction::LoadConstant(1),
+            Instruction::Add,
+            Instruction::SetGlobal(0),
+            Instruction::GetGlobal(0),
+            Instruction::LoadConstant(0),
+            Instruction::LessThan,
+            Instruction::Not,
+            Instruction::JumpNotTruthy(10),
+            Instruction::Null,
+            Instruction::Null,
+            Instruction::Pop,
+        ], vec![Object::Integer(2), Object::Integer(3)]);
+        let (optimized, _) = Peephole::optimize(bytecode);
+        // the 3 isn't loaded anymore, so the sum takes its place
+        assert_eq!(optimized.instructions, [
+            Instruction::LoadConstant(1),
+            Instruction::SetGlobal(0),
+            Instruction::GetGlobal(0),
+            Instruction::LoadConstant(0),
+            Instruction::LessThan,
+            Instruction::JumpTruthy(7),
+            Instruction::Null,
+            Instruction::Null,
+            Instruction::Pop,
+        ]);
+        assert_eq!(optimized.constants, [Object::Integer(2), Object::Integer(5)]);
+
+        // Not fails on anything but a boolean, so that has to stay
+        let unknown = vec![Instruction::GetGlobal(0), Instruction::Not, Instruction::JumpNotTruthy(3), Instruction::Null];
+        assert_eq!(Peephole::optimize(main(unknown.clone(), vec![])).0.instructions, unknown);
+        // and the vm reports the overflow
+        let overflow = vec![Instruction::LoadConstant(0), Instruction::LoadConstant(0), Instruction::Add, Instruction::Pop];
+        assert_eq!(Peephole::optimize(main(overflow.clone(), vec![Object::Integer(i64::MAX)])).0.instructions, overflow);
+    }
+
+    #[test]
+    fn test_unused_constants_are_dropped() {
+        // the function folds 1 + 2, then only main's load of the function and the 3 are left
+        let function = Function {
+            instructions: vec![Instruction::LoadConstant(0), Instruction::LoadConstant(1), Instruction::Add, Instruction::Return],
+            num_locals: 0,
+            num_parameters: 0,
+            lines: LineTable::default(),
+        };
+        let constants = vec![Object::Integer(1), Object::Integer(2), Object::Function(Rc::new(function))];
+        let bytecode = main(vec![Instruction::LoadConstant(2), Instruction::Call(0), Instruction::Pop], constants);
+        let (optimized, _) = Peephole::optimize(bytecode);
+        assert_eq!(optimized.instructions[0], Instruction::LoadConstant(0));
+        let [Object::Function(function), Object::Integer(3)] = optimized.constants.as_slice() else { panic!("{:?}", optimized.constants) };
+        assert_eq!(function.instructions, [Instruction::LoadConstant(1), Instruction::Return]);
+
+        let mut vm = VM::new(optimized).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.last_popped(), &Object::Integer(3));
+    }
+
+    #[test]
+    fn test_dead_local_stores() {
+        let span = |line: usize| Span { start: 0, end: 0, line, column: 1 };
+        let mut lines = LineTable::default();
+        lines.add(0, &span(1));
+        lines.add(1, &span(2));
+        lines.add(3, &span(3));
+        // local 1 is written and never read
+        let function = Function {
+            instructions: vec![Instruction::GetLocal(0), Instruction::Dup, Instruction::SetLocal(1), Instruction::Return],
+            num_locals: 2,
+            num_parameters: 1,
+            lines,
+        };
+        // main loads the function, a constant nothing loads would be dropped
+        let load = vec![Instruction::LoadConstant(0), Instruction::Pop];
+        let (optimized, stats) = Peephole::optimize(main(load, vec![Object::Function(Rc::new(function))]));
+        let Object::Function(function) = &optimized.constants[0] else { panic!("not a function") };
+        assert_eq!(function.instructions, [Instruction::GetLocal(0), Instruction::Return]);
+        assert_eq!((function.lines.lookup(0), function.lines.lookup(1)), (Some(&span(1)), Some(&span(3))));
+        assert_eq!(stats, Stats { before: 4 + 2, after: 2 + 2 });
+    }
+
+    #[test]
+    fn test_compiled_program_shrinks() {
+        let set = |n: i64| Box::new(expr(assign(var("x"), int(n))));
+        let equal = bin(var("x"), BinaryOp::Equal, int(4));
+        // let x = [4][0]; if !(x < 3) { if x == 4 { x = 1; } else { x = 2; } } else { x = 3; } x;
+        let program = vec![
+            decl("x", index(array(vec![*int(4)]), int(0))),
+            Statement::If {
+                condition: unary(PrefixOp::Not, bin(var("x"), BinaryOp::LessThan, int(3))),
+                then_branch: Box::new(Statement::If { condition: equal, then_branch: set(1), else_branch: Some(set(2)), span: Span::default() }),
+                else_branch: Some(set(3)),
+                span: Span::default(),
+            },
+            expr(var("x")),
+        ];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        let (optimized, stats) = Peephole::optimize(codegen.bytecode());
+        assert!(stats.after < stats.before, "{:?}", stats);
+
+        let run = |bytecode: Bytecode| {
+            let mut vm = VM::new(bytecode).unwrap();
+            vm.run().unwrap();
+            vm.last_popped().clone()
+        };
+        assert_eq!(run(optimized), Object::Integer(1));
+        assert_eq!(run(codegen.bytecode()), Object::Integer(1));
+    }
+}
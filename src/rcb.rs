@@ -0,0 +1,362 @@
+use std::{collections::HashMap, fmt::Display, rc::Rc};
+
+use crate::{ast::Span, code, codegen::{Bytecode, Function, Instruction, LineTable, Object}};
+
+// .rcb files hold a compiled program so it can run without its source
+//   magic "RCB\0", format version as a big endian u16
+//   source name (empty when unknown), global names, strings, constants, main's instructions and line table
+// every distinct string in the constants is stored once, string constants are an index into them
+// counts and lengths are unsigned varints, strings and instructions are a length and the bytes
+// line tables are a count of entries, each one the instructions since the last entry,
+// then line, column, start and length of the span
+
+pub const MAGIC: &[u8; 4] = b"RCB\0";
+pub const VERSION: u16 = 4;
+// arrays and functions nested deeper than this are taken as corrupt input
+const MAX_DEPTH: usize = 64;
+
+// tags of the constant pool entries
+const INTEGER: u8 = 0;
+const BOOLEAN: u8 = 1;
+const STRING: u8 = 2;
+const CHAR: u8 = 3;
+const ARRAY: u8 = 4;
+const FUNCTION: u8 = 5;
+const NULL: u8 = 6;
+
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub bytecode: Bytecode,
+    pub source: Option<String>, // the file it was compiled from
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadError {
+    pub message: String,
+    pub offset: usize, // where in the file the problem is
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+pub fn write(bytecode: &Bytecode, source: Option<&str>) -> Result<Vec<u8>, String> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_be_bytes());
+    write_str(&mut bytes, source.unwrap_or(""));
+    code::write_varint(&mut bytes, bytecode.globals.len() as u64);
+    for name in &bytecode.globals {
+        write_str(&mut bytes, name);
+    }
+    let mut strings = HashMap::new();
+    for constant in &bytecode.constants {
+        collect_strings(constant, &mut strings);
+    }
+    let mut table = strings.iter().collect::<Vec<_>>();
+    table.sort_by_key(|(_, index)| **index);
+    code::write_varint(&mut bytes, table.len() as u64);
+    for (s, _) in table {
+        write_str(&mut bytes, s);
+    }
+    code::write_varint(&mut bytes, bytecode.constants.len() as u64);
+    for constant in &bytecode.constants {
+        write_object(&mut bytes, constant, &strings)?;
+    }
+    write_blob(&mut bytes, &code::encode(&bytecode.instructions)?);
+    write_lines(&mut bytes, &bytecode.lines);
+    Ok(bytes)
+}
+
+pub fn read(bytes: &[u8]) -> Result<Script, ReadError> {
+    let mut reader = Reader { bytes, offset: 0, strings: Vec::new() };
+    if !bytes.starts_with(MAGIC) {
+        return Err(reader.error("not a compiled script"));
+    }
+    reader.take(MAGIC.len())?;
+    let version = u16::from_be_bytes([reader.byte()?, reader.byte()?]);
+    if version != VERSION {
+        return Err(reader.error_at(4, &format!("format version {} is not supported, expected {}", version, VERSION)));
+    }
+    let source = Some(reader.string()?).filter(|source| !source.is_empty());
+    let globals = (0..reader.count()?).map(|_| reader.string()).collect::<Result<_, _>>()?;
+    // read once and shared, so equal strings are one allocation again
+    reader.strings = (0..reader.count()?).map(|_| reader.string().map(Rc::from)).collect::<Result<_, _>>()?;
+    let constants = (0..reader.count()?).map(|_| reader.object(0)).collect::<Result<_, _>>()?;
+    let instructions = reader.instructions()?;
+    let lines = reader.lines(instructions.len())?;
+    if reader.offset != bytes.len() {
+        return Err(reader.error("unexpected bytes after the program"));
+    }
+    Ok(Script { bytecode: Bytecode { instructions, constants, globals, lines }, source })
+}
+
+// numbers each distinct string in the order they first appear
+fn collect_strings(object: &Object, strings: &mut HashMap<Rc<str>, usize>) {
+    match object {
+        Object::String(s) => {
+            let next = strings.len();
+            strings.entry(s.clone()).or_insert(next);
+        }
+        Object::Array(elements) => elements.iter().for_each(|element| collect_strings(element, strings)),
+        _ => {}
+    }
+}
+
+fn write_object(bytes: &mut Vec<u8>, object: &Object, strings: &HashMap<Rc<str>, usize>) -> Result<(), String> {
+    match object {
+        Object::Integer(n) => {
+            bytes.push(INTEGER);
+            code::write_varint(bytes, ((n << 1) ^ (n >> 63)) as u64);
+        }
+        Object::Boolean(b) => bytes.extend([BOOLEAN, *b as u8]),
+        Object::String(s) => {
+            bytes.push(STRING);
+            code::write_varint(bytes, strings[s] as u64);
+        }
+        Object::Char(c) => {
+            bytes.push(CHAR);
+            code::write_varint(bytes, *c as u64);
+        }
+        Object::Array(elements) => {
+            bytes.push(ARRAY);
+            code::write_varint(bytes, elements.len() as u64);
+            for element in elements {
+                write_object(bytes, element, strings)?;
+            }
+        }
+        Object::Function(function) => {
+            bytes.push(FUNCTION);
+            code::write_varint(bytes, function.num_locals as u64);
+            code::write_varint(bytes, function.num_parameters as u64);
+            write_blob(bytes, &code::encode(&function.instructions)?);
+            write_lines(bytes, &function.lines);
+        }
+        Object::Builtin(_) => return Err("a builtin only exists while the program runs, it can't be stored".to_string()),
+        Object::Null => bytes.push(NULL),
+    }
+    Ok(())
+}
+
+fn write_lines(bytes: &mut Vec<u8>, lines: &LineTable) {
+    code::write_varint(bytes, lines.entries().len() as u64);
+    let mut previous = 0;
+    for (instruction, span) in lines.entries() {
+        for n in [instruction - previous, span.line, span.column, span.start, span.end.saturating_sub(span.start)] {
+            code::write_varint(bytes, n as u64);
+        }
+        previous = *instruction;
+    }
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_blob(bytes, s.as_bytes());
+}
+
+fn write_blob(bytes: &mut Vec<u8>, blob: &[u8]) {
+    code::write_varint(bytes, blob.len() as u64);
+    bytes.extend(blob);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    strings: Vec<Rc<str>>,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> ReadError {
+        self.error_at(self.offset, message)
+    }
+
+    fn error_at(&self, offset: usize, message: &str) -> ReadError {
+        ReadError { message: message.to_string(), offset }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], ReadError> {
+        let bytes = self.bytes.get(self.offset..).and_then(|rest| rest.get(..count)).ok_or_else(|| self.error("unexpected end of file"))?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, ReadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, ReadError> {
+        let (value, size) = code::read_varint(&self.bytes[self.offset..]).ok_or_else(|| self.error("unexpected end of file"))?;
+        self.offset += size;
+        Ok(value)
+    }
+
+    // a count of things that take at least a byte each, so it can't be more than what's left
+    fn count(&mut self) -> Result<usize, ReadError> {
+        let start = self.offset;
+        let count = self.varint()?;
+        match usize::try_from(count) {
+            Ok(count) if count <= self.bytes.len() - self.offset => Ok(count),
+            _ => Err(self.error_at(start, &format!("count {} is larger than the rest of the file", count))),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ReadError> {
+        let start = self.offset;
+        let value = self.varint()?;
+        usize::try_from(value).map_err(|_| self.error_at(start, &format!("{} is too large", value)))
+    }
+
+    fn blob(&mut self) -> Result<&'a [u8], ReadError> {
+        let length = self.count()?;
+        self.take(length)
+    }
+
+    fn string(&mut self) -> Result<String, ReadError> {
+        let start = self.offset;
+        let blob = self.blob()?;
+        String::from_utf8(blob.to_vec()).map_err(|_| self.error_at(start, "string is not valid utf-8"))
+    }
+
+    fn instructions(&mut self) -> Result<Vec<Instruction>, ReadError> {
+        let start = self.offset;
+        let blob = self.blob()?;
+        code::decode(blob).map_err(|message| self.error_at(start, &message))
+    }
+
+    fn lines(&mut self, num_instructions: usize) -> Result<LineTable, ReadError> {
+        let mut lines = LineTable::default();
+        let mut instruction = 0;
+        for _ in 0..self.count()? {
+            let entry = self.offset;
+            let skipped = self.number()?;
+            if skipped >= num_instructions - instruction {
+                return Err(self.error_at(entry, &format!("line table entry past the last of {} instructions", num_instructions)));
+            }
+            instruction += skipped;
+            let (line, column, start) = (self.number()?, self.number()?, self.number()?);
+            let end = start.checked_add(self.number()?).ok_or_else(|| self.error_at(entry, "span is too large"))?;
+            lines.add(instruction, &Span { start, end, line, column });
+        }
+        Ok(lines)
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Object, ReadError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("constants are nested too deeply"));
+        }
+        let start = self.offset;
+        Ok(match self.byte()? {
+            INTEGER => {
+                let (n, size) = code::read_zigzag(&self.bytes[self.offset..]).ok_or_else(|| self.error("unexpected end of file"))?;
+                self.offset += size;
+                Object::Integer(n)
+            }
+            BOOLEAN => match self.byte()? {
+                0 => Object::Boolean(false),
+                1 => Object::Boolean(true),
+                other => return Err(self.error_at(start + 1, &format!("{} is not a boolean", other))),
+            },
+            STRING => {
+                let index = self.number()?;
+                let s = self.strings.get(index).ok_or_else(|| self.error_at(start + 1, &format!("string {} doesn't exist, there are {}", index, self.strings.len())))?;
+                Object::String(s.clone())
+            }
+            CHAR => {
+                let c = self.varint()?;
+                let c = u32::try_from(c).ok().and_then(char::from_u32);
+                Object::Char(c.ok_or_else(|| self.error_at(start + 1, "invalid char"))?)
+            }
+            ARRAY => Object::Array((0..self.count()?).map(|_| self.object(depth + 1)).collect::<Result<_, _>>()?),
+            FUNCTION => {
+                let num_locals = self.number()?;
+                let num_parameters = self.number()?;
+                if num_parameters > num_locals {
+                    return Err(self.error_at(start, &format!("function has {} parameters but only {} locals", num_parameters, num_locals)));
+                }
+                let instructions = self.instructions()?;
+                let lines = self.lines(instructions.len())?;
+                Object::Function(Rc::new(Function { instructions, num_locals, num_parameters, lines }))
+            }
+            NULL => Object::Null,
+            tag => return Err(self.error_at(start, &format!("unknown constant tag {}", tag))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Bytecode {
+        let mut lines = LineTable::default();
+        lines.add(0, &Span { start: 12, end: 30, line: 2, column: 5 });
+        lines.add(2, &Span { start: 40, end: 44, line: 3, column: 9 });
+        let function = Function {
+            instructions: vec![Instruction::GetLocal(0), Instruction::JumpNotTruthy(3), Instruction::Null, Instruction::Return],
+            num_locals: 1,
+            num_parameters: 1,
+            lines,
+        };
+        let mut lines = LineTable::default();
+        lines.add(0, &Span { start: 0, end: 50, line: 1, column: 1 });
+        Bytecode {
+            instructions: vec![Instruction::LoadConstant(0), Instruction::SetGlobal(0), Instruction::LoadConstant(5), Instruction::Pop],
+            constants: vec![
+                Object::Function(Rc::new(function)),
+                Object::Integer(-300),
+                Object::String("ok".into()),
+                Object::Char('好'),
+                Object::Boolean(true),
+                Object::Array(vec![Object::Integer(1), Object::String("ok".into()), Object::Array(vec![Object::Null])]),
+            ],
+            globals: vec!["f".to_string()],
+            lines,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = write(&sample(), Some("main.rc")).unwrap();
+        assert_eq!(&bytes[..6], b"RCB\0\x00\x04");
+        let script = read(&bytes).unwrap();
+        assert_eq!(script.source.as_deref(), Some("main.rc"));
+        assert_eq!(script.bytecode.instructions, sample().instructions);
+        assert_eq!(script.bytecode.constants, sample().constants);
+        assert_eq!(script.bytecode.globals, sample().globals);
+        assert_eq!(script.bytecode.lines, sample().lines);
+        // both "ok"s are stored once and read back as the same string
+        assert_eq!(bytes.windows(2).filter(|window| window == b"ok").count(), 1);
+        let Object::Array(elements) = &script.bytecode.constants[5] else { panic!("expected an array") };
+        match (&script.bytecode.constants[2], &elements[1]) {
+            (Object::String(a), Object::String(b)) => assert!(Rc::ptr_eq(a, b)),
+            other => panic!("expected two strings, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_input() {
+        let bytes = write(&sample(), None).unwrap();
+        // every truncation is caught, never a panic
+        for length in 0..bytes.len() {
+            assert!(read(&bytes[..length]).is_err(), "accepted {} bytes", length);
+        }
+
+        assert_eq!(read(b"RCX\0\x00\x04").unwrap_err().message, "not a compiled script");
+        assert_eq!(read(b"RCB\0\x00\x09").unwrap_err().message, "format version 9 is not supported, expected 4");
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(read(&trailing).unwrap_err().message, "unexpected bytes after the program");
+
+        // the first constant's tag sits right after the empty source name, the global "f" and the string "ok"
+        let mut tag = bytes.clone();
+        tag[15] = 42;
+        assert_eq!(read(&tag).unwrap_err(), ReadError { message: "unknown constant tag 42".to_string(), offset: 15 });
+
+        // main's line table is last, its only entry can't skip past the four instructions
+        let mut lines = bytes.clone();
+        let entry = lines.len() - 5;
+        lines[entry] = 4;
+        assert_eq!(read(&lines).unwrap_err(), ReadError { message: "line table entry past the last of 4 instructions".to_string(), offset: entry });
+    }
+}
@@ -0,0 +1,765 @@
+use std::collections::HashMap;
+
+use crate::{ast::{BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Parameter, Pattern, PrefixOp, Statement}, code::Opcode, codegen::{CompileError, ConstantPool, Object}, const_fold::ConstantFolder, deadcode::DeadCode, definite::DefiniteAssignment};
+
+// compiles the same tree as CodeGen for the register vm, every instruction names the
+// registers it reads and writes so a variable is used where it lives instead of being
+// pushed and popped
+// variables get a register for their whole scope, temporaries are taken above them and
+// given back in order, so a frame needs no more registers than its deepest expression
+// functions are called by name and aren't values, a call's frame starts at the caller's
+// first free register so the arguments are already where the parameters go
+// main's registers are the globals, a function reaches the ones it can see through GetGlobal and SetGlobal
+
+pub type Reg = usize;
+
+// every expression statement leaves its value here, like the stack vm's last popped value
+pub const RESULT: Reg = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegInstruction {
+    LoadConstant { dst: Reg, index: usize },
+    True(Reg),
+    False(Reg),
+    Null(Reg),
+    Move { dst: Reg, src: Reg },
+    Binary { op: Opcode, dst: Reg, left: Reg, right: Reg }, // op is one of the stack vm's two operand opcodes
+    Not { dst: Reg, src: Reg },
+    Neg { dst: Reg, src: Reg },
+    Jump(usize),
+    JumpIfFalse { condition: Reg, target: usize }, // jumps unless the register is truthy
+    Array { dst: Reg, start: Reg, count: usize },   // collects registers start..start + count
+    Index { dst: Reg, array: Reg, index: Reg },
+    SetIndex { array: Reg, index: Reg, value: Reg }, // replaces the element in place
+    Length { dst: Reg, src: Reg },
+    GetGlobal { dst: Reg, global: Reg }, // global is one of main's registers
+    SetGlobal { global: Reg, src: Reg },
+    Call { dst: Reg, function: usize, start: Reg, count: usize }, // the arguments are start + 1..=start + count
+    Return(Reg), // at the top level, ends the program with the register's value
+}
+
+// what the register vm needs to run a compiled program
+#[derive(Debug, Clone)]
+pub struct RegisterProgram {
+    pub instructions: Vec<RegInstruction>,
+    pub constants: Vec<Object>,
+    pub num_registers: usize,
+    pub functions: Vec<RegisterFunction>, // Call names them by index
+}
+
+// the parameters are the registers right after RESULT
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterFunction {
+    pub instructions: Vec<RegInstruction>,
+    pub num_registers: usize,
+    pub num_parameters: usize,
+}
+
+pub struct RegisterCodeGen {
+    instructions: Vec<RegInstruction>,
+    constants: ConstantPool,
+    scopes: Vec<HashMap<String, Binding>>, // innermost last
+    function_scopes: Vec<usize>,           // where the scopes of each function being compiled start, main's come first
+    functions: Vec<RegisterFunction>,
+    next: Reg, // the lowest free register
+    num_registers: usize,
+    loops: Vec<LoopContext>, // innermost last
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    Register(Reg),
+    Function(usize),
+}
+
+// where a name is, seen from the function being compiled
+enum Place {
+    Local(Reg),
+    Global(Reg),
+    Function(usize),
+}
+
+// the jumps out of a loop being compiled, patched once the loop's end is known
+struct LoopContext {
+    label: Option<String>,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+impl RegisterCodeGen {
+    pub fn new() -> Self {
+        RegisterCodeGen {
+            instructions: Vec::new(),
+            constants: ConstantPool::default(),
+            scopes: vec![HashMap::new()],
+            function_scopes: Vec::new(),
+            functions: Vec::new(),
+            next: RESULT + 1,
+            num_registers: RESULT + 1,
+            loops: Vec::new(),
+        }
+    }
+
+    // the same passes run first as for CodeGen, so both vms see the same program
+    pub fn compile(&mut self, program: Vec<Statement>) -> Result<(), CompileError> {
+        let program = ConstantFolder::fold(program);
+        let (program, _) = DeadCode::eliminate(program);
+        DefiniteAssignment::check(&program).map_err(CompileError::Unassigned)?;
+
+        for stmt in &program {
+            self.compile_statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    pub fn program(&self) -> RegisterProgram {
+        RegisterProgram {
+            instructions: self.instructions.clone(),
+            constants: self.constants.objects().to_vec(),
+            num_registers: self.num_registers,
+            functions: self.functions.clone(),
+        }
+    }
+
+    fn compile_statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        match stmt {
+            Statement::Block { statements, .. } => {
+                self.enter_scope();
+                let mark = self.next;
+                let result = statements.iter().try_for_each(|stmt| self.compile_statement(stmt));
+                self.leave_scope(mark);
+                result?;
+            }
+            Statement::If { condition, then_branch, else_branch, .. } => {
+                let skip = self.jump_if_false(condition)?;
+                self.compile_scoped(then_branch)?;
+                match else_branch {
+                    Some(else_branch) => {
+                        let end = self.emit(RegInstruction::Jump(0));
+                        self.patch_jump(skip)?;
+                        self.compile_scoped(else_branch)?;
+                        self.patch_jump(end)?;
+                    }
+                    None => self.patch_jump(skip)?,
+                }
+            }
+            Statement::While { label, condition, body, .. } => {
+                let start = self.instructions.len();
+                let exit = self.jump_if_false(condition)?;
+                let context = self.compile_loop_body(label, body)?;
+                self.emit(RegInstruction::Jump(start));
+                self.patch_jump(exit)?;
+                self.patch_loop(context, start)?;
+            }
+            Statement::For { label, init, condition, increment, body, .. } => {
+                self.enter_scope();
+                let mark = self.next;
+                let result = self.compile_for(label, init.as_deref(), condition, increment.as_deref(), body);
+                self.leave_scope(mark);
+                result?;
+            }
+            Statement::ForIn { label, variable, iterable, body, .. } => {
+                self.enter_scope();
+                let mark = self.next;
+                let result = self.compile_for_in(label, variable, iterable, body);
+                self.leave_scope(mark);
+                result?;
+            }
+            Statement::Break { label, .. } => {
+                let jump = self.emit(RegInstruction::Jump(0));
+                self.enclosing_loop("break", label.as_deref())?.breaks.push(jump);
+            }
+            Statement::Continue { label, .. } => {
+                let jump = self.emit(RegInstruction::Jump(0));
+                self.enclosing_loop("continue", label.as_deref())?.continues.push(jump);
+            }
+            Statement::Return { value, .. } => {
+                let mark = self.next;
+                let value = match value {
+                    Some(value) => self.operand(value)?,
+                    None => {
+                        let value = self.temp();
+                        self.emit(RegInstruction::Null(value));
+                        value
+                    }
+                };
+                self.emit(RegInstruction::Return(value));
+                self.next = mark;
+            }
+            Statement::Expression { expression, .. } => self.compile_expression(expression, RESULT)?,
+            Statement::VariableDeclaration { name, initializer, .. } => {
+                // the initializer still sees an outer variable of the same name
+                let register = self.temp();
+                match initializer {
+                    Some(initializer) => self.compile_expression(initializer, register)?,
+                    None => {
+                        self.emit(RegInstruction::Null(register));
+                    }
+                }
+                self.define(name, register);
+            }
+            Statement::FunctionDeclaration { name, parameters, body, .. } => self.compile_function(name, parameters, body)?,
+        }
+        Ok(())
+    }
+
+    // the body gets registers and loops of its own and is stored apart from the code around it
+    fn compile_function(&mut self, name: &str, parameters: &[Parameter], body: &Statement) -> Result<(), String> {
+        // bound before the body so it can call itself
+        let index = self.functions.len();
+        self.functions.push(RegisterFunction { instructions: Vec::new(), num_registers: 0, num_parameters: parameters.len() });
+        self.scopes.last_mut().unwrap().insert(name.to_string(), Binding::Function(index));
+
+        let outer = std::mem::take(&mut self.instructions);
+        let outer_loops = std::mem::take(&mut self.loops);
+        let (outer_next, outer_registers) = (self.next, self.num_registers);
+        (self.next, self.num_registers) = (RESULT + 1, RESULT + 1);
+        self.function_scopes.push(self.scopes.len());
+        self.enter_scope();
+        for param in parameters {
+            let register = self.temp();
+            self.define(&param.name, register);
+        }
+        let body = self.compile_statement(body);
+        // falling off the end returns null
+        let null = self.temp();
+        self.emit(RegInstruction::Null(null));
+        self.emit(RegInstruction::Return(null));
+        self.scopes.pop();
+        self.function_scopes.pop();
+
+        let instructions = std::mem::replace(&mut self.instructions, outer);
+        self.functions[index] = RegisterFunction { instructions, num_registers: self.num_registers, num_parameters: parameters.len() };
+        self.loops = outer_loops;
+        (self.next, self.num_registers) = (outer_next, outer_registers);
+        body
+    }
+
+    fn compile_for(&mut self, label: &Option<String>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement) -> Result<(), String> {
+        if let Some(init) = init {
+            self.compile_statement(init)?;
+        }
+        let start = self.instructions.len();
+        let exit = self.jump_if_false(condition)?;
+        let context = self.compile_loop_body(label, body)?;
+        // continue runs the increment before checking the condition again
+        let next = self.instructions.len();
+        if let Some(increment) = increment {
+            self.compile_statement(increment)?;
+        }
+        self.emit(RegInstruction::Jump(start));
+        self.patch_jump(exit)?;
+        self.patch_loop(context, next)?;
+        Ok(())
+    }
+
+    // a counter walks the range or the array indices, the 1 it counts by is loaded once
+    fn compile_for_in(&mut self, label: &Option<String>, variable: &str, iterable: &Iterable, body: &Statement) -> Result<(), String> {
+        let counter = self.temp();
+        let limit = self.temp();
+        let (array, compare) = match iterable {
+            Iterable::Range { start, end, inclusive } => {
+                self.compile_expression(start, counter)?;
+                self.compile_expression(end, limit)?;
+                (None, if *inclusive { Opcode::LessEqual } else { Opcode::LessThan })
+            }
+            Iterable::Array(array) => {
+                let register = self.temp();
+                self.compile_expression(array, register)?;
+                self.load_constant(counter, Object::Integer(0));
+                self.emit(RegInstruction::Length { dst: limit, src: register });
+                (Some(register), Opcode::LessThan)
+            }
+        };
+        let one = self.temp();
+        self.load_constant(one, Object::Integer(1));
+        let element = self.temp();
+        self.define(variable, element);
+
+        let start = self.instructions.len();
+        let more = self.temp();
+        self.emit(RegInstruction::Binary { op: compare, dst: more, left: counter, right: limit });
+        let exit = self.emit(RegInstruction::JumpIfFalse { condition: more, target: 0 });
+        self.next = more;
+        match array {
+            Some(array) => self.emit(RegInstruction::Index { dst: element, array, index: counter }),
+            None => self.emit(RegInstruction::Move { dst: element, src: counter }),
+        };
+
+        let context = self.compile_loop_body(label, body)?;
+        let next = self.instructions.len();
+        // an inclusive range stops at its bound instead of counting past it, `..=i64::MAX` would overflow
+        let last = match iterable {
+            Iterable::Range { inclusive: true, .. } => {
+                self.emit(RegInstruction::Binary { op: Opcode::NotEqual, dst: more, left: counter, right: limit });
+                Some(self.emit(RegInstruction::JumpIfFalse { condition: more, target: 0 }))
+            }
+            _ => None,
+        };
+        self.emit(RegInstruction::Binary { op: Opcode::Add, dst: counter, left: counter, right: one });
+        self.emit(RegInstruction::Jump(start));
+        self.patch_jump(exit)?;
+        if let Some(last) = last {
+            self.patch_jump(last)?;
+        }
+        self.patch_loop(context, next)?;
+        Ok(())
+    }
+
+    // leaves the value of `expr` in `dst`
+    fn compile_expression(&mut self, expr: &Expression, dst: Reg) -> Result<(), String> {
+        let mark = self.next;
+        match expr {
+            // short circuit, the right side only runs when it decides the result
+            Expression::Binary { left, operator: BinaryOp::And, right, .. } => {
+                self.compile_expression(left, dst)?;
+                let short = self.emit(RegInstruction::JumpIfFalse { condition: dst, target: 0 });
+                self.compile_expression(right, dst)?;
+                let end = self.emit(RegInstruction::Jump(0));
+                self.patch_jump(short)?;
+                self.emit(RegInstruction::False(dst));
+                self.patch_jump(end)?;
+            }
+            Expression::Binary { left, operator: BinaryOp::Or, right, .. } => {
+                self.compile_expression(left, dst)?;
+                let next = self.emit(RegInstruction::JumpIfFalse { condition: dst, target: 0 });
+                self.emit(RegInstruction::True(dst));
+                let end = self.emit(RegInstruction::Jump(0));
+                self.patch_jump(next)?;
+                self.compile_expression(right, dst)?;
+                self.patch_jump(end)?;
+            }
+            Expression::Binary { left, operator, right, .. } => {
+                let (left, right) = self.operands(left, right)?;
+                self.emit(RegInstruction::Binary { op: binary_opcode(operator), dst, left, right });
+            }
+            Expression::Unary { operator, operand, .. } => {
+                let src = self.operand(operand)?;
+                match operator {
+                    PrefixOp::Not => self.emit(RegInstruction::Not { dst, src }),
+                    PrefixOp::Neg => self.emit(RegInstruction::Neg { dst, src }),
+                };
+            }
+            Expression::Literal { value, .. } => self.load_literal(dst, value),
+            Expression::Variable { name, .. } => match self.resolve(name)? {
+                Place::Local(src) => self.emit_move(dst, src),
+                Place::Global(global) => {
+                    self.emit(RegInstruction::GetGlobal { dst, global });
+                }
+                Place::Function(_) => return Err(format!("`{}` is a function, the register vm only calls functions by name", name)),
+            },
+            Expression::Call { callee, arguments, .. } => {
+                let function = match callee.as_ref() {
+                    Expression::Variable { name, .. } => match self.resolve(name)? {
+                        Place::Function(index) => Some(index),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                let function = function.ok_or("the register vm only calls functions by name")?;
+                // the callee's RESULT, then the arguments in the registers its parameters use
+                let start = self.temp();
+                for argument in arguments {
+                    let register = self.temp();
+                    self.compile_expression(argument, register)?;
+                }
+                self.emit(RegInstruction::Call { dst, function, start, count: arguments.len() });
+            }
+            Expression::Assign { target, value, .. } => self.compile_assign(target, None, value, dst)?,
+            Expression::CompoundAssign { target, operator, value, .. } => self.compile_assign(target, Some(&operator.binary()), value, dst)?,
+            Expression::Increment { target, operator, span } => {
+                let (operator, one) = operator.lower(span);
+                self.compile_assign(target, Some(&operator.binary()), &one, dst)?;
+            }
+            Expression::Index { array, index, .. } => {
+                let (array, index) = self.operands(array, index)?;
+                self.emit(RegInstruction::Index { dst, array, index });
+            }
+            Expression::Array { elements, .. } => {
+                let start = self.next;
+                for element in elements {
+                    let register = self.temp();
+                    self.compile_expression(element, register)?;
+                }
+                self.emit(RegInstruction::Array { dst, start, count: elements.len() });
+            }
+            Expression::Match { scrutinee, arms, .. } => self.compile_match(scrutinee, arms, dst)?,
+        }
+        self.next = mark;
+        Ok(())
+    }
+
+    // the value is kept in a register of its own, the first arm that matches leaves its body in dst
+    fn compile_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], dst: Reg) -> Result<(), String> {
+        let value = self.temp();
+        self.compile_expression(scrutinee, value)?;
+        let mut ends = Vec::new();
+        for arm in arms {
+            self.enter_scope();
+            let mark = self.next;
+            let end = self.compile_arm(value, arm, dst);
+            self.leave_scope(mark);
+            ends.push(end?);
+        }
+        // no arm matched, the type checker rules this out for exhaustive matches
+        self.emit(RegInstruction::Null(dst));
+        for end in ends {
+            self.patch_jump(end)?;
+        }
+        Ok(())
+    }
+
+    // returns the jump taken after the body ran
+    fn compile_arm(&mut self, value: Reg, arm: &MatchArm, dst: Reg) -> Result<usize, String> {
+        for name in arm.pattern.bindings() {
+            let register = self.temp();
+            self.define(name, register);
+        }
+        let mut fails = self.compile_pattern(&arm.pattern, value)?;
+        if let Some(guard) = &arm.guard {
+            fails.push(self.jump_if_false(guard)?);
+        }
+        self.compile_expression(&arm.body, dst)?;
+        let end = self.emit(RegInstruction::Jump(0));
+        for fail in fails {
+            self.patch_jump(fail)?;
+        }
+        Ok(end)
+    }
+
+    // tests the value in `value` and binds names on the way, returns the jumps taken when it doesn't match
+    fn compile_pattern(&mut self, pattern: &Pattern, value: Reg) -> Result<Vec<usize>, String> {
+        let mark = self.next;
+        let mut fails = Vec::new();
+        match pattern {
+            Pattern::Wildcard { .. } => {}
+            Pattern::Binding { name, .. } => {
+                // the arm defined its bindings, so this is always one of them
+                if let Place::Local(register) = self.resolve(name)? {
+                    self.emit_move(register, value);
+                }
+            }
+            Pattern::Literal { value: literal, .. } => {
+                let equal = self.temp();
+                self.load_literal(equal, literal);
+                self.emit(RegInstruction::Binary { op: Opcode::Equal, dst: equal, left: value, right: equal });
+                fails.push(self.emit(RegInstruction::JumpIfFalse { condition: equal, target: 0 }));
+            }
+            Pattern::Array { elements, rest, .. } => {
+                let (length, expected) = (self.temp(), self.temp());
+                self.emit(RegInstruction::Length { dst: length, src: value });
+                self.load_constant(expected, Object::Integer(elements.len() as i64));
+                // with a rest, at least that many elements
+                let op = if *rest { Opcode::GreaterEqual } else { Opcode::Equal };
+                self.emit(RegInstruction::Binary { op, dst: length, left: length, right: expected });
+                fails.push(self.emit(RegInstruction::JumpIfFalse { condition: length, target: 0 }));
+                for (i, element) in elements.iter().enumerate() {
+                    if matches!(element, Pattern::Wildcard { .. }) {
+                        continue;
+                    }
+                    let (index, item) = (self.temp(), self.temp());
+                    self.load_constant(index, Object::Integer(i as i64));
+                    self.emit(RegInstruction::Index { dst: item, array: value, index });
+                    fails.extend(self.compile_pattern(element, item)?);
+                    self.next = index;
+                }
+            }
+            Pattern::Or { alternatives, .. } => {
+                let Some((last, others)) = alternatives.split_last() else {
+                    fails.push(self.emit(RegInstruction::Jump(0)));
+                    return Ok(fails);
+                };
+                // an alternative that fails falls through to the next one
+                let mut matched = Vec::new();
+                for alternative in others {
+                    let alternative_fails = self.compile_pattern(alternative, value)?;
+                    matched.push(self.emit(RegInstruction::Jump(0)));
+                    for fail in alternative_fails {
+                        self.patch_jump(fail)?;
+                    }
+                }
+                fails = self.compile_pattern(last, value)?;
+                for jump in matched {
+                    self.patch_jump(jump)?;
+                }
+            }
+        }
+        self.next = mark;
+        Ok(fails)
+    }
+
+    // `a[i][j] op= v` evaluates i and j once, then puts the changed elements back
+    // from the inside out, SetIndex changes the array in its register
+    fn compile_assign(&mut self, target: &Expression, operator: Option<&BinaryOp>, value: &Expression, dst: Reg) -> Result<(), String> {
+        let mut indices = Vec::new();
+        let mut base = target;
+        while let Expression::Index { array, index, .. } = base {
+            indices.push(index.as_ref());
+            base = array;
+        }
+        indices.reverse();
+        let Expression::Variable { name, .. } = base else {
+            return Err("invalid assignment target".to_string());
+        };
+        let (variable, global) = match self.resolve(name)? {
+            Place::Local(register) => (register, None),
+            // a function changes a copy of the global and writes it back
+            Place::Global(global) => {
+                let copy = self.temp();
+                self.emit(RegInstruction::GetGlobal { dst: copy, global });
+                (copy, Some(global))
+            }
+            Place::Function(_) => return Err(format!("cannot assign to the function `{}`", name)),
+        };
+
+        let mut slots = Vec::new();
+        for index in indices {
+            let slot = self.temp();
+            self.compile_expression(index, slot)?;
+            slots.push(slot);
+        }
+        // variable, variable[slot0], variable[slot0][slot1], ..., the last one is assigned to
+        let mut elements = vec![variable];
+        for _ in &slots {
+            elements.push(self.temp());
+        }
+        let load_elements = |this: &mut Self| {
+            for (depth, slot) in slots.iter().enumerate() {
+                this.emit(RegInstruction::Index { dst: elements[depth + 1], array: elements[depth], index: *slot });
+            }
+        };
+
+        let assigned = match operator {
+            Some(operator) => {
+                load_elements(self);
+                let target = elements[slots.len()];
+                // the old value is read before the right side could change it
+                let left = match pure(value) {
+                    true => target,
+                    false => {
+                        let copy = self.temp();
+                        self.emit_move(copy, target);
+                        copy
+                    }
+                };
+                let right = self.operand(value)?;
+                let result = if slots.is_empty() { variable } else { self.temp() };
+                self.emit(RegInstruction::Binary { op: binary_opcode(operator), dst: result, left, right });
+                result
+            }
+            // a short circuit writes its left side first, which the right side may still read
+            None if slots.is_empty() && !short_circuit(value) => {
+                self.compile_expression(value, variable)?;
+                variable
+            }
+            None => {
+                let result = self.temp();
+                self.compile_expression(value, result)?;
+                if slots.is_empty() {
+                    self.emit_move(variable, result);
+                }
+                result
+            }
+        };
+
+        if !slots.is_empty() {
+            // the right side may have changed the variable, so the arrays are read again
+            if let Some(global) = global {
+                self.emit(RegInstruction::GetGlobal { dst: variable, global });
+            }
+            load_elements(self);
+            let mut value = assigned;
+            for depth in (0..slots.len()).rev() {
+                self.emit(RegInstruction::SetIndex { array: elements[depth], index: slots[depth], value });
+                value = elements[depth];
+            }
+        }
+        if let Some(global) = global {
+            self.emit(RegInstruction::SetGlobal { global, src: variable });
+        }
+        self.emit_move(dst, assigned);
+        Ok(())
+    }
+
+    // where an operand can be read, a variable is read in place and anything else
+    // is compiled into a new temporary
+    fn operand(&mut self, expr: &Expression) -> Result<Reg, String> {
+        if let Expression::Variable { name, .. } = expr && let Place::Local(register) = self.resolve(name)? {
+            return Ok(register);
+        }
+        let register = self.temp();
+        self.compile_expression(expr, register)?;
+        Ok(register)
+    }
+
+    // a variable on the left is copied first when the right side could assign to it
+    fn operands(&mut self, left: &Expression, right: &Expression) -> Result<(Reg, Reg), String> {
+        let left = match pure(right) {
+            true => self.operand(left)?,
+            false => {
+                let register = self.temp();
+                self.compile_expression(left, register)?;
+                register
+            }
+        };
+        Ok((left, self.operand(right)?))
+    }
+
+    fn jump_if_false(&mut self, condition: &Expression) -> Result<usize, String> {
+        let mark = self.next;
+        let condition = self.operand(condition)?;
+        self.next = mark;
+        Ok(self.emit(RegInstruction::JumpIfFalse { condition, target: 0 }))
+    }
+
+    fn compile_scoped(&mut self, stmt: &Statement) -> Result<(), String> {
+        self.enter_scope();
+        let mark = self.next;
+        let result = self.compile_statement(stmt);
+        self.leave_scope(mark);
+        result
+    }
+
+    fn compile_loop_body(&mut self, label: &Option<String>, body: &Statement) -> Result<LoopContext, String> {
+        self.loops.push(LoopContext { label: label.clone(), breaks: Vec::new(), continues: Vec::new() });
+        let body = self.compile_scoped(body);
+        let context = self.loops.pop().unwrap();
+        body.map(|_| context)
+    }
+
+    // breaks land right after the loop, so call this once the loop is fully emitted
+    fn patch_loop(&mut self, context: LoopContext, continue_target: usize) -> Result<(), String> {
+        for jump in context.breaks {
+            self.patch_jump(jump)?;
+        }
+        for jump in context.continues {
+            self.patch_jump_to(jump, continue_target)?;
+        }
+        Ok(())
+    }
+
+    fn enclosing_loop(&mut self, keyword: &str, label: Option<&str>) -> Result<&mut LoopContext, String> {
+        let found = match label {
+            Some(label) => self.loops.iter().rposition(|l| l.label.as_deref() == Some(label)),
+            None => self.loops.len().checked_sub(1),
+        };
+        match (found, label) {
+            (Some(index), _) => Ok(&mut self.loops[index]),
+            (None, Some(label)) => Err(format!("`{} {}` doesn't match any enclosing loop", keyword, label)),
+            (None, None) => Err(format!("`{}` outside of a loop", keyword)),
+        }
+    }
+
+    fn enter_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    // the scope's variables and whatever was taken after `mark` are free again
+    fn leave_scope(&mut self, mark: Reg) {
+        self.scopes.pop();
+        self.next = mark;
+    }
+
+    fn resolve(&self, name: &str) -> Result<Place, String> {
+        let (depth, binding) = self.scopes.iter().enumerate().rev()
+            .find_map(|(depth, scope)| scope.get(name).map(|binding| (depth, *binding)))
+            .ok_or_else(|| format!("undefined variable `{}`", name))?;
+        match binding {
+            Binding::Function(index) => Ok(Place::Function(index)),
+            Binding::Register(register) if depth >= self.function_scopes.last().copied().unwrap_or(0) => Ok(Place::Local(register)),
+            Binding::Register(register) if depth < self.function_scopes[0] => Ok(Place::Global(register)),
+            Binding::Register(_) => Err(format!("`{}` belongs to an enclosing function, closures aren't supported yet", name)),
+        }
+    }
+
+    fn define(&mut self, name: &str, register: Reg) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), Binding::Register(register));
+    }
+
+    fn temp(&mut self) -> Reg {
+        self.next += 1;
+        self.num_registers = self.num_registers.max(self.next);
+        self.next - 1
+    }
+
+    fn load_literal(&mut self, dst: Reg, literal: &LiteralValue) {
+        match literal {
+            LiteralValue::Bool(true) => {
+                self.emit(RegInstruction::True(dst));
+            }
+            LiteralValue::Bool(false) => {
+                self.emit(RegInstruction::False(dst));
+            }
+            LiteralValue::Integer(n) => self.load_constant(dst, Object::Integer(*n)),
+            LiteralValue::String(s) => self.load_constant(dst, Object::String(s.as_str().into())),
+            LiteralValue::Char(c) => self.load_constant(dst, Object::Char(*c)),
+        }
+    }
+
+    fn load_constant(&mut self, dst: Reg, object: Object) {
+        let index = self.constants.add(object);
+        self.emit(RegInstruction::LoadConstant { dst, index });
+    }
+
+    fn emit_move(&mut self, dst: Reg, src: Reg) {
+        if dst != src {
+            self.emit(RegInstruction::Move { dst, src });
+        }
+    }
+
+    // returns the position of the instruction so jumps can be patched later
+    fn emit(&mut self, instruction: RegInstruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    // point the jump at `pos` to the next instruction to be emitted
+    fn patch_jump(&mut self, pos: usize) -> Result<(), String> {
+        self.patch_jump_to(pos, self.instructions.len())
+    }
+
+    fn patch_jump_to(&mut self, pos: usize, target: usize) -> Result<(), String> {
+        let Some(RegInstruction::Jump(to) | RegInstruction::JumpIfFalse { target: to, .. }) = self.instructions.get_mut(pos) else {
+            return Err(format!("instruction {} is not a jump", pos));
+        };
+        *to = target;
+        Ok(())
+    }
+}
+
+fn binary_opcode(operator: &BinaryOp) -> Opcode {
+    match operator {
+        BinaryOp::Plus => Opcode::Add,
+        BinaryOp::Minus => Opcode::Sub,
+        BinaryOp::Multiply => Opcode::Mul,
+        BinaryOp::Divide => Opcode::Div,
+        BinaryOp::Modulo => Opcode::Mod,
+        BinaryOp::BitAnd => Opcode::BitAnd,
+        BinaryOp::BitOr => Opcode::BitOr,
+        BinaryOp::BitXor => Opcode::BitXor,
+        BinaryOp::ShiftLeft => Opcode::ShiftLeft,
+        BinaryOp::ShiftRight => Opcode::ShiftRight,
+        BinaryOp::Equal => Opcode::Equal,
+        BinaryOp::NotEqual => Opcode::NotEqual,
+        BinaryOp::LessThan => Opcode::LessThan,
+        BinaryOp::GreaterThan => Opcode::GreaterThan,
+        BinaryOp::LessEqual => Opcode::LessEqual,
+        BinaryOp::GreaterEqual => Opcode::GreaterEqual,
+        BinaryOp::And | BinaryOp::Or => unreachable!("short circuits are compiled as jumps"),
+    }
+}
+
+// can't assign to anything, so a variable read before it still has the same value after
+fn pure(expr: &Expression) -> bool {
+    match expr {
+        Expression::Literal { .. } | Expression::Variable { .. } => true,
+        Expression::Unary { operand, .. } => pure(operand),
+        Expression::Binary { left, right, .. } | Expression::Index { array: left, index: right, .. } => pure(left) && pure(right),
+        Expression::Array { elements, .. } => elements.iter().all(pure),
+        _ => false,
+    }
+}
+
+fn short_circuit(expr: &Expression) -> bool {
+    matches!(expr, Expression::Binary { operator: BinaryOp::And | BinaryOp::Or, .. })
+}
@@ -0,0 +1,345 @@
+use std::rc::Rc;
+
+use crate::{codegen::Object, regcodegen::{RegInstruction, RegisterFunction, RegisterProgram, RESULT}, vm::{binary, element, truthy}};
+
+// a call needs more registers once this many are in use, the register vm's stack overflow
+const MAX_REGISTERS: usize = 1 << 16;
+
+// runs what RegisterCodeGen compiles, operators behave exactly as in the stack vm
+// programs only come from the compiler, so registers and jumps are trusted to be in range
+// every frame's registers start at its base, main's base is 0 so its registers are also the globals
+pub struct RegisterVM {
+    main: Rc<RegisterFunction>,
+    functions: Vec<Rc<RegisterFunction>>,
+    constants: Vec<Object>,
+    registers: Vec<Object>,
+    frames: Vec<Frame>, // the callers of the running function, main first
+}
+
+// where a caller continues once the call returns
+struct Frame {
+    code: Rc<RegisterFunction>,
+    ip: usize,
+    base: usize,
+    dst: usize, // the caller's register that gets the returned value
+}
+
+impl RegisterVM {
+    pub fn new(program: RegisterProgram) -> Self {
+        let main = RegisterFunction { instructions: program.instructions, num_registers: program.num_registers, num_parameters: 0 };
+        RegisterVM {
+            main: Rc::new(main),
+            functions: program.functions.into_iter().map(Rc::new).collect(),
+            constants: program.constants,
+            registers: vec![Object::Null; program.num_registers],
+            frames: Vec::new(),
+        }
+    }
+
+    // the value of the last expression statement (or top level ret)
+    pub fn result(&self) -> &Object {
+        &self.registers[RESULT]
+    }
+
+    pub fn run(&mut self) -> Result<(), String> {
+        let mut code = self.main.clone();
+        let mut ip = 0;
+        let mut base = 0;
+        // functions always end in a Return, so only main runs off its end
+        while ip < code.instructions.len() {
+            let instruction = &code.instructions[ip];
+            ip += 1;
+
+            match *instruction {
+                RegInstruction::LoadConstant { dst, index } => self.registers[base + dst] = self.constants[index].clone(),
+                RegInstruction::True(dst) => self.registers[base + dst] = Object::Boolean(true),
+                RegInstruction::False(dst) => self.registers[base + dst] = Object::Boolean(false),
+                RegInstruction::Null(dst) => self.registers[base + dst] = Object::Null,
+                RegInstruction::Move { dst, src } => self.registers[base + dst] = self.registers[base + src].clone(),
+                RegInstruction::Binary { op, dst, left, right } => {
+                    self.registers[base + dst] = binary(op, self.registers[base + left].clone(), self.registers[base + right].clone())?;
+                }
+                RegInstruction::Not { dst, src } => match &self.registers[base + src] {
+                    Object::Boolean(b) => self.registers[base + dst] = Object::Boolean(!b),
+                    other => return Err(format!("Not cannot be applied to {:?}", other)),
+                },
+                RegInstruction::Neg { dst, src } => match &self.registers[base + src] {
+                    Object::Integer(n) => self.registers[base + dst] = Object::Integer(n.checked_neg().ok_or("integer overflow")?),
+                    other => return Err(format!("Neg cannot be applied to {:?}", other)),
+                },
+                RegInstruction::Jump(target) => ip = target,
+                RegInstruction::JumpIfFalse { condition, target } => {
+                    if !truthy(&self.registers[base + condition]) {
+                        ip = target;
+                    }
+                }
+                RegInstruction::Array { dst, start, count } => {
+                    self.registers[base + dst] = Object::Array(self.registers[base + start..base + start + count].to_vec());
+                }
+                RegInstruction::Index { dst, array, index } => {
+                    let (elements, i) = element(&self.registers[base + array], &self.registers[base + index])?;
+                    self.registers[base + dst] = elements[i].clone();
+                }
+                RegInstruction::SetIndex { array, index, value } => {
+                    let (_, i) = element(&self.registers[base + array], &self.registers[base + index])?;
+                    let value = self.registers[base + value].clone();
+                    if let Object::Array(elements) = &mut self.registers[base + array] {
+                        elements[i] = value;
+                    }
+                }
+                RegInstruction::Length { dst, src } => match &self.registers[base + src] {
+                    Object::Array(elements) => self.registers[base + dst] = Object::Integer(elements.len() as i64),
+                    other => return Err(format!("{:?} has no length", other)),
+                },
+                RegInstruction::GetGlobal { dst, global } => self.registers[base + dst] = self.registers[global].clone(),
+                RegInstruction::SetGlobal { global, src } => self.registers[global] = self.registers[base + src].clone(),
+                RegInstruction::Call { dst, function, start, count } => {
+                    let callee = self.functions[function].clone();
+                    if count != callee.num_parameters {
+                        return Err(format!("expected {} arguments but got {}", callee.num_parameters, count));
+                    }
+                    let callee_base = base + start;
+                    let end = callee_base + callee.num_registers;
+                    if end > MAX_REGISTERS {
+                        return Err("stack overflow".to_string());
+                    }
+                    if self.registers.len() < end {
+                        self.registers.resize(end, Object::Null);
+                    }
+                    // the other registers start out as null, not as whatever an earlier call left there
+                    self.registers[callee_base + RESULT + 1 + count..end].fill(Object::Null);
+                    let caller = std::mem::replace(&mut code, callee);
+                    self.frames.push(Frame { code: caller, ip, base, dst });
+                    (ip, base) = (0, callee_base);
+                }
+                // ret at the top level ends the program with that value
+                RegInstruction::Return(src) => match self.frames.pop() {
+                    None => {
+                        self.registers[RESULT] = self.registers[src].clone();
+                        return Ok(());
+                    }
+                    Some(caller) => {
+                        let value = self.registers[base + src].clone();
+                        (code, ip, base) = (caller.code, caller.ip, caller.base);
+                        self.registers[base + caller.dst] = value;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::{build::*, AssignOp, BinaryOp, IncrementOp, Iterable, MatchArm, Span, Statement}, code::Opcode, codegen::CodeGen, regcodegen::RegisterCodeGen, vm::VM};
+
+    use super::*;
+
+    fn compile_error(program: Vec<Statement>) -> String {
+        RegisterCodeGen::new().compile(program).unwrap_err().to_string()
+    }
+
+    // runs the program on both vms, they have to agree
+    fn run(program: Vec<Statement>) -> Result<Object, String> {
+        let mut codegen = CodeGen::new();
+        codegen.compile(program.clone())?;
+        let mut vm = VM::new(codegen.bytecode())?;
+        let stack = vm.run().map_err(|e| e.message).map(|_| vm.last_popped().clone());
+
+        let mut regcodegen = RegisterCodeGen::new();
+        regcodegen.compile(program)?;
+        let mut vm = RegisterVM::new(regcodegen.program());
+        let register = vm.run().map(|_| vm.result().clone());
+        assert_eq!(stack, register);
+        register
+    }
+
+    #[test]
+    fn test_loops() {
+        // let s = 0; for i in 0..100 { s += i; } s;
+        let sum = vec![decl("s", int(0)), range("i", int(100), vec![expr(compound(var("s"), AssignOp::Plus, var("i")))]), expr(var("s"))];
+        assert_eq!(run(sum), Ok(Object::Integer(4950)));
+
+        // let a = 0; let b = 1; let n = 0; while n < 20 { let t = a + b; a = b; b = t; n += 1; } a;
+        let fibonacci = vec![
+            decl("a", int(0)),
+            decl("b", int(1)),
+            decl("n", int(0)),
+            Statement::While {
+                label: None,
+                condition: bin(var("n"), BinaryOp::LessThan, int(20)),
+                body: block(vec![
+                    decl("t", bin(var("a"), BinaryOp::Plus, var("b"))),
+                    expr(assign(var("a"), var("b"))),
+                    expr(assign(var("b"), var("t"))),
+                    expr(compound(var("n"), AssignOp::Plus, int(1))),
+                ]),
+                span: Span::default(),
+            },
+            expr(var("a")),
+        ];
+        assert_eq!(run(fibonacci), Ok(Object::Integer(6765)));
+    }
+
+    #[test]
+    fn test_inclusive_range_stops_at_its_bound() {
+        // let c = 0; for i in MAX - 2..=MAX { c += 1; } c;
+        let iterable = Iterable::Range { start: int(i64::MAX - 2), end: int(i64::MAX), inclusive: true };
+        let count = Statement::ForIn { label: None, variable: "i".to_string(), iterable, body: block(vec![expr(compound(var("c"), AssignOp::Plus, int(1)))]), span: Span::default() };
+        assert_eq!(run(vec![decl("c", int(0)), count, expr(var("c"))]), Ok(Object::Integer(3)));
+    }
+
+    #[test]
+    fn test_arrays() {
+        // bubble sort: for i in 0..5 { for j in 0..4 - i { if xs[j] > xs[j + 1] { swap } } }
+        let next = || bin(var("j"), BinaryOp::Plus, int(1));
+        let swap = vec![
+            decl("t", index(var("xs"), var("j"))),
+            expr(assign(index(var("xs"), var("j")), index(var("xs"), next()))),
+            expr(assign(index(var("xs"), next()), var("t"))),
+        ];
+        let compare = Statement::If {
+            condition: bin(index(var("xs"), var("j")), BinaryOp::GreaterThan, index(var("xs"), next())),
+            then_branch: block(swap),
+            else_branch: None,
+            span: Span::default(),
+        };
+        let sort = vec![
+            decl("xs", array(vec![*int(5), *int(3), *int(4), *int(1), *int(2)])),
+            range("i", int(5), vec![range("j", bin(int(4), BinaryOp::Minus, var("i")), vec![compare])]),
+            expr(var("xs")),
+        ];
+        let sorted = (1..=5).map(Object::Integer).collect();
+        assert_eq!(run(sort), Ok(Object::Array(sorted)));
+
+        // let m = [[1, 2], [3, 4]]; m[1][0] += 10; m;
+        let matrix = vec![
+            decl("m", array(vec![*array(vec![*int(1), *int(2)]), *array(vec![*int(3), *int(4)])])),
+            expr(compound(index(index(var("m"), int(1)), int(0)), AssignOp::Plus, int(10))),
+            expr(var("m")),
+        ];
+        let expected = Object::Array(vec![
+            Object::Array(vec![Object::Integer(1), Object::Integer(2)]),
+            Object::Array(vec![Object::Integer(13), Object::Integer(4)]),
+        ]);
+        assert_eq!(run(matrix), Ok(expected));
+
+        // let m = [1, 2]; --m[0]; ++m[1] * 10;
+        let steps = vec![
+            decl("m", array(vec![*int(1), *int(2)])),
+            expr(increment(index(var("m"), int(0)), IncrementOp::Decrement)),
+            expr(bin(increment(index(var("m"), int(1)), IncrementOp::Increment), BinaryOp::Multiply, int(10))),
+        ];
+        assert_eq!(run(steps), Ok(Object::Integer(30)));
+
+        let out_of_bounds = vec![decl("xs", array(vec![*int(1)])), expr(index(var("xs"), int(3)))];
+        assert_eq!(run(out_of_bounds), Err("index 3 out of bounds for length 1".to_string()));
+    }
+
+    #[test]
+    fn test_evaluation_order() {
+        // y = y + (y = 5), the left y is read before the assignment
+        let program = vec![decl("y", int(1)), expr(assign(var("y"), bin(var("y"), BinaryOp::Plus, assign(var("y"), int(5)))))];
+        assert_eq!(run(program), Ok(Object::Integer(6)));
+
+        // y = false || y, the right side still sees the old y
+        let program = vec![decl("y", int(7)), expr(assign(var("y"), bin(boolean(false), BinaryOp::Or, var("y"))))];
+        assert_eq!(run(program), Ok(Object::Integer(7)));
+    }
+
+    #[test]
+    fn test_fewer_instructions() {
+        let program = vec![decl("s", int(0)), range("i", int(10), vec![expr(compound(var("s"), AssignOp::Plus, var("i")))]), expr(var("s"))];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program.clone()).unwrap();
+        let mut regcodegen = RegisterCodeGen::new();
+        regcodegen.compile(program).unwrap();
+        // the loop body is a single Add instead of get, get, add, dup, set, pop
+        let registers = regcodegen.program();
+        assert!(registers.instructions.len() < codegen.bytecode().instructions.len());
+        assert!(registers.instructions.contains(&RegInstruction::Binary { op: Opcode::Add, dst: 1, left: 1, right: 5 }));
+    }
+
+    #[test]
+    fn test_calls() {
+        // fun fib(n) { if n < 2 { ret n; } ret fib(n - 1) + fib(n - 2); } 1 + fib(15);
+        // the 1 is in the caller's register below the call, the callee's frame mustn't touch it
+        let fib = function("fib", &["n"], vec![
+            if_then(bin(var("n"), BinaryOp::LessThan, int(2)), vec![ret(var("n"))]),
+            ret(bin(call("fib", vec![*bin(var("n"), BinaryOp::Minus, int(1))]), BinaryOp::Plus, call("fib", vec![*bin(var("n"), BinaryOp::Minus, int(2))]))),
+        ]);
+        assert_eq!(run(vec![fib, expr(bin(int(1), BinaryOp::Plus, call("fib", vec![*int(15)])))]), Ok(Object::Integer(611)));
+
+        // fun outer(n) { fun down(k) { if k == 0 { ret 10; } ret down(k - 1); } ret down(n); } outer(3);
+        let down = function("down", &["k"], vec![
+            if_then(bin(var("k"), BinaryOp::Equal, int(0)), vec![ret(int(10))]),
+            ret(call("down", vec![*bin(var("k"), BinaryOp::Minus, int(1))])),
+        ]);
+        let outer = function("outer", &["n"], vec![down, ret(call("down", vec![*var("n")]))]);
+        assert_eq!(run(vec![outer, expr(call("outer", vec![*int(3)]))]), Ok(Object::Integer(10)));
+
+        // a function without ret gives null
+        assert_eq!(run(vec![function("nothing", &[], vec![]), expr(call("nothing", vec![]))]), Ok(Object::Null));
+    }
+
+    #[test]
+    fn test_functions_share_main_variables() {
+        // let total = 0; let xs = [0, 0]; fun add(n) { total += n; xs[1] = total; } add(3); add(4); [total, xs[1]];
+        let add = function("add", &["n"], vec![expr(compound(var("total"), AssignOp::Plus, var("n"))), expr(assign(index(var("xs"), int(1)), var("total")))]);
+        let program = vec![
+            decl("total", int(0)),
+            decl("xs", array(vec![*int(0), *int(0)])),
+            add,
+            expr(call("add", vec![*int(3)])),
+            expr(call("add", vec![*int(4)])),
+            expr(array(vec![*var("total"), *index(var("xs"), int(1))])),
+        ];
+        assert_eq!(run(program), Ok(Object::Array(vec![Object::Integer(7), Object::Integer(7)])));
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let identity = || function("id", &["x"], vec![ret(var("x"))]);
+        assert_eq!(run(vec![identity(), expr(call("id", vec![*int(1), *int(2)]))]), Err("expected 1 arguments but got 2".to_string()));
+
+        // fun down(n) { ret down(n + 1); } down(0);
+        let down = function("down", &["n"], vec![ret(call("down", vec![*bin(var("n"), BinaryOp::Plus, int(1))]))]);
+        assert_eq!(run(vec![down, expr(call("down", vec![*int(0)]))]), Err("stack overflow".to_string()));
+
+        // functions aren't values here, and a nested function can't read the locals around it
+        assert_eq!(compile_error(vec![identity(), decl("f", var("id"))]), "`id` is a function, the register vm only calls functions by name");
+        assert_eq!(compile_error(vec![decl("x", int(1)), expr(call("x", vec![]))]), "the register vm only calls functions by name");
+        let inner = function("inner", &[], vec![ret(var("a"))]);
+        let program = vec![function("outer", &["a"], vec![inner, ret(call("inner", vec![]))])];
+        assert_eq!(compile_error(program), "`a` belongs to an enclosing function, closures aren't supported yet");
+    }
+
+    #[test]
+    fn test_match() {
+        let matcher = |name: &str, arms: Vec<MatchArm>| function(name, &["x"], vec![ret(match_on(var("x"), arms))]);
+        // fun pick(x) { ret match x { 0 | 1 => 10, n if n > 100 => n - 100, _ => 30 }; }
+        let pick = matcher("pick", vec![
+            arm(or(vec![int_pattern(0), int_pattern(1)]), None, int(10)),
+            arm(binding("n"), Some(bin(var("n"), BinaryOp::GreaterThan, int(100))), bin(var("n"), BinaryOp::Minus, int(100))),
+            arm(wildcard(), None, int(30)),
+        ]);
+        // fun head(x) { ret match x { [first, _, ..] => first, [] => 20, _ => 40 }; }
+        let head = matcher("head", vec![
+            arm(array_pattern(vec![binding("first"), wildcard()], true), None, var("first")),
+            arm(array_pattern(vec![], false), None, int(20)),
+            arm(wildcard(), None, int(40)),
+        ]);
+        let calls = vec![
+            *call("pick", vec![*int(1)]),
+            *call("pick", vec![*int(105)]),
+            *call("pick", vec![*int(5)]),
+            *call("head", vec![*array(vec![*int(7), *int(8), *int(9)])]),
+            *call("head", vec![*array(vec![])]),
+            *call("head", vec![*array(vec![*int(7)])]),
+        ];
+        let program = vec![pick, head, expr(array(calls))];
+        let expected = [10, 5, 30, 7, 20, 40].into_iter().map(Object::Integer).collect();
+        assert_eq!(run(program), Ok(Object::Array(expected)));
+    }
+}
@@ -0,0 +1,449 @@
+use std::{collections::{HashMap, HashSet}, marker::PhantomData};
+
+use crate::{ast::{AssignOp, Expression, Iterable, MatchArm, Parameter, Pattern, Span, Statement, Type}, codegen::{ScopeKind, Symbol, SymbolScope, SymbolTable}, visitor::{ExprVisitor, StmtVisitor}};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.message)
+    }
+}
+
+// identifies one node of the ast by the address of its span, every node owns exactly one
+// span values aren't unique (nodes built without a source all have the default span), addresses are
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    pub fn of(span: &Span) -> Self {
+        NodeId(span as *const Span as usize)
+    }
+}
+
+// borrows the program it was made from, so the nodes can't move or be freed while ids point at them
+#[derive(Debug)]
+pub struct Resolution<'ast> {
+    pub symbols: HashMap<NodeId, Symbol>,
+    pub num_locals: HashMap<NodeId, usize>, // per function declaration
+    pub hidden: HashMap<NodeId, Vec<Symbol>>, // the `$` slots of a loop, match or element assignment, in allocation order
+    pub num_globals: usize,
+    program: PhantomData<&'ast [Statement]>,
+}
+
+// runs the symbol table over the ast once so later passes don't look names up by string
+// every variable use, declaration and parameter gets its symbol recorded under its node
+pub struct Resolver {
+    table: SymbolTable,
+    pending: Vec<HashSet<String>>, // names declared in each open block but not reached yet
+    symbols: HashMap<NodeId, Symbol>,
+    num_locals: HashMap<NodeId, usize>,
+    hidden: HashMap<NodeId, Vec<Symbol>>,
+    errors: Vec<ResolveError>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self {
+            table: SymbolTable::new(),
+            pending: Vec::new(),
+            symbols: HashMap::new(),
+            num_locals: HashMap::new(),
+            hidden: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn resolve(mut self, program: &[Statement]) -> Result<Resolution<'_>, Vec<ResolveError>> {
+        self.resolve_statements(program);
+
+        if self.errors.is_empty() {
+            let num_globals = self.table.num_locals();
+            Ok(Resolution { symbols: self.symbols, num_locals: self.num_locals, hidden: self.hidden, num_globals, program: PhantomData })
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn error(&mut self, message: String, span: &Span) {
+        self.errors.push(ResolveError { message, span: span.clone() });
+    }
+
+    fn resolve_statements(&mut self, statements: &[Statement]) {
+        let names = statements.iter().filter_map(|stmt| match stmt {
+            Statement::VariableDeclaration { name, .. } | Statement::FunctionDeclaration { name, .. } => Some(name.clone()),
+            _ => None,
+        });
+        self.pending.push(names.collect());
+        for stmt in statements {
+            self.visit_stmt(stmt);
+        }
+        self.pending.pop();
+    }
+
+    fn resolve_scoped(&mut self, stmt: &Statement) {
+        self.table.enter_scope(ScopeKind::Block);
+        self.resolve_statements(std::slice::from_ref(stmt));
+        self.table.leave_scope();
+    }
+
+    fn define(&mut self, name: &str, span: &Span) {
+        if self.table.resolve_local(name).is_some() {
+            self.error(format!("`{}` is already defined in this scope", name), span);
+            return;
+        }
+        self.pending.last_mut().unwrap().remove(name);
+
+        let symbol = self.table.define(name.to_string());
+        self.symbols.insert(NodeId::of(span), symbol);
+    }
+
+    // loop counters, indices and matched values live in slots no name can reach
+    // the `$` names can't clash with identifiers
+    fn reserve(&mut self, node: &Span, name: &str) {
+        let symbol = self.table.define(name.to_string());
+        self.hidden.entry(NodeId::of(node)).or_default().push(symbol);
+    }
+
+    // `a[i][j] op= v` stores i, j and the new value in hidden slots of their own scope
+    fn resolve_assign(&mut self, target: &Expression, value: &Expression, span: &Span) {
+        let mut indices = Vec::new();
+        let mut base = target;
+        while let Expression::Index { array, index, .. } = base {
+            indices.push(index.as_ref());
+            base = array;
+        }
+        indices.reverse();
+
+        self.visit_expr(base);
+        self.table.enter_scope(ScopeKind::Block);
+        for (i, index) in indices.iter().enumerate() {
+            self.visit_expr(index);
+            self.reserve(span, &format!("$index{}", i));
+        }
+        self.visit_expr(value);
+        if !indices.is_empty() {
+            self.reserve(span, "$value");
+        }
+        self.table.leave_scope();
+    }
+
+    // the first alternative of an or-pattern defines the names, the others share its slots
+    fn define_pattern(&mut self, pattern: &Pattern, first: bool) {
+        match pattern {
+            Pattern::Wildcard { .. } | Pattern::Literal { .. } => {}
+            Pattern::Binding { name, span } if first => self.define(name, span),
+            Pattern::Binding { name, span } => {
+                if let Some(symbol) = self.table.resolve_local(name) {
+                    self.symbols.insert(NodeId::of(span), symbol);
+                }
+            }
+            Pattern::Array { elements, .. } => {
+                for element in elements {
+                    self.define_pattern(element, first);
+                }
+            }
+            Pattern::Or { alternatives, .. } => {
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    self.define_pattern(alternative, first && i == 0);
+                }
+            }
+        }
+    }
+}
+
+impl StmtVisitor<()> for Resolver {
+    fn visit_block(&mut self, statements: &[Statement], _span: &Span) {
+        self.table.enter_scope(ScopeKind::Block);
+        self.resolve_statements(statements);
+        self.table.leave_scope();
+    }
+
+    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, _span: &Span) {
+        self.visit_expr(condition);
+        self.resolve_scoped(then_branch);
+        if let Some(else_branch) = else_branch {
+            self.resolve_scoped(else_branch);
+        }
+    }
+
+    fn visit_while(&mut self, _label: Option<&str>, condition: &Expression, body: &Statement, _span: &Span) {
+        self.visit_expr(condition);
+        self.resolve_scoped(body);
+    }
+
+    fn visit_for(&mut self, _label: Option<&str>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement, _span: &Span) {
+        self.table.enter_scope(ScopeKind::Block);
+        self.pending.push(HashSet::new());
+        if let Some(init) = init {
+            self.visit_stmt(init);
+        }
+        self.visit_expr(condition);
+        if let Some(increment) = increment {
+            self.visit_stmt(increment);
+        }
+        self.resolve_scoped(body);
+        self.pending.pop();
+        self.table.leave_scope();
+    }
+
+    fn visit_for_in(&mut self, _label: Option<&str>, variable: &str, iterable: &Iterable, body: &Statement, span: &Span) {
+        // the range or array is evaluated before the loop variable exists
+        self.table.enter_scope(ScopeKind::Block);
+        self.reserve(span, "$index");
+        match iterable {
+            Iterable::Range { start, end, .. } => {
+                self.visit_expr(start);
+                self.visit_expr(end);
+                self.reserve(span, "$end");
+            }
+            Iterable::Array(array) => {
+                self.visit_expr(array);
+                self.reserve(span, "$array");
+                self.reserve(span, "$length");
+            }
+        }
+
+        self.pending.push(HashSet::new());
+        self.define(variable, span);
+        self.resolve_scoped(body);
+        self.pending.pop();
+        self.table.leave_scope();
+    }
+
+    fn visit_variable_declaration(&mut self, name: &str, _type_ann: &Option<Type>, initializer: Option<&Expression>, span: &Span) {
+        // the initializer can't see the name it initializes
+        if let Some(initializer) = initializer {
+            self.visit_expr(initializer);
+        }
+        self.define(name, span);
+    }
+
+    fn visit_function_declaration(&mut self, name: &str, parameters: &[Parameter], _return_type: &Option<Type>, body: &Statement, span: &Span) {
+        // defined first so the body can call itself
+        self.define(name, span);
+
+        let global = self.symbols.get(&NodeId::of(span)).is_none_or(|symbol| symbol.scope == SymbolScope::Global);
+
+        self.table.enter_scope(ScopeKind::Function);
+        self.pending.push(HashSet::new());
+        // a nested function can't reach the local that holds it, inside it the name is the running function
+        if !global {
+            self.table.define_function_name(name.to_string());
+        }
+        for param in parameters {
+            self.define(&param.name, &param.span);
+        }
+        self.visit_stmt(body);
+        self.pending.pop();
+        self.num_locals.insert(NodeId::of(span), self.table.leave_scope());
+    }
+}
+
+impl ExprVisitor<()> for Resolver {
+    fn visit_assign(&mut self, target: &Expression, value: &Expression, span: &Span) {
+        self.resolve_assign(target, value, span);
+    }
+
+    fn visit_compound_assign(&mut self, target: &Expression, _operator: &AssignOp, value: &Expression, span: &Span) {
+        self.resolve_assign(target, value, span);
+    }
+
+    fn visit_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], span: &Span) {
+        // the value is kept in a hidden slot so every arm can test it again
+        self.table.enter_scope(ScopeKind::Block);
+        self.visit_expr(scrutinee);
+        self.reserve(span, "$match");
+        for arm in arms {
+            self.table.enter_scope(ScopeKind::Block);
+            self.pending.push(HashSet::new());
+            self.define_pattern(&arm.pattern, true);
+            if let Some(guard) = &arm.guard {
+                self.visit_expr(guard);
+            }
+            self.visit_expr(&arm.body);
+            self.pending.pop();
+            self.table.leave_scope();
+        }
+        self.table.leave_scope();
+    }
+
+    fn visit_variable(&mut self, name: &str, span: &Span) {
+        match self.table.resolve(name) {
+            Ok(Some(symbol)) => {
+                self.symbols.insert(NodeId::of(span), symbol);
+            }
+            Ok(None) if self.pending.iter().any(|names| names.contains(name)) => {
+                self.error(format!("`{}` is used before its definition", name), span);
+            }
+            Ok(None) => self.error(format!("undefined variable `{}`", name), span),
+            Err(message) => self.error(message, span),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{build, LiteralValue};
+
+    use super::*;
+
+    fn span(start: usize) -> Span {
+        Span { start, end: start + 1, line: 1, column: start + 1 }
+    }
+
+    fn var(name: &str, at: usize) -> Box<Expression> {
+        Box::new(Expression::Variable { name: name.to_string(), span: span(at) })
+    }
+
+    fn decl(name: &str, initializer: Option<Box<Expression>>, at: usize) -> Statement {
+        Statement::VariableDeclaration { name: name.to_string(), type_ann: None, initializer, span: span(at) }
+    }
+
+    #[test]
+    fn test_resolves_globals_and_locals() {
+        // def g; fun f(a) { def b = a; ret g; }
+        let program = vec![
+            decl("g", None, 0),
+            Statement::FunctionDeclaration {
+                name: "f".to_string(),
+                parameters: vec![Parameter { name: "a".to_string(), type_ann: None, span: span(2) }],
+                return_type: None,
+                body: Box::new(Statement::Block {
+                    statements: vec![
+                        decl("b", Some(var("a", 4)), 3),
+                        Statement::Return { value: Some(var("g", 6)), span: span(5) },
+                    ],
+                    span: span(1),
+                }),
+                span: span(1),
+            },
+        ];
+
+        let resolution = Resolver::new().resolve(&program).unwrap();
+        let symbols = &resolution.symbols;
+        let Statement::FunctionDeclaration { body, span: f, .. } = &program[1] else { unreachable!() };
+        let Statement::Block { statements, .. } = body.as_ref() else { unreachable!() };
+        let Statement::VariableDeclaration { initializer: Some(a), span: b, .. } = &statements[0] else { unreachable!() };
+        let Statement::Return { value: Some(g), .. } = &statements[1] else { unreachable!() };
+
+        let a = &symbols[&NodeId::of(a.span())];
+        assert_eq!((a.scope.clone(), a.index), (SymbolScope::Local, 0));
+        let g = &symbols[&NodeId::of(g.span())];
+        assert_eq!((g.scope.clone(), g.index), (SymbolScope::Global, 0));
+        assert_eq!(symbols[&NodeId::of(b)].index, 1);
+        assert_eq!(resolution.num_locals[&NodeId::of(f)], 2);
+    }
+
+    #[test]
+    fn test_reports_resolution_errors() {
+        // def x = y; def y; def y; z;
+        let program = vec![
+            decl("x", Some(var("y", 1)), 0),
+            decl("y", None, 2),
+            decl("y", None, 3),
+            Statement::Expression { expression: var("z", 4), span: span(4) },
+        ];
+
+        let errors = Resolver::new().resolve(&program).unwrap_err();
+        let messages = errors.iter().map(|e| e.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages, vec![
+            "`y` is used before its definition",
+            "`y` is already defined in this scope",
+            "undefined variable `z`",
+        ]);
+        assert_eq!(errors[1].span, span(3));
+    }
+
+    #[test]
+    fn test_block_scopes() {
+        // def x; { def x; x; } x;
+        let program = vec![
+            decl("x", None, 0),
+            Statement::Block {
+                statements: vec![decl("x", None, 2), Statement::Expression { expression: var("x", 3), span: span(3) }],
+                span: span(1),
+            },
+            Statement::Expression { expression: var("x", 4), span: span(4) },
+        ];
+
+        let symbols = Resolver::new().resolve(&program).unwrap().symbols;
+        let used = |stmt: &Statement| match stmt {
+            Statement::Expression { expression, .. } => symbols[&NodeId::of(expression.span())].index,
+            _ => unreachable!(),
+        };
+        let Statement::Block { statements, .. } = &program[1] else { unreachable!() };
+        assert_eq!((used(&statements[1]), used(&program[2])), (1, 0));
+    }
+
+    #[test]
+    fn test_nodes_with_equal_spans() {
+        // def x; def y; y; x; built without a source, so every span is the default one
+        let use_of = |name: &str| build::expr(build::var(name));
+        let program = vec![
+            Statement::VariableDeclaration { name: "x".to_string(), type_ann: None, initializer: None, span: Span::default() },
+            Statement::VariableDeclaration { name: "y".to_string(), type_ann: None, initializer: None, span: Span::default() },
+            use_of("y"),
+            use_of("x"),
+        ];
+
+        let symbols = Resolver::new().resolve(&program).unwrap().symbols;
+        let used = |stmt: &Statement| match stmt {
+            Statement::Expression { expression, .. } => symbols[&NodeId::of(expression.span())].index,
+            _ => unreachable!(),
+        };
+        assert_eq!((used(&program[2]), used(&program[3])), (1, 0));
+        assert_eq!(symbols.len(), 4);
+    }
+
+    #[test]
+    fn test_hidden_slots() {
+        // fun f() { def s = 0; for x in [1, 2] { s += x; } match s { n => n }; def t = s; ret t; }
+        let int = |n: i64| Box::new(Expression::Literal { value: LiteralValue::Integer(n), span: span(9) });
+        let add = Expression::CompoundAssign { target: var("s", 11), operator: AssignOp::Plus, value: var("x", 12), span: span(13) };
+        let arm = MatchArm { pattern: Pattern::Binding { name: "n".to_string(), span: span(15) }, guard: None, body: var("n", 16), span: span(17) };
+        let program = vec![Statement::FunctionDeclaration {
+            name: "f".to_string(),
+            parameters: vec![],
+            return_type: None,
+            body: Box::new(Statement::Block {
+                statements: vec![
+                    decl("s", Some(int(0)), 2),
+                    Statement::ForIn {
+                        label: None,
+                        variable: "x".to_string(),
+                        iterable: Iterable::Array(Box::new(Expression::Array { elements: vec![*int(1), *int(2)], span: span(10) })),
+                        body: Box::new(Statement::Expression { expression: Box::new(add), span: span(14) }),
+                        span: span(3),
+                    },
+                    Statement::Expression {
+                        expression: Box::new(Expression::Match { scrutinee: var("s", 18), arms: vec![arm], span: span(19) }),
+                        span: span(20),
+                    },
+                    decl("t", Some(var("s", 21)), 4),
+                    Statement::Return { value: Some(var("t", 22)), span: span(23) },
+                ],
+                span: span(1),
+            }),
+            span: span(0),
+        }];
+
+        let resolution = Resolver::new().resolve(&program).unwrap();
+        let Statement::FunctionDeclaration { body, span: f, .. } = &program[0] else { unreachable!() };
+        let Statement::Block { statements, .. } = body.as_ref() else { unreachable!() };
+        let (Statement::ForIn { span: x, .. }, Statement::VariableDeclaration { span: t, .. }) = (&statements[1], &statements[3]) else { unreachable!() };
+        let Statement::Expression { expression, .. } = &statements[2] else { unreachable!() };
+        let indices = |node: &Span| resolution.hidden[&NodeId::of(node)].iter().map(|symbol| symbol.index).collect::<Vec<_>>();
+        // $index, $array and $length come before x, the match and t reuse the slots the loop freed
+        assert_eq!(indices(x), vec![1, 2, 3]);
+        assert_eq!(resolution.symbols[&NodeId::of(x)].index, 4);
+        assert_eq!(indices(expression.span()), vec![1]);
+        assert_eq!(resolution.symbols[&NodeId::of(t)].index, 1);
+        assert_eq!(resolution.num_locals[&NodeId::of(f)], 5);
+    }
+}
@@ -0,0 +1,1656 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, rc::Rc};
+
+use crate::{ast::{BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Pattern, PrefixOp, Statement}, codegen::{Bytecode, ConstantPool, Function, Instruction, LineTable, Object}};
+
+// typed three-address ir in ssa form, sits between the ast and the stack bytecode
+// every value is defined exactly once, phis merge values where control flow joins
+// top level variables are globals and stay in memory (functions read them by name),
+// everything else lives in ssa values
+
+pub type Value = usize;
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrType {
+    Int,
+    Bool,
+    Str,
+    Char,
+    Null,
+    Function,
+    Array,
+    Any, // not known statically, e.g. parameters and call results
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+    Char(char),
+    Null,
+    Function(usize), // index into Module::functions
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Const(Constant),
+    Param(usize),
+    Binary(BinOp, Value, Value),
+    Not(Value),
+    Neg(Value),
+    Call(Value, Vec<Value>),
+    Array(Vec<Value>),
+    Index(Value, Value),
+    SetIndex(Value, Value, Value), // array, index, element -> the updated copy
+    Length(Value),
+    GetGlobal(String),
+    SetGlobal(String, Value), // produces no value
+    Phi(Vec<(BlockId, Value)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch(Value, BlockId, BlockId),
+    Return(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct ValueData {
+    pub inst: Inst,
+    pub ty: IrType,
+    pub block: BlockId,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    pub phis: Vec<Value>,
+    pub insts: Vec<Value>,
+    pub terminator: Option<Terminator>,
+    pub preds: Vec<BlockId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IrFunction {
+    pub name: String,
+    pub num_params: usize,
+    pub blocks: Vec<Block>,
+    pub values: Vec<ValueData>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub functions: Vec<IrFunction>, // functions[0] is the top level of the script
+    pub globals: Vec<String>,       // global slot i holds globals[i]
+}
+
+impl Inst {
+    fn operands(&self) -> Vec<Value> {
+        match self {
+            Inst::Binary(_, a, b) | Inst::Index(a, b) => vec![*a, *b],
+            Inst::SetIndex(a, i, v) => vec![*a, *i, *v],
+            Inst::Not(v) | Inst::Neg(v) | Inst::Length(v) | Inst::SetGlobal(_, v) => vec![*v],
+            Inst::Array(elements) => elements.clone(),
+            Inst::Call(callee, args) => std::iter::once(*callee).chain(args.iter().copied()).collect(),
+            Inst::Phi(operands) => operands.iter().map(|(_, v)| *v).collect(),
+            Inst::Const(_) | Inst::Param(_) | Inst::GetGlobal(_) => vec![],
+        }
+    }
+
+    fn has_value(&self) -> bool {
+        !matches!(self, Inst::SetGlobal(..))
+    }
+}
+
+impl Terminator {
+    pub fn targets(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(to) => vec![*to],
+            Terminator::Branch(_, then_block, else_block) => vec![*then_block, *else_block],
+            Terminator::Return(_) => vec![],
+        }
+    }
+}
+
+impl Module {
+    pub fn build(program: &[Statement]) -> Result<Module, String> {
+        // every top level declaration is a global, collected up front so functions can use later ones
+        let globals = program.iter().filter_map(|stmt| match stmt {
+            Statement::VariableDeclaration { name, .. } | Statement::FunctionDeclaration { name, .. } => Some(name.clone()),
+            _ => None,
+        });
+        let mut module = Module { functions: Vec::new(), globals: Vec::new() };
+        for name in globals {
+            if !module.globals.contains(&name) {
+                module.globals.push(name);
+            }
+        }
+
+        module.functions.push(IrFunction::new("main", 0));
+        let main = FunctionBuilder::new(&mut module, "main", &[], true, None).build(program)?;
+        module.functions[0] = main;
+        Ok(module)
+    }
+
+    pub fn verify(&self) -> Result<(), Vec<String>> {
+        let errors = self.functions.iter().flat_map(|f| f.verify().err().unwrap_or_default()).collect::<Vec<_>>();
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    // out of ssa: every value gets its own slot and phis become copies at the end of each predecessor
+    // in the top level the slots are globals after the named ones, in functions they are locals
+    pub fn lower(&self) -> Bytecode {
+        let mut lowered: Vec<Option<Function>> = vec![None; self.functions.len()];
+        let mut constants = ConstantPool::default();
+
+        // nested functions always come after the function declaring them
+        for index in (1..self.functions.len()).rev() {
+            let function = Lowering::new(self, &self.functions[index], &lowered, &mut constants).run();
+            lowered[index] = Some(function);
+        }
+        let main = Lowering::new(self, &self.functions[0], &lowered, &mut constants).run();
+
+        Bytecode {
+            instructions: main.instructions,
+            constants: constants.objects().to_vec(),
+            // the slots after the named globals hold main's ssa variables
+            globals: self.globals.iter().cloned().chain((0..main.num_locals).map(|slot| format!("${}", slot))).collect(),
+            lines: main.lines,
+        }
+    }
+}
+
+impl IrFunction {
+    fn new(name: &str, num_params: usize) -> Self {
+        IrFunction { name: name.to_string(), num_params, blocks: Vec::new(), values: Vec::new() }
+    }
+
+    // the values in the order they appear in the blocks, dropped phis are not in there
+    pub fn placed_values(&self) -> Vec<Value> {
+        self.blocks.iter().flat_map(|block| block.phis.iter().chain(&block.insts).copied()).collect()
+    }
+
+    pub fn verify(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        let mut position = HashMap::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            for (i, &v) in block.phis.iter().chain(&block.insts).enumerate() {
+                if v >= self.values.len() {
+                    errors.push(format!("b{} places undefined value %{}", id, v));
+                    continue;
+                }
+                if position.insert(v, (id, i)).is_some() {
+                    errors.push(format!("%{} is defined more than once", v));
+                }
+                if self.values[v].block != id {
+                    errors.push(format!("%{} is placed in b{} but belongs to b{}", v, id, self.values[v].block));
+                }
+                let is_phi = matches!(self.values[v].inst, Inst::Phi(_));
+                if is_phi != (i < block.phis.len()) {
+                    errors.push(format!("%{} is a phi outside the phi list, or the other way round", v));
+                }
+            }
+
+            let Some(terminator) = &block.terminator else {
+                errors.push(format!("b{} has no terminator", id));
+                continue;
+            };
+            for target in terminator.targets() {
+                if target >= self.blocks.len() {
+                    errors.push(format!("b{} jumps to missing block b{}", id, target));
+                } else if !self.blocks[target].preds.contains(&id) {
+                    errors.push(format!("b{} jumps to b{} but is not one of its predecessors", id, target));
+                }
+            }
+        }
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            for &pred in &block.preds {
+                let jumps_here = self.blocks.get(pred).and_then(|p| p.terminator.as_ref()).is_some_and(|t| t.targets().contains(&id));
+                if !jumps_here {
+                    errors.push(format!("b{} lists b{} as predecessor but it doesn't jump there", id, pred));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(self.prefixed(errors));
+        }
+
+        // every use has to be dominated by its definition
+        let dominators = self.dominators();
+        let dominates = |def: Value, block: BlockId, index: usize| -> bool {
+            let Some(&(def_block, def_index)) = position.get(&def) else { return false };
+            if def_block == block {
+                def_index < index
+            } else {
+                dominators[block].as_ref().is_some_and(|doms| doms.contains(&def_block))
+            }
+        };
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            // uses in unreachable code can't go wrong
+            if dominators[id].is_none() {
+                continue;
+            }
+            for (i, &v) in block.phis.iter().chain(&block.insts).enumerate() {
+                let data = &self.values[v];
+                match &data.inst {
+                    Inst::Phi(operands) => {
+                        let mut from = operands.iter().map(|(b, _)| *b).collect::<Vec<_>>();
+                        let mut preds = block.preds.clone();
+                        from.sort();
+                        preds.sort();
+                        if from != preds {
+                            errors.push(format!("phi %{} doesn't have one operand per predecessor of b{}", v, id));
+                        }
+                        for &(pred, operand) in operands {
+                            let end = self.blocks.get(pred).map_or(0, |b| b.phis.len() + b.insts.len());
+                            if dominators.get(pred).is_some_and(|d| d.is_some()) && !dominates(operand, pred, end) {
+                                errors.push(format!("phi %{} uses %{} which doesn't reach the end of b{}", v, operand, pred));
+                            }
+                        }
+                    }
+                    inst => {
+                        for operand in inst.operands() {
+                            if !dominates(operand, id, i) {
+                                errors.push(format!("%{} uses %{} before it is defined", v, operand));
+                            } else if !self.values[operand].inst.has_value() {
+                                errors.push(format!("%{} uses %{} which has no value", v, operand));
+                            }
+                        }
+                    }
+                }
+            }
+
+            let end = block.phis.len() + block.insts.len();
+            match block.terminator {
+                Some(Terminator::Branch(cond, ..)) => {
+                    if !dominates(cond, id, end) {
+                        errors.push(format!("b{} branches on %{} before it is defined", id, cond));
+                    } else if !matches!(self.values[cond].ty, IrType::Bool | IrType::Any) {
+                        errors.push(format!("b{} branches on %{} of type {:?}", id, cond, self.values[cond].ty));
+                    }
+                }
+                Some(Terminator::Return(v)) if !dominates(v, id, end) => {
+                    errors.push(format!("b{} returns %{} before it is defined", id, v));
+                }
+                _ => {}
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(self.prefixed(errors)) }
+    }
+
+    fn prefixed(&self, errors: Vec<String>) -> Vec<String> {
+        errors.into_iter().map(|message| format!("{}: {}", self.name, message)).collect()
+    }
+
+    // dominator sets of the reachable blocks, None for unreachable ones
+    fn dominators(&self) -> Vec<Option<HashSet<BlockId>>> {
+        let n = self.blocks.len();
+        let mut reachable = vec![false; n];
+        let mut stack = vec![0];
+        while let Some(id) = stack.pop() {
+            if id < n && !std::mem::replace(&mut reachable[id], true) {
+                stack.extend(self.blocks[id].terminator.iter().flat_map(|t| t.targets()));
+            }
+        }
+
+        let all = (0..n).filter(|&id| reachable[id]).collect::<HashSet<_>>();
+        let mut doms = (0..n)
+            .map(|id| match id {
+                0 => Some(HashSet::from([0])),
+                id if reachable[id] => Some(all.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in 1..n {
+                if !reachable[id] {
+                    continue;
+                }
+                let mut new = self.blocks[id].preds.iter()
+                    .filter_map(|&p| doms[p].clone())
+                    .reduce(|a, b| a.intersection(&b).copied().collect())
+                    .unwrap_or_default();
+                new.insert(id);
+                if doms[id].as_ref() != Some(&new) {
+                    doms[id] = Some(new);
+                    changed = true;
+                }
+            }
+        }
+        doms
+    }
+}
+
+
+// builds one function with the on-the-fly ssa construction of Braun et al.
+// a block is sealed once all its predecessors are known, reads in unsealed blocks get a placeholder phi
+struct FunctionBuilder<'m> {
+    module: &'m mut Module,
+    function: IrFunction,
+    is_main: bool,
+    own: Option<usize>, // the module index of a nested function, so its body can name it
+    current: BlockId,
+    scopes: Vec<HashMap<String, usize>>, // name -> variable id
+    num_vars: usize,
+    defs: HashMap<(usize, BlockId), Value>,
+    sealed: HashSet<BlockId>,
+    incomplete: HashMap<BlockId, Vec<(usize, Value)>>,
+    replaced: HashMap<Value, Value>, // trivial phis and what they stand for
+    loops: Vec<LoopTargets>,          // innermost last
+}
+
+// where break and continue jump to inside one loop
+struct LoopTargets {
+    label: Option<String>,
+    next: BlockId,
+    after: BlockId,
+}
+
+impl<'m> FunctionBuilder<'m> {
+    fn new(module: &'m mut Module, name: &str, parameters: &[String], is_main: bool, own: Option<usize>) -> Self {
+        let mut builder = FunctionBuilder {
+            module,
+            function: IrFunction::new(name, parameters.len()),
+            is_main,
+            own,
+            current: 0,
+            scopes: vec![HashMap::new()],
+            num_vars: 0,
+            defs: HashMap::new(),
+            sealed: HashSet::new(),
+            incomplete: HashMap::new(),
+            replaced: HashMap::new(),
+            loops: Vec::new(),
+        };
+
+        let entry = builder.new_block();
+        builder.seal(entry);
+        for (i, name) in parameters.iter().enumerate() {
+            let value = builder.push(Inst::Param(i), IrType::Any);
+            let var = builder.declare(name);
+            builder.write(var, entry, value);
+        }
+        builder
+    }
+
+    fn build(mut self, body: &[Statement]) -> Result<IrFunction, String> {
+        for stmt in body {
+            self.lower_statement(stmt)?;
+        }
+        // falling off the end returns null
+        let null = self.push(Inst::Const(Constant::Null), IrType::Null);
+        self.terminate(Terminator::Return(null));
+
+        self.finish();
+        Ok(self.function)
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(Block::default());
+        self.function.blocks.len() - 1
+    }
+
+    fn push(&mut self, inst: Inst, ty: IrType) -> Value {
+        let value = self.function.values.len();
+        self.function.values.push(ValueData { inst, ty, block: self.current });
+        self.function.blocks[self.current].insts.push(value);
+        value
+    }
+
+    fn constant(&mut self, constant: Constant) -> Value {
+        let ty = match constant {
+            Constant::Int(_) => IrType::Int,
+            Constant::Bool(_) => IrType::Bool,
+            Constant::Str(_) => IrType::Str,
+            Constant::Char(_) => IrType::Char,
+            Constant::Null => IrType::Null,
+            Constant::Function(_) => IrType::Function,
+        };
+        self.push(Inst::Const(constant), ty)
+    }
+
+    fn new_phi(&mut self, block: BlockId) -> Value {
+        let value = self.function.values.len();
+        self.function.values.push(ValueData { inst: Inst::Phi(Vec::new()), ty: IrType::Any, block });
+        self.function.blocks[block].phis.push(value);
+        value
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        for target in terminator.targets() {
+            self.function.blocks[target].preds.push(self.current);
+        }
+        self.function.blocks[self.current].terminator = Some(terminator);
+    }
+
+    fn declare(&mut self, name: &str) -> usize {
+        let var = self.num_vars;
+        self.num_vars += 1;
+        self.scopes.last_mut().unwrap().insert(name.to_string(), var);
+        var
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).copied()
+    }
+
+    fn find(&self, mut value: Value) -> Value {
+        while let Some(&to) = self.replaced.get(&value) {
+            value = to;
+        }
+        value
+    }
+
+    fn write(&mut self, var: usize, block: BlockId, value: Value) {
+        self.defs.insert((var, block), value);
+    }
+
+    fn read(&mut self, var: usize, block: BlockId) -> Value {
+        if let Some(&value) = self.defs.get(&(var, block)) {
+            return self.find(value);
+        }
+
+        let value = if !self.sealed.contains(&block) {
+            let phi = self.new_phi(block);
+            self.incomplete.entry(block).or_default().push((var, phi));
+            phi
+        } else if self.function.blocks[block].preds.len() == 1 {
+            let pred = self.function.blocks[block].preds[0];
+            self.read(var, pred)
+        } else if self.function.blocks[block].preds.is_empty() {
+            // read on a path where the variable was never written, e.g. unreachable code
+            let current = std::mem::replace(&mut self.current, block);
+            let null = self.constant(Constant::Null);
+            self.current = current;
+            null
+        } else {
+            let phi = self.new_phi(block);
+            self.write(var, block, phi);
+            self.add_phi_operands(var, phi)
+        };
+        self.write(var, block, value);
+        value
+    }
+
+    fn add_phi_operands(&mut self, var: usize, phi: Value) -> Value {
+        let block = self.function.values[phi].block;
+        let mut operands = Vec::new();
+        for pred in self.function.blocks[block].preds.clone() {
+            operands.push((pred, self.read(var, pred)));
+        }
+        self.function.values[phi].inst = Inst::Phi(operands);
+        self.remove_trivial_phi(phi)
+    }
+
+    // a phi whose operands are all the same value (or itself) is just that value
+    fn remove_trivial_phi(&mut self, phi: Value) -> Value {
+        let Inst::Phi(operands) = &self.function.values[phi].inst else { return phi };
+        let mut same = None;
+        for &(_, operand) in operands {
+            let operand = self.find(operand);
+            if Some(operand) == same || operand == phi {
+                continue;
+            }
+            if same.is_some() {
+                return phi;
+            }
+            same = Some(operand);
+        }
+
+        let Some(same) = same else { return phi };
+        self.replaced.insert(phi, same);
+        same
+    }
+
+    fn seal(&mut self, block: BlockId) {
+        for (var, phi) in self.incomplete.remove(&block).unwrap_or_default() {
+            self.add_phi_operands(var, phi);
+        }
+        self.sealed.insert(block);
+    }
+
+    // rewrite uses of removed phis, drop them and work out the phi types
+    fn finish(&mut self) {
+        // removing one trivial phi can make the phis using it trivial too
+        loop {
+            let phis = self.function.blocks.iter().flat_map(|b| b.phis.clone()).collect::<Vec<_>>();
+            let before = self.replaced.len();
+            for phi in phis {
+                if !self.replaced.contains_key(&phi) {
+                    self.remove_trivial_phi(phi);
+                }
+            }
+            if self.replaced.len() == before {
+                break;
+            }
+        }
+
+        for block in 0..self.function.blocks.len() {
+            let replaced = &self.replaced;
+            self.function.blocks[block].phis.retain(|phi| !replaced.contains_key(phi));
+        }
+        for v in 0..self.function.values.len() {
+            let inst = match &self.function.values[v].inst {
+                Inst::Binary(op, a, b) => Inst::Binary(*op, self.find(*a), self.find(*b)),
+                Inst::Not(a) => Inst::Not(self.find(*a)),
+                Inst::Neg(a) => Inst::Neg(self.find(*a)),
+                Inst::Call(callee, args) => Inst::Call(self.find(*callee), args.iter().map(|a| self.find(*a)).collect()),
+                Inst::Array(elements) => Inst::Array(elements.iter().map(|a| self.find(*a)).collect()),
+                Inst::Index(a, i) => Inst::Index(self.find(*a), self.find(*i)),
+                Inst::SetIndex(a, i, v) => Inst::SetIndex(self.find(*a), self.find(*i), self.find(*v)),
+                Inst::Length(a) => Inst::Length(self.find(*a)),
+                Inst::SetGlobal(name, a) => Inst::SetGlobal(name.clone(), self.find(*a)),
+                Inst::Phi(operands) => Inst::Phi(operands.iter().map(|(b, a)| (*b, self.find(*a))).collect()),
+                inst => inst.clone(),
+            };
+            self.function.values[v].inst = inst;
+        }
+        for block in 0..self.function.blocks.len() {
+            let terminator = match &self.function.blocks[block].terminator {
+                Some(Terminator::Branch(c, t, e)) => Some(Terminator::Branch(self.find(*c), *t, *e)),
+                Some(Terminator::Return(v)) => Some(Terminator::Return(self.find(*v))),
+                other => other.clone(),
+            };
+            self.function.blocks[block].terminator = terminator;
+        }
+
+        // phis were typed Any while their operands were unknown, redo phis and what depends on them
+        // None is "not known yet", loops start there and only ever go up towards Any
+        let placed = self.function.placed_values();
+        let mut types: HashMap<Value, Option<IrType>> = placed.iter().map(|&v| match self.function.values[v].inst {
+            Inst::Phi(_) | Inst::Binary(..) | Inst::Neg(_) => (v, None),
+            _ => (v, Some(self.function.values[v].ty)),
+        }).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &v in &placed {
+                let ty = |v: &Value| types.get(v).copied().flatten();
+                let new = match &self.function.values[v].inst {
+                    Inst::Phi(operands) => operands.iter().filter_map(|(_, v)| ty(v)).reduce(join),
+                    Inst::Binary(op, a, b) => ty(a).zip(ty(b)).map(|(ta, tb)| binary_type(*op, ta, tb)),
+                    Inst::Neg(a) => ty(a).map(|ta| if ta == IrType::Int { IrType::Int } else { IrType::Any }),
+                    _ => continue,
+                };
+                if new != types[&v] {
+                    types.insert(v, new);
+                    changed = true;
+                }
+            }
+        }
+        for (v, ty) in types {
+            self.function.values[v].ty = ty.unwrap_or(IrType::Any);
+        }
+    }
+
+    fn lower_block(&mut self, statements: &[Statement]) -> Result<(), String> {
+        self.scopes.push(HashMap::new());
+        let result = statements.iter().try_for_each(|stmt| self.lower_statement(stmt));
+        self.scopes.pop();
+        result
+    }
+
+    fn lower_statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        match stmt {
+            Statement::Block { statements, .. } => self.lower_block(statements)?,
+            Statement::If { condition, then_branch, else_branch, .. } => {
+                let cond = self.lower_expression(condition)?;
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                let join = match else_branch {
+                    Some(_) => self.new_block(),
+                    None => else_block,
+                };
+                self.terminate(Terminator::Branch(cond, then_block, else_block));
+                self.seal(then_block);
+                self.seal(else_block);
+
+                self.current = then_block;
+                self.lower_block(std::slice::from_ref(then_branch))?;
+                self.terminate(Terminator::Jump(join));
+
+                if let Some(else_branch) = else_branch {
+                    self.current = else_block;
+                    self.lower_block(std::slice::from_ref(else_branch))?;
+                    self.terminate(Terminator::Jump(join));
+                    self.seal(join);
+                }
+                self.current = join;
+            }
+            Statement::While { label, condition, body, .. } => self.lower_loop(label, None, condition, None, body)?,
+            Statement::For { label, init, condition, increment, body, .. } => {
+                self.scopes.push(HashMap::new());
+                let result = self.lower_loop(label, init.as_deref(), condition, increment.as_deref(), body);
+                self.scopes.pop();
+                result?;
+            }
+            Statement::ForIn { label, variable, iterable, body, .. } => {
+                self.scopes.push(HashMap::new());
+                let result = self.lower_for_in(label, variable, iterable, body);
+                self.scopes.pop();
+                result?;
+            }
+            Statement::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.lower_expression(value)?,
+                    None => self.constant(Constant::Null),
+                };
+                self.terminate(Terminator::Return(value));
+                // anything after is unreachable, it goes into a block nobody jumps to
+                self.current = self.new_block();
+                self.seal(self.current);
+            }
+            Statement::Break { label, .. } | Statement::Continue { label, .. } => {
+                let is_break = matches!(stmt, Statement::Break { .. });
+                let keyword = if is_break { "break" } else { "continue" };
+                let found = self.loops.iter().rev().find(|l| label.is_none() || l.label == *label);
+                let to = match (found, label) {
+                    (Some(targets), _) if is_break => targets.after,
+                    (Some(targets), _) => targets.next,
+                    (None, Some(label)) => return Err(format!("`{} {}` doesn't match any enclosing loop", keyword, label)),
+                    (None, None) => return Err(format!("`{}` outside of a loop", keyword)),
+                };
+                self.terminate(Terminator::Jump(to));
+                self.current = self.new_block();
+                self.seal(self.current);
+            }
+            Statement::Expression { expression, .. } => {
+                self.lower_expression(expression)?;
+            }
+            Statement::VariableDeclaration { name, initializer, .. } => {
+                let value = match initializer {
+                    Some(initializer) => self.lower_expression(initializer)?,
+                    None => self.constant(Constant::Null),
+                };
+                self.bind(name, value);
+            }
+            Statement::FunctionDeclaration { name, parameters, body, .. } => {
+                let index = self.module.functions.len();
+                self.module.functions.push(IrFunction::new(name, parameters.len()));
+                // defined before the body as in codegen, a global is found by name and a nested function names itself
+                let global = self.is_main && self.scopes.len() == 1;
+                let value = self.constant(Constant::Function(index));
+                self.bind(name, value);
+
+                let parameters = parameters.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+                let own = (!global).then_some(index);
+                let function = FunctionBuilder::new(self.module, name, &parameters, false, own).build(std::slice::from_ref(body))?;
+                self.module.functions[index] = function;
+            }
+        }
+        Ok(())
+    }
+
+    fn lower_loop(&mut self, label: &Option<String>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement) -> Result<(), String> {
+        if let Some(init) = init {
+            self.lower_statement(init)?;
+        }
+
+        // the header stays unsealed until the back edge exists, the exit until every break is known
+        let header = self.new_block();
+        self.terminate(Terminator::Jump(header));
+        self.current = header;
+        let cond = self.lower_expression(condition)?;
+        let body_block = self.new_block();
+        let after = self.new_block();
+        self.terminate(Terminator::Branch(cond, body_block, after));
+        self.seal(body_block);
+
+        // continue in a for loop still runs the increment
+        let next = match increment {
+            Some(_) => self.new_block(),
+            None => header,
+        };
+        self.current = body_block;
+        self.loops.push(LoopTargets { label: label.clone(), next, after });
+        let result = self.lower_block(std::slice::from_ref(body));
+        self.loops.pop();
+        result?;
+
+        if let Some(increment) = increment {
+            self.terminate(Terminator::Jump(next));
+            self.seal(next);
+            self.current = next;
+            self.lower_statement(increment)?;
+        }
+        self.terminate(Terminator::Jump(header));
+        self.seal(header);
+        self.seal(after);
+        self.current = after;
+        Ok(())
+    }
+
+    // a hidden counter walks the range or the array indices, ranges never build an array
+    fn lower_for_in(&mut self, label: &Option<String>, variable: &str, iterable: &Iterable, body: &Statement) -> Result<(), String> {
+        let (start, limit, array) = match iterable {
+            Iterable::Range { start, end, .. } => (self.lower_expression(start)?, self.lower_expression(end)?, None),
+            Iterable::Array(array) => {
+                let array = self.lower_expression(array)?;
+                let zero = self.constant(Constant::Int(0));
+                let length = self.push(Inst::Length(array), IrType::Int);
+                (zero, length, Some(array))
+            }
+        };
+        let counter = self.declare("$index");
+        self.write(counter, self.current, start);
+
+        let header = self.new_block();
+        self.terminate(Terminator::Jump(header));
+        self.current = header;
+        let index = self.read(counter, header);
+        let op = match iterable {
+            Iterable::Range { inclusive: true, .. } => BinOp::Le,
+            _ => BinOp::Lt,
+        };
+        let cond = self.push(Inst::Binary(op, index, limit), IrType::Bool);
+        let body_block = self.new_block();
+        let after = self.new_block();
+        let next = self.new_block();
+        self.terminate(Terminator::Branch(cond, body_block, after));
+        self.seal(body_block);
+
+        self.current = body_block;
+        let element = match array {
+            Some(array) => self.push(Inst::Index(array, index), IrType::Any),
+            None => index,
+        };
+        let var = self.declare(variable);
+        self.write(var, body_block, element);
+        self.loops.push(LoopTargets { label: label.clone(), next, after });
+        let result = self.lower_block(std::slice::from_ref(body));
+        self.loops.pop();
+        result?;
+
+        self.terminate(Terminator::Jump(next));
+        self.seal(next);
+        self.current = next;
+        let index = self.read(counter, next);
+        // an inclusive range stops at its bound instead of counting past it, `..=i64::MAX` would overflow
+        if let Iterable::Range { inclusive: true, .. } = iterable {
+            let more = self.push(Inst::Binary(BinOp::Ne, index, limit), IrType::Bool);
+            let increment = self.new_block();
+            self.terminate(Terminator::Branch(more, increment, after));
+            self.seal(increment);
+            self.current = increment;
+        }
+        let one = self.constant(Constant::Int(1));
+        let incremented = self.push(Inst::Binary(BinOp::Add, index, one), IrType::Int);
+        self.write(counter, self.current, incremented);
+        self.terminate(Terminator::Jump(header));
+        self.seal(header);
+        self.seal(after);
+        self.current = after;
+        Ok(())
+    }
+
+    // the top level of main writes globals, everything else is an ssa variable
+    fn bind(&mut self, name: &str, value: Value) {
+        if self.is_main && self.scopes.len() == 1 {
+            self.push(Inst::SetGlobal(name.to_string(), value), IrType::Null);
+        } else {
+            let var = self.declare(name);
+            self.write(var, self.current, value);
+        }
+    }
+
+    fn lower_expression(&mut self, expr: &Expression) -> Result<Value, String> {
+        Ok(match expr {
+            Expression::Binary { left, operator: operator @ (BinaryOp::And | BinaryOp::Or), right, .. } => {
+                // short circuit: the value of the left side when it decides, otherwise the right side
+                let left = self.lower_expression(left)?;
+                let decided = self.constant(Constant::Bool(matches!(operator, BinaryOp::Or)));
+                let from = self.current;
+                let rhs = self.new_block();
+                let join = self.new_block();
+                match operator {
+                    BinaryOp::And => self.terminate(Terminator::Branch(left, rhs, join)),
+                    _ => self.terminate(Terminator::Branch(left, join, rhs)),
+                }
+                self.seal(rhs);
+
+                self.current = rhs;
+                let right = self.lower_expression(right)?;
+                let rhs_end = self.current;
+                self.terminate(Terminator::Jump(join));
+                self.seal(join);
+
+                self.current = join;
+                let phi = self.new_phi(join);
+                self.function.values[phi].inst = Inst::Phi(vec![(from, decided), (rhs_end, right)]);
+                phi
+            }
+            Expression::Binary { left, operator, right, .. } => {
+                let a = self.lower_expression(left)?;
+                let b = self.lower_expression(right)?;
+                self.binary(operator, a, b)
+            }
+            Expression::Unary { operator, operand, .. } => {
+                let v = self.lower_expression(operand)?;
+                match operator {
+                    PrefixOp::Not => self.push(Inst::Not(v), IrType::Bool),
+                    PrefixOp::Neg => {
+                        let ty = if self.function.values[v].ty == IrType::Int { IrType::Int } else { IrType::Any };
+                        self.push(Inst::Neg(v), ty)
+                    }
+                }
+            }
+            Expression::Literal { value, .. } => self.constant(literal_constant(value)),
+            Expression::Variable { name, .. } => self.load(name)?,
+            Expression::Call { callee, arguments, .. } => {
+                let callee = self.lower_expression(callee)?;
+                let args = arguments.iter().map(|arg| self.lower_expression(arg)).collect::<Result<Vec<_>, _>>()?;
+                self.push(Inst::Call(callee, args), IrType::Any)
+            }
+            Expression::Array { elements, .. } => {
+                let elements = elements.iter().map(|element| self.lower_expression(element)).collect::<Result<Vec<_>, _>>()?;
+                self.push(Inst::Array(elements), IrType::Array)
+            }
+            Expression::Assign { target, value, .. } => self.lower_assign(target, None, value)?,
+            Expression::CompoundAssign { target, operator, value, .. } => self.lower_assign(target, Some(&operator.binary()), value)?,
+            Expression::Increment { target, operator, span } => {
+                let (operator, one) = operator.lower(span);
+                self.lower_assign(target, Some(&operator.binary()), &one)?
+            }
+            Expression::Index { array, index, .. } => {
+                let array = self.lower_expression(array)?;
+                let index = self.lower_expression(index)?;
+                self.push(Inst::Index(array, index), IrType::Any)
+            }
+            Expression::Match { scrutinee, arms, .. } => {
+                self.scopes.push(HashMap::new());
+                let result = self.lower_match(scrutinee, arms);
+                self.scopes.pop();
+                result?
+            }
+        })
+    }
+
+    fn binary(&mut self, operator: &BinaryOp, a: Value, b: Value) -> Value {
+        let op = match operator {
+            BinaryOp::Plus => BinOp::Add,
+            BinaryOp::Minus => BinOp::Sub,
+            BinaryOp::Multiply => BinOp::Mul,
+            BinaryOp::Divide => BinOp::Div,
+            BinaryOp::Modulo => BinOp::Mod,
+            BinaryOp::Equal => BinOp::Eq,
+            BinaryOp::NotEqual => BinOp::Ne,
+            BinaryOp::LessThan => BinOp::Lt,
+            BinaryOp::GreaterThan => BinOp::Gt,
+            BinaryOp::LessEqual => BinOp::Le,
+            BinaryOp::GreaterEqual => BinOp::Ge,
+            BinaryOp::BitAnd => BinOp::BitAnd,
+            BinaryOp::BitOr => BinOp::BitOr,
+            BinaryOp::BitXor => BinOp::BitXor,
+            BinaryOp::ShiftLeft => BinOp::Shl,
+            BinaryOp::ShiftRight => BinOp::Shr,
+            BinaryOp::And | BinaryOp::Or => unreachable!("short circuits are lowered to branches"),
+        };
+        let ty = binary_type(op, self.function.values[a].ty, self.function.values[b].ty);
+        self.push(Inst::Binary(op, a, b), ty)
+    }
+
+    // a nested function's own name, unless something in it shadows the name
+    fn own_index(&self, name: &str) -> Option<usize> {
+        self.own.filter(|_| self.function.name == name && self.lookup(name).is_none())
+    }
+
+    fn load(&mut self, name: &str) -> Result<Value, String> {
+        if let Some(index) = self.own_index(name) {
+            return Ok(self.constant(Constant::Function(index)));
+        }
+        match self.lookup(name) {
+            Some(var) => Ok(self.read(var, self.current)),
+            None if self.module.globals.iter().any(|global| global == name) => Ok(self.push(Inst::GetGlobal(name.to_string()), IrType::Any)),
+            None => Err(format!("undefined variable `{}`", name)),
+        }
+    }
+
+    fn store(&mut self, name: &str, value: Value) -> Result<(), String> {
+        if self.own_index(name).is_some() {
+            return Err(format!("cannot assign to `{}` inside its own body", name));
+        }
+        match self.lookup(name) {
+            Some(var) => self.write(var, self.current, value),
+            None if self.module.globals.iter().any(|global| global == name) => {
+                self.push(Inst::SetGlobal(name.to_string(), value), IrType::Null);
+            }
+            None => return Err(format!("undefined variable `{}`", name)),
+        }
+        Ok(())
+    }
+
+    // the indices of `a[i][j] op= v` are lowered once, then every array holding the
+    // element is rebuilt with SetIndex from the inside out
+    fn lower_assign(&mut self, target: &Expression, operator: Option<&BinaryOp>, value: &Expression) -> Result<Value, String> {
+        let mut indices = Vec::new();
+        let mut base = target;
+        while let Expression::Index { array, index, .. } = base {
+            indices.push(index.as_ref());
+            base = array;
+        }
+        indices.reverse();
+        let Expression::Variable { name, .. } = base else {
+            return Err("invalid assignment target".to_string());
+        };
+        let indices = indices.into_iter().map(|index| self.lower_expression(index)).collect::<Result<Vec<_>, _>>()?;
+
+        let value = match operator {
+            Some(operator) => {
+                let mut current = self.load(name)?;
+                for &index in &indices {
+                    current = self.push(Inst::Index(current, index), IrType::Any);
+                }
+                let value = self.lower_expression(value)?;
+                self.binary(operator, current, value)
+            }
+            None => self.lower_expression(value)?,
+        };
+
+        let mut containers = Vec::new();
+        if let Some((_, outer)) = indices.split_last() {
+            containers.push(self.load(name)?);
+            for &index in outer {
+                let container = self.push(Inst::Index(*containers.last().unwrap(), index), IrType::Any);
+                containers.push(container);
+            }
+        }
+        let mut rebuilt = value;
+        for (&container, &index) in containers.iter().zip(&indices).rev() {
+            rebuilt = self.push(Inst::SetIndex(container, index, rebuilt), IrType::Array);
+        }
+        self.store(name, rebuilt)?;
+        Ok(value)
+    }
+
+    // every arm writes a hidden variable and jumps to a join block, where reading it gives the phi
+    // an arm whose test fails goes on to the next arm's test
+    fn lower_match(&mut self, scrutinee: &Expression, arms: &[MatchArm]) -> Result<Value, String> {
+        let value = self.lower_expression(scrutinee)?;
+        let result = self.declare("$match");
+        let join = self.new_block();
+        for arm in arms {
+            let next = self.new_block();
+            self.scopes.push(HashMap::new());
+            let lowered = self.lower_arm(value, arm, next, join, result);
+            self.scopes.pop();
+            lowered?;
+            self.seal(next);
+            self.current = next;
+        }
+
+        // no arm matched
+        let null = self.constant(Constant::Null);
+        self.write(result, self.current, null);
+        self.terminate(Terminator::Jump(join));
+        self.seal(join);
+        self.current = join;
+        Ok(self.read(result, join))
+    }
+
+    fn lower_arm(&mut self, value: Value, arm: &MatchArm, next: BlockId, join: BlockId, result: usize) -> Result<(), String> {
+        self.lower_pattern(&arm.pattern, value, next)?;
+        if let Some(guard) = &arm.guard {
+            let cond = self.lower_expression(guard)?;
+            self.test(cond, next);
+        }
+        let body = self.lower_expression(&arm.body)?;
+        self.write(result, self.current, body);
+        self.terminate(Terminator::Jump(join));
+        Ok(())
+    }
+
+    // carries on in a new block when the pattern matches `value`, otherwise branches to `fail`
+    fn lower_pattern(&mut self, pattern: &Pattern, value: Value, fail: BlockId) -> Result<(), String> {
+        match pattern {
+            Pattern::Wildcard { .. } => {}
+            Pattern::Binding { name, .. } => {
+                // the alternatives of an or-pattern write the same variable
+                let var = match self.scopes.last().unwrap().get(name) {
+                    Some(&var) => var,
+                    None => self.declare(name),
+                };
+                self.write(var, self.current, value);
+            }
+            Pattern::Literal { value: literal, .. } => {
+                let literal = self.constant(literal_constant(literal));
+                let cond = self.push(Inst::Binary(BinOp::Eq, value, literal), IrType::Bool);
+                self.test(cond, fail);
+            }
+            Pattern::Array { elements, rest, .. } => {
+                let length = self.push(Inst::Length(value), IrType::Int);
+                let expected = self.constant(Constant::Int(elements.len() as i64));
+                let op = if *rest { BinOp::Ge } else { BinOp::Eq };
+                let cond = self.push(Inst::Binary(op, length, expected), IrType::Bool);
+                self.test(cond, fail);
+                for (i, element) in elements.iter().enumerate() {
+                    let index = self.constant(Constant::Int(i as i64));
+                    let element_value = self.push(Inst::Index(value, index), IrType::Any);
+                    self.lower_pattern(element, element_value, fail)?;
+                }
+            }
+            Pattern::Or { alternatives, .. } => {
+                let matched = self.new_block();
+                if alternatives.is_empty() {
+                    self.terminate(Terminator::Jump(fail));
+                }
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    let last = i + 1 == alternatives.len();
+                    let next = if last { fail } else { self.new_block() };
+                    self.lower_pattern(alternative, value, next)?;
+                    self.terminate(Terminator::Jump(matched));
+                    if !last {
+                        self.seal(next);
+                        self.current = next;
+                    }
+                }
+                self.seal(matched);
+                self.current = matched;
+            }
+        }
+        Ok(())
+    }
+
+    fn test(&mut self, cond: Value, fail: BlockId) {
+        let pass = self.new_block();
+        self.terminate(Terminator::Branch(cond, pass, fail));
+        self.seal(pass);
+        self.current = pass;
+    }
+}
+
+fn literal_constant(value: &LiteralValue) -> Constant {
+    match value {
+        LiteralValue::Integer(n) => Constant::Int(*n),
+        LiteralValue::String(s) => Constant::Str(s.clone()),
+        LiteralValue::Char(c) => Constant::Char(*c),
+        LiteralValue::Bool(b) => Constant::Bool(*b),
+    }
+}
+
+fn binary_type(op: BinOp, left: IrType, right: IrType) -> IrType {
+    match op {
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => IrType::Bool,
+        _ if left == IrType::Int && right == IrType::Int => IrType::Int,
+        BinOp::Add if left == IrType::Str && right == IrType::Str => IrType::Str,
+        _ => IrType::Any,
+    }
+}
+
+fn join(a: IrType, b: IrType) -> IrType {
+    if a == b { a } else { IrType::Any }
+}
+
+
+struct Lowering<'a> {
+    module: &'a Module,
+    function: &'a IrFunction,
+    lowered: &'a [Option<Function>],
+    constants: &'a mut ConstantPool,
+    slots: HashMap<Value, usize>,
+    instructions: Vec<Instruction>,
+    patches: Vec<(usize, BlockId)>,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(module: &'a Module, function: &'a IrFunction, lowered: &'a [Option<Function>], constants: &'a mut ConstantPool) -> Self {
+        // parameters already sit in the first local slots
+        let mut slots = HashMap::new();
+        let mut next = function.num_params;
+        for v in function.placed_values() {
+            match function.values[v].inst {
+                Inst::Param(i) => {
+                    slots.insert(v, i);
+                }
+                ref inst if inst.has_value() => {
+                    slots.insert(v, next);
+                    next += 1;
+                }
+                _ => {}
+            }
+        }
+        Lowering { module, function, lowered, constants, slots, instructions: Vec::new(), patches: Vec::new() }
+    }
+
+    fn run(mut self) -> Function {
+        let mut starts = Vec::new();
+        for (id, block) in self.function.blocks.iter().enumerate() {
+            starts.push(self.instructions.len());
+            for &v in &block.insts {
+                self.lower_value(v);
+            }
+            match block.terminator.as_ref().expect("unterminated block") {
+                Terminator::Jump(to) => {
+                    self.copies(id, *to);
+                    self.jump(*to);
+                }
+                Terminator::Branch(cond, then_block, else_block) => {
+                    self.get(*cond);
+                    let to_else = self.emit(Instruction::JumpNotTruthy(0));
+                    self.copies(id, *then_block);
+                    self.jump(*then_block);
+                    let target = self.instructions.len();
+                    self.instructions[to_else] = Instruction::JumpNotTruthy(target);
+                    self.copies(id, *else_block);
+                    self.jump(*else_block);
+                }
+                Terminator::Return(v) => {
+                    self.get(*v);
+                    self.emit(Instruction::Return);
+                }
+            }
+        }
+
+        for (pos, block) in std::mem::take(&mut self.patches) {
+            self.instructions[pos] = Instruction::Jump(starts[block]);
+        }
+
+        Function {
+            instructions: self.instructions,
+            num_locals: self.function.num_params + self.slots.values().filter(|&&s| s >= self.function.num_params).count(),
+            num_parameters: self.function.num_params,
+            // the ir doesn't keep spans, so there is nothing to map back to
+            lines: LineTable::default(),
+        }
+    }
+
+    fn is_main(&self) -> bool {
+        std::ptr::eq(self.function, &self.module.functions[0])
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    fn jump(&mut self, to: BlockId) {
+        let pos = self.emit(Instruction::Jump(0));
+        self.patches.push((pos, to));
+    }
+
+    fn get(&mut self, v: Value) {
+        let slot = self.slots[&v];
+        match self.is_main() {
+            true => self.emit(Instruction::GetGlobal(self.module.globals.len() + slot)),
+            false => self.emit(Instruction::GetLocal(slot)),
+        };
+    }
+
+    fn set(&mut self, v: Value) {
+        let slot = self.slots[&v];
+        match self.is_main() {
+            true => self.emit(Instruction::SetGlobal(self.module.globals.len() + slot)),
+            false => self.emit(Instruction::SetLocal(slot)),
+        };
+    }
+
+    fn global(&self, name: &str) -> usize {
+        self.module.globals.iter().position(|g| g == name).expect("unknown global")
+    }
+
+    // the phis of `to` take their values from `from`, pushed first so they copy in parallel
+    fn copies(&mut self, from: BlockId, to: BlockId) {
+        let phis = &self.function.blocks[to].phis;
+        for &phi in phis {
+            let Inst::Phi(operands) = &self.function.values[phi].inst else { unreachable!() };
+            let (_, operand) = operands.iter().find(|(b, _)| *b == from).expect("missing phi operand");
+            self.get(*operand);
+        }
+        for &phi in phis.iter().rev() {
+            self.set(phi);
+        }
+    }
+
+    fn lower_value(&mut self, v: Value) {
+        match &self.function.values[v].inst {
+            Inst::Const(Constant::Bool(true)) => {
+                self.emit(Instruction::True);
+            }
+            Inst::Const(Constant::Bool(false)) => {
+                self.emit(Instruction::False);
+            }
+            Inst::Const(Constant::Null) => {
+                self.emit(Instruction::Null);
+            }
+            // a function naming itself, its constant can't exist before it is lowered
+            Inst::Const(Constant::Function(index)) if std::ptr::eq(&self.module.functions[*index], self.function) => {
+                self.emit(Instruction::CurrentFunction);
+            }
+            Inst::Const(constant) => {
+                let object = match constant {
+                    Constant::Int(n) => Object::Integer(*n),
+                    Constant::Str(s) => Object::String(s.as_str().into()),
+                    Constant::Char(c) => Object::Char(*c),
+                    Constant::Bool(_) | Constant::Null => unreachable!(),
+                    Constant::Function(index) => Object::Function(Rc::new(self.lowered[*index].clone().expect("function lowered out of order"))),
+                };
+                let index = self.constants.add(object);
+                self.emit(Instruction::LoadConstant(index));
+            }
+            Inst::Param(_) => return,
+            Inst::Binary(op, a, b) => {
+                self.get(*a);
+                self.get(*b);
+                match op {
+                    BinOp::Add => self.emit(Instruction::Add),
+                    BinOp::Sub => self.emit(Instruction::Sub),
+                    BinOp::Mul => self.emit(Instruction::Mul),
+                    BinOp::Div => self.emit(Instruction::Div),
+                    BinOp::Mod => self.emit(Instruction::Mod),
+                    BinOp::BitAnd => self.emit(Instruction::BitAnd),
+                    BinOp::BitOr => self.emit(Instruction::BitOr),
+                    BinOp::BitXor => self.emit(Instruction::BitXor),
+                    BinOp::Shl => self.emit(Instruction::ShiftLeft),
+                    BinOp::Shr => self.emit(Instruction::ShiftRight),
+                    BinOp::Eq => self.emit(Instruction::Equal),
+                    BinOp::Ne => self.emit(Instruction::NotEqual),
+                    BinOp::Lt => self.emit(Instruction::LessThan),
+                    BinOp::Gt => self.emit(Instruction::GreaterThan),
+                    BinOp::Le => self.emit(Instruction::LessEqual),
+                    BinOp::Ge => self.emit(Instruction::GreaterEqual),
+                };
+            }
+            Inst::Not(a) => {
+                self.get(*a);
+                self.emit(Instruction::Not);
+            }
+            Inst::Neg(a) => {
+                self.get(*a);
+                self.emit(Instruction::Neg);
+            }
+            Inst::Call(callee, args) => {
+                self.get(*callee);
+                for arg in args {
+                    self.get(*arg);
+                }
+                self.emit(Instruction::Call(args.len()));
+            }
+            Inst::Array(elements) => {
+                for element in elements {
+                    self.get(*element);
+                }
+                self.emit(Instruction::Array(elements.len()));
+            }
+            Inst::Index(array, index) => {
+                self.get(*array);
+                self.get(*index);
+                self.emit(Instruction::Index);
+            }
+            Inst::SetIndex(array, index, element) => {
+                self.get(*array);
+                self.get(*index);
+                self.get(*element);
+                self.emit(Instruction::SetIndex);
+            }
+            Inst::Length(array) => {
+                self.get(*array);
+                self.emit(Instruction::Length);
+            }
+            Inst::GetGlobal(name) => {
+                self.emit(Instruction::GetGlobal(self.global(name)));
+            }
+            Inst::SetGlobal(name, a) => {
+                self.get(*a);
+                self.emit(Instruction::SetGlobal(self.global(name)));
+                return;
+            }
+            Inst::Phi(_) => unreachable!("phis are not in the instruction list"),
+        }
+        self.set(v);
+    }
+}
+
+
+impl Display for IrType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constant::Int(n) => write!(f, "{}", n),
+            Constant::Bool(b) => write!(f, "{}", b),
+            Constant::Str(s) => write!(f, "{:?}", s),
+            Constant::Char(c) => write!(f, "{:?}", c),
+            Constant::Null => write!(f, "null"),
+            Constant::Function(index) => write!(f, "fun#{}", index),
+        }
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inst::Const(c) => write!(f, "const {}", c),
+            Inst::Param(i) => write!(f, "param {}", i),
+            Inst::Binary(op, a, b) => write!(f, "{} %{}, %{}", format!("{:?}", op).to_lowercase(), a, b),
+            Inst::Not(a) => write!(f, "not %{}", a),
+            Inst::Neg(a) => write!(f, "neg %{}", a),
+            Inst::Call(callee, args) => {
+                let args = args.iter().map(|a| format!("%{}", a)).collect::<Vec<_>>();
+                write!(f, "call %{}({})", callee, args.join(", "))
+            }
+            Inst::Array(elements) => {
+                let elements = elements.iter().map(|a| format!("%{}", a)).collect::<Vec<_>>();
+                write!(f, "array [{}]", elements.join(", "))
+            }
+            Inst::Index(array, index) => write!(f, "index %{}, %{}", array, index),
+            Inst::SetIndex(array, index, element) => write!(f, "set_index %{}, %{}, %{}", array, index, element),
+            Inst::Length(array) => write!(f, "length %{}", array),
+            Inst::GetGlobal(name) => write!(f, "get_global {}", name),
+            Inst::SetGlobal(name, a) => write!(f, "set_global {}, %{}", name, a),
+            Inst::Phi(operands) => {
+                let operands = operands.iter().map(|(b, v)| format!("[b{}: %{}]", b, v)).collect::<Vec<_>>();
+                write!(f, "phi {}", operands.join(", "))
+            }
+        }
+    }
+}
+
+impl Display for IrFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "fun {}({}) {{", self.name, self.num_params)?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            let preds = block.preds.iter().map(|p| format!("b{}", p)).collect::<Vec<_>>();
+            match preds.is_empty() {
+                true => writeln!(f, "b{}:", id)?,
+                false => writeln!(f, "b{}:  ; preds {}", id, preds.join(", "))?,
+            }
+            for &v in block.phis.iter().chain(&block.insts) {
+                let data = &self.values[v];
+                match data.inst.has_value() {
+                    true => writeln!(f, "    %{}: {} = {}", v, data.ty, data.inst)?,
+                    false => writeln!(f, "    {}", data.inst)?,
+                }
+            }
+            match &block.terminator {
+                Some(Terminator::Jump(to)) => writeln!(f, "    jump b{}", to)?,
+                Some(Terminator::Branch(c, t, e)) => writeln!(f, "    br %{}, b{}, b{}", c, t, e)?,
+                Some(Terminator::Return(v)) => writeln!(f, "    ret %{}", v)?,
+                None => writeln!(f, "    <no terminator>")?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::{build::*, Parameter, Span}, vm::VM};
+
+    use super::*;
+
+    fn while_loop(condition: Box<Expression>, body: Vec<Statement>) -> Statement {
+        Statement::While { label: None, condition, body: block(body), span: Span::default() }
+    }
+
+    // fun sum(n) { def total = 0; def i = 0; while (i < n) { total = total + i; i = i + 1; } ret total; }
+    fn sum_function() -> Statement {
+        function("sum", &["n"], vec![
+            decl("total", int(0)),
+            decl("i", int(0)),
+            while_loop(bin(var("i"), BinaryOp::LessThan, var("n")), vec![
+                expr(assign(var("total"), bin(var("total"), BinaryOp::Plus, var("i")))),
+                expr(assign(var("i"), bin(var("i"), BinaryOp::Plus, int(1)))),
+            ]),
+            ret(var("total")),
+        ])
+    }
+
+    #[test]
+    fn test_loop_gets_phis() {
+        let module = Module::build(&[sum_function()]).unwrap();
+        module.verify().unwrap();
+
+        let sum = &module.functions[1];
+        let header = &sum.blocks[1];
+        assert_eq!(header.phis.len(), 2);
+        for &phi in &header.phis {
+            assert_eq!(sum.values[phi].ty, IrType::Int);
+        }
+        assert!(sum.to_string().contains("phi [b0: %1], [b2: %"));
+    }
+
+    #[test]
+    fn test_for_in_array_counts_with_a_phi() {
+        // def total = 0; for x in [1, 2] { total = total + x; }
+        let program = vec![
+            decl("total", int(0)),
+            Statement::ForIn {
+                label: None,
+                variable: "x".to_string(),
+                iterable: Iterable::Array(array(vec![*int(1), *int(2)])),
+                body: Box::new(expr(assign(var("total"), bin(var("total"), BinaryOp::Plus, var("x"))))),
+                span: Span::default(),
+            },
+        ];
+
+        let module = Module::build(&program).unwrap();
+        module.verify().unwrap();
+        let dump = module.functions[0].to_string();
+        assert!(dump.contains("length %"));
+        assert!(dump.contains("index %"));
+        // the hidden counter is the only phi, total lives in a global
+        assert_eq!(module.functions[0].blocks[1].phis.len(), 1);
+    }
+
+    #[test]
+    fn test_match_joins_arms_with_a_phi() {
+        // fun f(n) { ret match n { 0 | 1 => n, x if x > 5 => 1, _ => 2 }; }
+        let arms = vec![
+            arm(or(vec![int_pattern(0), int_pattern(1)]), None, var("n")),
+            arm(binding("x"), Some(bin(var("x"), BinaryOp::GreaterThan, int(5))), int(1)),
+            arm(wildcard(), None, int(2)),
+        ];
+        let program = vec![Statement::FunctionDeclaration {
+            name: "f".to_string(),
+            parameters: vec![Parameter { name: "n".to_string(), type_ann: None, span: Span::default() }],
+            return_type: None,
+            body: Box::new(ret(match_on(var("n"), arms))),
+            span: Span::default(),
+        }];
+
+        let module = Module::build(&program).unwrap();
+        module.verify().unwrap();
+        let f = &module.functions[1];
+        let result = f.blocks.iter().find_map(|b| match b.terminator {
+            Some(Terminator::Return(value)) => Some(value),
+            _ => None,
+        }).unwrap();
+        // three arms and the no-match fallback
+        assert!(matches!(&f.values[result].inst, Inst::Phi(operands) if operands.len() == 4));
+        module.lower();
+    }
+
+    #[test]
+    fn test_if_without_changes_has_no_phi() {
+        // fun f(c) { def x = 1; if (c) x; ret x; }
+        let program = vec![function("f", &["c"], vec![
+            decl("x", int(1)),
+            Statement::If { condition: var("c"), then_branch: Box::new(expr(var("x"))), else_branch: None, span: Span::default() },
+            ret(var("x")),
+        ])];
+
+        let module = Module::build(&program).unwrap();
+        module.verify().unwrap();
+        let f = &module.functions[1];
+        assert!(f.blocks.iter().all(|b| b.phis.is_empty()));
+        assert_eq!(f.blocks[2].terminator, Some(Terminator::Return(1)));
+    }
+
+    #[test]
+    fn test_break_and_continue_edges() {
+        // fun f(n) { def i = 0; while (i < n) { i = i + 1; if (i == 3) { continue; } if (i == 5) { break; } } ret i; }
+        let program = vec![function("f", &["n"], vec![
+            decl("i", int(0)),
+            while_loop(bin(var("i"), BinaryOp::LessThan, var("n")), vec![
+                expr(assign(var("i"), bin(var("i"), BinaryOp::Plus, int(1)))),
+                if_then(bin(var("i"), BinaryOp::Equal, int(3)), vec![Statement::Continue { label: None, span: Span::default() }]),
+                if_then(bin(var("i"), BinaryOp::Equal, int(5)), vec![Statement::Break { label: None, span: Span::default() }]),
+            ]),
+            ret(var("i")),
+        ])];
+
+        let module = Module::build(&program).unwrap();
+        module.verify().unwrap();
+        // the exit block merges the loop condition failing and the break
+        let f = &module.functions[1];
+        let exit = f.blocks.iter().find(|b| matches!(b.terminator, Some(Terminator::Return(_))) && !b.preds.is_empty()).unwrap();
+        assert_eq!(exit.preds.len(), 2);
+        assert_eq!(exit.phis.len(), 1);
+
+        let program = vec![Statement::Continue { label: Some("outer".to_string()), span: Span::default() }];
+        assert_eq!(Module::build(&program).unwrap_err(), "`continue outer` doesn't match any enclosing loop");
+    }
+
+    #[test]
+    fn test_dump_format() {
+        // def g = 2 * 3;
+        let module = Module::build(&[decl("g", bin(int(2), BinaryOp::Multiply, int(3)))]).unwrap();
+        assert_eq!(module.to_string(), "\
+fun main(0) {
+b0:
+    %0: Int = const 2
+    %1: Int = const 3
+    %2: Int = mul %0, %1
+    set_global g, %2
+    %4: Null = const null
+    ret %4
+}
+");
+    }
+
+    #[test]
+    fn test_verifier_rejects_broken_ir() {
+        let mut module = Module::build(&[sum_function()]).unwrap();
+        let sum = &mut module.functions[1];
+
+        // make the return use a value defined in the loop body, which doesn't dominate the exit
+        let body_value = sum.blocks[2].insts[0];
+        let exit = sum.blocks.iter().position(|b| matches!(b.terminator, Some(Terminator::Return(_)))).unwrap();
+        sum.blocks[exit].terminator = Some(Terminator::Return(body_value));
+        sum.blocks[0].terminator = None;
+
+        let errors = module.verify().unwrap_err();
+        assert_eq!(errors[0], "sum: b0 has no terminator");
+
+        module.functions[1].blocks[0].terminator = Some(Terminator::Jump(1));
+        let errors = module.verify().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("returns"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_lowers_to_bytecode() {
+        let module = Module::build(&[sum_function()]).unwrap();
+        let bytecode = module.lower();
+
+        // main: load the function, store it in its global
+        assert_eq!(&bytecode.instructions[..4], &[
+            Instruction::LoadConstant(bytecode.constants.len() - 1),
+            Instruction::SetGlobal(1),
+            Instruction::GetGlobal(1),
+            Instruction::SetGlobal(0),
+        ]);
+        let Object::Function(sum) = &bytecode.constants[bytecode.constants.len() - 1] else { panic!("expected sum") };
+        assert_eq!(sum.num_parameters, 1);
+
+        // every jump lands inside the function
+        for instruction in &sum.instructions {
+            if let Instruction::Jump(to) | Instruction::JumpNotTruthy(to) = instruction {
+                assert!(*to < sum.instructions.len());
+            }
+        }
+        // the loop header phis are filled by copies on both incoming edges
+        let phi_slots = sum.instructions.iter().filter(|i| matches!(i, Instruction::SetLocal(_))).count();
+        assert!(phi_slots >= 4);
+        assert!(sum.num_locals > 1);
+    }
+
+    #[test]
+    fn test_inclusive_range_stops_at_its_bound() {
+        // def c = 0; for i in MAX - 2..=MAX { c = c + 1; } ret c;
+        let program = vec![
+            decl("c", int(0)),
+            Statement::ForIn {
+                label: None,
+                variable: "i".to_string(),
+                iterable: Iterable::Range { start: int(i64::MAX - 2), end: int(i64::MAX), inclusive: true },
+                body: Box::new(expr(assign(var("c"), bin(var("c"), BinaryOp::Plus, int(1))))),
+                span: Span::default(),
+            },
+            ret(var("c")),
+        ];
+        let module = Module::build(&program).unwrap();
+        module.verify().unwrap();
+        let mut vm = VM::new(module.lower()).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.last_popped(), &Object::Integer(3));
+    }
+
+    #[test]
+    fn test_nested_function_calls_itself() {
+        // fun outer(n) { fun down(k) { if (k == 0) { ret 10; } ret down(k - 1); } ret down(n); } ret outer(3);
+        let down = function("down", &["k"], vec![
+            if_then(bin(var("k"), BinaryOp::Equal, int(0)), vec![ret(int(10))]),
+            ret(call("down", vec![*bin(var("k"), BinaryOp::Minus, int(1))])),
+        ]);
+        let program = vec![
+            function("outer", &["n"], vec![down, ret(call("down", vec![*var("n")]))]),
+            ret(call("outer", vec![*int(3)])),
+        ];
+        let module = Module::build(&program).unwrap();
+        module.verify().unwrap();
+
+        let bytecode = module.lower();
+        let Some(Object::Function(down)) = bytecode.constants.iter().find(|constant| matches!(constant, Object::Function(f) if f.instructions.contains(&Instruction::CurrentFunction))) else {
+            panic!("expected down to load itself")
+        };
+        assert_eq!(down.num_parameters, 1);
+        let mut vm = VM::new(bytecode).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.last_popped(), &Object::Integer(10));
+    }
+}
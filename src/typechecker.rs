@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+
+use crate::{ast::{AssignOp, BinaryOp, Expression, Iterable, LiteralValue, MatchArm, Parameter, Pattern, PrefixOp, Span, Statement, Type}, cfg::{Cfg, Terminator}, exhaustive, visitor::{ExprVisitor, StmtVisitor}};
+
+#[derive(Debug, Clone)]
+pub struct TypeError {
+    pub message: String,
+    pub span: Span,
+}
+
+// walks the ast before anything runs and collects every type error it finds
+// expressions whose type can't be known (undefined names, missing annotations)
+// evaluate to None so one mistake doesn't cascade into many errors
+pub struct TypeChecker {
+    scopes: Vec<HashMap<String, Option<Type>>>,
+    return_types: Vec<Option<Type>>, // return type of each enclosing function
+    errors: Vec<TypeError>,
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            return_types: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn check(&mut self, program: &[Statement]) -> Result<(), Vec<TypeError>> {
+        for stmt in program {
+            self.visit_stmt(stmt);
+        }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn error(&mut self, message: String, span: &Span) {
+        self.errors.push(TypeError { message, span: span.clone() });
+    }
+
+    fn define(&mut self, name: &str, ty: Option<Type>) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), ty);
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Option<Type>> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    // report a mismatch unless either side is already unknown
+    fn expect(&mut self, expected: &Type, found: &Option<Type>, span: &Span) {
+        if let Some(found) = found && found != expected {
+            self.error(format!("mismatched types: expected {}, found {}", expected, found), span);
+        }
+    }
+
+    fn check_condition(&mut self, condition: &Expression) {
+        let ty = self.visit_expr(condition);
+        self.expect(&Type::Bool, &ty, condition.span());
+    }
+
+    fn check_scoped(&mut self, stmt: &Statement) {
+        self.scopes.push(HashMap::new());
+        self.visit_stmt(stmt);
+        self.scopes.pop();
+    }
+
+    // defines the names the pattern binds, typed by the part of the value they bind
+    fn check_pattern(&mut self, pattern: &Pattern, ty: Option<&Type>) {
+        match pattern {
+            Pattern::Wildcard { .. } => {}
+            Pattern::Literal { value, span } => {
+                let literal_ty = literal_type(value);
+                if let Some(ty) = ty && *ty != literal_ty {
+                    self.error(format!("a {} pattern cannot match a value of type {}", literal_ty, ty), span);
+                }
+            }
+            Pattern::Binding { name, .. } => self.define(name, ty.cloned()),
+            Pattern::Array { elements, span, .. } => {
+                let element_ty = match ty {
+                    Some(Type::Array(element)) => Some(element.as_ref().clone()),
+                    Some(other) => {
+                        self.error(format!("an array pattern cannot match a value of type {}", other), span);
+                        None
+                    }
+                    None => None,
+                };
+                for element in elements {
+                    self.check_pattern(element, element_ty.as_ref());
+                }
+            }
+            Pattern::Or { alternatives, .. } => {
+                // the arm body can only use names every alternative binds
+                let mut expected = pattern.bindings();
+                expected.sort();
+                for alternative in alternatives {
+                    let mut names = alternative.bindings();
+                    names.sort();
+                    if names != expected {
+                        self.error(format!("every alternative of `{}` has to bind the same names", pattern), alternative.span());
+                        break;
+                    }
+                }
+                for alternative in alternatives {
+                    self.check_pattern(alternative, ty);
+                }
+            }
+        }
+    }
+}
+
+impl StmtVisitor<(), Option<Type>> for TypeChecker {
+    fn visit_block(&mut self, statements: &[Statement], _span: &Span) {
+        self.scopes.push(HashMap::new());
+        for stmt in statements {
+            self.visit_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, _span: &Span) {
+        self.check_condition(condition);
+        self.check_scoped(then_branch);
+        if let Some(else_branch) = else_branch {
+            self.check_scoped(else_branch);
+        }
+    }
+
+    fn visit_while(&mut self, _label: Option<&str>, condition: &Expression, body: &Statement, _span: &Span) {
+        self.check_condition(condition);
+        self.check_scoped(body);
+    }
+
+    fn visit_for(&mut self, _label: Option<&str>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement, _span: &Span) {
+        // the loop variable lives in its own scope around the whole loop
+        self.scopes.push(HashMap::new());
+        if let Some(init) = init {
+            self.visit_stmt(init);
+        }
+        self.check_condition(condition);
+        if let Some(increment) = increment {
+            self.visit_stmt(increment);
+        }
+        self.check_scoped(body);
+        self.scopes.pop();
+    }
+
+    fn visit_for_in(&mut self, _label: Option<&str>, variable: &str, iterable: &Iterable, body: &Statement, _span: &Span) {
+        let element_ty = match iterable {
+            Iterable::Range { start, end, .. } => {
+                for bound in [start, end] {
+                    let ty = self.visit_expr(bound);
+                    self.expect(&Type::Int, &ty, bound.span());
+                }
+                Some(Type::Int)
+            }
+            Iterable::Array(array) => match self.visit_expr(array) {
+                Some(Type::Array(element)) => Some(*element),
+                Some(other) => {
+                    self.error(format!("cannot iterate over {}", other), array.span());
+                    None
+                }
+                None => None,
+            },
+        };
+
+        self.scopes.push(HashMap::new());
+        self.define(variable, element_ty);
+        self.check_scoped(body);
+        self.scopes.pop();
+    }
+
+    fn visit_return(&mut self, value: Option<&Expression>, span: &Span) {
+        let found = match value {
+            Some(value) => self.visit_expr(value),
+            None => Some(Type::Void),
+        };
+
+        // a top level ret and a function without a declared return type are not checked
+        match (self.return_types.last().cloned(), value) {
+            (Some(Some(Type::Void)), Some(value)) => {
+                self.error("`ret` with a value in a function returning Void".to_string(), value.span());
+            }
+            (Some(Some(expected)), None) if expected != Type::Void => {
+                self.error(format!("`ret` without a value in a function returning {}", expected), span);
+            }
+            (Some(Some(expected)), _) => {
+                let span = value.map(Expression::span).unwrap_or(span);
+                self.expect(&expected, &found, span);
+            }
+            _ => {}
+        }
+    }
+
+    fn visit_variable_declaration(&mut self, name: &str, type_ann: &Option<Type>, initializer: Option<&Expression>, _span: &Span) {
+        let init_ty = initializer.and_then(|init| self.visit_expr(init));
+
+        if let (Some(ann), Some(init)) = (type_ann, initializer) {
+            self.expect(ann, &init_ty, init.span());
+        }
+
+        let ty = type_ann.clone().or(init_ty);
+        self.define(name, ty);
+    }
+
+    fn visit_function_declaration(&mut self, name: &str, parameters: &[Parameter], return_type: &Option<Type>, body: &Statement, span: &Span) {
+        // only a fully annotated signature gives the function a known type
+        let param_types = parameters.iter().map(|p| p.type_ann.clone()).collect::<Option<Vec<_>>>();
+        let fn_ty = match (param_types, return_type) {
+            (Some(params), Some(ret)) => Some(Type::Function(params, Box::new(ret.clone()))),
+            _ => None,
+        };
+        // defined before the body so recursive calls resolve
+        self.define(name, fn_ty);
+
+        self.scopes.push(HashMap::new());
+        for param in parameters {
+            self.define(&param.name, param.type_ann.clone());
+        }
+        self.return_types.push(return_type.clone());
+        self.visit_stmt(body);
+        self.return_types.pop();
+        self.scopes.pop();
+
+        if let Some(ret) = return_type && *ret != Type::Void && can_fall_off(body) {
+            self.error(format!("`{}` can reach the end without returning a value of type {}", name, ret), span);
+        }
+    }
+}
+
+impl ExprVisitor<Option<Type>> for TypeChecker {
+    fn visit_binary(&mut self, left: &Expression, operator: &BinaryOp, right: &Expression, span: &Span) -> Option<Type> {
+        let left_ty = self.visit_expr(left);
+        let right_ty = self.visit_expr(right);
+        let (left_ty, right_ty) = (left_ty?, right_ty?);
+
+        let result = binary_type(operator, &left_ty, &right_ty);
+        if result.is_none() {
+            self.error(format!("operator {:?} cannot be applied to {} and {}", operator, left_ty, right_ty), span);
+        }
+        result
+    }
+
+    fn visit_unary(&mut self, operator: &PrefixOp, operand: &Expression, span: &Span) -> Option<Type> {
+        let ty = self.visit_expr(operand)?;
+        let result = match (operator, &ty) {
+            (PrefixOp::Not, Type::Bool) => Some(Type::Bool),
+            (PrefixOp::Neg, Type::Int) => Some(Type::Int),
+            (PrefixOp::Neg, Type::Float) => Some(Type::Float),
+            _ => None,
+        };
+
+        if result.is_none() {
+            self.error(format!("operator {:?} cannot be applied to {}", operator, ty), span);
+        }
+        result
+    }
+
+    fn visit_literal(&mut self, value: &LiteralValue, _span: &Span) -> Option<Type> {
+        Some(literal_type(value))
+    }
+
+    fn visit_assign(&mut self, target: &Expression, value: &Expression, span: &Span) -> Option<Type> {
+        let value_ty = self.visit_expr(value);
+
+        let Expression::Variable { name, span: target_span } = target else {
+            // a[i] = v, the element type is already fixed
+            if !is_place(target) {
+                self.error("invalid assignment target".to_string(), span);
+                return None;
+            }
+            let target_ty = self.visit_expr(target);
+            if let Some(target_ty) = &target_ty {
+                self.expect(target_ty, &value_ty, value.span());
+            }
+            return target_ty;
+        };
+
+        match self.lookup(name).cloned() {
+            Some(Some(target_ty)) => {
+                self.expect(&target_ty, &value_ty, value.span());
+                Some(target_ty)
+            }
+            // declared without a type, the first assignment decides it
+            Some(None) => {
+                if value_ty.is_some() {
+                    for scope in self.scopes.iter_mut().rev() {
+                        if let Some(slot) = scope.get_mut(name) {
+                            *slot = value_ty.clone();
+                            break;
+                        }
+                    }
+                }
+                value_ty
+            }
+            None => {
+                self.error(format!("undefined variable `{}`", name), target_span);
+                None
+            }
+        }
+    }
+
+    fn visit_compound_assign(&mut self, target: &Expression, operator: &AssignOp, value: &Expression, span: &Span) -> Option<Type> {
+        if !is_place(target) {
+            self.error("invalid assignment target".to_string(), span);
+            return None;
+        }
+        // `x += v` reads x, so x needs a type already
+        let target_ty = self.visit_expr(target);
+        let value_ty = self.visit_expr(value);
+        let (target_ty, value_ty) = (target_ty?, value_ty?);
+
+        match binary_type(&operator.binary(), &target_ty, &value_ty) {
+            Some(result) if result == target_ty => {}
+            Some(result) => self.error(format!("mismatched types: expected {}, found {}", target_ty, result), span),
+            None => self.error(format!("operator {:?} cannot be applied to {} and {}", operator, target_ty, value_ty), span),
+        }
+        Some(target_ty)
+    }
+
+    fn visit_index(&mut self, array: &Expression, index: &Expression, _span: &Span) -> Option<Type> {
+        let array_ty = self.visit_expr(array);
+        let index_ty = self.visit_expr(index);
+        self.expect(&Type::Int, &index_ty, index.span());
+        match array_ty? {
+            Type::Array(element) => Some(*element),
+            other => {
+                self.error(format!("cannot index into {}", other), array.span());
+                None
+            }
+        }
+    }
+
+    fn visit_call(&mut self, callee: &Expression, arguments: &[Expression], span: &Span) -> Option<Type> {
+        let callee_ty = self.visit_expr(callee);
+        let arg_tys = arguments.iter().map(|arg| self.visit_expr(arg)).collect::<Vec<_>>();
+
+        match callee_ty? {
+            Type::Function(params, ret) => {
+                if params.len() != arguments.len() {
+                    self.error(format!("expected {} arguments, found {}", params.len(), arguments.len()), span);
+                } else {
+                    for ((param, arg_ty), arg) in params.iter().zip(&arg_tys).zip(arguments) {
+                        self.expect(param, arg_ty, arg.span());
+                    }
+                }
+                Some(*ret)
+            }
+            other => {
+                self.error(format!("{} is not callable", other), callee.span());
+                None
+            }
+        }
+    }
+
+    fn visit_variable(&mut self, name: &str, span: &Span) -> Option<Type> {
+        match self.lookup(name) {
+            Some(ty) => ty.clone(),
+            None => {
+                self.error(format!("undefined variable `{}`", name), span);
+                None
+            }
+        }
+    }
+
+    fn visit_array(&mut self, elements: &[Expression], _span: &Span) -> Option<Type> {
+        // the first element with a known type decides, an empty array has no element type to check against
+        let types = elements.iter().map(|element| self.visit_expr(element)).collect::<Vec<_>>();
+        let element_ty = types.iter().flatten().next().cloned()?;
+        for (ty, element) in types.iter().zip(elements) {
+            self.expect(&element_ty, ty, element.span());
+        }
+        Some(Type::Array(Box::new(element_ty)))
+    }
+
+    fn visit_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], span: &Span) -> Option<Type> {
+        let scrutinee_ty = self.visit_expr(scrutinee);
+
+        // like arrays, the first arm with a known type decides
+        let mut result: Option<Type> = None;
+        for arm in arms {
+            self.scopes.push(HashMap::new());
+            self.check_pattern(&arm.pattern, scrutinee_ty.as_ref());
+            if let Some(guard) = &arm.guard {
+                self.check_condition(guard);
+            }
+            let ty = self.visit_expr(&arm.body);
+            self.scopes.pop();
+            match &result {
+                Some(expected) => self.expect(&expected.clone(), &ty, arm.body.span()),
+                None => result = ty,
+            }
+        }
+
+        // a guard may reject the value, so guarded arms don't count towards covering it
+        let patterns = arms.iter().filter(|arm| arm.guard.is_none()).map(|arm| &arm.pattern).collect::<Vec<_>>();
+        if let Some(missing) = exhaustive::uncovered(&patterns, scrutinee_ty.as_ref()) {
+            self.error(format!("match is not exhaustive, `{}` is not covered", missing), span);
+        }
+        result
+    }
+}
+
+// true when some path through a function body ends without a ret
+// loops like `while (true)` only leave through a ret, so their exit edge doesn't count
+fn can_fall_off(body: &Statement) -> bool {
+    let cfg = Cfg::build(std::slice::from_ref(body));
+
+    let mut seen = vec![false; cfg.blocks.len()];
+    let mut stack = vec![cfg.entry];
+    while let Some(id) = stack.pop() {
+        if std::mem::replace(&mut seen[id], true) {
+            continue;
+        }
+        match &cfg.block(id).terminator {
+            Terminator::Goto(to) if *to == cfg.exit => return true,
+            Terminator::Goto(to) => stack.push(*to),
+            Terminator::Branch { condition, then_block, else_block } => match condition {
+                Expression::Literal { value: LiteralValue::Bool(true), .. } => stack.push(*then_block),
+                Expression::Literal { value: LiteralValue::Bool(false), .. } => stack.push(*else_block),
+                _ => stack.extend([*then_block, *else_block]),
+            },
+            Terminator::Iterate { body_block, after, .. } => stack.extend([*body_block, *after]),
+            Terminator::Return { .. } | Terminator::Exit => {}
+        }
+    }
+    false
+}
+
+// None when the operator doesn't apply to the operand types
+fn binary_type(operator: &BinaryOp, left: &Type, right: &Type) -> Option<Type> {
+    match operator {
+        BinaryOp::Plus => match (left, right) {
+            (Type::Int, Type::Int) => Some(Type::Int),
+            (Type::Float, Type::Float) => Some(Type::Float),
+            (Type::String, Type::String) => Some(Type::String),
+            _ => None,
+        },
+        BinaryOp::Minus | BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => match (left, right) {
+            (Type::Int, Type::Int) => Some(Type::Int),
+            (Type::Float, Type::Float) => Some(Type::Float),
+            _ => None,
+        },
+        BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor | BinaryOp::ShiftLeft | BinaryOp::ShiftRight => {
+            (*left == Type::Int && *right == Type::Int).then_some(Type::Int)
+        }
+        BinaryOp::LessThan | BinaryOp::GreaterThan | BinaryOp::LessEqual | BinaryOp::GreaterEqual => match (left, right) {
+            (Type::Int, Type::Int) | (Type::Float, Type::Float) | (Type::Char, Type::Char) => Some(Type::Bool),
+            _ => None,
+        },
+        BinaryOp::Equal | BinaryOp::NotEqual => (left == right).then_some(Type::Bool),
+        BinaryOp::And | BinaryOp::Or => (*left == Type::Bool && *right == Type::Bool).then_some(Type::Bool),
+    }
+}
+
+// a variable, or an element of something assignable
+pub fn is_place(expr: &Expression) -> bool {
+    match expr {
+        Expression::Variable { .. } => true,
+        Expression::Index { array, .. } => is_place(array),
+        _ => false,
+    }
+}
+
+pub fn literal_type(value: &LiteralValue) -> Type {
+    match value {
+        LiteralValue::Integer(_) => Type::Int,
+        LiteralValue::String(_) => Type::String,
+        LiteralValue::Char(_) => Type::Char,
+        LiteralValue::Bool(_) => Type::Bool,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize) -> Span {
+        Span { start: line, end: line + 1, line, column: 1 }
+    }
+
+    fn lit(value: LiteralValue) -> Box<Expression> {
+        Box::new(Expression::Literal { value, span: span(0) })
+    }
+
+    fn var(name: &str, line: usize) -> Box<Expression> {
+        Box::new(Expression::Variable { name: name.to_string(), span: span(line) })
+    }
+
+    fn decl(name: &str, type_ann: Option<Type>, initializer: Box<Expression>, line: usize) -> Statement {
+        Statement::VariableDeclaration {
+            name: name.to_string(),
+            type_ann,
+            initializer: Some(initializer),
+            span: span(line),
+        }
+    }
+
+    #[test]
+    fn test_well_typed_program() {
+        // def x: Int = 1 + 2; if (x < 5) { ret x; }
+        let program = vec![
+            decl("x", Some(Type::Int), Box::new(Expression::Binary {
+                left: lit(LiteralValue::Integer(1)),
+                operator: BinaryOp::Plus,
+                right: lit(LiteralValue::Integer(2)),
+                span: span(1),
+            }), 1),
+            Statement::If {
+                condition: Box::new(Expression::Binary {
+                    left: var("x", 2),
+                    operator: BinaryOp::LessThan,
+                    right: lit(LiteralValue::Integer(5)),
+                    span: span(2),
+                }),
+                then_branch: Box::new(Statement::Return { value: Some(var("x", 3)), span: span(3) }),
+                else_branch: None,
+                span: span(2),
+            },
+        ];
+
+        assert!(TypeChecker::new().check(&program).is_ok());
+    }
+
+    #[test]
+    fn test_annotation_and_condition_errors() {
+        // def x: Int = true; def y = "a" - 1; while (x) {}
+        let program = vec![
+            decl("x", Some(Type::Int), lit(LiteralValue::Bool(true)), 1),
+            decl("y", None, Box::new(Expression::Binary {
+                left: lit(LiteralValue::String("a".to_string())),
+                operator: BinaryOp::Minus,
+                right: lit(LiteralValue::Integer(1)),
+                span: span(2),
+            }), 2),
+            Statement::While {
+                label: None,
+                condition: var("x", 3),
+                body: Box::new(Statement::Block { statements: vec![], span: span(3) }),
+                span: span(3),
+            },
+        ];
+
+        let errors = TypeChecker::new().check(&program).unwrap_err();
+        let lines = errors.iter().map(|e| e.span.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![0, 2, 3]);
+        assert_eq!(errors[0].message, "mismatched types: expected Int, found Bool");
+    }
+
+    #[test]
+    fn test_function_calls_and_returns() {
+        // fun inc(n: Int): Int { ret true; } inc("a", 1); def r: Bool = inc(1);
+        let program = vec![
+            Statement::FunctionDeclaration {
+                name: "inc".to_string(),
+                parameters: vec![Parameter { name: "n".to_string(), type_ann: Some(Type::Int), span: span(1) }],
+                return_type: Some(Type::Int),
+                body: Box::new(Statement::Block {
+                    statements: vec![Statement::Return { value: Some(lit(LiteralValue::Bool(true))), span: span(1) }],
+                    span: span(1),
+                }),
+                span: span(1),
+            },
+            Statement::Expression {
+                expression: Box::new(Expression::Call {
+                    callee: var("inc", 2),
+                    arguments: vec![*lit(LiteralValue::String("a".to_string())), *lit(LiteralValue::Integer(1))],
+                    span: span(2),
+                }),
+                span: span(2),
+            },
+            decl("r", Some(Type::Bool), Box::new(Expression::Call {
+                callee: var("inc", 3),
+                arguments: vec![*lit(LiteralValue::Integer(1))],
+                span: span(3),
+            }), 3),
+        ];
+
+        let errors = TypeChecker::new().check(&program).unwrap_err();
+        let messages = errors.iter().map(|e| e.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages, vec![
+            "mismatched types: expected Int, found Bool",
+            "expected 1 arguments, found 2",
+            "mismatched types: expected Bool, found Int",
+        ]);
+        assert_eq!(errors[2].span.line, 3);
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let program = vec![decl("x", None, var("missing", 4), 4)];
+        let errors = TypeChecker::new().check(&program).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.line, 4);
+    }
+
+    #[test]
+    fn test_missing_returns() {
+        let ret = |value: Option<Box<Expression>>, line| Statement::Return { value, span: span(line) };
+        let function = |name: &str, return_type, statements, line| Statement::FunctionDeclaration {
+            name: name.to_string(),
+            parameters: vec![Parameter { name: "c".to_string(), type_ann: Some(Type::Bool), span: span(line) }],
+            return_type: Some(return_type),
+            body: Box::new(Statement::Block { statements, span: span(line) }),
+            span: span(line),
+        };
+
+        let program = vec![
+            // fun a(c: Bool): Int { if (c) { ret 1; } }
+            function("a", Type::Int, vec![Statement::If {
+                condition: var("c", 1),
+                then_branch: Box::new(ret(Some(lit(LiteralValue::Integer(1))), 1)),
+                else_branch: None,
+                span: span(1),
+            }], 1),
+            // fun b(c: Bool): Int { while (true) { ret 1; } }
+            function("b", Type::Int, vec![Statement::While {
+                label: None,
+                condition: lit(LiteralValue::Bool(true)),
+                body: Box::new(ret(Some(lit(LiteralValue::Integer(1))), 2)),
+                span: span(2),
+            }], 2),
+            // fun d(c: Bool): Int { ret; }  fun e(c: Bool): Void { ret 1; }  fun f(c: Bool): Void { }
+            function("d", Type::Int, vec![ret(None, 3)], 3),
+            function("e", Type::Void, vec![ret(Some(lit(LiteralValue::Integer(1))), 4)], 4),
+            function("f", Type::Void, vec![], 5),
+        ];
+
+        let errors = TypeChecker::new().check(&program).unwrap_err();
+        let messages = errors.iter().map(|e| e.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages, vec![
+            "`a` can reach the end without returning a value of type Int",
+            "`ret` without a value in a function returning Int",
+            "`ret` with a value in a function returning Void",
+        ]);
+        assert_eq!(errors[0].span.line, 1);
+    }
+
+    #[test]
+    fn test_match_exhaustiveness() {
+        let arm = |pattern: Pattern, guard: Option<Box<Expression>>, line: usize| MatchArm {
+            pattern,
+            guard,
+            body: lit(LiteralValue::Integer(0)),
+            span: span(line),
+        };
+        let boolean = |b: bool| Pattern::Literal { value: LiteralValue::Bool(b), span: span(0) };
+        let binding = |name: &str| Pattern::Binding { name: name.to_string(), span: span(0) };
+        let check = |arms: Vec<MatchArm>| {
+            let program = vec![
+                decl("b", Some(Type::Bool), lit(LiteralValue::Bool(true)), 1),
+                Statement::Expression {
+                    expression: Box::new(Expression::Match { scrutinee: var("b", 2), arms, span: span(2) }),
+                    span: span(2),
+                },
+            ];
+            TypeChecker::new().check(&program).err().map(|errors| errors[0].message.clone())
+        };
+
+        // match b { true => 0, x if x => 0 }
+        let message = check(vec![arm(boolean(true), None, 3), arm(binding("x"), Some(var("x", 4)), 4)]);
+        assert_eq!(message.as_deref(), Some("match is not exhaustive, `false` is not covered"));
+
+        // match b { true => 0, false => 0 }
+        assert_eq!(check(vec![arm(boolean(true), None, 3), arm(boolean(false), None, 4)]), None);
+
+        // match b { x | true => 0, _ => 0 }
+        let or = Pattern::Or { alternatives: vec![binding("x"), boolean(true)], span: span(3) };
+        let message = check(vec![arm(or, None, 3), arm(Pattern::Wildcard { span: span(4) }, None, 4)]);
+        assert_eq!(message.as_deref(), Some("every alternative of `x | true` has to bind the same names"));
+    }
+}
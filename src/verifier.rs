@@ -0,0 +1,250 @@
+use std::{fmt::Display, rc::Rc};
+
+use crate::{builtins::BUILTINS, codegen::{Bytecode, Function, Instruction, Object}};
+
+// checks bytecode before the vm runs it, so the vm can trust every index and jump
+//   jump targets land on an instruction (or the end of main)
+//   constants, globals, locals and builtins exist, and no constant holds a builtin
+//   every path reaches an instruction with the same stack height, and never pops an empty stack
+//   functions end in Return, they can't run off their last instruction
+//   a function's parameters fit in its locals, and the locals fit in a one byte operand
+
+const MAX_LOCALS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub message: String,
+    pub function: String, // "main" or "constant i"
+    pub instruction: usize,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, instruction {}: {}", self.function, self.instruction, self.message)
+    }
+}
+
+// what the vm may assume about verified code
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verified {
+    pub max_stack: usize, // the most values any one frame has on its stack at once
+}
+
+pub fn verify(bytecode: &Bytecode) -> Result<Verified, VerifyError> {
+    let mut max_stack = Verifier { bytecode, function: "main".to_string(), instructions: &bytecode.instructions, num_locals: None, num_parameters: 0 }.run()?;
+    for (name, function) in functions(&bytecode.constants) {
+        let verifier = Verifier {
+            bytecode,
+            function: name,
+            instructions: &function.instructions,
+            num_locals: Some(function.num_locals),
+            num_parameters: function.num_parameters,
+        };
+        max_stack = max_stack.max(verifier.run()?);
+    }
+    Ok(Verified { max_stack })
+}
+
+// every function in the constant pool with where it is, arrays in the pool can hold functions too
+pub fn functions(constants: &[Object]) -> Vec<(String, &Rc<Function>)> {
+    fn walk<'a>(object: &'a Object, name: String, found: &mut Vec<(String, &'a Rc<Function>)>) {
+        match object {
+            Object::Function(function) => found.push((name, function)),
+            Object::Array(elements) => {
+                for (i, element) in elements.iter().enumerate() {
+                    walk(element, format!("{}, element {}", name, i), found);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut found = Vec::new();
+    for (index, constant) in constants.iter().enumerate() {
+        walk(constant, format!("constant {}", index), &mut found);
+    }
+    found
+}
+
+struct Verifier<'a> {
+    bytecode: &'a Bytecode,
+    function: String,
+    instructions: &'a [Instruction],
+    num_locals: Option<usize>, // None in main, which only has globals
+    num_parameters: usize,
+}
+
+impl Verifier<'_> {
+    fn error(&self, instruction: usize, message: String) -> VerifyError {
+        VerifyError { message, function: self.function.clone(), instruction }
+    }
+
+    // returns the maximum stack height
+    fn run(&self) -> Result<usize, VerifyError> {
+        // the arguments become the first locals, and GetLocal takes a one byte index
+        if let Some(num_locals) = self.num_locals {
+            if self.num_parameters > num_locals {
+                return Err(self.error(0, format!("{} parameters don't fit in {} locals", self.num_parameters, num_locals)));
+            }
+            if num_locals > MAX_LOCALS {
+                return Err(self.error(0, format!("{} locals, at most {} fit in a frame", num_locals, MAX_LOCALS)));
+            }
+        }
+
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            self.check_operands(i, instruction)?;
+        }
+
+        // the height before each instruction, found by walking every path from the start
+        let mut heights = vec![None; self.instructions.len() + 1];
+        let mut pending = vec![(0, 0)];
+        let mut max_stack = 0;
+        while let Some((at, height)) = pending.pop() {
+            match heights[at] {
+                Some(known) if known == height => continue,
+                Some(known) => return Err(self.error(at, format!("reached with {} values on the stack on one path and {} on another", known, height))),
+                None => heights[at] = Some(height),
+            }
+            let Some(instruction) = self.instructions.get(at) else {
+                if self.num_locals.is_some() {
+                    return Err(self.error(at, "function runs past its last instruction without returning".to_string()));
+                }
+                continue;
+            };
+
+            let (pops, pushes) = effect(instruction);
+            if height < pops {
+                return Err(self.error(at, format!("{:?} needs {} values but the stack has {}", instruction, pops, height)));
+            }
+            let height = height - pops + pushes;
+            max_stack = max_stack.max(height);
+            match instruction {
+                Instruction::Return => {}
+                Instruction::Jump(target) => pending.push((*target, height)),
+                Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => pending.extend([(*target, height), (at + 1, height)]),
+                Instruction::JumpTable { targets, default, .. } => {
+                    pending.extend(targets.iter().chain([default]).map(|target| (*target, height)));
+                }
+                _ => pending.push((at + 1, height)),
+            }
+        }
+        Ok(max_stack)
+    }
+
+    fn check_operands(&self, at: usize, instruction: &Instruction) -> Result<(), VerifyError> {
+        let check = |index: usize, length: usize, what: &str| match index < length {
+            true => Ok(()),
+            false => Err(self.error(at, format!("{} {} doesn't exist, there are {}", what, index, length))),
+        };
+        match instruction {
+            Instruction::LoadConstant(index) => {
+                check(*index, self.bytecode.constants.len(), "constant")?;
+                match holds_builtin(&self.bytecode.constants[*index]) {
+                    true => Err(self.error(at, format!("constant {} holds a builtin, only GetBuiltin makes those", index))),
+                    false => Ok(()),
+                }
+            }
+            Instruction::GetBuiltin(index) => check(*index, BUILTINS.len(), "builtin"),
+            Instruction::GetGlobal(index) | Instruction::SetGlobal(index) => check(*index, self.bytecode.globals.len(), "global"),
+            Instruction::GetLocal(index) | Instruction::SetLocal(index) => match self.num_locals {
+                Some(num_locals) => check(*index, num_locals, "local"),
+                None => Err(self.error(at, "main has no locals".to_string())),
+            },
+            Instruction::CurrentFunction if self.num_locals.is_none() => Err(self.error(at, "main isn't a function".to_string())),
+            Instruction::Jump(target) | Instruction::JumpNotTruthy(target) | Instruction::JumpTruthy(target) => self.check_target(at, *target),
+            Instruction::JumpTable { targets, default, .. } => {
+                targets.iter().chain([default]).try_for_each(|target| self.check_target(at, *target))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_target(&self, at: usize, target: usize) -> Result<(), VerifyError> {
+        match target <= self.instructions.len() {
+            true => Ok(()),
+            false => Err(self.error(at, format!("jump to {} is past the end, there are {} instructions", target, self.instructions.len()))),
+        }
+    }
+}
+
+fn holds_builtin(object: &Object) -> bool {
+    match object {
+        Object::Builtin(_) => true,
+        Object::Array(elements) => elements.iter().any(holds_builtin),
+        _ => false,
+    }
+}
+
+// how many values an instruction takes off the stack and how many it leaves
+fn effect(instruction: &Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::LoadConstant(_) | Instruction::True | Instruction::False | Instruction::Null
+        | Instruction::GetGlobal(_) | Instruction::GetLocal(_) | Instruction::CurrentFunction | Instruction::GetBuiltin(_) => (0, 1),
+        Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::Mod
+        | Instruction::BitAnd | Instruction::BitOr | Instruction::BitXor | Instruction::ShiftLeft | Instruction::ShiftRight
+        | Instruction::Equal | Instruction::NotEqual | Instruction::LessThan | Instruction::GreaterThan
+        | Instruction::LessEqual | Instruction::GreaterEqual | Instruction::And | Instruction::Or
+        | Instruction::Index => (2, 1),
+        Instruction::Not | Instruction::Neg | Instruction::Length => (1, 1),
+        Instruction::Dup => (1, 2),
+        Instruction::Pop | Instruction::SetGlobal(_) | Instruction::SetLocal(_)
+        | Instruction::JumpNotTruthy(_) | Instruction::JumpTruthy(_) | Instruction::JumpTable { .. } | Instruction::Return => (1, 0),
+        Instruction::Jump(_) => (0, 0),
+        Instruction::Call(arguments) => (arguments + 1, 1),
+        Instruction::Array(count) => (*count, 1),
+        Instruction::SetIndex => (3, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::codegen::{Function, LineTable};
+
+    use super::*;
+
+    fn main(instructions: Vec<Instruction>) -> Bytecode {
+        Bytecode { instructions, constants: vec![Object::Integer(1)], globals: vec!["x".to_string()], lines: LineTable::default() }
+    }
+
+    #[test]
+    fn test_max_stack() {
+        // x = [1, 1 + 1, 1][0], behind a branch that skips nothing
+        let bytecode = main(vec![
+            Instruction::LoadConstant(0),
+            Instruction::LoadConstant(0),
+            Instruction::LoadConstant(0),
+            Instruction::Add,
+            Instruction::LoadConstant(0),
+            Instruction::Array(3),
+            Instruction::LoadConstant(0),
+            Instruction::Index,
+            Instruction::Dup,
+            Instruction::JumpNotTruthy(10),
+            Instruction::SetGlobal(0),
+        ]);
+        assert_eq!(verify(&bytecode), Ok(Verified { max_stack: 3 }));
+    }
+
+    #[test]
+    fn test_rejects_invalid_code() {
+        let error = |instructions: Vec<Instruction>| verify(&main(instructions)).unwrap_err();
+        assert_eq!(error(vec![Instruction::Jump(3)]).message, "jump to 3 is past the end, there are 1 instructions");
+        assert_eq!(error(vec![Instruction::LoadConstant(1), Instruction::Pop]).message, "constant 1 doesn't exist, there are 1");
+        assert_eq!(error(vec![Instruction::GetGlobal(2)]).message, "global 2 doesn't exist, there are 1");
+        assert_eq!(error(vec![Instruction::GetLocal(0)]).message, "main has no locals");
+        assert_eq!(error(vec![Instruction::CurrentFunction, Instruction::Pop]).message, "main isn't a function");
+        assert_eq!(error(vec![Instruction::True, Instruction::Add]), VerifyError {
+            message: "Add needs 2 values but the stack has 1".to_string(),
+            function: "main".to_string(),
+            instruction: 1,
+        });
+        // one branch leaves an extra value where the paths meet
+        let unbalanced = error(vec![Instruction::True, Instruction::JumpNotTruthy(3), Instruction::True, Instruction::Null, Instruction::Pop]);
+        assert_eq!((unbalanced.instruction, unbalanced.message.as_str()), (3, "reached with 1 values on the stack on one path and 0 on another"));
+
+        let function = Function { instructions: vec![Instruction::GetLocal(1), Instruction::Pop], num_locals: 2, num_parameters: 0, lines: LineTable::default() };
+        let bytecode = Bytecode { instructions: vec![], constants: vec![Object::Function(Rc::new(function))], globals: vec![], lines: LineTable::default() };
+        assert_eq!(verify(&bytecode).unwrap_err().to_string(), "constant 0, instruction 2: function runs past its last instruction without returning");
+    }
+}
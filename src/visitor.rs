@@ -1,13 +1,108 @@
-use crate::ast::{BinaryOp, Expression, LiteralValue, PrefixOp, Span, Statement, Type};
+use crate::ast::{AssignOp, BinaryOp, IncrementOp, Expression, Iterable, LiteralValue, MatchArm, Parameter, PrefixOp, Span, Statement, Type};
 
-pub trait StmtVisitor<T> { // 这里为什么要使用T 
-    fn visit_block(&mut self, statements: &[Statement], span: &Span) -> T;
-    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, span: &Span) -> T;
-    fn visit_while(&mut self, condition: &Expression, body: &Statement, span: &Span) -> T;
-    fn visit_for(&mut self, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement, span: &Span) -> T;
-    fn visit_return(&mut self, value: Option<&Expression>, span: &Span) -> T;
-    fn visit_expression(&mut self, expression: &Expression, span: &Span) -> T;
-    fn visit_variable_declaration(&mut self, name: &str, type_ann: &Option<Type>, initializer: Option<&Expression>, span: &Span) -> T;
+// what a visit gives back when the default body only walked the children
+pub trait Walked {
+    fn walked() -> Self;
+}
+
+impl Walked for () {
+    fn walked() -> Self {}
+}
+
+impl<U> Walked for Option<U> {
+    fn walked() -> Self {
+        None
+    }
+}
+
+impl<U: Walked, E> Walked for Result<U, E> {
+    fn walked() -> Self {
+        Ok(U::walked())
+    }
+}
+
+// every visit defaults to walking the children, override only the nodes you care about
+// E is what the expression visits of the same visitor return
+pub trait StmtVisitor<T: Walked, E: Walked = T>: ExprVisitor<E> { // 这里为什么要使用T 
+    fn visit_block(&mut self, statements: &[Statement], _span: &Span) -> T {
+        for stmt in statements {
+            self.visit_stmt(stmt);
+        }
+        T::walked()
+    }
+
+    fn visit_if(&mut self, condition: &Expression, then_branch: &Statement, else_branch: Option<&Statement>, _span: &Span) -> T {
+        self.visit_expr(condition);
+        self.visit_stmt(then_branch);
+        if let Some(else_branch) = else_branch {
+            self.visit_stmt(else_branch);
+        }
+        T::walked()
+    }
+
+    fn visit_while(&mut self, _label: Option<&str>, condition: &Expression, body: &Statement, _span: &Span) -> T {
+        self.visit_expr(condition);
+        self.visit_stmt(body);
+        T::walked()
+    }
+
+    fn visit_for(&mut self, _label: Option<&str>, init: Option<&Statement>, condition: &Expression, increment: Option<&Statement>, body: &Statement, _span: &Span) -> T {
+        if let Some(init) = init {
+            self.visit_stmt(init);
+        }
+        self.visit_expr(condition);
+        if let Some(increment) = increment {
+            self.visit_stmt(increment);
+        }
+        self.visit_stmt(body);
+        T::walked()
+    }
+
+    fn visit_for_in(&mut self, _label: Option<&str>, _variable: &str, iterable: &Iterable, body: &Statement, _span: &Span) -> T {
+        match iterable {
+            Iterable::Range { start, end, .. } => {
+                self.visit_expr(start);
+                self.visit_expr(end);
+            }
+            Iterable::Array(array) => {
+                self.visit_expr(array);
+            }
+        }
+        self.visit_stmt(body);
+        T::walked()
+    }
+
+    fn visit_return(&mut self, value: Option<&Expression>, _span: &Span) -> T {
+        if let Some(value) = value {
+            self.visit_expr(value);
+        }
+        T::walked()
+    }
+
+    fn visit_break(&mut self, _label: Option<&str>, _span: &Span) -> T {
+        T::walked()
+    }
+
+    fn visit_continue(&mut self, _label: Option<&str>, _span: &Span) -> T {
+        T::walked()
+    }
+
+    fn visit_expression(&mut self, expression: &Expression, _span: &Span) -> T {
+        self.visit_expr(expression);
+        T::walked()
+    }
+
+    fn visit_variable_declaration(&mut self, _name: &str, _type_ann: &Option<Type>, initializer: Option<&Expression>, _span: &Span) -> T {
+        if let Some(initializer) = initializer {
+            self.visit_expr(initializer);
+        }
+        T::walked()
+    }
+
+    fn visit_function_declaration(&mut self, _name: &str, _parameters: &[Parameter], _return_type: &Option<Type>, body: &Statement, _span: &Span) -> T {
+        self.visit_stmt(body);
+        T::walked()
+    }
 
     // 遍历路由 完美解耦
     fn visit_stmt(&mut self, stmt: &Statement) -> T {
@@ -19,15 +114,25 @@ pub trait StmtVisitor<T> { // 这里为什么要使用T
                 else_branch, 
                 span 
             } => self.visit_if(condition, then_branch, else_branch.as_deref(), span),
-            Statement::While { condition, body, span } => self.visit_while(condition, body, span),
+            Statement::While { label, condition, body, span } => self.visit_while(label.as_deref(), condition, body, span),
             Statement::For { 
+                label,
                 init, 
                 condition, 
                 increment, 
                 body, 
                 span 
-            } => self.visit_for(init.as_deref(), condition, increment.as_deref(), body, span),
+            } => self.visit_for(label.as_deref(), init.as_deref(), condition, increment.as_deref(), body, span),
+            Statement::ForIn { 
+                label, 
+                variable, 
+                iterable, 
+                body, 
+                span 
+            } => self.visit_for_in(label.as_deref(), variable, iterable, body, span),
             Statement::Return { value, span } => self.visit_return(value.as_deref(), span),
+            Statement::Break { label, span } => self.visit_break(label.as_deref(), span),
+            Statement::Continue { label, span } => self.visit_continue(label.as_deref(), span),
             Statement::Expression { expression, span } => self.visit_expression(expression, span),
             Statement::VariableDeclaration { 
                 name, 
@@ -35,18 +140,86 @@ pub trait StmtVisitor<T> { // 这里为什么要使用T
                 initializer, 
                 span 
             } => self.visit_variable_declaration(name, type_ann, initializer.as_deref(), span),
+            Statement::FunctionDeclaration { 
+                name, 
+                parameters, 
+                return_type, 
+                body, 
+                span 
+            } => self.visit_function_declaration(name, parameters, return_type, body, span),
         }
     }
 }
 
-pub trait ExprVisitor<T> {
-    fn visit_binary(&mut self, left: &Expression, operator: &BinaryOp, right: &Expression, span: &Span) -> T;
-    fn visit_unary(&mut self, operator: &PrefixOp, operand: &Expression, span: &Span) -> T;
-    fn visit_literal(&mut self, value: &LiteralValue, span: &Span) -> T;
-    fn visit_assign(&mut self, target: &Expression, value: &Expression, span: &Span) -> T;
-    fn visit_call(&mut self, callee: &Expression, arguments: &[Expression], span: &Span) -> T;
-    fn visit_variable(&mut self, name: &str, span: &Span) -> T;
+pub trait ExprVisitor<T: Walked> {
+    fn visit_binary(&mut self, left: &Expression, _operator: &BinaryOp, right: &Expression, _span: &Span) -> T {
+        self.visit_expr(left);
+        self.visit_expr(right);
+        T::walked()
+    }
+
+    fn visit_unary(&mut self, _operator: &PrefixOp, operand: &Expression, _span: &Span) -> T {
+        self.visit_expr(operand);
+        T::walked()
+    }
+
+    fn visit_literal(&mut self, _value: &LiteralValue, _span: &Span) -> T {
+        T::walked()
+    }
+
+    fn visit_assign(&mut self, target: &Expression, value: &Expression, _span: &Span) -> T {
+        self.visit_expr(target);
+        self.visit_expr(value);
+        T::walked()
+    }
 
+    fn visit_compound_assign(&mut self, target: &Expression, _operator: &AssignOp, value: &Expression, _span: &Span) -> T {
+        self.visit_expr(target);
+        self.visit_expr(value);
+        T::walked()
+    }
+
+    // the default treats ++x as x += 1
+    fn visit_increment(&mut self, target: &Expression, operator: &IncrementOp, span: &Span) -> T {
+        let (operator, one) = operator.lower(span);
+        self.visit_compound_assign(target, &operator, &one, span)
+    }
+
+    fn visit_index(&mut self, array: &Expression, index: &Expression, _span: &Span) -> T {
+        self.visit_expr(array);
+        self.visit_expr(index);
+        T::walked()
+    }
+
+    fn visit_call(&mut self, callee: &Expression, arguments: &[Expression], _span: &Span) -> T {
+        self.visit_expr(callee);
+        for arg in arguments {
+            self.visit_expr(arg);
+        }
+        T::walked()
+    }
+
+    fn visit_variable(&mut self, _name: &str, _span: &Span) -> T {
+        T::walked()
+    }
+
+    fn visit_array(&mut self, elements: &[Expression], _span: &Span) -> T {
+        for element in elements {
+            self.visit_expr(element);
+        }
+        T::walked()
+    }
+
+    fn visit_match(&mut self, scrutinee: &Expression, arms: &[MatchArm], _span: &Span) -> T {
+        self.visit_expr(scrutinee);
+        for arm in arms {
+            if let Some(guard) = &arm.guard {
+                self.visit_expr(guard);
+            }
+            self.visit_expr(&arm.body);
+        }
+        T::walked()
+    }
 
     fn visit_expr(&mut self, expr: &Expression) -> T {
         match expr {
@@ -59,9 +232,355 @@ pub trait ExprVisitor<T> {
             Expression::Unary { operator, operand, span } => self.visit_unary(operator, operand, span),
             Expression::Literal { value, span } => self.visit_literal(value, span),
             Expression::Assign { target, value, span } => self.visit_assign(target, value, span),
+            Expression::CompoundAssign { 
+                target, 
+                operator, 
+                value, 
+                span 
+            } => self.visit_compound_assign(target, operator, value, span),
+            Expression::Increment { target, operator, span } => self.visit_increment(target, operator, span),
+            Expression::Index { array, index, span } => self.visit_index(array, index, span),
             Expression::Call { callee, arguments, span } => self.visit_call(callee, arguments, span),
-            Expression::Variable { name, span } => self.visit_variable(name, span)
+            Expression::Variable { name, span } => self.visit_variable(name, span),
+            Expression::Array { elements, span } => self.visit_array(elements, span),
+            Expression::Match { scrutinee, arms, span } => self.visit_match(scrutinee, arms, span),
         }
     }
     
-}
\ No newline at end of file
+}
+
+// rewrites the tree in place, override only the nodes you care about
+// and call walk_stmt / walk_expr to keep recursing into children
+pub trait VisitorMut {
+    fn visit_stmt_mut(&mut self, stmt: &mut Statement) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expression) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_stmt<V: VisitorMut + ?Sized>(visitor: &mut V, stmt: &mut Statement) {
+    match stmt {
+        Statement::Block { statements, .. } => {
+            for stmt in statements {
+                visitor.visit_stmt_mut(stmt);
+            }
+        }
+        Statement::If { condition, then_branch, else_branch, .. } => {
+            visitor.visit_expr_mut(condition);
+            visitor.visit_stmt_mut(then_branch);
+            if let Some(else_branch) = else_branch {
+                visitor.visit_stmt_mut(else_branch);
+            }
+        }
+        Statement::While { condition, body, .. } => {
+            visitor.visit_expr_mut(condition);
+            visitor.visit_stmt_mut(body);
+        }
+        Statement::For { init, condition, increment, body, .. } => {
+            if let Some(init) = init {
+                visitor.visit_stmt_mut(init);
+            }
+            visitor.visit_expr_mut(condition);
+            if let Some(increment) = increment {
+                visitor.visit_stmt_mut(increment);
+            }
+            visitor.visit_stmt_mut(body);
+        }
+        Statement::ForIn { iterable, body, .. } => {
+            match iterable {
+                Iterable::Range { start, end, .. } => {
+                    visitor.visit_expr_mut(start);
+                    visitor.visit_expr_mut(end);
+                }
+                Iterable::Array(array) => visitor.visit_expr_mut(array),
+            }
+            visitor.visit_stmt_mut(body);
+        }
+        Statement::Return { value, .. } => {
+            if let Some(value) = value {
+                visitor.visit_expr_mut(value);
+            }
+        }
+        Statement::Break { .. } | Statement::Continue { .. } => {}
+        Statement::Expression { expression, .. } => visitor.visit_expr_mut(expression),
+        Statement::VariableDeclaration { initializer, .. } => {
+            if let Some(initializer) = initializer {
+                visitor.visit_expr_mut(initializer);
+            }
+        }
+        Statement::FunctionDeclaration { body, .. } => visitor.visit_stmt_mut(body),
+    }
+}
+
+pub fn walk_expr<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expression) {
+    match expr {
+        Expression::Binary { left, right, .. } => {
+            visitor.visit_expr_mut(left);
+            visitor.visit_expr_mut(right);
+        }
+        Expression::Unary { operand, .. } | Expression::Increment { target: operand, .. } => visitor.visit_expr_mut(operand),
+        Expression::Literal { .. } | Expression::Variable { .. } => {}
+        Expression::Call { callee, arguments, .. } => {
+            visitor.visit_expr_mut(callee);
+            for arg in arguments {
+                visitor.visit_expr_mut(arg);
+            }
+        }
+        Expression::Assign { target, value, .. } | Expression::CompoundAssign { target, value, .. } => {
+            visitor.visit_expr_mut(target);
+            visitor.visit_expr_mut(value);
+        }
+        Expression::Index { array, index, .. } => {
+            visitor.visit_expr_mut(array);
+            visitor.visit_expr_mut(index);
+        }
+        Expression::Array { elements, .. } => {
+            for element in elements {
+                visitor.visit_expr_mut(element);
+            }
+        }
+        Expression::Match { scrutinee, arms, .. } => {
+            visitor.visit_expr_mut(scrutinee);
+            for arm in arms {
+                if let Some(guard) = &mut arm.guard {
+                    visitor.visit_expr_mut(guard);
+                }
+                visitor.visit_expr_mut(&mut arm.body);
+            }
+        }
+    }
+}
+
+
+// consumes the tree and builds a new one, the default rebuilds every node unchanged
+// override fold_stmt / fold_expr and call fold_stmt_children / fold_expr_children to recurse
+pub trait Folder {
+    fn fold_program(&mut self, program: Vec<Statement>) -> Vec<Statement> {
+        program.into_iter().map(|stmt| self.fold_stmt(stmt)).collect()
+    }
+
+    fn fold_stmt(&mut self, stmt: Statement) -> Statement {
+        fold_stmt_children(self, stmt)
+    }
+
+    fn fold_expr(&mut self, expr: Expression) -> Expression {
+        fold_expr_children(self, expr)
+    }
+}
+
+pub fn fold_stmt_children<F: Folder + ?Sized>(folder: &mut F, stmt: Statement) -> Statement {
+    match stmt {
+        Statement::Block { statements, span } => Statement::Block {
+            statements: folder.fold_program(statements),
+            span,
+        },
+        Statement::If { condition, then_branch, else_branch, span } => Statement::If {
+            condition: Box::new(folder.fold_expr(*condition)),
+            then_branch: Box::new(folder.fold_stmt(*then_branch)),
+            else_branch: else_branch.map(|stmt| Box::new(folder.fold_stmt(*stmt))),
+            span,
+        },
+        Statement::While { label, condition, body, span } => Statement::While {
+            label,
+            condition: Box::new(folder.fold_expr(*condition)),
+            body: Box::new(folder.fold_stmt(*body)),
+            span,
+        },
+        Statement::For { label, init, condition, increment, body, span } => Statement::For {
+            label,
+            init: init.map(|stmt| Box::new(folder.fold_stmt(*stmt))),
+            condition: Box::new(folder.fold_expr(*condition)),
+            increment: increment.map(|stmt| Box::new(folder.fold_stmt(*stmt))),
+            body: Box::new(folder.fold_stmt(*body)),
+            span,
+        },
+        Statement::ForIn { label, variable, iterable, body, span } => Statement::ForIn {
+            label,
+            variable,
+            iterable: match iterable {
+                Iterable::Range { start, end, inclusive } => Iterable::Range {
+                    start: Box::new(folder.fold_expr(*start)),
+                    end: Box::new(folder.fold_expr(*end)),
+                    inclusive,
+                },
+                Iterable::Array(array) => Iterable::Array(Box::new(folder.fold_expr(*array))),
+            },
+            body: Box::new(folder.fold_stmt(*body)),
+            span,
+        },
+        Statement::Return { value, span } => Statement::Return {
+            value: value.map(|expr| Box::new(folder.fold_expr(*expr))),
+            span,
+        },
+        Statement::Break { .. } | Statement::Continue { .. } => stmt,
+        Statement::Expression { expression, span } => Statement::Expression {
+            expression: Box::new(folder.fold_expr(*expression)),
+            span,
+        },
+        Statement::VariableDeclaration { name, type_ann, initializer, span } => Statement::VariableDeclaration {
+            name,
+            type_ann,
+            initializer: initializer.map(|expr| Box::new(folder.fold_expr(*expr))),
+            span,
+        },
+        Statement::FunctionDeclaration { name, parameters, return_type, body, span } => Statement::FunctionDeclaration {
+            name,
+            parameters,
+            return_type,
+            body: Box::new(folder.fold_stmt(*body)),
+            span,
+        },
+    }
+}
+
+pub fn fold_expr_children<F: Folder + ?Sized>(folder: &mut F, expr: Expression) -> Expression {
+    match expr {
+        Expression::Binary { left, operator, right, span } => Expression::Binary {
+            left: Box::new(folder.fold_expr(*left)),
+            operator,
+            right: Box::new(folder.fold_expr(*right)),
+            span,
+        },
+        Expression::Unary { operator, operand, span } => Expression::Unary {
+            operator,
+            operand: Box::new(folder.fold_expr(*operand)),
+            span,
+        },
+        Expression::Literal { .. } | Expression::Variable { .. } => expr,
+        Expression::Call { callee, arguments, span } => Expression::Call {
+            callee: Box::new(folder.fold_expr(*callee)),
+            arguments: arguments.into_iter().map(|arg| folder.fold_expr(arg)).collect(),
+            span,
+        },
+        Expression::Assign { target, value, span } => Expression::Assign {
+            target: Box::new(folder.fold_expr(*target)),
+            value: Box::new(folder.fold_expr(*value)),
+            span,
+        },
+        Expression::CompoundAssign { target, operator, value, span } => Expression::CompoundAssign {
+            target: Box::new(folder.fold_expr(*target)),
+            operator,
+            value: Box::new(folder.fold_expr(*value)),
+            span,
+        },
+        Expression::Increment { target, operator, span } => Expression::Increment {
+            target: Box::new(folder.fold_expr(*target)),
+            operator,
+            span,
+        },
+        Expression::Index { array, index, span } => Expression::Index {
+            array: Box::new(folder.fold_expr(*array)),
+            index: Box::new(folder.fold_expr(*index)),
+            span,
+        },
+        Expression::Array { elements, span } => Expression::Array {
+            elements: elements.into_iter().map(|element| folder.fold_expr(element)).collect(),
+            span,
+        },
+        Expression::Match { scrutinee, arms, span } => Expression::Match {
+            scrutinee: Box::new(folder.fold_expr(*scrutinee)),
+            arms: arms.into_iter().map(|arm| MatchArm {
+                pattern: arm.pattern,
+                guard: arm.guard.map(|guard| Box::new(folder.fold_expr(*guard))),
+                body: Box::new(folder.fold_expr(*arm.body)),
+                span: arm.span,
+            }).collect(),
+            span,
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::build::*;
+
+    use super::*;
+
+    fn sample() -> Vec<Statement> {
+        // def y = x; while (x) { x = !x; }
+        vec![
+            decl("y", var("x")),
+            Statement::While { label: None, condition: var("x"), body: block(vec![expr(assign(var("x"), unary(PrefixOp::Not, var("x"))))]), span: Span::default() },
+        ]
+    }
+
+    fn variables(program: &mut [Statement]) -> Vec<String> {
+        struct Collect(Vec<String>);
+        impl VisitorMut for Collect {
+            fn visit_expr_mut(&mut self, expr: &mut Expression) {
+                if let Expression::Variable { name, .. } = expr {
+                    self.0.push(name.clone());
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut collect = Collect(Vec::new());
+        for stmt in program {
+            collect.visit_stmt_mut(stmt);
+        }
+        collect.0
+    }
+
+    #[test]
+    fn test_visitor_mut_renames_in_place() {
+        struct Rename;
+        impl VisitorMut for Rename {
+            fn visit_expr_mut(&mut self, expr: &mut Expression) {
+                if let Expression::Variable { name, .. } = expr {
+                    *name = format!("{}1", name);
+                }
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut program = sample();
+        for stmt in &mut program {
+            Rename.visit_stmt_mut(stmt);
+        }
+        assert_eq!(variables(&mut program), vec!["x1", "x1", "x1", "x1"]);
+    }
+
+    #[test]
+    fn test_default_visits_walk_children() {
+        // only variables are overridden, the defaults reach every one of them
+        struct Names(Vec<String>);
+        impl StmtVisitor<()> for Names {}
+        impl ExprVisitor<()> for Names {
+            fn visit_variable(&mut self, name: &str, _span: &Span) {
+                self.0.push(name.to_string());
+            }
+        }
+
+        let mut names = Names(Vec::new());
+        for stmt in &sample() {
+            names.visit_stmt(stmt);
+        }
+        assert_eq!(names.0, vec!["x", "x", "x", "x"]);
+    }
+
+    #[test]
+    fn test_folder_rebuilds_tree() {
+        // replaces every !operand with the bare operand
+        struct DropNot;
+        impl Folder for DropNot {
+            fn fold_expr(&mut self, expr: Expression) -> Expression {
+                match expr {
+                    Expression::Unary { operator: PrefixOp::Not, operand, .. } => self.fold_expr(*operand),
+                    expr => fold_expr_children(self, expr),
+                }
+            }
+        }
+
+        let mut program = DropNot.fold_program(sample());
+        let Statement::While { body, .. } = &program[1] else { unreachable!() };
+        let Statement::Block { statements, .. } = body.as_ref() else { unreachable!() };
+        let Statement::Expression { expression, .. } = &statements[0] else { unreachable!() };
+        assert!(matches!(expression.as_ref(), Expression::Assign { value, .. } if matches!(value.as_ref(), Expression::Variable { .. })));
+        assert_eq!(variables(&mut program).len(), 4);
+    }
+}
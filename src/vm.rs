@@ -1,16 +1,621 @@
-use crate::codegen::{Instruction, Object};
+use std::{collections::HashMap, fmt::Display, rc::Rc};
+
+use crate::{builtins::BUILTINS, code::{self, Opcode}, codegen::{Bytecode, Function, Instruction, LineTable, Object}, verifier};
+
+const STACK_SIZE: usize = 2048;
 
 pub struct VM {
     constants: Vec<Object>,
     globals: Vec<Object>,
     stack: Vec<Object>,
-    sp: usize,
-    frams: Vec<Frame>,
+    sp: usize, // always points to the next free slot, the top of the stack is stack[sp - 1]
+    frames: Vec<Frame>,
     frame_index: usize,
+    functions: HashMap<*const Function, Rc<Code>>, // every function in the constants, encoded once
+    max_stack: usize,                             // the most any frame pushes above its locals
+    source: String, // the file the code was compiled from, for error locations
+}
+
+// a function the way the vm runs it
+struct Code {
+    instructions: Vec<u8>,
+    lines: LineTable, // keyed by byte offset into instructions
+    num_locals: usize,
+    num_parameters: usize,
 }
 
+// the locals of a call are stack[base_pointer..base_pointer + num_locals], the parameters first,
+// its own values go above them and the function being called sits right below
 struct Frame {
-    instructions: Vec<Instruction>,
+    code: Rc<Code>,
     ip: usize,
-    base_Pointer: usize
-}
\ No newline at end of file
+    base_pointer: usize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub trace: Vec<String>, // file:line:col of each frame, innermost first
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for location in &self.trace {
+            write!(f, "\n    at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl VM {
+    // only verified code runs, so indexes, jumps and stack heights aren't checked again while
+    // running; what's left are the errors that depend on the values
+    pub fn new(bytecode: Bytecode) -> Result<Self, String> {
+        let verified = verifier::verify(&bytecode).map_err(|e| e.to_string())?;
+        if verified.max_stack > STACK_SIZE {
+            return Err("stack overflow".to_string());
+        }
+        let mut functions = HashMap::new();
+        for (_, function) in verifier::functions(&bytecode.constants) {
+            let code = encode(&function.instructions, &function.lines, function.num_locals, function.num_parameters)?;
+            functions.insert(Rc::as_ptr(function), Rc::new(code));
+        }
+        let main = Frame { code: Rc::new(encode(&bytecode.instructions, &bytecode.lines, 0, 0)?), ip: 0, base_pointer: 0 };
+        Ok(VM {
+            constants: bytecode.constants,
+            globals: vec![Object::Null; bytecode.globals.len()],
+            stack: vec![Object::Null; STACK_SIZE],
+            sp: 0,
+            frames: vec![main],
+            frame_index: 0,
+            functions,
+            max_stack: verified.max_stack,
+            source: "<script>".to_string(),
+        })
+    }
+
+    pub fn set_source(&mut self, source: &str) {
+        self.source = source.to_string();
+    }
+
+    // the value the last Pop (or a top level ret) took off the stack
+    // with a full stack the last instruction pushed, so nothing was popped
+    pub fn last_popped(&self) -> &Object {
+        self.stack.get(self.sp).unwrap_or(&Object::Null)
+    }
+
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.execute().map_err(|message| RuntimeError { message, trace: self.trace() })
+    }
+
+    // where every frame is, each one's ip is already past the instruction it's running
+    fn trace(&self) -> Vec<String> {
+        self.frames[..=self.frame_index].iter().rev().map(|frame| {
+            match frame.code.lines.lookup(frame.ip.saturating_sub(1)) {
+                Some(span) => format!("{}:{}:{}", self.source, span.line, span.column),
+                None => self.source.clone(),
+            }
+        }).collect()
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
+        while self.frame().ip < self.frame().code.instructions.len() {
+            let op = Opcode::from_byte(self.read_u8() as u8).unwrap();
+
+            match op {
+                Opcode::LoadConstant => {
+                    let index = self.read_u16();
+                    self.push(self.constants[index].clone());
+                }
+                Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod
+                | Opcode::BitAnd | Opcode::BitOr | Opcode::BitXor | Opcode::ShiftLeft | Opcode::ShiftRight
+                | Opcode::Equal | Opcode::NotEqual
+                | Opcode::LessThan | Opcode::GreaterThan | Opcode::LessEqual | Opcode::GreaterEqual => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.push(binary(op, left, right)?);
+                }
+                Opcode::And | Opcode::Or => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let result = match (op, left, right) {
+                        (Opcode::And, Object::Boolean(l), Object::Boolean(r)) => l && r,
+                        (Opcode::Or, Object::Boolean(l), Object::Boolean(r)) => l || r,
+                        (_, l, r) => return Err(format!("{:?} cannot be applied to {:?} and {:?}", op, l, r)),
+                    };
+                    self.push(Object::Boolean(result));
+                }
+                Opcode::Not => match self.pop() {
+                    Object::Boolean(b) => self.push(Object::Boolean(!b)),
+                    other => return Err(format!("Not cannot be applied to {:?}", other)),
+                },
+                Opcode::Neg => match self.pop() {
+                    Object::Integer(n) => self.push(Object::Integer(n.checked_neg().ok_or("integer overflow")?)),
+                    other => return Err(format!("Neg cannot be applied to {:?}", other)),
+                },
+                Opcode::True => self.push(Object::Boolean(true)),
+                Opcode::False => self.push(Object::Boolean(false)),
+                Opcode::Null => self.push(Object::Null),
+                Opcode::Dup => {
+                    let top = self.pop();
+                    self.push(top.clone());
+                    self.push(top);
+                }
+                Opcode::Pop => {
+                    self.pop();
+                }
+                Opcode::Jump => self.frame_mut().ip = self.read_u16(),
+                Opcode::JumpNotTruthy => {
+                    let target = self.read_u16();
+                    if !truthy(&self.pop()) {
+                        self.frame_mut().ip = target;
+                    }
+                }
+                Opcode::JumpTruthy => {
+                    let target = self.read_u16();
+                    if truthy(&self.pop()) {
+                        self.frame_mut().ip = target;
+                    }
+                }
+                Opcode::JumpTable => self.jump_table(),
+                Opcode::SetGlobal => {
+                    let index = self.read_u16();
+                    self.globals[index] = self.pop();
+                }
+                Opcode::GetGlobal => {
+                    let index = self.read_u16();
+                    self.push(self.globals[index].clone());
+                }
+                Opcode::SetLocal => {
+                    let slot = self.frame().base_pointer + self.read_u8();
+                    self.stack[slot] = self.pop();
+                }
+                Opcode::GetLocal => {
+                    let slot = self.frame().base_pointer + self.read_u8();
+                    self.push(self.stack[slot].clone());
+                }
+                // the callee stays right below the frame's locals for the whole call
+                Opcode::CurrentFunction => {
+                    let callee = self.frame().base_pointer - 1;
+                    self.push(self.stack[callee].clone());
+                }
+                Opcode::GetBuiltin => {
+                    let index = self.read_u8();
+                    self.push(Object::Builtin(index));
+                }
+                Opcode::Call => {
+                    let arguments = self.read_u8();
+                    self.call(arguments)?;
+                }
+                // ret at the top level ends the program with that value
+                Opcode::Return if self.frame_index == 0 => {
+                    self.pop();
+                    return Ok(());
+                }
+                // the caller gets the value in place of the function and its arguments
+                Opcode::Return => {
+                    let value = self.pop();
+                    self.sp = self.frame().base_pointer - 1;
+                    self.frames.pop();
+                    self.frame_index -= 1;
+                    self.push(value);
+                }
+                Opcode::Array => {
+                    let start = self.sp - self.read_u16();
+                    let elements = self.stack[start..self.sp].to_vec();
+                    self.sp = start;
+                    self.push(Object::Array(elements));
+                }
+                Opcode::Index => {
+                    let index = self.pop();
+                    let array = self.pop();
+                    let (elements, i) = element(&array, &index)?;
+                    self.push(elements[i].clone());
+                }
+                Opcode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let array = self.pop();
+                    let (elements, i) = element(&array, &index)?;
+                    let mut elements = elements.to_vec();
+                    elements[i] = value;
+                    self.push(Object::Array(elements));
+                }
+                Opcode::Length => match self.pop() {
+                    Object::Array(elements) => self.push(Object::Integer(elements.len() as i64)),
+                    other => return Err(format!("{:?} has no length", other)),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // the function and its arguments are on the stack, the arguments become its first locals
+    fn call(&mut self, arguments: usize) -> Result<(), String> {
+        // functions can't be made while running, every one is a constant that was encoded up front
+        let code = match &self.stack[self.sp - arguments - 1] {
+            Object::Function(function) => self.functions[&Rc::as_ptr(function)].clone(),
+            Object::Builtin(index) => return self.call_builtin(*index, arguments),
+            other => return Err(format!("{:?} is not a function", other)),
+        };
+        if arguments != code.num_parameters {
+            return Err(format!("expected {} arguments but got {}", code.num_parameters, arguments));
+        }
+        let base_pointer = self.sp - arguments;
+        if base_pointer + code.num_locals + self.max_stack > STACK_SIZE {
+            return Err("stack overflow".to_string());
+        }
+        // the other locals start out as null, not as whatever an earlier call left there
+        self.stack[self.sp..base_pointer + code.num_locals].fill(Object::Null);
+        self.sp = base_pointer + code.num_locals;
+        self.frames.push(Frame { code, ip: 0, base_pointer });
+        self.frame_index += 1;
+        Ok(())
+    }
+
+    // a builtin runs right away, its result replaces it and its arguments like a return would
+    fn call_builtin(&mut self, index: usize, arguments: usize) -> Result<(), String> {
+        let builtin = &BUILTINS[index];
+        if arguments != builtin.arity {
+            return Err(format!("expected {} arguments but got {}", builtin.arity, arguments));
+        }
+        let result = (builtin.run)(&self.stack[self.sp - arguments..self.sp])?;
+        self.sp -= arguments + 1;
+        self.push(result);
+        Ok(())
+    }
+
+    // the targets are read in place, only the one taken is decoded
+    fn jump_table(&mut self) {
+        let code = self.frame().code.clone();
+        let ip = self.frame().ip;
+        let (low, size) = code::read_zigzag(&code.instructions[ip..]).unwrap();
+        let default = short(&code.instructions, ip + size);
+        let (count, count_size) = code::read_varint(&code.instructions[ip + size + 2..]).unwrap();
+        let count = count as usize;
+        let targets = ip + size + 2 + count_size;
+
+        let slot = match self.pop() {
+            Object::Integer(n) => n.checked_sub(low).and_then(|i| usize::try_from(i).ok()).filter(|&i| i < count),
+            _ => None,
+        };
+        self.frame_mut().ip = match slot {
+            Some(slot) => short(&code.instructions, targets + 2 * slot),
+            None => default,
+        };
+    }
+
+    fn read_u8(&mut self) -> usize {
+        let frame = self.frame_mut();
+        let byte = frame.code.instructions[frame.ip];
+        frame.ip += 1;
+        byte as usize
+    }
+
+    fn read_u16(&mut self) -> usize {
+        let frame = self.frame_mut();
+        let value = short(&frame.code.instructions, frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn frame(&self) -> &Frame {
+        &self.frames[self.frame_index]
+    }
+
+    fn frame_mut(&mut self) -> &mut Frame {
+        &mut self.frames[self.frame_index]
+    }
+
+    // every frame was checked to fit when it was entered
+    fn push(&mut self, object: Object) {
+        self.stack[self.sp] = object;
+        self.sp += 1;
+    }
+
+    // the slot is left as is so last_popped can still read it
+    fn pop(&mut self) -> Object {
+        self.sp -= 1;
+        self.stack[self.sp].clone()
+    }
+}
+
+fn encode(instructions: &[Instruction], lines: &LineTable, num_locals: usize, num_parameters: usize) -> Result<Code, String> {
+    let lines = lines.remap(&code::offsets(instructions)?);
+    Ok(Code { instructions: code::encode(instructions)?, lines, num_locals, num_parameters })
+}
+
+// same rules as the evaluator, only false and null are falsy
+pub fn truthy(object: &Object) -> bool {
+    !matches!(object, Object::Boolean(false) | Object::Null)
+}
+
+fn short(instructions: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([instructions[offset], instructions[offset + 1]]) as usize
+}
+
+pub fn binary(op: Opcode, left: Object, right: Object) -> Result<Object, String> {
+    let result = match (&left, &right) {
+        (Object::Integer(l), Object::Integer(r)) => {
+            let (l, r) = (*l, *r);
+            match op {
+                Opcode::Add => l.checked_add(r).map(Object::Integer),
+                Opcode::Sub => l.checked_sub(r).map(Object::Integer),
+                Opcode::Mul => l.checked_mul(r).map(Object::Integer),
+                Opcode::Div | Opcode::Mod if r == 0 => return Err("division by zero".to_string()),
+                Opcode::Div => l.checked_div(r).map(Object::Integer),
+                Opcode::Mod => l.checked_rem(r).map(Object::Integer),
+                Opcode::BitAnd => Some(Object::Integer(l & r)),
+                Opcode::BitOr => Some(Object::Integer(l | r)),
+                Opcode::BitXor => Some(Object::Integer(l ^ r)),
+                Opcode::ShiftLeft | Opcode::ShiftRight if !(0..64).contains(&r) => return Err(format!("cannot shift by {}", r)),
+                Opcode::ShiftLeft => Some(Object::Integer(l << r)),
+                Opcode::ShiftRight => Some(Object::Integer(l >> r)),
+                Opcode::Equal => Some(Object::Boolean(l == r)),
+                Opcode::NotEqual => Some(Object::Boolean(l != r)),
+                Opcode::LessThan => Some(Object::Boolean(l < r)),
+                Opcode::GreaterThan => Some(Object::Boolean(l > r)),
+                Opcode::LessEqual => Some(Object::Boolean(l <= r)),
+                Opcode::GreaterEqual => Some(Object::Boolean(l >= r)),
+                _ => return Err(format!("{:?} is not a binary operator", op)),
+            }
+            .ok_or_else(|| "integer overflow".to_string())?
+        }
+        (Object::String(l), Object::String(r)) if op == Opcode::Add => Object::String(format!("{}{}", l, r).into()),
+        // constants are interned, strings built while running still need the comparison
+        (Object::String(l), Object::String(r)) if matches!(op, Opcode::Equal | Opcode::NotEqual) => {
+            Object::Boolean((Rc::ptr_eq(l, r) || l == r) == (op == Opcode::Equal))
+        }
+        (Object::Char(l), Object::Char(r)) => match op {
+            Opcode::LessThan => Object::Boolean(l < r),
+            Opcode::GreaterThan => Object::Boolean(l > r),
+            Opcode::LessEqual => Object::Boolean(l <= r),
+            Opcode::GreaterEqual => Object::Boolean(l >= r),
+            Opcode::Equal => Object::Boolean(l == r),
+            Opcode::NotEqual => Object::Boolean(l != r),
+            _ => return Err(format!("{:?} cannot be applied to chars", op)),
+        },
+        _ => match op {
+            Opcode::Equal => Object::Boolean(left == right),
+            Opcode::NotEqual => Object::Boolean(left != right),
+            _ => return Err(format!("{:?} cannot be applied to {:?} and {:?}", op, left, right)),
+        },
+    };
+    Ok(result)
+}
+
+// the elements of `array` and `index` checked against them
+pub fn element<'a>(array: &'a Object, index: &Object) -> Result<(&'a [Object], usize), String> {
+    let Object::Array(elements) = array else {
+        return Err(format!("cannot index into {:?}", array));
+    };
+    let Object::Integer(n) = index else {
+        return Err(format!("cannot index with {:?}", index));
+    };
+    match usize::try_from(*n) {
+        Ok(i) if i < elements.len() => Ok((elements, i)),
+        _ => Err(format!("index {} out of bounds for length {}", n, elements.len())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::{build::*, BinaryOp, Expression, PrefixOp, Span, Statement}, codegen::CodeGen};
+
+    use crate::codegen::Instruction;
+
+    use super::*;
+
+    // compiles `expression;` and runs it, the statement's Pop leaves the value behind
+    fn eval(expression: Box<Expression>) -> Result<Object, String> {
+        let program = vec![expr(expression)];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program)?;
+        let mut vm = VM::new(codegen.bytecode())?;
+        vm.run().map_err(|e| e.message)?;
+        Ok(vm.last_popped().clone())
+    }
+
+    // runs hand written instructions, constant folding would otherwise hide most operators
+    fn execute(instructions: Vec<Instruction>, constants: Vec<Object>) -> Result<Object, String> {
+        let mut vm = VM::new(Bytecode { instructions, constants, globals: vec!["x".to_string()], lines: LineTable::default() })?;
+        vm.run().map_err(|e| e.message)?;
+        Ok(vm.last_popped().clone())
+    }
+
+    #[test]
+    fn test_binary_opcodes() {
+        let cases = [
+            (Instruction::Add, 7, 2, Object::Integer(9)),
+            (Instruction::Sub, 7, 2, Object::Integer(5)),
+            (Instruction::Mul, 7, 2, Object::Integer(14)),
+            (Instruction::Div, 7, 2, Object::Integer(3)),
+            (Instruction::Mod, 7, 2, Object::Integer(1)),
+            (Instruction::BitAnd, 6, 3, Object::Integer(2)),
+            (Instruction::BitOr, 6, 3, Object::Integer(7)),
+            (Instruction::BitXor, 6, 3, Object::Integer(5)),
+            (Instruction::ShiftLeft, 6, 3, Object::Integer(48)),
+            (Instruction::ShiftRight, 6, 1, Object::Integer(3)),
+            (Instruction::Equal, 2, 2, Object::Boolean(true)),
+            (Instruction::NotEqual, 2, 2, Object::Boolean(false)),
+            (Instruction::LessThan, 2, 2, Object::Boolean(false)),
+            (Instruction::GreaterThan, 3, 2, Object::Boolean(true)),
+            (Instruction::LessEqual, 2, 2, Object::Boolean(true)),
+            (Instruction::LessEqual, 3, 2, Object::Boolean(false)),
+            (Instruction::GreaterEqual, 2, 2, Object::Boolean(true)),
+            (Instruction::GreaterEqual, 1, 2, Object::Boolean(false)),
+        ];
+        for (instruction, l, r, expected) in cases {
+            let program = vec![Instruction::LoadConstant(0), Instruction::LoadConstant(1), instruction.clone(), Instruction::Pop];
+            let result = execute(program, vec![Object::Integer(l), Object::Integer(r)]);
+            assert_eq!(result, Ok(expected), "{:?}", instruction);
+        }
+
+        let divide = vec![Instruction::LoadConstant(0), Instruction::LoadConstant(1), Instruction::Div, Instruction::Pop];
+        assert_eq!(execute(divide, vec![Object::Integer(1), Object::Integer(0)]), Err("division by zero".to_string()));
+    }
+
+    #[test]
+    fn test_unary_and_literal_opcodes() {
+        let run = |instructions: Vec<Instruction>| execute(instructions, vec![Object::Integer(5)]);
+        assert_eq!(run(vec![Instruction::LoadConstant(0), Instruction::Neg, Instruction::Pop]), Ok(Object::Integer(-5)));
+        assert_eq!(run(vec![Instruction::True, Instruction::Not, Instruction::Pop]), Ok(Object::Boolean(false)));
+        assert_eq!(run(vec![Instruction::True, Instruction::False, Instruction::And, Instruction::Pop]), Ok(Object::Boolean(false)));
+        assert_eq!(run(vec![Instruction::True, Instruction::False, Instruction::Or, Instruction::Pop]), Ok(Object::Boolean(true)));
+        assert_eq!(run(vec![Instruction::Null, Instruction::Pop]), Ok(Object::Null));
+        // Dup keeps a copy below the one SetGlobal takes
+        let dup = vec![Instruction::LoadConstant(0), Instruction::Dup, Instruction::SetGlobal(0), Instruction::Neg, Instruction::GetGlobal(0), Instruction::Add, Instruction::Pop];
+        assert_eq!(run(dup), Ok(Object::Integer(0)));
+        assert!(run(vec![Instruction::True, Instruction::Neg]).is_err());
+        // caught before running anything
+        assert_eq!(run(vec![Instruction::Pop]), Err("main, instruction 0: Pop needs 1 values but the stack has 0".to_string()));
+    }
+
+    #[test]
+    fn test_compiled_operators() {
+        assert_eq!(eval(bin(unary(PrefixOp::Neg, int(4)), BinaryOp::LessEqual, int(-4))), Ok(Object::Boolean(true)));
+        assert_eq!(eval(bin(int(3), BinaryOp::GreaterEqual, int(4))), Ok(Object::Boolean(false)));
+        assert_eq!(eval(bin(unary(PrefixOp::Not, boolean(true)), BinaryOp::Or, bin(int(1), BinaryOp::LessThan, int(2)))), Ok(Object::Boolean(true)));
+        assert_eq!(eval(index(array(vec![*int(1), *int(2), *int(3)]), int(2))), Ok(Object::Integer(3)));
+    }
+
+    #[test]
+    fn test_jump_table() {
+        // match x { -1 => 10, 0 | 2 => 20, 1 => 30, _ => 0 }, x comes from an array so it isn't folded
+        let pick = |x: i64| {
+            let arms = vec![
+                arm(int_pattern(-1), None, int(10)),
+                arm(or(vec![int_pattern(0), int_pattern(2)]), None, int(20)),
+                arm(int_pattern(1), None, int(30)),
+                arm(wildcard(), None, int(0)),
+            ];
+            eval(match_on(index(array(vec![*int(x)]), int(0)), arms))
+        };
+        assert_eq!(pick(-1), Ok(Object::Integer(10)));
+        assert_eq!(pick(2), Ok(Object::Integer(20)));
+        assert_eq!(pick(1), Ok(Object::Integer(30)));
+        assert_eq!(pick(3), Ok(Object::Integer(0)));
+        assert_eq!(pick(-9), Ok(Object::Integer(0)));
+    }
+
+    #[test]
+    fn test_error_location() {
+        let at = |line: usize, column: usize| Span { start: 0, end: 0, line, column };
+        // line 3: `[1][2];`, the failing Index points at the index expression
+        let array = Box::new(Expression::Array { elements: vec![*int(1)], span: at(3, 1) });
+        let index = Box::new(Expression::Index { array, index: int(2), span: at(3, 4) });
+        let program = vec![Statement::Expression { expression: index, span: at(3, 1) }];
+        let mut codegen = CodeGen::new();
+        codegen.compile(program).unwrap();
+        let mut vm = VM::new(codegen.bytecode()).unwrap();
+        vm.set_source("main.rc");
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.trace, ["main.rc:3:4"]);
+        assert_eq!(error.to_string(), "index 2 out of bounds for length 1\n    at main.rc:3:4");
+    }
+
+    fn run(program: Vec<Statement>) -> Result<Object, String> {
+        let mut codegen = CodeGen::new();
+        codegen.compile(program)?;
+        let mut vm = VM::new(codegen.bytecode())?;
+        vm.run().map_err(|e| e.message)?;
+        Ok(vm.last_popped().clone())
+    }
+
+    #[test]
+    fn test_recursion() {
+        // fun fib(n) { if n < 2 { ret n; } ret fib(n - 1) + fib(n - 2); } fib(20);
+        let fib = function("fib", &["n"], vec![
+            if_then(bin(var("n"), BinaryOp::LessThan, int(2)), vec![ret(var("n"))]),
+            ret(bin(
+                call("fib", vec![*bin(var("n"), BinaryOp::Minus, int(1))]),
+                BinaryOp::Plus,
+                call("fib", vec![*bin(var("n"), BinaryOp::Minus, int(2))]),
+            )),
+        ]);
+        let program = vec![fib, expr(call("fib", vec![*int(20)]))];
+        assert_eq!(run(program), Ok(Object::Integer(6765)));
+    }
+
+    #[test]
+    fn test_nested_functions() {
+        // fun outer(n) { fun down(k) { if k == 0 { ret 10; } ret down(k - 1); } ret down(n); } outer(3);
+        let down = function("down", &["k"], vec![
+            if_then(bin(var("k"), BinaryOp::Equal, int(0)), vec![ret(int(10))]),
+            ret(call("down", vec![*bin(var("k"), BinaryOp::Minus, int(1))])),
+        ]);
+        let outer = function("outer", &["n"], vec![down, ret(call("down", vec![*var("n")]))]);
+        let program = vec![outer, expr(call("outer", vec![*int(3)]))];
+        assert_eq!(run(program), Ok(Object::Integer(10)));
+
+        // fun outer(x) { fun inner() { ret x; } ret inner(); } outer(1);
+        // inner would need a closure to see x, so this doesn't compile
+        let inner = function("inner", &[], vec![ret(var("x"))]);
+        let outer = function("outer", &["x"], vec![inner, ret(call("inner", vec![]))]);
+        let program = vec![outer, expr(call("outer", vec![*int(1)]))];
+        assert_eq!(run(program), Err("0:0: `x` belongs to an enclosing function, closures aren't supported yet".to_string()));
+    }
+
+    #[test]
+    fn test_locals() {
+        // fun f(a, b) { let c = a * 10; let d = c + b; ret [a, b, c, d]; } 1 + f(2, 3)[3];
+        // the 1 stays on the caller's stack below the call, the callee's locals mustn't touch it
+        let f = function("f", &["a", "b"], vec![
+            decl("c", bin(var("a"), BinaryOp::Multiply, int(10))),
+            decl("d", bin(var("c"), BinaryOp::Plus, var("b"))),
+            ret(array(vec![*var("a"), *var("b"), *var("c"), *var("d")])),
+        ]);
+        let result = index(call("f", vec![*int(2), *int(3)]), int(3));
+        let program = vec![f, expr(bin(int(1), BinaryOp::Plus, result))];
+        assert_eq!(run(program), Ok(Object::Integer(24)));
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let identity = || function("id", &["x"], vec![ret(var("x"))]);
+        let program = vec![identity(), expr(call("id", vec![*int(1), *int(2)]))];
+        assert_eq!(run(program), Err("expected 1 arguments but got 2".to_string()));
+
+        let program = vec![decl("x", int(1)), expr(call("x", vec![]))];
+        assert_eq!(run(program), Err("Integer(1) is not a function".to_string()));
+
+        // fun down(n) { ret down(n + 1); } down(0);
+        let down = function("down", &["n"], vec![ret(call("down", vec![*bin(var("n"), BinaryOp::Plus, int(1))]))]);
+        let program = vec![down, expr(call("down", vec![*int(0)]))];
+        assert_eq!(run(program), Err("stack overflow".to_string()));
+    }
+
+    #[test]
+    fn test_frames_the_verifier_rejects() {
+        // called with two arguments that don't fit in its one local
+        let load = |num_locals: usize, num_parameters: usize| {
+            let function = Function { instructions: vec![Instruction::Null, Instruction::Return], num_locals, num_parameters, lines: LineTable::default() };
+            let instructions = vec![Instruction::LoadConstant(0), Instruction::True, Instruction::True, Instruction::Call(2), Instruction::Pop];
+            let bytecode = Bytecode { instructions, constants: vec![Object::Function(Rc::new(function))], globals: vec![], lines: LineTable::default() };
+            VM::new(bytecode).map(|mut vm| vm.run().map_err(|e| e.message))
+        };
+        assert_eq!(load(1, 2).err(), Some("constant 0, instruction 0: 2 parameters don't fit in 1 locals".to_string()));
+        assert_eq!(load(257, 2).err(), Some("constant 0, instruction 0: 257 locals, at most 256 fit in a frame".to_string()));
+        assert_eq!(load(2, 2).ok(), Some(Ok(())));
+    }
+
+    // the vm doesn't check indexes and stack heights itself, bad code never gets that far
+    #[test]
+    fn test_bad_code_is_rejected_before_running() {
+        let load = |instructions: Vec<Instruction>| {
+            VM::new(Bytecode { instructions, constants: vec![], globals: vec![], lines: LineTable::default() }).err()
+        };
+        assert_eq!(load(vec![Instruction::LoadConstant(0), Instruction::Pop]), Some("main, instruction 0: constant 0 doesn't exist, there are 0".to_string()));
+        assert_eq!(load(vec![Instruction::Pop]), Some("main, instruction 0: Pop needs 1 values but the stack has 0".to_string()));
+        assert_eq!(load(vec![Instruction::CurrentFunction, Instruction::Pop]), Some("main, instruction 0: main isn't a function".to_string()));
+        assert_eq!(load(vec![Instruction::Jump(5)]), Some("main, instruction 0: jump to 5 is past the end, there are 1 instructions".to_string()));
+    }
+
+    #[test]
+    fn test_last_popped_on_a_full_stack() {
+        let mut vm = VM::new(Bytecode { instructions: vec![], constants: vec![], globals: vec![], lines: LineTable::default() }).unwrap();
+        vm.sp = STACK_SIZE;
+        assert_eq!(vm.last_popped(), &Object::Null);
+    }
+}